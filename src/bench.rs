@@ -0,0 +1,424 @@
+use crate::config::{ImageOptionsOverflowPolicy, MaxOptionsPerImage};
+use crate::image_ops::image_types::Extensions;
+use crate::image_ops::operations;
+use crate::image_ops::operations::{ProcessingParams, ResizeFilter, ResizeFilters};
+use crate::store::memory_cache::{MemoryCache, MemoryCacheOptions, memory_cache};
+use crate::store::processed_cache::ProcessedImagesCache;
+use crate::store::processed_memory_cache::MemoryProcessedImageCache;
+use crate::utils::striped_lock::DEFAULT_STRIPES;
+use crate::utils::types::{ImageContainer, ImageId};
+use image::DynamicImage;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Combinations to measure, parsed from `bench` arguments
+struct BenchOptions {
+    image_path: String,
+    /// (width, height), height is `None` to keep aspect ratio
+    sizes: Vec<(u32, Option<u32>)>,
+    formats: Vec<Extensions>,
+    /// `None` is default filters pair of server
+    filters: Vec<Option<ResizeFilter>>,
+    qualities: Vec<Option<u32>>,
+    iterations: u32,
+}
+
+fn parse_list<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| parse(v).ok_or_else(|| format!("Invalid value `{}`", v)))
+        .collect()
+}
+
+fn parse_size(value: &str) -> Option<(u32, Option<u32>)> {
+    match value.split_once('x') {
+        Some((width, height)) => Some((width.parse().ok()?, Some(height.parse().ok()?))),
+        None => Some((value.parse().ok()?, None)),
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<BenchOptions, String> {
+    let (image_path, flags) = args.split_first().ok_or("Image path is required")?;
+    let mut options = BenchOptions {
+        image_path: image_path.clone(),
+        sizes: vec![(320, None), (640, None), (1280, None)],
+        formats: vec![Extensions::Webp, Extensions::Avif],
+        filters: vec![None],
+        qualities: vec![None],
+        iterations: 3,
+    };
+
+    for pair in flags.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(format!("Missing value of `{}`", pair[0]));
+        };
+        match flag.as_str() {
+            "--sizes" => options.sizes = parse_list(value, parse_size)?,
+            "--formats" => options.formats = parse_list(value, |v| Extensions::from_str(v).ok())?,
+            "--filters" => {
+                options.filters = parse_list(value, |v| ResizeFilter::from_str(v).ok().map(Some))?
+            }
+            "--qualities" => options.qualities = parse_list(value, |v| v.parse().ok().map(Some))?,
+            "--iterations" => {
+                options.iterations = value
+                    .parse()
+                    .ok()
+                    .filter(|v| *v > 0)
+                    .ok_or("Iterations must be positive number")?
+            }
+            _ => return Err(format!("Unknown flag `{}`", flag)),
+        }
+    }
+    Ok(options)
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn run(options: BenchOptions) -> Result<(), String> {
+    let img: DynamicImage = image::open(&options.image_path)
+        .map_err(|err| format!("Failed to open {}: {}", options.image_path, err))?;
+    println!(
+        "Source {} ({}x{}), {} iterations per combination",
+        options.image_path,
+        img.width(),
+        img.height(),
+        options.iterations
+    );
+    println!(
+        "{:<11} {:<7} {:<10} {:>7} {:>10} {:>10} {:>10}",
+        "size", "format", "filter", "quality", "resize ms", "encode ms", "bytes"
+    );
+
+    for &(width, height) in &options.sizes {
+        let height = height
+            .unwrap_or_else(|| (img.height() as u64 * width as u64 / img.width() as u64) as u32);
+        for &filter in &options.filters {
+            let filters = filter.map(ResizeFilters::single).unwrap_or_default();
+            for &format in &options.formats {
+                for &quality in &options.qualities {
+                    let (mut resize_time, mut encode_time, mut bytes) =
+                        (Duration::ZERO, Duration::ZERO, 0);
+                    for _ in 0..options.iterations {
+                        let start = Instant::now();
+                        let resized = operations::resize::<DynamicImage>(
+                            &img,
+                            Some(width),
+                            Some(height),
+                            None,
+                            None,
+                            filters,
+                            None,
+                        );
+                        resize_time += start.elapsed();
+
+                        let start = Instant::now();
+                        bytes = operations::cast_to_extension::<DynamicImage>(
+                            resized, format, quality, None, None, false,
+                        )
+                        .len();
+                        encode_time += start.elapsed();
+                    }
+                    println!(
+                        "{:<11} {:<7} {:<10} {:>7} {:>10.1} {:>10.1} {:>10}",
+                        format!("{}x{}", width, height),
+                        format.name(),
+                        filter.map_or("default".to_string(), |f| f.to_string()),
+                        quality
+                            .or(operations::default_quality(format))
+                            .map_or("-".to_string(), |quality| quality.to_string()),
+                        millis(resize_time) / options.iterations as f64,
+                        millis(encode_time) / options.iterations as f64,
+                        bytes
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Measure resize and encode throughput and output sizes across combinations of sizes, formats,
+/// filters and qualities, to pick defaults for own content.
+///
+/// Usage: `bench <image> [--sizes 320,640x480] [--formats Webp,Avif,PNG] [--filters Lanczos3,Mitchell]
+/// [--qualities 60,82] [--iterations 3]`. Returns whether benchmark was run
+pub fn bench(args: &[String]) -> bool {
+    let result = parse_args(args).and_then(run);
+    if let Err(err) = &result {
+        eprintln!("{}", err);
+    }
+    result.is_ok()
+}
+
+/// Memory cache contention benchmark settings, parsed from `bench-cache` arguments
+struct CacheBenchOptions {
+    shards: Vec<usize>,
+    threads: usize,
+    capacity: usize,
+    /// Operations per thread
+    operations: usize,
+}
+
+fn parse_cache_args(args: &[String]) -> Result<CacheBenchOptions, String> {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut options = CacheBenchOptions {
+        shards: vec![1, cores, cores * 4, cores * 16],
+        threads: cores,
+        capacity: 1024,
+        operations: 1_000_000,
+    };
+
+    let positive = |value: &str, name: &str| {
+        value
+            .parse()
+            .ok()
+            .filter(|v| *v > 0)
+            .ok_or(format!("{} must be positive number", name))
+    };
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(format!("Missing value of `{}`", pair[0]));
+        };
+        match flag.as_str() {
+            "--shards" => {
+                options.shards = parse_list(value, |v| v.parse().ok().filter(|v| *v > 0))?
+            }
+            "--threads" => options.threads = positive(value, "Threads")?,
+            "--capacity" => options.capacity = positive(value, "Capacity")?,
+            "--operations" => options.operations = positive(value, "Operations")?,
+            _ => return Err(format!("Unknown flag `{}`", flag)),
+        }
+    }
+    Ok(options)
+}
+
+fn run_cache(options: CacheBenchOptions) -> Result<(), String> {
+    // twice as many ids as capacity, so there are both hits and evictions
+    let image_ids: Vec<ImageId> = (0..options.capacity * 2)
+        .map(|i| format!("image-{}", i))
+        .collect();
+    let data = Arc::new(vec![0u8; 1024]);
+    println!(
+        "{} threads, {} operations per thread (90% reads), capacity {}",
+        options.threads, options.operations, options.capacity
+    );
+    println!("{:<8} {:>12} {:>9}", "shards", "Mops/sec", "hit rate");
+
+    for &shards in &options.shards {
+        let cache: MemoryCache<ImageId, Arc<Vec<u8>>> = memory_cache(
+            options.capacity,
+            &MemoryCacheOptions {
+                shards: Some(shards),
+                ..Default::default()
+            },
+        );
+        let hits = AtomicUsize::new(0);
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..options.threads {
+                scope.spawn(|| {
+                    let mut rng = fastrand::Rng::new();
+                    let mut thread_hits = 0;
+                    for _ in 0..options.operations {
+                        let image_id = &image_ids[rng.usize(..image_ids.len())];
+                        match rng.u8(..10) {
+                            0 => cache.insert(image_id.clone(), data.clone()),
+                            _ => thread_hits += cache.get(image_id).is_some() as usize,
+                        }
+                    }
+                    hits.fetch_add(thread_hits, Ordering::Relaxed);
+                });
+            }
+        });
+        let elapsed = start.elapsed();
+        let total = (options.threads * options.operations) as f64;
+        println!(
+            "{:<8} {:>12.2} {:>8.1}%",
+            shards,
+            total / elapsed.as_secs_f64() / 1_000_000.0,
+            hits.load(Ordering::Relaxed) as f64 / (total * 0.9) * 100.0
+        );
+    }
+    Ok(())
+}
+
+/// Measure throughput of memory caches under concurrent access with different shard counts, to
+/// tune `MEMORY_CACHE_SHARDS` for own hardware.
+///
+/// Usage: `bench-cache [--shards 1,8,32] [--threads 8] [--capacity 1024] [--operations 1000000]`.
+/// Returns whether benchmark was run
+pub fn bench_cache(args: &[String]) -> bool {
+    let result = parse_cache_args(args).and_then(run_cache);
+    if let Err(err) = &result {
+        eprintln!("{}", err);
+    }
+    result.is_ok()
+}
+
+/// Processed cache write contention benchmark settings, parsed from `bench-cache-locks` arguments
+struct LocksBenchOptions {
+    /// Counts of set locks, 1 is single lock for all images
+    stripes: Vec<usize>,
+    threads: usize,
+    /// Concurrently writing tasks
+    tasks: usize,
+    /// Sets per task, each of distinct image
+    operations: usize,
+}
+
+fn parse_locks_args(args: &[String]) -> Result<LocksBenchOptions, String> {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut options = LocksBenchOptions {
+        stripes: vec![1, DEFAULT_STRIPES],
+        threads: cores,
+        tasks: cores * 4,
+        operations: 10_000,
+    };
+
+    let positive = |value: &str, name: &str| {
+        value
+            .parse()
+            .ok()
+            .filter(|v| *v > 0)
+            .ok_or(format!("{} must be positive number", name))
+    };
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(format!("Missing value of `{}`", pair[0]));
+        };
+        match flag.as_str() {
+            "--stripes" => {
+                options.stripes = parse_list(value, |v| v.parse().ok().filter(|v| *v > 0))?
+            }
+            "--threads" => options.threads = positive(value, "Threads")?,
+            "--tasks" => options.tasks = positive(value, "Tasks")?,
+            "--operations" => options.operations = positive(value, "Operations")?,
+            _ => return Err(format!("Unknown flag `{}`", flag)),
+        }
+    }
+    Ok(options)
+}
+
+fn run_locks(options: LocksBenchOptions) -> Result<(), String> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(options.threads)
+        .build()
+        .map_err(|err| format!("Failed to start runtime: {}", err))?;
+    let image = Arc::new(ImageContainer::new(
+        Box::new(vec![0u8; 1024]),
+        None,
+        Extensions::Webp,
+    ));
+    let params = ProcessingParams {
+        width: Some(100),
+        ..Default::default()
+    };
+    println!(
+        "{} threads, {} tasks, {} sets of distinct images per task",
+        options.threads, options.tasks, options.operations
+    );
+    println!("{:<8} {:>12}", "stripes", "Ksets/sec");
+
+    for &stripes in &options.stripes {
+        let total = options.tasks * options.operations;
+        let cache = Arc::new(
+            MemoryProcessedImageCache::new(
+                NonZeroUsize::new(total),
+                MaxOptionsPerImage::new(NonZeroUsize::new(1).unwrap(), Default::default()),
+                ImageOptionsOverflowPolicy::Rewrite,
+                &MemoryCacheOptions::default(),
+            )
+            .with_lock_stripes(stripes),
+        );
+        let elapsed = rt.block_on(async {
+            let start = Instant::now();
+            let tasks: Vec<_> = (0..options.tasks)
+                .map(|task| {
+                    let (cache, image, params) = (cache.clone(), image.clone(), params.clone());
+                    tokio::spawn(async move {
+                        for i in 0..options.operations {
+                            let image_id = format!("image-{}-{}", task, i);
+                            let _ = cache
+                                .set(image_id, params.clone(), image.clone(), false)
+                                .await;
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            start.elapsed()
+        });
+        println!(
+            "{:<8} {:>12.1}",
+            stripes,
+            total as f64 / elapsed.as_secs_f64() / 1000.0
+        );
+    }
+    Ok(())
+}
+
+/// Measure throughput of concurrent processed cache sets of distinct images with single set lock
+/// and striped ones, to check gain of lock striping on own hardware.
+///
+/// Usage: `bench-cache-locks [--stripes 1,64] [--threads 8] [--tasks 32] [--operations 10000]`.
+/// Returns whether benchmark was run
+pub fn bench_cache_locks(args: &[String]) -> bool {
+    let result = parse_locks_args(args).and_then(run_locks);
+    if let Err(err) = &result {
+        eprintln!("{}", err);
+    }
+    result.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn tiny_benchmark_runs() {
+        let image = testing::temp_file("bench.png", &testing::png(16, 8));
+        assert!(bench(&args(&[
+            &image,
+            "--sizes",
+            "8,4x4",
+            "--formats",
+            "Webp,PNG",
+            "--filters",
+            "Nearest",
+            "--iterations",
+            "1",
+        ])));
+
+        assert!(!bench(&args(&[&image, "--sizes", "big"])));
+        assert!(!bench(&args(&["missing.png", "--iterations", "1"])));
+    }
+
+    #[test]
+    fn tiny_locks_benchmark_runs() {
+        assert!(bench_cache_locks(&args(&[
+            "--stripes",
+            "1,4",
+            "--threads",
+            "2",
+            "--tasks",
+            "4",
+            "--operations",
+            "10",
+        ])));
+        assert!(!bench_cache_locks(&args(&["--stripes", "0"])));
+    }
+}
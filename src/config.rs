@@ -1,24 +1,43 @@
 use crate::image_ops::image_types::Extensions;
-use crate::image_ops::processing::Processor;
-use crate::proxying_images::{FileApiBackend, SimpleFileApiBackend};
-use crate::store::persistent_store::PersistentStore;
+use crate::image_ops::operations;
+use crate::image_ops::operations::{ProcessingParams, ResizeFilter, ResizeFilters};
+use crate::image_ops::processing::{Processor, ProcessorOptions};
+use crate::image_ops::transforms;
+use crate::image_ops::transforms::ImageTransform;
+use crate::proxying_images::{
+    ChainedFileApiBackend, FileApiBackend, SimpleFileApiBackend, is_private_ip,
+};
+use crate::routes::concurrency::PreloadLimiter;
+use crate::store::memory_cache::{MemoryCacheOptions, PinnedImages};
+use crate::store::persistent_store::{CompactionSchedule, HoursWindow, PersistentStore};
 use crate::store::procesessed_persistent_cache::PersistentProcessedImageCache;
 use crate::store::processed_cache::ProcessedImagesCache;
 use crate::store::processed_memory_cache::MemoryProcessedImageCache;
-use crate::store::source_image_storage::{CachingStorage, OriginalImageStorage, PersistentStorage};
+use crate::store::source_image_storage::{
+    CachingStorage, OriginalImageStorage, PersistentStorage, TieredStorage, TieredWritePolicy,
+};
+use crate::utils::types::ImageId;
+use crate::warm;
 use envconfig;
 use envconfig::Envconfig;
+use http::StatusCode;
 use log::info;
+use sanitize_filename::sanitize;
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::num::NonZeroUsize;
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
-use strum::EnumString;
+use std::time::Duration;
+use strum::{EnumString, IntoEnumIterator};
 
 #[derive(Clone, EnumString, strum::Display, Eq, PartialEq)]
 pub enum StorageImplementation {
     InMemory,
     Persistent,
+    /// In memory layer over persistent one
+    Tiered,
 }
 
 #[derive(Clone, EnumString, strum::Display, Eq, PartialEq)]
@@ -33,9 +52,474 @@ pub enum ImageOptionsOverflowPolicy {
     Rewrite,
 }
 
+/// Behaviour on requesting width or height, which is not in allowed list
+#[derive(Clone, EnumString, strum::Display, Eq, PartialEq)]
+pub enum AllowedSizesPolicy {
+    /// Snap to the nearest allowed value
+    Snap,
+    /// Reject request with error
+    Reject,
+}
+
+/// Behaviour on requesting animation frame after the last one
+#[derive(Clone, Copy, EnumString, strum::Display, Eq, PartialEq)]
+pub enum FrameOutOfRangePolicy {
+    /// Serve the last frame
+    Last,
+    /// Reject request with error
+    Reject,
+}
+
+/// Size of the missing dimension, when only width or height is requested
+#[derive(Clone, Copy, EnumString, strum::Display, Eq, PartialEq)]
+pub enum SingleDimensionPolicy {
+    /// Compute it from source aspect ratio, so image is scaled without cropping
+    PreserveRatio,
+    /// Keep source width or height
+    SourceDimension,
+}
+
+impl SingleDimensionPolicy {
+    /// Resulting size of image with `source` dimensions
+    pub fn target_size(
+        self,
+        (source_width, source_height): (u32, u32),
+        width: Option<u32>,
+        height: Option<u32>,
+    ) -> (u32, u32) {
+        let scaled = |value: u32, from: u32, to: u32| {
+            ((value as f64 * to as f64 / from.max(1) as f64).round() as u32).max(1)
+        };
+        match (self, width, height) {
+            (_, Some(width), Some(height)) => (width, height),
+            (SingleDimensionPolicy::PreserveRatio, Some(width), None) => {
+                (width, scaled(width, source_width, source_height))
+            }
+            (SingleDimensionPolicy::PreserveRatio, None, Some(height)) => {
+                (scaled(height, source_height, source_width), height)
+            }
+            _ => (
+                width.unwrap_or(source_width),
+                height.unwrap_or(source_height),
+            ),
+        }
+    }
+}
+
+/// Sorted list of allowed dimension values, empty list allows any value
+#[derive(Clone, Default)]
+pub struct AllowedSizes(Vec<u32>);
+
+impl AllowedSizes {
+    /// Check value is in list, any value is allowed for empty list
+    pub fn is_allowed(&self, value: u32) -> bool {
+        self.0.is_empty() || self.0.binary_search(&value).is_ok()
+    }
+
+    /// Nearest allowed value (larger one on tie), None if any value is allowed
+    pub fn nearest(&self, value: u32) -> Option<u32> {
+        self.0
+            .iter()
+            .copied()
+            .min_by_key(|allowed| (allowed.abs_diff(value), u32::MAX - allowed))
+    }
+}
+
+impl FromStr for AllowedSizes {
+    type Err = ParseSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sizes = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            match item.parse::<u32>() {
+                Ok(size) if size > 0 => sizes.push(size),
+                _ => {
+                    return Err(ParseSizeError {
+                        msg: format!("Expected list of sizes \"320,640\", got {}", s),
+                    });
+                }
+            }
+        }
+        sizes.sort();
+        sizes.dedup();
+        Ok(AllowedSizes(sizes))
+    }
+}
+
+/// Named sizes in form `thumb=160x160,card=640x`, either width or height may be omitted
+#[derive(Clone, Default)]
+pub struct SizePresets(Vec<(String, Option<u32>, Option<u32>)>);
+
+impl SizePresets {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Width and height of preset
+    pub fn get(&self, name: &str) -> Option<(Option<u32>, Option<u32>)> {
+        self.0
+            .iter()
+            .find(|(preset, _, _)| preset == name)
+            .map(|(_, width, height)| (*width, *height))
+    }
+
+    /// Whether any preset has the width
+    pub fn has_width(&self, width: u32) -> bool {
+        self.0.iter().any(|(_, preset, _)| *preset == Some(width))
+    }
+}
+
+impl FromStr for SizePresets {
+    type Err = ParseSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let dimension = |value: &str| match value.trim() {
+            "" => Some(None),
+            value => value.parse::<u32>().ok().filter(|v| *v > 0).map(Some),
+        };
+        let mut presets = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let parsed = item.split_once('=').and_then(|(name, size)| {
+                let (width, height) = size.split_once('x')?;
+                Some((name.trim(), dimension(width)?, dimension(height)?))
+            });
+            match parsed {
+                Some((name, width, height))
+                    if !name.is_empty() && (width.is_some() || height.is_some()) =>
+                {
+                    presets.push((name.to_string(), width, height))
+                }
+                _ => {
+                    return Err(ParseSizeError {
+                        msg: format!("Expected \"name=WIDTHxHEIGHT\", got {}", item),
+                    });
+                }
+            }
+        }
+        Ok(SizePresets(presets))
+    }
+}
+
+/// Limit of processed options per image, with overrides by image id prefix
+#[derive(Clone)]
+pub struct MaxOptionsPerImage {
+    default: NonZeroUsize,
+    /// sorted by prefix length (longest first), so most specific prefix wins
+    overrides: Vec<(String, NonZeroUsize)>,
+}
+
+impl MaxOptionsPerImage {
+    pub fn new(default: NonZeroUsize, overrides: OptionsLimitOverrides) -> Self {
+        let mut overrides = overrides.0;
+        overrides.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        MaxOptionsPerImage { default, overrides }
+    }
+
+    pub fn for_image(&self, image_id: &ImageId) -> NonZeroUsize {
+        self.overrides
+            .iter()
+            .find(|(prefix, _)| image_id.starts_with(prefix.as_str()))
+            .map_or(self.default, |(_, limit)| *limit)
+    }
+}
+
+/// Overrides of max options per image in form `prefix=limit,prefix=limit`
+#[derive(Clone, Default)]
+pub struct OptionsLimitOverrides(Vec<(String, NonZeroUsize)>);
+
+pub struct ParseOptionsLimitOverridesError {
+    #[allow(dead_code)]
+    msg: String,
+}
+
+impl FromStr for OptionsLimitOverrides {
+    type Err = ParseOptionsLimitOverridesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut overrides = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let parsed = item
+                .rsplit_once('=')
+                .and_then(|(prefix, limit)| Some((prefix, limit.trim().parse().ok()?)))
+                .filter(|(prefix, _)| !prefix.is_empty());
+            match parsed {
+                Some((prefix, limit)) => overrides.push((prefix.to_string(), limit)),
+                None => {
+                    return Err(ParseOptionsLimitOverridesError {
+                        msg: format!("Expected \"prefix=limit\", got {}", item),
+                    });
+                }
+            }
+        }
+        Ok(OptionsLimitOverrides(overrides))
+    }
+}
+
+/// Quality, below which requested quality is clamped, if format has no own floor
+pub const DEFAULT_MIN_QUALITY: u32 = 10;
+
+/// Per-format quality in form `Webp=30,Avif=40`
+#[derive(Clone, Default)]
+pub struct QualityPerFormat(Vec<(Extensions, u32)>);
+
+impl QualityPerFormat {
+    pub fn get(&self, extension: Extensions) -> Option<u32> {
+        self.0
+            .iter()
+            .find(|(ext, _)| *ext == extension)
+            .map(|(_, quality)| *quality)
+    }
+}
+
+pub struct ParseQualityPerFormatError {
+    #[allow(dead_code)]
+    msg: String,
+}
+
+impl FromStr for QualityPerFormat {
+    type Err = ParseQualityPerFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut qualities = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let parsed = item.split_once('=').and_then(|(ext, quality)| {
+                Some((
+                    Extensions::from_str(ext.trim()).ok()?,
+                    quality.trim().parse().ok()?,
+                ))
+            });
+            match parsed {
+                Some((ext, quality)) if (1..=100).contains(&quality) => {
+                    qualities.push((ext, quality))
+                }
+                _ => {
+                    return Err(ParseQualityPerFormatError {
+                        msg: format!("Expected \"extension=quality\" (1-100), got {}", item),
+                    });
+                }
+            }
+        }
+        Ok(QualityPerFormat(qualities))
+    }
+}
+
+/// Per-format encode timeout (in seconds, 0 - unlimited) in form `Avif=10,Webp=3`
+#[derive(Clone, Default)]
+pub struct EncodeTimeouts(Vec<(Extensions, Option<Duration>)>);
+
+impl EncodeTimeouts {
+    pub fn get(&self, extension: Extensions) -> Option<Duration> {
+        self.0
+            .iter()
+            .find(|(ext, _)| *ext == extension)
+            .and_then(|(_, timeout)| *timeout)
+    }
+
+    /// Set timeout of format, which has no own one
+    fn with_default(mut self, extension: Extensions, timeout: Option<Duration>) -> Self {
+        if !self.0.iter().any(|(ext, _)| *ext == extension) {
+            self.0.push((extension, timeout));
+        }
+        self
+    }
+}
+
+pub struct ParseEncodeTimeoutsError {
+    #[allow(dead_code)]
+    msg: String,
+}
+
+impl FromStr for EncodeTimeouts {
+    type Err = ParseEncodeTimeoutsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut timeouts = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let parsed = item.split_once('=').and_then(|(ext, secs)| {
+                Some((
+                    Extensions::from_str(ext.trim()).ok()?,
+                    secs.trim().parse::<u64>().ok()?,
+                ))
+            });
+            match parsed {
+                Some((ext, secs)) => {
+                    timeouts.push((ext, (secs > 0).then(|| Duration::from_secs(secs))))
+                }
+                None => {
+                    return Err(ParseEncodeTimeoutsError {
+                        msg: format!("Expected \"extension=seconds\", got {}", item),
+                    });
+                }
+            }
+        }
+        Ok(EncodeTimeouts(timeouts))
+    }
+}
+
+/// Preference order of extensions for `auto` extension, in form `Avif,Webp,PNG`
+#[derive(Clone)]
+pub struct FormatPriority(pub Vec<Extensions>);
+
+pub struct ParseFormatPriorityError {
+    #[allow(dead_code)]
+    msg: String,
+}
+
+impl FromStr for FormatPriority {
+    type Err = ParseFormatPriorityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut priority = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            match Extensions::from_str(item) {
+                Ok(ext) if !priority.contains(&ext) => priority.push(ext),
+                Ok(_) => {}
+                Err(_) => {
+                    return Err(ParseFormatPriorityError {
+                        msg: format!("Expected one of Webp, Avif, PNG, got {}", item),
+                    });
+                }
+            }
+        }
+        if priority.is_empty() {
+            return Err(ParseFormatPriorityError {
+                msg: "At least one extension is required".to_string(),
+            });
+        }
+        Ok(FormatPriority(priority))
+    }
+}
+
+/// Ordered names of custom transforms, in form `sepia,watermark`
+#[derive(Clone, Default)]
+pub struct CustomTransforms(Vec<Arc<dyn ImageTransform>>);
+
+pub struct ParseCustomTransformsError {
+    #[allow(dead_code)]
+    msg: String,
+}
+
+impl FromStr for CustomTransforms {
+    type Err = ParseCustomTransformsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let registered = transforms::registered_transforms();
+        let mut enabled = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            match registered.iter().find(|transform| transform.name() == name) {
+                Some(transform) => enabled.push(transform.clone()),
+                None => {
+                    return Err(ParseCustomTransformsError {
+                        msg: format!("Unknown transform {}", name),
+                    });
+                }
+            }
+        }
+        Ok(CustomTransforms(enabled))
+    }
+}
+
+/// Processing params of variants, separated by `;`, like `w=320&fmt=Avif;w=640`
+#[derive(Clone, Default)]
+pub struct WarmVariants(Vec<ProcessingParams>);
+
+impl WarmVariants {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ProcessingParams> {
+        self.0.iter()
+    }
+}
+
+pub struct ParseWarmVariantsError {
+    #[allow(dead_code)]
+    msg: String,
+}
+
+impl FromStr for WarmVariants {
+    type Err = ParseWarmVariantsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(|query| warm::parse_params(query).map_err(|msg| ParseWarmVariantsError { msg }))
+            .collect::<Result<Vec<_>, _>>()
+            .map(WarmVariants)
+    }
+}
+
+/// Glob patterns of image ids (`*` matches any chars, `?` matches single char), like `avatar_*,*.png`
+#[derive(Clone, Default)]
+pub struct ImageIdPatterns(Vec<String>);
+
+impl ImageIdPatterns {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any of patterns matches whole id
+    pub fn matches(&self, image_id: &str) -> bool {
+        self.0.iter().any(|pattern| glob_matches(pattern, image_id))
+    }
+}
+
+/// Match glob pattern, backtracking to the last `*` on mismatch
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let (pattern, value): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), value.chars().collect());
+    let (mut p, mut v) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, v));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match last_star {
+                // let star consume one more char
+                Some((star_p, star_v)) => {
+                    last_star = Some((star_p, star_v + 1));
+                    p = star_p + 1;
+                    v = star_v + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl FromStr for ImageIdPatterns {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ImageIdPatterns(
+            s.split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+}
+
+#[derive(Clone, EnumString, strum::Display, Eq, PartialEq)]
+pub enum AccessLogFormat {
+    Off,
+    Combined,
+    Json,
+}
+
 pub struct Size {
-    width: u32,
-    height: u32,
+    pub width: u32,
+    pub height: u32,
 }
 
 impl Size {
@@ -93,13 +577,54 @@ struct EnvConfig {
     pub host: String,
     #[envconfig(from = "PORT", default = "3021")]
     pub port: u32,
+    /// Listen on unix domain socket at this path instead of HOST:PORT
+    #[envconfig(from = "LISTEN_UDS")]
+    pub listen_uds: Option<String>,
 
     // ------------------
     // Fetching from base api and prefetching
+    /// Comma separated list of file apis, tried in order until image is found
     #[envconfig(from = "BASE_FILE_API_URL")]
     base_file_api_url: Option<String>,
     #[envconfig(from = "BASE_FILE_API_URL_TIMEOUT", default = "30")]
     base_file_api_timeout: u32,
+    /// Max count of fetches from base api at once, excess ones wait for free slot. 0 disables limit
+    #[envconfig(from = "MAX_CONCURRENT_ORIGIN_FETCHES", default = "0")]
+    max_concurrent_origin_fetches: usize,
+    /// Seconds to answer 404 for image ids not found in base api, without refetching. 0 disables it
+    #[envconfig(from = "NOT_FOUND_CACHE_TTL", default = "10")]
+    not_found_cache_ttl: u64,
+    /// Seconds after fetch from base api, after which stored original is revalidated (by conditional
+    /// request) on access and replaced, if it's changed. 0 disables it
+    #[envconfig(from = "ORIGIN_REVALIDATE_AGE", default = "0")]
+    origin_revalidate_age: u64,
+    /// Serve cached processed images of originals due for revalidation at once (as stale ones),
+    /// revalidating them in background. Otherwise cached images are served without revalidation
+    #[envconfig(from = "STALE_WHILE_REVALIDATE", default = "false")]
+    stale_while_revalidate: bool,
+    /// JSON pointer to changed image id (or array of them) in payload of `/webhook/origin-updated`
+    #[envconfig(from = "ORIGIN_WEBHOOK_IDS_POINTER", default = "/image_ids")]
+    origin_webhook_ids_pointer: String,
+    /// Variants (processing params queries separated by `;`), processed again for images changed on origin
+    #[envconfig(from = "ORIGIN_WEBHOOK_WARM_VARIANTS", default = "")]
+    origin_webhook_warm_variants: WarmVariants,
+    /// Max timeout (in seconds) for per-request `fetch_timeout` override
+    #[envconfig(from = "MAX_FETCH_TIMEOUT", default = "120")]
+    pub max_fetch_timeout: u32,
+    /// User-Agent of requests to base api (default `imgr-serve/{version}`)
+    #[envconfig(from = "FILE_API_USER_AGENT")]
+    file_api_user_agent: Option<String>,
+    /// Allow fetching from private network addresses (loopback, private and link-local ranges),
+    /// required for base api in internal network
+    #[envconfig(from = "ALLOW_PRIVATE_ORIGIN_IPS", default = "false")]
+    allow_private_origin_ips: bool,
+    /// Max redirects of base api followed per fetch. 0 disables redirects, treating them as errors
+    #[envconfig(from = "MAX_ORIGIN_REDIRECTS", default = "5")]
+    max_origin_redirects: usize,
+    /// Max size (in bytes) of originals fetched from base api, larger ones are rejected without
+    /// downloading them completely. 0 disables limit
+    #[envconfig(from = "MAX_ORIGIN_BYTES", default = "0")]
+    max_origin_bytes: usize,
     #[envconfig(from = "API_KEY", default = "")]
     pub api_key: String,
 
@@ -107,6 +632,12 @@ struct EnvConfig {
     // Caching settings settings
     #[envconfig(from = "STORAGE_IMPLEMENTATION", default = "InMemory")]
     pub storage_implementation: StorageImplementation,
+    /// When originals are written to persistent layer of Tiered storage: WriteThrough or WriteBack
+    #[envconfig(from = "TIERED_STORAGE_WRITE_POLICY", default = "WriteThrough")]
+    pub tiered_storage_write_policy: TieredWritePolicy,
+    /// Compress originals of uncompressed formats (like BMP) in persistent storage
+    #[envconfig(from = "COMPRESS_PERSISTENT_ORIGINALS", default = "false")]
+    pub compress_persistent_originals: bool,
     #[envconfig(from = "PROCESSING_CACHE_IMPLEMENTATION", default = "InMemory")]
     pub processing_cache_implementation: ProcessingCacheImplementation,
     /// Count of original images cached in memory
@@ -115,18 +646,84 @@ struct EnvConfig {
     /// Count of processed images (after resize, crop and etc) stored in memory
     #[envconfig(from = "PROCESSING_CACHE_SIZE", default = "1024")]
     pub processing_cache_size: NonZeroUsize,
+    /// Max count of processed versions of all images in persistent processing cache, the least recently used
+    /// ones are evicted across images on exceeding it. 0 - unlimited
+    #[envconfig(from = "MAX_PROCESSED_VARIANTS", default = "0")]
+    pub max_processed_variants: usize,
+    /// Comma separated image ids, which originals and processed versions are never evicted from memory
+    #[envconfig(from = "PINNED_IMAGES", default = "")]
+    pub pinned_images: String,
+    /// Count of independently locked parts of memory caches, to lower contention on many cores. 0 is 4 per core
+    #[envconfig(from = "MEMORY_CACHE_SHARDS", default = "0")]
+    pub memory_cache_shards: usize,
+    /// Part of memory caches capacity (0-1) for frequently used images, the rest is for recently used ones
+    #[envconfig(from = "MEMORY_CACHE_HOT_ALLOCATION", default = "0.97")]
+    pub memory_cache_hot_allocation: f64,
+    /// Originals fetched from base api larger than this (in bytes) are served, but not stored,
+    /// to not evict many small images. 0 disables limit
+    #[envconfig(from = "MAX_CACHEABLE_ORIGINAL_BYTES", default = "0")]
+    pub max_cacheable_original_bytes: usize,
     /// Persistent db location (directory) for both processing and storage cache
     #[envconfig(from = "PERSISTENT_STORAGE_DIR", default = ".imgr-serve")]
     pub persistent_storage_dir: String,
+    /// Min time (in seconds) between compactions of persistent db, dropping tombstones of
+    /// evicted and invalidated entries. 0 disables compaction
+    #[envconfig(from = "COMPACTION_INTERVAL_SECONDS", default = "86400")]
+    pub compaction_interval_seconds: u64,
+    /// UTC hours (`start-end`, like `2-5`) of low traffic, compaction is postponed until them. Empty allows any time
+    #[envconfig(from = "COMPACTION_HOURS")]
+    pub compaction_hours: Option<HoursWindow>,
 
     // ------------------
     // Processing settings
     /// Client cache (in browser) duration (in seconds) for served images
     #[envconfig(from = "CLIENT_CACHE_TTL", default = "31536000")]
     pub client_cache_ttl: usize,
+    /// Interpolation filter on upscaling: Nearest, Bilinear, Hamming, CatmullRom, Mitchell, Gaussian, Lanczos3
+    #[envconfig(from = "UPSCALE_FILTER", default = "Mitchell")]
+    pub upscale_filter: ResizeFilter,
+    /// Interpolation filter on downscaling
+    #[envconfig(from = "DOWNSCALE_FILTER", default = "Lanczos3")]
+    pub downscale_filter: ResizeFilter,
+    /// Max count of avif encodes at once, so they can't starve cheap webp ones of blocking threads. 0 disables limit
+    #[envconfig(from = "MAX_CONCURRENT_AVIF_ENCODES", default = "2")]
+    pub max_concurrent_avif_encodes: usize,
+    /// Serve WebP instead of AVIF, if encoding it failed or exceeded `AVIF_ENCODE_TIMEOUT`
+    #[envconfig(from = "AVIF_FALLBACK_TO_WEBP", default = "true")]
+    pub avif_fallback_to_webp: bool,
+    /// Max seconds of AVIF encode before falling back to WebP. 0 disables limit
+    #[envconfig(from = "AVIF_ENCODE_TIMEOUT", default = "10")]
+    pub avif_encode_timeout: u64,
+    /// Max seconds of encode per format (`Webp=3,PNG=5`), exceeding ones fail. Avif one overrides
+    /// `AVIF_ENCODE_TIMEOUT`. Formats without own timeout are unlimited
+    #[envconfig(from = "ENCODE_TIMEOUTS", default = "")]
+    pub encode_timeouts: EncodeTimeouts,
+    /// Max difference of color channel (0-255) from border color, to consider pixel as border on `trim`
+    #[envconfig(from = "TRIM_TOLERANCE", default = "10")]
+    pub trim_tolerance: u8,
     /// Max image resulting size after resize (width,height)
     #[envconfig(from = "MAX_IMAGE_RESIZE", default = "1920,1080")]
     pub max_image_resize: Size,
+    /// Max aspect ratio distortion (resulting ratio to source ratio, e.g. 2.0) for Resize ratio policy.
+    /// Requests exceeding it are rejected. 0 disables check
+    #[envconfig(from = "MAX_RESIZE_DISTORTION", default = "0")]
+    pub max_resize_distortion: f64,
+    /// Allowed widths (comma separated), requested width is snapped to the nearest one.
+    /// Bounds count of processed variants for responsive images (srcset). Empty allows any width
+    #[envconfig(from = "ALLOWED_WIDTHS", default = "")]
+    pub allowed_widths: AllowedSizes,
+    /// Allowed heights (comma separated). Empty allows any height
+    #[envconfig(from = "ALLOWED_HEIGHTS", default = "")]
+    pub allowed_heights: AllowedSizes,
+    /// Behaviour on requesting not allowed width or height: Snap (to the nearest allowed) or Reject
+    #[envconfig(from = "ALLOWED_SIZES_POLICY", default = "Snap")]
+    pub allowed_sizes_policy: AllowedSizesPolicy,
+    /// Named sizes, requested by `preset` param (`thumb=160x160,card=640x`)
+    #[envconfig(from = "SIZE_PRESETS", default = "")]
+    pub size_presets: SizePresets,
+    /// Reject raw `width`, `height` and `dpr`, so only `SIZE_PRESETS` are processed (for public deployments)
+    #[envconfig(from = "PRESET_SIZES_ONLY", default = "false")]
+    pub preset_sizes_only: bool,
 
     /// Default resulting extension
     #[envconfig(from = "DEFAULT_EXTENSION", default = "Webp")]
@@ -134,54 +731,381 @@ struct EnvConfig {
     /// Allow custom extensions (if false, only DEFAULT_EXTENSION will be returned)
     #[envconfig(from = "ALLOW_CUSTOM_EXTENSION", default = "true")]
     pub allow_custom_extension: bool,
+    /// Min quality per format (`Webp=30,Avif=40`), lower requested quality is clamped to it.
+    /// Formats without floor are clamped to 10
+    #[envconfig(from = "MIN_QUALITY", default = "")]
+    pub min_quality: QualityPerFormat,
+    /// Quality per lossy format (`Webp=82,Avif=92`), used when request has no quality.
+    /// Formats without own default use built-in one (82 for Webp, 92 for Avif)
+    #[envconfig(from = "DEFAULT_QUALITY", default = "")]
+    pub default_quality: QualityPerFormat,
+    /// Preference order of extensions, negotiated for `auto` extension by `Accept` header of client
+    #[envconfig(from = "FORMAT_PRIORITY", default = "Avif,Webp,PNG")]
+    pub format_priority: FormatPriority,
 
     /// Restrict max options (size, extensions and etc) per image
     /// This option prevents poisoning processing cache with insufficient options
     #[envconfig(from = "MAX_OPTIONS_PER_IMAGE", default = "32")]
     pub max_options_per_image: NonZeroUsize,
+    /// Override MAX_OPTIONS_PER_IMAGE for image ids starting with prefix (`prefix=limit,prefix=limit`)
+    #[envconfig(from = "MAX_OPTIONS_PER_IMAGE_OVERRIDES", default = "")]
+    pub max_options_per_image_overrides: OptionsLimitOverrides,
     /// Behaviour on exceeding limit of MAX_OPTIONS_PER_IMAGE.
     /// by default it will be rewrite it as LRU cache
     #[envconfig(from = "MAX_OPTIONS_PER_IMAGE_OVERFLOW_POLICY", default = "Rewrite")]
     pub max_options_per_image_overflow_policy: ImageOptionsOverflowPolicy,
 
+    /// Image, served (resized per request) instead of JSON error, when requested image is not found
+    #[envconfig(from = "FALLBACK_IMAGE_PATH")]
+    pub fallback_image_path: Option<String>,
+    /// Http status of fallback image response
+    #[envconfig(from = "FALLBACK_IMAGE_STATUS", default = "404")]
+    pub fallback_image_status: u16,
+
+    /// Trim whitespace around requested image ids
+    #[envconfig(from = "IMAGE_ID_TRIM", default = "true")]
+    pub image_id_trim: bool,
+    /// Lowercase requested image ids, so ids differing only by case share cache.
+    /// Should be enabled only for case-insensitive base api
+    #[envconfig(from = "IMAGE_ID_LOWERCASE", default = "false")]
+    pub image_id_lowercase: bool,
+    /// Comma separated glob patterns of image ids, allowed to be served and preloaded. Empty allows any id
+    #[envconfig(from = "ALLOWED_IMAGE_IDS", default = "")]
+    pub allowed_image_ids: ImageIdPatterns,
+    /// Comma separated glob patterns of image ids, denied even if they match allowed ones
+    #[envconfig(from = "DENIED_IMAGE_IDS", default = "")]
+    pub denied_image_ids: ImageIdPatterns,
+
+    /// Filename of served image (without extension), if original filename is unknown.
+    /// `{id}` is replaced with image id
+    #[envconfig(from = "DEFAULT_FILENAME_PATTERN", default = "image")]
+    pub default_filename_pattern: String,
+
     /// Enable OpenAPI and Swagger docs routes
     #[envconfig(from = "ENABLE_DOCS", default = "true")]
     pub enable_docs: bool,
+    /// Enable `/images/{id}/debug` route, describing processing result. Intended for development
+    #[envconfig(from = "ENABLE_DEBUG_ENDPOINTS", default = "false")]
+    pub enable_debug_endpoints: bool,
+    /// Max count of requests in flight, exceeding ones are rejected with 503. 0 disables limit
+    #[envconfig(from = "MAX_CONCURRENT_REQUESTS", default = "0")]
+    pub max_concurrent_requests: usize,
+    /// Max count of preloads in flight, exceeding ones are rejected with 429. 0 disables limit
+    #[envconfig(from = "MAX_CONCURRENT_PRELOADS", default = "0")]
+    pub max_concurrent_preloads: usize,
+    /// Max bytes of originals ingested by preloads per minute, exceeding ones are rejected with 429.
+    /// 0 disables limit
+    #[envconfig(from = "MAX_PRELOAD_BYTES_PER_MINUTE", default = "0")]
+    pub max_preload_bytes_per_minute: u64,
+    /// Max count of open connections, excess ones wait for accept (in listen backlog). 0 disables limit
+    #[envconfig(from = "MAX_CONNECTIONS", default = "0")]
+    pub max_connections: usize,
+    /// Max count of content transforms (tint, sharpen, etc.) applied at once, to bound cost of request. 0 disables limit
+    #[envconfig(from = "MAX_TRANSFORMS_PER_REQUEST", default = "0")]
+    pub max_transforms_per_request: usize,
+    /// Max size (in bytes) of image body of `/transform`, larger ones are rejected with 413. 0 disables limit
+    #[envconfig(from = "MAX_TRANSFORM_BODY_BYTES", default = "33554432")]
+    pub max_transform_body_bytes: usize,
+    /// Max time (in seconds) to wait for in-flight requests on shutdown, before forcing exit. 0 waits indefinitely
+    #[envconfig(from = "SHUTDOWN_GRACE_SECONDS", default = "30")]
+    pub shutdown_grace_seconds: u64,
+    /// Emit `Digest` header (sha-256 of served bytes) for integrity verification by clients
+    #[envconfig(from = "RESPONSE_DIGEST", default = "false")]
+    pub response_digest: bool,
+    /// Emit `X-Image-Width`, `X-Image-Height` and `X-Image-Format` headers of served image
+    #[envconfig(from = "IMAGE_INFO_HEADERS", default = "false")]
+    pub image_info_headers: bool,
+    /// Emit `X-Cache` (processed cache `HIT`/`MISS`) and `X-Cache-Original` (source of processed original) headers
+    #[envconfig(from = "CACHE_STATUS_HEADERS", default = "false")]
+    pub cache_status_headers: bool,
+    /// Rotate/flip images by their EXIF orientation, unless request overrides it with `auto_orient`
+    #[envconfig(from = "AUTO_ORIENT", default = "true")]
+    pub auto_orient: bool,
+    /// Behaviour on requesting `frame` after the last one: Last or Reject
+    #[envconfig(from = "FRAME_OUT_OF_RANGE_POLICY", default = "Last")]
+    pub frame_out_of_range_policy: FrameOutOfRangePolicy,
+    /// Size of the missing dimension, when only width or height is requested: PreserveRatio or SourceDimension
+    #[envconfig(from = "SINGLE_DIMENSION_POLICY", default = "PreserveRatio")]
+    pub single_dimension_policy: SingleDimensionPolicy,
+    /// Comma separated names of custom transforms, applied in order after resizing
+    #[envconfig(from = "CUSTOM_TRANSFORMS", default = "")]
+    pub custom_transforms: CustomTransforms,
+    /// Format of access log lines (one per request): Off, Combined or Json
+    #[envconfig(from = "ACCESS_LOG_FORMAT", default = "Combined")]
+    pub access_log_format: AccessLogFormat,
+    /// Log client ip from `X-Forwarded-For` instead of peer address, only for trusted reverse proxy in front
+    #[envconfig(from = "TRUST_FORWARDED_FOR", default = "false")]
+    pub trust_forwarded_for: bool,
+}
+
+impl EnvConfig {
+    /// Load config from env vars, collecting all malformed vars instead of failing on the first one
+    fn load(mut vars: HashMap<String, String>) -> Result<EnvConfig, Vec<String>> {
+        let mut problems = Vec::new();
+        let env_conf = loop {
+            match EnvConfig::init_from_hashmap(&vars) {
+                Ok(env_conf) => break env_conf,
+                // retry without malformed var (falling back to default), to find next ones
+                Err(envconfig::Error::ParseError { name }) if vars.contains_key(name) => {
+                    problems.push(format!(
+                        "{}: can't parse {:?}",
+                        name,
+                        vars.remove(name).unwrap()
+                    ));
+                }
+                Err(err) => {
+                    problems.push(err.to_string());
+                    return Err(problems);
+                }
+            }
+        };
+        problems.extend(env_conf.validate(&vars));
+        match problems.is_empty() {
+            true => Ok(env_conf),
+            false => Err(problems),
+        }
+    }
+
+    /// Check values, which are parsed, but can't be used
+    fn validate(&self, vars: &HashMap<String, String>) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.listen_uds.is_some() {
+            if vars.contains_key("HOST") || vars.contains_key("PORT") {
+                problems.push("LISTEN_UDS: can't be used together with HOST or PORT".to_string());
+            }
+        } else if !(1..=65535).contains(&self.port) {
+            problems.push(format!("PORT: expected 1-65535, got {}", self.port));
+        }
+        if let Some(urls) = &self.base_file_api_url {
+            for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+                match reqwest::Url::parse(url) {
+                    Ok(parsed)
+                        if !self.allow_private_origin_ips
+                            && parsed.host_str().is_some_and(|host| {
+                                host.eq_ignore_ascii_case("localhost")
+                                    || host
+                                        .trim_matches(['[', ']'])
+                                        .parse()
+                                        .is_ok_and(is_private_ip)
+                            }) =>
+                    {
+                        problems.push(format!(
+                            "BASE_FILE_API_URL: {:?} is private network address, set ALLOW_PRIVATE_ORIGIN_IPS=true to allow it",
+                            url
+                        ))
+                    }
+                    Ok(parsed) if ["http", "https"].contains(&parsed.scheme()) => {}
+                    Ok(_) => problems.push(format!(
+                        "BASE_FILE_API_URL: expected http(s) url, got {:?}",
+                        url
+                    )),
+                    Err(err) => problems.push(format!(
+                        "BASE_FILE_API_URL: invalid url {:?} ({})",
+                        url, err
+                    )),
+                }
+            }
+        }
+        if !(0.0..=1.0).contains(&self.memory_cache_hot_allocation) {
+            problems.push(format!(
+                "MEMORY_CACHE_HOT_ALLOCATION: expected 0-1, got {}",
+                self.memory_cache_hot_allocation
+            ));
+        }
+        for extension in Extensions::iter() {
+            let floor = self
+                .min_quality
+                .get(extension)
+                .unwrap_or(DEFAULT_MIN_QUALITY);
+            let default = self
+                .default_quality
+                .get(extension)
+                .or(operations::default_quality(extension));
+            if let Some(quality) = default
+                && quality < floor
+            {
+                problems.push(format!(
+                    "DEFAULT_QUALITY: {:?} default {} is below its MIN_QUALITY {}",
+                    extension, quality, floor
+                ));
+            }
+        }
+        if !self.origin_webhook_ids_pointer.is_empty()
+            && !self.origin_webhook_ids_pointer.starts_with('/')
+        {
+            problems.push(format!(
+                "ORIGIN_WEBHOOK_IDS_POINTER: expected JSON pointer starting with \"/\", got {:?}",
+                self.origin_webhook_ids_pointer
+            ));
+        }
+        if self.preset_sizes_only && self.size_presets.is_empty() {
+            problems.push("PRESET_SIZES_ONLY: requires SIZE_PRESETS".to_string());
+        }
+        if self.base_file_api_timeout == 0 {
+            problems.push("BASE_FILE_API_URL_TIMEOUT: should be positive".to_string());
+        }
+        if self.max_fetch_timeout == 0 {
+            problems.push("MAX_FETCH_TIMEOUT: should be positive".to_string());
+        }
+        if !(self.max_resize_distortion == 0.0 || self.max_resize_distortion >= 1.0) {
+            problems.push(format!(
+                "MAX_RESIZE_DISTORTION: expected 0 (disabled) or ratio from 1.0, got {}",
+                self.max_resize_distortion
+            ));
+        }
+        if let Some(path) = &self.fallback_image_path
+            && !Path::new(path).is_file()
+        {
+            problems.push(format!("FALLBACK_IMAGE_PATH: file {:?} is not found", path));
+        }
+        if StatusCode::from_u16(self.fallback_image_status).is_err() {
+            problems.push(format!(
+                "FALLBACK_IMAGE_STATUS: {} is not valid http status",
+                self.fallback_image_status
+            ));
+        }
+        problems
+    }
+
+    /// Options of memory caches, with shards defaulting to 4 per core
+    fn memory_cache_options(&self) -> MemoryCacheOptions {
+        // pinned ids are normalized the same way as requested ones
+        let pinned_images: Vec<ImageId> = self
+            .pinned_images
+            .split(',')
+            .map(str::trim)
+            .filter(|image_id| !image_id.is_empty())
+            .map(|image_id| match self.image_id_lowercase {
+                true => sanitize(image_id.to_lowercase()),
+                false => sanitize(image_id),
+            })
+            .collect();
+        if !pinned_images.is_empty() {
+            info!("Pinning {} images in memory", pinned_images.len());
+        }
+        let memory_cache_shards = match self.memory_cache_shards {
+            0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()) * 4,
+            shards => shards,
+        };
+        info!("Using {} shards of memory caches", memory_cache_shards);
+        MemoryCacheOptions {
+            pinned: PinnedImages::new(pinned_images),
+            shards: Some(memory_cache_shards),
+            hot_allocation: Some(self.memory_cache_hot_allocation),
+        }
+    }
 }
 
 pub struct Config {
     pub host: String,
     pub port: u32,
+    pub listen_uds: Option<String>,
     pub api_key: String,
     pub processor: Processor,
+    pub max_fetch_timeout: Duration,
 
     pub client_cache_ttl: usize,
     pub max_image_resize: Size,
+    pub allowed_widths: AllowedSizes,
+    pub allowed_heights: AllowedSizes,
+    pub allowed_sizes_policy: AllowedSizesPolicy,
+    pub size_presets: SizePresets,
+    pub preset_sizes_only: bool,
+    pub default_filename_pattern: String,
+    pub image_id_trim: bool,
+    pub image_id_lowercase: bool,
+    pub allowed_image_ids: ImageIdPatterns,
+    pub denied_image_ids: ImageIdPatterns,
+    pub fallback_image_status: StatusCode,
     pub enable_docs: bool,
+    pub enable_debug_endpoints: bool,
+    pub access_log_format: AccessLogFormat,
+    pub trust_forwarded_for: bool,
+    pub max_concurrent_requests: Option<usize>,
+    /// Max count of open connections, None is unlimited
+    pub max_connections: Option<usize>,
+    pub preload_limiter: PreloadLimiter,
+    /// Max time to wait for in-flight requests on shutdown, None waits indefinitely
+    pub shutdown_grace: Option<Duration>,
+    /// Max count of content transforms applied at once, None is unlimited
+    pub max_transforms: Option<usize>,
+    /// Max size of image body of `/transform`, None is unlimited
+    pub max_transform_body_bytes: Option<usize>,
+    pub min_quality: QualityPerFormat,
+    pub response_digest: bool,
+    pub image_info_headers: bool,
+    pub cache_status_headers: bool,
+    pub auto_orient: bool,
+    pub format_priority: FormatPriority,
+    pub origin_webhook_ids_pointer: String,
+    pub origin_webhook_warm_variants: WarmVariants,
+}
+
+/// Whether `/images/{id}/debug` route is enabled, read alone to generate docs without building stores
+pub fn debug_endpoints_enabled() -> bool {
+    std::env::var("ENABLE_DEBUG_ENDPOINTS").is_ok_and(|v| v.parse().unwrap_or(false))
 }
 
 impl Config {
+    /// Load config from env. Exits with summary of all invalid vars, if there are any
     pub fn from_env() -> Config {
-        let env_conf = EnvConfig::init_from_env().unwrap();
-        let base_file_api = match env_conf.base_file_api_url {
+        match Config::from_vars(std::env::vars().collect()) {
+            Ok(config) => config,
+            Err(problems) => {
+                eprintln!("Invalid configuration:");
+                for problem in problems {
+                    eprintln!("  - {}", problem);
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Build config from env vars, returning all invalid vars, if there are any
+    pub fn from_vars(vars: HashMap<String, String>) -> Result<Config, Vec<String>> {
+        let env_conf = EnvConfig::load(vars)?;
+        let base_file_api = match &env_conf.base_file_api_url {
             None => None,
-            Some(url) => Some(Arc::new(SimpleFileApiBackend::new(
-                url,
-                Some(env_conf.base_file_api_timeout),
-            )) as Arc<dyn FileApiBackend + Send + Sync>),
+            Some(urls) => {
+                let mut backends: Vec<Arc<dyn FileApiBackend + Send + Sync>> = urls
+                    .split(',')
+                    .map(|url| url.trim())
+                    .filter(|url| !url.is_empty())
+                    .map(|url| {
+                        Arc::new(SimpleFileApiBackend::new(
+                            url.to_string(),
+                            Some(env_conf.base_file_api_timeout),
+                            env_conf.file_api_user_agent.clone(),
+                            env_conf.max_origin_redirects,
+                            env_conf.allow_private_origin_ips,
+                            (env_conf.max_origin_bytes > 0).then_some(env_conf.max_origin_bytes),
+                        )) as Arc<dyn FileApiBackend + Send + Sync>
+                    })
+                    .collect();
+                match backends.len() {
+                    0 => None,
+                    1 => backends.pop(),
+                    _ => Some(Arc::new(ChainedFileApiBackend::new(backends))
+                        as Arc<dyn FileApiBackend + Send + Sync>),
+                }
+            }
         };
 
         let storage_size = env_conf.storage_cache_size;
         let cache_size = env_conf.processing_cache_size;
-        let need_persist_store = env_conf.storage_implementation
-            == StorageImplementation::Persistent
+        let memory_cache_options = env_conf.memory_cache_options();
+        let persistent_storage = matches!(
+            env_conf.storage_implementation,
+            StorageImplementation::Persistent | StorageImplementation::Tiered
+        );
+        let need_persist_store = persistent_storage
             || env_conf.processing_cache_implementation
                 == ProcessingCacheImplementation::Persistent;
         let persistent_store = match need_persist_store {
             true => Some(Arc::new(PersistentStore::new(
                 Box::from(Path::new(env_conf.persistent_storage_dir.as_str())),
                 {
-                    if env_conf.storage_implementation == StorageImplementation::Persistent {
+                    if persistent_storage {
                         storage_size
                     } else {
                         NonZeroUsize::new(1).unwrap()
@@ -204,7 +1128,7 @@ impl Config {
         let storage: Arc<tokio::sync::RwLock<dyn OriginalImageStorage + Send + Sync>> =
             match env_conf.storage_implementation {
                 StorageImplementation::InMemory => Arc::new(tokio::sync::RwLock::with_max_readers(
-                    CachingStorage::new(Some(storage_size)),
+                    CachingStorage::new(Some(storage_size), &memory_cache_options),
                     1024,
                 )),
                 StorageImplementation::Persistent => {
@@ -212,12 +1136,39 @@ impl Config {
                         PersistentStorage::new(
                             persistent_store.clone().unwrap(),
                             Some(storage_size),
+                            env_conf.compress_persistent_originals,
+                        ),
+                        1024,
+                    ))
+                }
+                StorageImplementation::Tiered => {
+                    info!(
+                        "Using {} writes to persistent layer",
+                        env_conf.tiered_storage_write_policy
+                    );
+                    Arc::new(tokio::sync::RwLock::with_max_readers(
+                        TieredStorage::new(
+                            Box::new(CachingStorage::new(
+                                Some(storage_size),
+                                &memory_cache_options,
+                            )),
+                            Box::new(PersistentStorage::new(
+                                persistent_store.clone().unwrap(),
+                                Some(storage_size),
+                                env_conf.compress_persistent_originals,
+                            )),
+                            env_conf.tiered_storage_write_policy,
                         ),
                         1024,
                     ))
                 }
             };
 
+        let max_options_per_image = MaxOptionsPerImage::new(
+            env_conf.max_options_per_image,
+            env_conf.max_options_per_image_overrides,
+        );
+
         info!(
             "Using {} processing cache",
             env_conf.processing_cache_implementation
@@ -228,8 +1179,9 @@ impl Config {
                     Arc::new(tokio::sync::RwLock::with_max_readers(
                         MemoryProcessedImageCache::new(
                             Some(storage_size),
-                            env_conf.max_options_per_image.clone(),
+                            max_options_per_image.clone(),
                             env_conf.max_options_per_image_overflow_policy.clone(),
+                            &memory_cache_options,
                         ),
                         1024,
                     ))
@@ -239,31 +1191,245 @@ impl Config {
                         PersistentProcessedImageCache::new(
                             persistent_store.clone().unwrap(),
                             Some(storage_size),
-                            env_conf.max_options_per_image.clone(),
+                            max_options_per_image.clone(),
                             env_conf.max_options_per_image_overflow_policy.clone(),
+                            NonZeroUsize::new(env_conf.max_processed_variants),
                         ),
                         1024,
                     ))
                 }
             };
 
+        let fallback_image = env_conf.fallback_image_path.map(|path| {
+            info!("Using fallback image {}", path);
+            std::fs::read(&path).expect("Failed to read FALLBACK_IMAGE_PATH")
+        });
+        let fallback_image_status = StatusCode::from_u16(env_conf.fallback_image_status)
+            .expect("FALLBACK_IMAGE_STATUS should be valid http status");
+
         let processor = Processor::new(
             storage,
             cache,
             base_file_api,
             persistent_store,
-            env_conf.default_extension,
-            env_conf.allow_custom_extension,
+            ProcessorOptions {
+                default_extension: env_conf.default_extension,
+                allow_custom_extension: env_conf.allow_custom_extension,
+                fallback_image,
+                max_resize_distortion: (env_conf.max_resize_distortion > 0.0)
+                    .then_some(env_conf.max_resize_distortion),
+                // animations get the same pixel budget as the largest image
+                max_animation_pixels: Some(
+                    env_conf.max_image_resize.width as u64
+                        * env_conf.max_image_resize.height as u64,
+                ),
+                max_cacheable_original_bytes: (env_conf.max_cacheable_original_bytes > 0)
+                    .then_some(env_conf.max_cacheable_original_bytes),
+                resize_filters: ResizeFilters {
+                    upscale: env_conf.upscale_filter,
+                    downscale: env_conf.downscale_filter,
+                },
+                trim_tolerance: env_conf.trim_tolerance,
+                max_concurrent_avif_encodes: (env_conf.max_concurrent_avif_encodes > 0)
+                    .then_some(env_conf.max_concurrent_avif_encodes),
+                avif_webp_fallback: env_conf.avif_fallback_to_webp,
+                encode_timeouts: env_conf.encode_timeouts.with_default(
+                    Extensions::Avif,
+                    (env_conf.avif_encode_timeout > 0)
+                        .then(|| Duration::from_secs(env_conf.avif_encode_timeout)),
+                ),
+                compute_digest: env_conf.response_digest,
+                auto_orient: env_conf.auto_orient,
+                frame_out_of_range_policy: env_conf.frame_out_of_range_policy,
+                single_dimension_policy: env_conf.single_dimension_policy,
+                transforms: env_conf.custom_transforms.0,
+                default_quality: env_conf.default_quality,
+                origin_revalidate_age: (env_conf.origin_revalidate_age > 0)
+                    .then(|| Duration::from_secs(env_conf.origin_revalidate_age)),
+                stale_while_revalidate: env_conf.stale_while_revalidate,
+                not_found_ttl: (env_conf.not_found_cache_ttl > 0)
+                    .then(|| Duration::from_secs(env_conf.not_found_cache_ttl)),
+                max_concurrent_origin_fetches: (env_conf.max_concurrent_origin_fetches > 0)
+                    .then_some(env_conf.max_concurrent_origin_fetches),
+                compaction: (env_conf.compaction_interval_seconds > 0).then(|| {
+                    CompactionSchedule {
+                        interval: Duration::from_secs(env_conf.compaction_interval_seconds),
+                        hours: env_conf.compaction_hours,
+                    }
+                }),
+            },
         );
 
-        Config {
+        Ok(Config {
             host: env_conf.host,
             port: env_conf.port,
+            listen_uds: env_conf.listen_uds,
             api_key: env_conf.api_key,
             processor,
+            max_fetch_timeout: Duration::from_secs(env_conf.max_fetch_timeout as u64),
             client_cache_ttl: env_conf.client_cache_ttl,
             max_image_resize: env_conf.max_image_resize,
+            allowed_widths: env_conf.allowed_widths,
+            allowed_heights: env_conf.allowed_heights,
+            allowed_sizes_policy: env_conf.allowed_sizes_policy,
+            size_presets: env_conf.size_presets,
+            preset_sizes_only: env_conf.preset_sizes_only,
+            default_filename_pattern: env_conf.default_filename_pattern,
+            image_id_trim: env_conf.image_id_trim,
+            image_id_lowercase: env_conf.image_id_lowercase,
+            allowed_image_ids: env_conf.allowed_image_ids,
+            denied_image_ids: env_conf.denied_image_ids,
+            fallback_image_status,
             enable_docs: env_conf.enable_docs,
+            enable_debug_endpoints: env_conf.enable_debug_endpoints,
+            access_log_format: env_conf.access_log_format,
+            trust_forwarded_for: env_conf.trust_forwarded_for,
+            max_concurrent_requests: (env_conf.max_concurrent_requests > 0)
+                .then_some(env_conf.max_concurrent_requests),
+            max_connections: (env_conf.max_connections > 0).then_some(env_conf.max_connections),
+            preload_limiter: PreloadLimiter::new(
+                (env_conf.max_concurrent_preloads > 0).then_some(env_conf.max_concurrent_preloads),
+                (env_conf.max_preload_bytes_per_minute > 0)
+                    .then_some(env_conf.max_preload_bytes_per_minute),
+            ),
+            shutdown_grace: (env_conf.shutdown_grace_seconds > 0)
+                .then(|| Duration::from_secs(env_conf.shutdown_grace_seconds)),
+            max_transforms: (env_conf.max_transforms_per_request > 0)
+                .then_some(env_conf.max_transforms_per_request),
+            max_transform_body_bytes: (env_conf.max_transform_body_bytes > 0)
+                .then_some(env_conf.max_transform_body_bytes),
+            min_quality: env_conf.min_quality,
+            response_digest: env_conf.response_digest,
+            image_info_headers: env_conf.image_info_headers,
+            cache_status_headers: env_conf.cache_status_headers,
+            auto_orient: env_conf.auto_orient,
+            format_priority: env_conf.format_priority,
+            origin_webhook_ids_pointer: env_conf.origin_webhook_ids_pointer,
+            origin_webhook_warm_variants: env_conf.origin_webhook_warm_variants,
+        })
+    }
+
+    /// Normalize requested image id, before any storage, cache or base api lookups
+    pub fn normalize_image_id(&self, image_id: String) -> ImageId {
+        let mut image_id = match self.image_id_trim {
+            true => image_id.trim().to_string(),
+            false => image_id,
+        };
+        if self.image_id_lowercase {
+            image_id = image_id.to_lowercase();
+        }
+        sanitize(image_id)
+    }
+
+    /// Raise requested quality (and frame quality) up to floor of resulting format.
+    /// Returns floor, if anything was clamped
+    pub fn clamp_quality(&self, params: &mut ProcessingParams) -> Option<u32> {
+        let floor = self
+            .min_quality
+            .get(self.processor.determine_extension(params))
+            .unwrap_or(DEFAULT_MIN_QUALITY);
+        let mut clamped = false;
+        for quality in [&mut params.quality, &mut params.frame_quality]
+            .into_iter()
+            .flatten()
+        {
+            if *quality < floor {
+                *quality = floor;
+                clamped = true;
+            }
         }
+        clamped.then_some(floor)
+    }
+
+    /// Check normalized image id against allowed and denied patterns
+    pub fn is_allowed_image_id(&self, image_id: &ImageId) -> bool {
+        (self.allowed_image_ids.is_empty() || self.allowed_image_ids.matches(image_id))
+            && !self.denied_image_ids.matches(image_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory_cache::{MemoryCache, memory_cache};
+
+    fn problems(vars: &[(&str, &str)]) -> Vec<String> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        match Config::from_vars(vars) {
+            Ok(_) => Vec::new(),
+            Err(problems) => problems,
+        }
+    }
+
+    #[test]
+    fn all_invalid_vars_are_reported() {
+        let problems = problems(&[
+            ("PORT", "http"),
+            ("BASE_FILE_API_URL", "ftp://files.local"),
+            ("MAX_FETCH_TIMEOUT", "0"),
+            ("MAX_RESIZE_DISTORTION", "0.5"),
+            ("FALLBACK_IMAGE_STATUS", "1000"),
+        ]);
+        assert_eq!(
+            problems,
+            [
+                "PORT: can't parse \"http\"",
+                "BASE_FILE_API_URL: expected http(s) url, got \"ftp://files.local\"",
+                "MAX_FETCH_TIMEOUT: should be positive",
+                "MAX_RESIZE_DISTORTION: expected 0 (disabled) or ratio from 1.0, got 0.5",
+                "FALLBACK_IMAGE_STATUS: 1000 is not valid http status",
+            ]
+        );
+    }
+
+    #[test]
+    fn exclusive_listeners_are_reported() {
+        assert_eq!(
+            problems(&[("LISTEN_UDS", "/tmp/imgr.sock"), ("PORT", "3021")]),
+            ["LISTEN_UDS: can't be used together with HOST or PORT"]
+        );
+        assert_eq!(
+            problems(&[("PORT", "70000")]),
+            ["PORT: expected 1-65535, got 70000"]
+        );
+        assert!(problems(&[("PORT", "3021")]).is_empty());
+    }
+
+    #[test]
+    fn memory_cache_options_are_applied() {
+        let env_conf = |vars: &[(&str, &str)]| {
+            EnvConfig::load(
+                vars.iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            )
+            .ok()
+            .unwrap()
+        };
+
+        let options = env_conf(&[
+            ("MEMORY_CACHE_SHARDS", "8"),
+            ("MEMORY_CACHE_HOT_ALLOCATION", "0.5"),
+            ("PINNED_IMAGES", "logo, avatar.png"),
+        ])
+        .memory_cache_options();
+        assert_eq!(options.shards, Some(8));
+        assert_eq!(options.hot_allocation, Some(0.5));
+        assert!(options.pinned.contains("logo") && options.pinned.contains("avatar.png"));
+        let cache: MemoryCache<ImageId, u32> = memory_cache(1024, &options);
+        assert_eq!(cache.num_shards(), 8);
+
+        let cores = std::thread::available_parallelism().unwrap().get();
+        let options = env_conf(&[]).memory_cache_options();
+        assert_eq!(options.shards, Some(cores * 4));
+        assert_eq!(options.hot_allocation, Some(0.97));
+
+        assert_eq!(
+            problems(&[("MEMORY_CACHE_HOT_ALLOCATION", "1.5")]),
+            ["MEMORY_CACHE_HOT_ALLOCATION: expected 0-1, got 1.5"]
+        );
     }
 }
@@ -1,6 +1,7 @@
+use image::ImageFormat;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use strum::EnumString;
+use strum::{EnumIter, EnumString};
 
 pub trait MimeType {
     fn mime_type(&self) -> &str;
@@ -17,6 +18,7 @@ pub trait MimeType {
     Copy,
     Clone,
     EnumString,
+    EnumIter,
     Ord,
     PartialOrd,
 )]
@@ -27,6 +29,16 @@ pub enum Extensions {
 }
 
 impl Extensions {
+    /// Extension of decoded source format, if it's one of output ones
+    pub fn from_format(format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::WebP => Some(Extensions::Webp),
+            ImageFormat::Avif => Some(Extensions::Avif),
+            ImageFormat::Png => Some(Extensions::PNG),
+            _ => None,
+        }
+    }
+
     pub fn name(&self) -> &str {
         match self {
             Extensions::Webp => "webp",
@@ -36,6 +48,41 @@ impl Extensions {
     }
 }
 
+impl From<RequestedExtension> for Option<Extensions> {
+    fn from(value: RequestedExtension) -> Self {
+        match value {
+            RequestedExtension::Webp => Some(Extensions::Webp),
+            RequestedExtension::Avif => Some(Extensions::Avif),
+            RequestedExtension::PNG => Some(Extensions::PNG),
+            RequestedExtension::Auto => None,
+        }
+    }
+}
+
+/// Requested resulting extension. `auto` picks one by `Accept` header of client and `FORMAT_PRIORITY`
+#[derive(
+    Deserialize, Serialize, JsonSchema, Debug, PartialEq, Hash, Eq, Copy, Clone, Ord, PartialOrd,
+)]
+// variants mirror Extensions, as they share query and cache key representation
+#[allow(clippy::upper_case_acronyms)]
+pub enum RequestedExtension {
+    Webp,
+    Avif,
+    PNG,
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+impl From<Extensions> for RequestedExtension {
+    fn from(value: Extensions) -> Self {
+        match value {
+            Extensions::Webp => RequestedExtension::Webp,
+            Extensions::Avif => RequestedExtension::Avif,
+            Extensions::PNG => RequestedExtension::PNG,
+        }
+    }
+}
+
 impl Default for Extensions {
     fn default() -> Self {
         Extensions::Webp
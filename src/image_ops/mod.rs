@@ -1,3 +1,4 @@
 pub mod image_types;
 pub mod operations;
 pub mod processing;
+pub mod transforms;
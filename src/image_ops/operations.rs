@@ -1,9 +1,36 @@
-use crate::image_ops::image_types::Extensions;
-use fast_image_resize::Resizer;
-use image::{DynamicImage, GenericImageView, ImageBuffer, ImageEncoder, Pixel, Rgba};
+use crate::image_ops::image_types::{Extensions, RequestedExtension};
+use fast_image_resize::{FilterType, ResizeAlg, ResizeOptions, Resizer};
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops;
+use image::imageops::colorops;
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, ImageBuffer, ImageDecoder, ImageEncoder,
+    ImageFormat, Pixel, Rgba, RgbaImage,
+};
 use schemars::JsonSchema;
+use std::io::{Cursor, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use strum::EnumString;
+use tokio::sync::OwnedSemaphorePermit;
 
 pub const DEFAULT_COMPRESSION_QUALITY: u32 = 82;
+const AVIF_SPEED: u8 = 8;
+const AVIF_QUALITY: u32 = 92;
+
+/// Built-in quality of lossy formats, used when neither request nor config sets it.
+/// None for lossless ones
+pub fn default_quality(extension: Extensions) -> Option<u32> {
+    match extension {
+        Extensions::Webp => Some(DEFAULT_COMPRESSION_QUALITY),
+        Extensions::Avif => Some(AVIF_QUALITY),
+        Extensions::PNG => None,
+    }
+}
 
 /// Behaviour on requesting images with different ratio, then source
 #[derive(
@@ -23,6 +50,8 @@ pub enum RatioPolicy {
     Resize,
     /// Keep original ratio with cropping to center
     CropToCenter,
+    /// Keep original ratio, fitting image inside target size and padding the rest with background
+    Pad,
 }
 
 impl Default for RatioPolicy {
@@ -31,6 +60,7 @@ impl Default for RatioPolicy {
     }
 }
 
+/// Interpolation filter of resizing
 #[derive(
     serde::Deserialize,
     serde::Serialize,
@@ -39,16 +69,224 @@ impl Default for RatioPolicy {
     Hash,
     Eq,
     Clone,
+    Copy,
     Debug,
     Ord,
     PartialOrd,
+    EnumString,
+    strum::Display,
+)]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+    Hamming,
+    CatmullRom,
+    Mitchell,
+    Gaussian,
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    fn resize_alg(&self) -> ResizeAlg {
+        match self {
+            ResizeFilter::Nearest => ResizeAlg::Nearest,
+            ResizeFilter::Bilinear => ResizeAlg::Convolution(FilterType::Bilinear),
+            ResizeFilter::Hamming => ResizeAlg::Convolution(FilterType::Hamming),
+            ResizeFilter::CatmullRom => ResizeAlg::Convolution(FilterType::CatmullRom),
+            ResizeFilter::Mitchell => ResizeAlg::Convolution(FilterType::Mitchell),
+            ResizeFilter::Gaussian => ResizeAlg::Convolution(FilterType::Gaussian),
+            ResizeFilter::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+        }
+    }
+}
+
+/// Filters, selected by resize direction. Lanczos3 is sharp on downscaling, but rings on upscaling
+#[derive(Clone, Copy, Debug)]
+pub struct ResizeFilters {
+    pub upscale: ResizeFilter,
+    pub downscale: ResizeFilter,
+}
+
+impl ResizeFilters {
+    /// The same filter for both directions
+    pub fn single(filter: ResizeFilter) -> Self {
+        ResizeFilters {
+            upscale: filter,
+            downscale: filter,
+        }
+    }
+
+    /// Filter for resizing from `src` to `dst` (width, height), by change of pixels count
+    pub fn select(&self, src: (u32, u32), dst: (u32, u32)) -> ResizeFilter {
+        match dst.0 as u64 * dst.1 as u64 > src.0 as u64 * src.1 as u64 {
+            true => self.upscale,
+            false => self.downscale,
+        }
+    }
+
+    fn options(&self, src: (u32, u32), dst: (u32, u32)) -> ResizeOptions {
+        ResizeOptions::new().resize_alg(self.select(src, dst).resize_alg())
+    }
+}
+
+impl Default for ResizeFilters {
+    fn default() -> Self {
+        ResizeFilters {
+            upscale: ResizeFilter::Mitchell,
+            downscale: ResizeFilter::Lanczos3,
+        }
+    }
+}
+
+/// Part of image, retained on cropping (`CropToCenter` ratio policy), or position of image on padding
+/// (`Pad` ratio policy)
+#[derive(
+    serde::Deserialize,
+    serde::Serialize,
+    JsonSchema,
+    PartialEq,
+    Hash,
+    Eq,
+    Clone,
+    Copy,
+    Debug,
+    Ord,
+    PartialOrd,
+    Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+impl Gravity {
+    /// Crop offset (x, y) for cropping out `excess` (width, height) of resized image
+    pub fn crop_offset(&self, excess: (u32, u32)) -> (u32, u32) {
+        let (excess_w, excess_h) = excess;
+        let x = match self {
+            Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0,
+            Gravity::East | Gravity::NorthEast | Gravity::SouthEast => excess_w,
+            Gravity::Center | Gravity::North | Gravity::South => excess_w / 2,
+        };
+        let y = match self {
+            Gravity::North | Gravity::NorthEast | Gravity::NorthWest => 0,
+            Gravity::South | Gravity::SouthEast | Gravity::SouthWest => excess_h,
+            Gravity::Center | Gravity::East | Gravity::West => excess_h / 2,
+        };
+        (x, y)
+    }
+}
+
+#[derive(
+    serde::Deserialize,
+    serde::Serialize,
+    JsonSchema,
+    PartialEq,
+    Hash,
+    Eq,
+    Clone,
+    Debug,
+    Default,
+    Ord,
+    PartialOrd,
 )]
 pub struct ProcessingParams {
+    /// Short alias `w`
+    #[serde(alias = "w")]
     pub width: Option<u32>,
+    /// Short alias `h`
+    #[serde(alias = "h")]
     pub height: Option<u32>,
-    pub extension: Option<Extensions>,
+    /// Short alias `fmt`
+    #[serde(alias = "fmt")]
+    pub extension: Option<RequestedExtension>,
+    /// Short alias `q`
+    #[serde(alias = "q")]
     pub quality: Option<u32>,
     pub ratio_policy: Option<RatioPolicy>,
+    /// Retained part of image on cropping (`center` by default)
+    pub gravity: Option<Gravity>,
+    /// Interpolation filter, overrides configured ones for upscaling and downscaling
+    pub filter: Option<ResizeFilter>,
+    /// Animation loop count for animated sources (0 - infinite). Ignored for still images
+    pub loop_count: Option<u32>,
+    /// Quality of each frame for animated sources, `quality` is used if not set
+    pub frame_quality: Option<u32>,
+    /// Hex color (`rrggbb` or `rgb`, optionally with `#`) of duotone tint: shadows stay black,
+    /// highlights white, midtones take the color
+    pub tint: Option<String>,
+    /// Brightness change in percents (-100..100). Adjustments are integer to keep params hashable cache key
+    pub brightness: Option<i32>,
+    /// Contrast change in percents (-100..100)
+    pub contrast: Option<i32>,
+    /// Saturation change in percents (-100..100), -100 is grayscale
+    pub saturation: Option<i32>,
+    /// Unsharp mask intensity after resize (1..100), restores crispness of downscaled images
+    pub sharpen: Option<u32>,
+    /// Hex color of padding for `Pad` ratio policy, transparent if not set
+    pub background: Option<String>,
+    /// Remove uniform color borders (like whitespace around product photo) before resizing
+    pub trim: Option<bool>,
+    /// Serve source as is, if it's not larger than requested size and already has requested format.
+    /// Avoids re-encoding, that may enlarge tiny sources. Not applied, if any adjustment, non default
+    /// quality or EXIF orientation is applied. Served source keeps its metadata
+    pub skip_smaller: Option<bool>,
+    /// Bit depth of avif output (8 or 10), 10 bit keeps gradients of high fidelity sources smooth.
+    /// No-op for other formats
+    pub bit_depth: Option<u8>,
+    /// Multiply color channels by alpha before encoding, for renderers expecting premultiplied alpha.
+    /// Semi-transparent pixels look darker in usual (straight alpha) viewers
+    pub premultiply_alpha: Option<bool>,
+    /// Rotate/flip image by its EXIF orientation (`AUTO_ORIENT` by default). When off, stored pixel
+    /// orientation is kept as is
+    pub auto_orient: Option<bool>,
+    /// Index (from 0) of animation frame, served as still image. Still sources have the only frame 0
+    pub frame: Option<u32>,
+    /// Max frame rate of animated output, frames exceeding it are dropped to shrink output
+    pub fps: Option<u32>,
+    /// Resolution (dots per inch), written into output metadata for print. Pixels are not changed,
+    /// formats without density field (Webp, Avif) ignore it
+    pub dpi: Option<u32>,
+    /// Interlaced (Adam7) PNG output, shown progressively while loading on slow connections.
+    /// Output is usually larger, no-op for other formats
+    pub interlace: Option<bool>,
+}
+
+impl ProcessingParams {
+    /// Names of requested transforms, changing image content apart from resizing
+    pub fn adjustments(&self) -> Vec<&'static str> {
+        [
+            ("trim", self.trim == Some(true)),
+            ("tint", self.tint.is_some()),
+            ("brightness", self.brightness.is_some_and(|v| v != 0)),
+            ("contrast", self.contrast.is_some_and(|v| v != 0)),
+            ("saturation", self.saturation.is_some_and(|v| v != 0)),
+            ("sharpen", self.sharpen.is_some_and(|v| v > 0)),
+            ("premultiply_alpha", self.premultiply_alpha == Some(true)),
+        ]
+        .into_iter()
+        .filter_map(|(name, applied)| applied.then_some(name))
+        .collect()
+    }
+
+    /// Whether params change image content apart from resizing
+    pub fn has_adjustments(&self) -> bool {
+        !self.adjustments().is_empty()
+    }
+
+    /// Whether source of given size already satisfies requested size, so it can be served without resizing
+    pub fn is_satisfied_by(&self, (width, height): (u32, u32)) -> bool {
+        self.width.is_none_or(|w| width <= w) && self.height.is_none_or(|h| height <= h)
+    }
 }
 
 pub fn resize<I: GenericImageView<Pixel = Rgba<u8>>>(
@@ -56,6 +294,9 @@ pub fn resize<I: GenericImageView<Pixel = Rgba<u8>>>(
     width: Option<u32>,
     height: Option<u32>,
     ratio_policy: Option<RatioPolicy>,
+    gravity: Option<Gravity>,
+    filters: ResizeFilters,
+    background: Option<Rgba<u8>>,
 ) -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>> {
     let w = width.unwrap_or(img.width());
     let h = height.unwrap_or(img.height());
@@ -71,7 +312,8 @@ pub fn resize<I: GenericImageView<Pixel = Rgba<u8>>>(
         RatioPolicy::Resize => {
             // Use fast_image_resize for parallel processing instead of image crate's resize_exact
             let mut dst_img = DynamicImage::new(w, h, img.color());
-            let resize_res = resizer.resize(img, &mut dst_img, None);
+            let options = filters.options(img.dimensions(), (w, h));
+            let resize_res = resizer.resize(img, &mut dst_img, &options);
             if let Err(resize_err) = resize_res {
                 panic!("There should be no error on resize, got {}", resize_err)
             };
@@ -83,7 +325,8 @@ pub fn resize<I: GenericImageView<Pixel = Rgba<u8>>>(
             if (orig_ratio - target_ratio).abs() < f64::EPSILON {
                 // Same ratio, just resize
                 let mut dst_img = DynamicImage::new(w, h, img.color());
-                let resize_res = resizer.resize(img, &mut dst_img, None);
+                let options = filters.options(img.dimensions(), (w, h));
+                let resize_res = resizer.resize(img, &mut dst_img, &options);
                 if let Err(resize_err) = resize_res {
                     panic!("There should be no error on resize, got {}", resize_err)
                 };
@@ -107,26 +350,310 @@ pub fn resize<I: GenericImageView<Pixel = Rgba<u8>>>(
                 let mut resized = DynamicImage::new(resize_w, resize_h, img.color());
                 // Resize to cover dimensions
 
-                // Calculate crop coordinates (center)
-                let offset_x = (resize_w.saturating_sub(w)) / 2;
-                let offset_y = (resize_h.saturating_sub(h)) / 2;
+                // Calculate crop coordinates by gravity
+                let (offset_x, offset_y) = gravity
+                    .unwrap_or_default()
+                    .crop_offset((resize_w.saturating_sub(w), resize_h.saturating_sub(h)));
 
-                let resize_res = resizer.resize(img, &mut resized, None);
+                let options = filters.options(img.dimensions(), (resize_w, resize_h));
+                let resize_res = resizer.resize(img, &mut resized, &options);
                 if let Err(resize_err) = resize_res {
                     panic!("There should be no error on resize, got {}", resize_err)
                 };
                 resized.crop(offset_x, offset_y, w, h)
             }
         }
+        RatioPolicy::Pad => {
+            // Resize to fit inside target dimensions, then place onto background canvas
+            let scale = (w as f64 / img.width() as f64).min(h as f64 / img.height() as f64);
+            let fit_w = ((img.width() as f64 * scale).round() as u32).clamp(1, w.max(1));
+            let fit_h = ((img.height() as f64 * scale).round() as u32).clamp(1, h.max(1));
+
+            let mut resized = DynamicImage::new(fit_w, fit_h, img.color());
+            let options = filters.options(img.dimensions(), (fit_w, fit_h));
+            let resize_res = resizer.resize(img, &mut resized, &options);
+            if let Err(resize_err) = resize_res {
+                panic!("There should be no error on resize, got {}", resize_err)
+            };
+
+            let (offset_x, offset_y) = gravity
+                .unwrap_or_default()
+                .crop_offset((w.saturating_sub(fit_w), h.saturating_sub(fit_h)));
+            let mut canvas = RgbaImage::from_pixel(w, h, background.unwrap_or(Rgba([0, 0, 0, 0])));
+            imageops::overlay(
+                &mut canvas,
+                &resized.to_rgba8(),
+                offset_x as i64,
+                offset_y as i64,
+            );
+            DynamicImage::ImageRgba8(canvas)
+        }
     };
 
     resulting_image.to()
 }
 
+/// Bounds (x, y, width, height) of image content inside uniform color borders.
+///
+/// Border color is taken from the top left pixel, pixels differing from it by at most `tolerance` in every
+/// channel are considered border. Returns None, if there is nothing to trim
+pub fn trim_bounds(img: &DynamicImage, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+    let rgba = img.to_rgba8();
+    let border = *rgba.get_pixel_checked(0, 0)?;
+    let is_content = |pixel: &Rgba<u8>| {
+        pixel
+            .0
+            .iter()
+            .zip(border.0)
+            .any(|(channel, border)| channel.abs_diff(border) > tolerance)
+    };
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        if is_content(pixel) {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    // uniform image, keep it as is
+    if min_x > max_x || min_y > max_y {
+        return None;
+    }
+    let bounds = (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1);
+    match bounds == (0, 0, rgba.width(), rgba.height()) {
+        true => None,
+        false => Some(bounds),
+    }
+}
+
+/// Parse hex color into canonical `rrggbb` form, to not fragment cache by color notation
+pub fn normalize_hex_color(color: &str) -> Option<String> {
+    let hex = color.trim_start_matches('#').to_lowercase();
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    match hex.len() {
+        6 => Some(hex),
+        3 => Some(hex.chars().flat_map(|c| [c, c]).collect()),
+        _ => None,
+    }
+}
+
+pub fn parse_hex_color(color: &str) -> Option<[u8; 3]> {
+    let hex = normalize_hex_color(color)?;
+    let channel = |idx: usize| u8::from_str_radix(&hex[idx..idx + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Map pixel luminance onto black - tint - white gradient, keeping alpha
+fn tint(img: &mut RgbaImage, color: [u8; 3]) {
+    for pixel in img.pixels_mut() {
+        let [r, g, b, _] = pixel.0;
+        let luminance = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32) / 255.0;
+        for (channel, tint_channel) in pixel.0.iter_mut().take(3).zip(color) {
+            let tint_channel = tint_channel as f32;
+            let value = match luminance < 0.5 {
+                true => tint_channel * luminance * 2.0,
+                false => tint_channel + (255.0 - tint_channel) * (luminance - 0.5) * 2.0,
+            };
+            *channel = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Scale distance of color channels from pixel luminance, keeping alpha
+fn saturate(img: &mut RgbaImage, saturation: i32) {
+    let factor = 1.0 + saturation as f32 / 100.0;
+    for pixel in img.pixels_mut() {
+        let [r, g, b, _] = pixel.0;
+        let luminance = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+        for channel in pixel.0.iter_mut().take(3) {
+            let value = luminance + (*channel as f32 - luminance) * factor;
+            *channel = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Blur radius of unsharp mask for max `sharpen`
+const MAX_SHARPEN_SIGMA: f32 = 2.0;
+
+fn premultiply_alpha(img: &mut RgbaImage) {
+    for pixel in img.pixels_mut() {
+        let alpha = pixel.0[3] as u32;
+        for channel in &mut pixel.0[..3] {
+            *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Apply sharpening and color adjustments of params to resized image
+pub fn adjust(img: &mut RgbaImage, params: &ProcessingParams) {
+    if let Some(sharpen) = params.sharpen.filter(|v| *v > 0) {
+        let sigma = sharpen.min(100) as f32 / 100.0 * MAX_SHARPEN_SIGMA;
+        *img = imageops::unsharpen(img, sigma, 1);
+    }
+    if let Some(brightness) = params.brightness.filter(|v| *v != 0) {
+        colorops::brighten_in_place(img, brightness * 255 / 100);
+    }
+    if let Some(contrast) = params.contrast.filter(|v| *v != 0) {
+        colorops::contrast_in_place(img, contrast as f32);
+    }
+    if let Some(saturation) = params.saturation.filter(|v| *v != 0) {
+        saturate(img, saturation);
+    }
+    if let Some(color) = params.tint.as_deref().and_then(parse_hex_color) {
+        tint(img, color);
+    }
+    // the last one, as other adjustments expect straight alpha
+    if params.premultiply_alpha == Some(true) {
+        premultiply_alpha(img);
+    }
+}
+
+/// Grid of images, cropped to square cells of `cell` size, `cols` per row. Empty cells are transparent
+///
+/// Returns None, if grid size overflows
+pub fn montage(
+    images: &[DynamicImage],
+    cols: u32,
+    cell: u32,
+    filters: ResizeFilters,
+) -> Option<RgbaImage> {
+    let cols = cols.clamp(1, images.len().max(1) as u32);
+    let rows = (images.len() as u32).div_ceil(cols);
+    let mut canvas = RgbaImage::new(cols.checked_mul(cell)?, rows.checked_mul(cell)?);
+    for (idx, img) in images.iter().enumerate() {
+        let tile = resize::<DynamicImage>(
+            img,
+            Some(cell),
+            Some(cell),
+            Some(RatioPolicy::CropToCenter),
+            None,
+            filters,
+            None,
+        );
+        let (col, row) = (idx as u32 % cols, idx as u32 / cols);
+        imageops::overlay(&mut canvas, &tile, (col * cell) as i64, (row * cell) as i64);
+    }
+    Some(canvas)
+}
+
+/// Side of downscaled image, colors are computed from
+const COLORS_SAMPLE_SIZE: u32 = 64;
+/// Max count of colors in palette
+const PALETTE_SIZE: usize = 5;
+
+#[derive(serde::Serialize, JsonSchema, Clone, Debug)]
+pub struct PaletteColor {
+    /// Hex color (`#rrggbb`)
+    pub color: String,
+    /// Share of image pixels (0..1) close to this color
+    pub share: f32,
+}
+
+#[derive(serde::Serialize, JsonSchema, Clone, Debug)]
+pub struct ImageColors {
+    /// Average hex color (`#rrggbb`) of image, usable as placeholder background
+    pub average: String,
+    /// Dominant colors, most common first
+    pub palette: Vec<PaletteColor>,
+}
+
+fn to_hex_color(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn mean_color(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    for pixel in pixels {
+        for (acc, channel) in sum.iter_mut().zip(pixel) {
+            *acc += *channel as u64;
+        }
+    }
+    let count = pixels.len().max(1) as u64;
+    sum.map(|v| (v / count) as u8)
+}
+
+/// Channel with the widest range of values and the range itself
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), p| {
+                (min.min(p[channel]), max.max(p[channel]))
+            });
+            (channel, max.saturating_sub(min))
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+/// Average color and dominant colors palette of image.
+///
+/// Computed on downscaled copy with median cut: pixels are split by median of the widest channel,
+/// until palette is filled. Transparent pixels are ignored (unless whole image is transparent)
+pub fn image_colors(img: &DynamicImage) -> ImageColors {
+    let sample = img
+        .thumbnail(COLORS_SAMPLE_SIZE, COLORS_SAMPLE_SIZE)
+        .to_rgba8();
+    let mut pixels: Vec<[u8; 3]> = sample
+        .pixels()
+        .filter(|p| p.0[3] >= 128)
+        .map(|p| [p.0[0], p.0[1], p.0[2]])
+        .collect();
+    if pixels.is_empty() {
+        pixels = sample.pixels().map(|p| [p.0[0], p.0[1], p.0[2]]).collect();
+    }
+    let total = pixels.len().max(1) as f32;
+    let average = to_hex_color(mean_color(&pixels));
+
+    let mut buckets = vec![pixels];
+    while buckets.len() < PALETTE_SIZE {
+        let Some((idx, channel)) = buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| bucket.len() > 1)
+            .map(|(idx, bucket)| (idx, widest_channel(bucket)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(idx, (channel, _))| (idx, channel))
+        else {
+            break;
+        };
+        let mut bucket = buckets.swap_remove(idx);
+        bucket.sort_unstable_by_key(|p| p[channel]);
+        // equal values are kept in the same bucket, to not split one color into several
+        let median = bucket[bucket.len() / 2][channel];
+        let split = match bucket.partition_point(|p| p[channel] < median) {
+            0 => bucket.partition_point(|p| p[channel] <= median),
+            split => split,
+        };
+        let upper = bucket.split_off(split);
+        buckets.push(bucket);
+        buckets.push(upper);
+    }
+
+    buckets.sort_by_key(|bucket| std::cmp::Reverse(bucket.len()));
+    let palette = buckets
+        .iter()
+        .filter(|bucket| !bucket.is_empty())
+        .map(|bucket| PaletteColor {
+            color: to_hex_color(mean_color(bucket)),
+            share: bucket.len() as f32 / total,
+        })
+        .collect();
+
+    ImageColors { average, palette }
+}
+
 pub fn cast_to_extension<I: GenericImageView<Pixel = Rgba<u8>>>(
     img: ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>,
     extension: Extensions,
     quality: Option<u32>,
+    bit_depth: Option<u8>,
+    dpi: Option<u32>,
+    interlace: bool,
 ) -> Vec<u8> {
     let new_width = img.width();
     let new_height = img.height();
@@ -143,10 +670,33 @@ pub fn cast_to_extension<I: GenericImageView<Pixel = Rgba<u8>>>(
                 .to_owned();
             bytes_img
         }
+        Extensions::Avif if bit_depth == Some(10) => {
+            let quality = quality.unwrap_or(AVIF_QUALITY) as f32;
+            // image crate encoder is limited to 8 bit, so ravif is used directly
+            let pixels: Vec<ravif::RGBA8> = new_data
+                .chunks_exact(4)
+                .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+                .collect();
+            ravif::Encoder::new()
+                .with_quality(quality)
+                .with_alpha_quality(quality)
+                .with_speed(AVIF_SPEED)
+                .with_bit_depth(ravif::BitDepth::Ten)
+                .encode_rgba(ravif::Img::new(
+                    pixels.as_slice(),
+                    new_width as usize,
+                    new_height as usize,
+                ))
+                .unwrap()
+                .avif_file
+        }
         Extensions::Avif => {
             let mut bytes_img: Vec<u8> = Vec::new();
-            let codec =
-                image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut bytes_img, 8, 92);
+            let codec = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                &mut bytes_img,
+                AVIF_SPEED,
+                quality.unwrap_or(AVIF_QUALITY) as u8,
+            );
 
             codec
                 .write_image(
@@ -159,6 +709,13 @@ pub fn cast_to_extension<I: GenericImageView<Pixel = Rgba<u8>>>(
 
             bytes_img
         }
+        Extensions::PNG if interlace => {
+            let bytes_img = encode_interlaced_png(&new_data, new_width, new_height);
+            match dpi {
+                Some(dpi) => png_with_dpi(bytes_img, dpi),
+                None => bytes_img,
+            }
+        }
         Extensions::PNG => {
             let mut bytes_img: Vec<u8> = Vec::new();
             let codec = image::codecs::png::PngEncoder::new(&mut bytes_img);
@@ -172,7 +729,718 @@ pub fn cast_to_extension<I: GenericImageView<Pixel = Rgba<u8>>>(
                 )
                 .unwrap();
 
-            bytes_img
+            match dpi {
+                Some(dpi) => png_with_dpi(bytes_img, dpi),
+                None => bytes_img,
+            }
+        }
+    }
+}
+
+/// End of PNG signature and IHDR chunk, which should be the first one
+const PNG_IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+
+/// Add `pHYs` chunk with density of encoded PNG (right after IHDR, as it should precede image data)
+fn png_with_dpi(mut data: Vec<u8>, dpi: u32) -> Vec<u8> {
+    // pHYs stores pixels per meter
+    let pixels_per_meter = (dpi as f64 / 0.0254).round() as u32;
+    let mut body = Vec::with_capacity(9);
+    body.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    body.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    // unit is meter
+    body.push(1);
+    data.splice(PNG_IHDR_END..PNG_IHDR_END, png_chunk(b"pHYs", &body));
+    data
+}
+
+/// PNG chunk: length, type, body and CRC of type with body
+fn png_chunk(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut crc = flate2::Crc::new();
+    crc.update(kind);
+    crc.update(body);
+
+    let mut chunk = Vec::with_capacity(4 + kind.len() + body.len() + 4);
+    chunk.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(body);
+    chunk.extend_from_slice(&crc.sum().to_be_bytes());
+    chunk
+}
+
+/// Adam7 passes: x and y of the first pixel, x and y steps
+const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Encode RGBA8 pixels as Adam7 interlaced PNG, as png encoder writes only non-interlaced rows
+fn encode_interlaced_png(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let mut scanlines = Vec::with_capacity(pixels.len() + pixels.len() / 4);
+    for (x0, y0, dx, dy) in ADAM7_PASSES {
+        // prior row of each pass starts as zeros
+        let mut previous: Vec<u8> = Vec::new();
+        for y in (y0..height).step_by(dy as usize) {
+            let row: Vec<u8> = (x0..width)
+                .step_by(dx as usize)
+                .flat_map(|x| {
+                    let offset = ((y * width + x) * 4) as usize;
+                    pixels[offset..offset + 4].iter().copied()
+                })
+                .collect();
+            if row.is_empty() {
+                break;
+            }
+            // Paeth filter, the best on average for photos
+            scanlines.push(4);
+            scanlines.extend(row.iter().enumerate().map(|(i, &value)| {
+                let left = i.checked_sub(4).map_or(0, |i| row[i]);
+                let up = previous.get(i).copied().unwrap_or(0);
+                let up_left = i
+                    .checked_sub(4)
+                    .and_then(|i| previous.get(i).copied())
+                    .unwrap_or(0);
+                value.wrapping_sub(paeth_predictor(left, up, up_left))
+            }));
+            previous = row;
+        }
+    }
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&scanlines).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bit RGBA, deflate compression, adaptive filtering, Adam7 interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 1]);
+
+    let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+    data.extend(png_chunk(b"IHDR", &header));
+    data.extend(png_chunk(b"IDAT", &compressed));
+    data.extend(png_chunk(b"IEND", &[]));
+    data
+}
+
+/// Neighbour (left, up or up-left), closest to their linear prediction
+fn paeth_predictor(left: u8, up: u8, up_left: u8) -> u8 {
+    let prediction = left as i16 + up as i16 - up_left as i16;
+    let distance = |value: u8| (prediction - value as i16).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Decode image of known format, applying its EXIF orientation if requested
+pub fn decode_image(data: &[u8], format: ImageFormat, auto_orient: bool) -> Option<DynamicImage> {
+    let mut decoder = image::ImageReader::with_format(Cursor::new(data), format)
+        .into_decoder()
+        .ok()?;
+    // missing or malformed orientation is not a reason to fail whole image
+    let orientation = match auto_orient {
+        true => decoder.orientation().ok(),
+        false => None,
+    };
+    let mut img = DynamicImage::from_decoder(decoder).ok()?;
+    if let Some(orientation) = orientation {
+        img.apply_orientation(orientation);
+    }
+    Some(img)
+}
+
+/// Frame of animated source by index, or the last one if there are fewer frames. Returns frame
+/// with its actual index, still sources have the only frame
+pub fn decode_frame(data: &[u8], format: ImageFormat, index: u32) -> Option<(DynamicImage, u32)> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data)).ok()?.into_frames(),
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data)).ok()?;
+            if !decoder.has_animation() {
+                return Some((image::load_from_memory_with_format(data, format).ok()?, 0));
+            }
+            decoder.into_frames()
+        }
+        _ => return Some((image::load_from_memory_with_format(data, format).ok()?, 0)),
+    };
+    // frames are decoded one by one, so frames after requested one are not decoded at all
+    let mut last = None;
+    for (i, frame) in frames.take(index as usize + 1).enumerate() {
+        last = Some((
+            DynamicImage::ImageRgba8(frame.ok()?.into_buffer()),
+            i as u32,
+        ));
+    }
+    last
+}
+
+/// Read image dimensions from header, without decoding whole image
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Image dimensions from header, swapped if EXIF orientation turns image by quarter
+pub fn oriented_dimensions(data: &[u8], auto_orient: bool) -> Option<(u32, u32)> {
+    let mut decoder = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let (width, height) = decoder.dimensions();
+    let quarter_turn = auto_orient
+        && matches!(
+            decoder.orientation(),
+            Ok(Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH)
+        );
+    Some(match quarter_turn {
+        true => (height, width),
+        false => (width, height),
+    })
+}
+
+/// Whether source has EXIF orientation, which changes its pixels on auto orienting
+pub fn is_reoriented(data: &[u8]) -> bool {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.orientation().ok())
+        .is_some_and(|orientation| orientation != Orientation::NoTransforms)
+}
+
+/// How much aspect ratio of resulting image differs from source (1.0 - same ratio)
+pub fn resize_distortion(source: (u32, u32), width: Option<u32>, height: Option<u32>) -> f64 {
+    let (src_w, src_h) = source;
+    let w = width.unwrap_or(src_w).max(1) as f64;
+    let h = height.unwrap_or(src_h).max(1) as f64;
+    let src_ratio = src_w.max(1) as f64 / src_h.max(1) as f64;
+    let target_ratio = w / h;
+    (target_ratio / src_ratio).max(src_ratio / target_ratio)
+}
+
+/// Check that encoder for the extension is actually working (it depends on compiled features)
+pub fn can_encode(extension: Extensions) -> bool {
+    let img: RgbaImage = ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 0, 0]));
+    std::panic::catch_unwind(|| {
+        cast_to_extension::<DynamicImage>(img, extension, None, None, None, false)
+    })
+    .map(|data| !data.is_empty())
+    .unwrap_or(false)
+}
+
+/// Encode image, catching encoder panics. With deadline encoding runs on its own thread,
+/// which is left to finish in background, if it's exceeded.
+///
+/// Encode slot `permit` is released only when encoder returns, so encodes left in background
+/// are still counted by concurrency limit
+#[allow(clippy::too_many_arguments)]
+pub fn encode_within(
+    img: RgbaImage,
+    extension: Extensions,
+    quality: Option<u32>,
+    bit_depth: Option<u8>,
+    dpi: Option<u32>,
+    interlace: bool,
+    deadline: Option<Duration>,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Result<Vec<u8>, String> {
+    run_encoder(
+        move || {
+            Ok(cast_to_extension::<DynamicImage>(
+                img, extension, quality, bit_depth, dpi, interlace,
+            ))
+        },
+        deadline,
+        permit,
+    )
+}
+
+/// Encode resized frames into animated webp, the same way as [`encode_within`] does for images
+pub fn encode_animation_within(
+    frames: Vec<(RgbaImage, i32)>,
+    loop_count: Option<u32>,
+    quality: Option<u32>,
+    deadline: Option<Duration>,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Result<Vec<u8>, String> {
+    run_encoder(
+        move || cast_animation_to_webp(frames, loop_count, quality),
+        deadline,
+        permit,
+    )
+}
+
+fn run_encoder(
+    encode: impl FnOnce() -> Result<Vec<u8>, String> + Send + 'static,
+    deadline: Option<Duration>,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Result<Vec<u8>, String> {
+    let encode = move || {
+        let _permit = permit;
+        encode()
+    };
+    let data = match deadline {
+        Some(deadline) => {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = sender.send(encode());
+            });
+            receiver.recv_timeout(deadline).map_err(|err| match err {
+                RecvTimeoutError::Timeout => format!("exceeded deadline of {:?}", deadline),
+                RecvTimeoutError::Disconnected => "encoder failed".to_string(),
+            })??
+        }
+        None => panic::catch_unwind(AssertUnwindSafe(encode))
+            .map_err(|_| "encoder failed".to_string())??,
+    };
+    match data.is_empty() {
+        true => Err("encoder returned no data".to_string()),
+        false => Ok(data),
+    }
+}
+
+/// Small gradient png, processed by self-test through full pipeline
+pub fn self_test_sample() -> Vec<u8> {
+    let img: RgbaImage = ImageBuffer::from_fn(32, 24, |x, y| {
+        Rgba([(x * 8) as u8, (y * 10) as u8, 128, 255])
+    });
+    cast_to_extension::<DynamicImage>(img, Extensions::PNG, None, None, None, false)
+}
+
+/// Decode all frames of animated source (gif, animated webp) with their start timestamps (ms)
+///
+/// Returns None for still images, so they can go through usual single frame processing
+pub fn decode_animation(data: &[u8], format: ImageFormat) -> Option<Vec<(DynamicImage, i32)>> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))
+            .ok()?
+            .into_frames()
+            .collect_frames()
+            .ok()?,
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data)).ok()?;
+            if !decoder.has_animation() {
+                return None;
+            }
+            decoder.into_frames().collect_frames().ok()?
+        }
+        _ => return None,
+    };
+    if frames.len() < 2 {
+        return None;
+    }
+
+    let mut timestamp = 0;
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let start = timestamp;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            timestamp += (numer / denom.max(1)) as i32;
+            (DynamicImage::ImageRgba8(frame.into_buffer()), start)
+        })
+        .collect();
+    Some(frames)
+}
+
+/// Drop frames, following previous kept one sooner than frame interval of fps.
+/// Kept frames last until the next kept one, so animation keeps its timing
+pub fn resample_frames(frames: Vec<(DynamicImage, i32)>, fps: u32) -> Vec<(DynamicImage, i32)> {
+    let interval = 1000 / fps.max(1) as i32;
+    let mut next_timestamp = 0;
+    frames
+        .into_iter()
+        .filter(|(_, timestamp)| {
+            if *timestamp < next_timestamp {
+                return false;
+            }
+            next_timestamp = timestamp + interval;
+            true
+        })
+        .collect()
+}
+
+/// Encode already resized frames into animated webp
+pub fn cast_animation_to_webp(
+    frames: Vec<(RgbaImage, i32)>,
+    loop_count: Option<u32>,
+    quality: Option<u32>,
+) -> Result<Vec<u8>, String> {
+    let (width, height) = frames
+        .first()
+        .map(|(frame, _)| frame.dimensions())
+        .ok_or_else(|| "animation has no frames".to_string())?;
+
+    let mut config =
+        webp::WebPConfig::new().map_err(|_| "failed to init webp config".to_string())?;
+    config.quality = quality.unwrap_or(DEFAULT_COMPRESSION_QUALITY) as f32;
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(loop_count.unwrap_or(0) as i32);
+    for (frame, timestamp) in frames.iter() {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.as_raw(),
+            frame.width(),
+            frame.height(),
+            *timestamp,
+        ));
+    }
+
+    encoder
+        .try_encode()
+        .map(|data| data.as_ref().to_owned())
+        .map_err(|err| format!("failed with {:?}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use crate::utils::testing::params;
+    use strum::IntoEnumIterator;
+
+    /// Loop count of animated webp, stored in ANIM chunk after its size and background color
+    fn webp_loop_count(data: &[u8]) -> u16 {
+        let anim = data.windows(4).position(|chunk| chunk == b"ANIM").unwrap();
+        u16::from_le_bytes([data[anim + 12], data[anim + 13]])
+    }
+
+    #[test]
+    fn animation_round_trips_with_loop_count() {
+        let frames = [[255, 0, 0, 255], [0, 0, 255, 255], [0, 255, 0, 255]]
+            .into_iter()
+            .enumerate()
+            .map(|(index, color)| (RgbaImage::from_pixel(8, 6, Rgba(color)), index as i32 * 100))
+            .collect();
+
+        let data = cast_animation_to_webp(frames, Some(3), Some(90)).unwrap();
+
+        assert_eq!(webp_loop_count(&data), 3);
+        let decoded = decode_animation(&data, ImageFormat::WebP).unwrap();
+        assert_eq!(decoded.len(), 3);
+        let (frame, timestamp) = &decoded[1];
+        assert_eq!(frame.dimensions(), (8, 6));
+        assert_eq!(*timestamp, 100);
+        let pixel = frame.get_pixel(4, 3);
+        assert!(pixel[2] > 200 && pixel[0] < 50, "{:?}", pixel);
+    }
+
+    #[test]
+    fn animation_without_frames_fails() {
+        assert!(cast_animation_to_webp(Vec::new(), None, None).is_err());
+        assert!(
+            encode_animation_within(Vec::new(), None, None, Some(Duration::from_secs(1)), None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn encode_deadline_fires_but_keeps_permit() {
+        let permits = std::sync::Arc::new(tokio::sync::Semaphore::new(1));
+        let permit = permits.clone().try_acquire_owned().unwrap();
+        let slow_encode = || {
+            thread::sleep(Duration::from_millis(300));
+            Ok(vec![1])
+        };
+
+        let started = std::time::Instant::now();
+        let result = run_encoder(slow_encode, Some(Duration::from_millis(50)), Some(permit));
+        assert_eq!(result, Err("exceeded deadline of 50ms".to_string()));
+        assert!(started.elapsed() < Duration::from_millis(300));
+        // stuck encode still takes its slot, until it returns
+        assert_eq!(permits.available_permits(), 0);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(permits.available_permits(), 1);
+
+        assert_eq!(run_encoder(slow_encode, None, None), Ok(vec![1]));
+        assert_eq!(
+            run_encoder(slow_encode, Some(Duration::from_secs(5)), None),
+            Ok(vec![1])
+        );
+    }
+
+    #[test]
+    fn compiled_encoders_pass_self_check() {
+        for extension in Extensions::iter() {
+            assert!(can_encode(extension), "{:?}", extension);
+        }
+    }
+
+    #[test]
+    fn short_aliases_match_long_params() {
+        let long = params("width=10&height=20&quality=50&extension=PNG");
+        for query in [
+            "w=10&h=20&q=50&fmt=PNG",
+            "w=10&height=20&q=50&extension=PNG",
+            "width=10&h=20&quality=50&fmt=PNG",
+        ] {
+            assert_eq!(params(query), long, "{}", query);
+        }
+    }
+
+    #[test]
+    fn gravity_selects_retained_region() {
+        let crop = |image: &DynamicImage, gravity| {
+            let cropped: RgbaImage = resize::<RgbaImage>(
+                image,
+                Some(10),
+                Some(10),
+                Some(RatioPolicy::CropToCenter),
+                Some(gravity),
+                ResizeFilters::single(ResizeFilter::Nearest),
+                None,
+            );
+            cropped
+        };
+        // red grows from left to right, green from top to bottom
+        let wide = testing::gradient(40, 20);
+        for (gravity, range) in [
+            (Gravity::West, 0..20),
+            (Gravity::Center, 50..80),
+            (Gravity::East, 110..145),
+            (Gravity::NorthEast, 110..145),
+        ] {
+            let red = crop(&wide, gravity).get_pixel(0, 0).0[0];
+            assert!(range.contains(&red), "{:?}: {}", gravity, red);
+        }
+        let tall = testing::gradient(20, 40);
+        for (gravity, range) in [
+            (Gravity::North, 0..20),
+            (Gravity::Center, 50..80),
+            (Gravity::South, 110..145),
+            (Gravity::SouthWest, 110..145),
+        ] {
+            let green = crop(&tall, gravity).get_pixel(0, 0).0[1];
+            assert!(range.contains(&green), "{:?}: {}", gravity, green);
+        }
+    }
+
+    #[test]
+    fn filter_is_selected_by_resize_direction() {
+        let filters = ResizeFilters {
+            upscale: ResizeFilter::Nearest,
+            downscale: ResizeFilter::Bilinear,
+        };
+        assert_eq!(filters.select((10, 10), (20, 20)), ResizeFilter::Nearest);
+        assert_eq!(filters.select((10, 10), (5, 5)), ResizeFilter::Bilinear);
+        assert_eq!(filters.select((10, 10), (10, 10)), ResizeFilter::Bilinear);
+
+        // only nearest filter keeps upscaled black and white pixels without intermediate shades
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| match x {
+            0 => Rgba([0, 0, 0, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        }));
+        let reversed = ResizeFilters {
+            upscale: ResizeFilter::Bilinear,
+            downscale: ResizeFilter::Nearest,
+        };
+        for (filters, pure) in [(filters, true), (reversed, false)] {
+            let upscaled: RgbaImage = resize::<RgbaImage>(
+                &image,
+                Some(16),
+                Some(8),
+                Some(RatioPolicy::Resize),
+                None,
+                filters,
+                None,
+            );
+            let only_pure = upscaled.pixels().all(|pixel| matches!(pixel.0[0], 0 | 255));
+            assert_eq!(only_pure, pure, "{:?}", filters);
+        }
+    }
+
+    /// Single pixel image of `color`, after adjustments of `query`
+    fn adjusted(color: [u8; 4], query: &str) -> [u8; 4] {
+        let mut img = RgbaImage::from_pixel(1, 1, Rgba(color));
+        adjust(&mut img, &params(query));
+        img.get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn dpi_is_written_into_png() {
+        let img: RgbaImage = ImageBuffer::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        let png = cast_to_extension::<DynamicImage>(
+            img.clone(),
+            Extensions::PNG,
+            None,
+            None,
+            Some(300),
+            false,
+        );
+
+        // pixels per meter for both axes and meter unit
+        let phys = png.windows(4).position(|chunk| chunk == b"pHYs").unwrap();
+        assert_eq!(png[phys + 4..phys + 8], 11811u32.to_be_bytes());
+        assert_eq!(png[phys + 8..phys + 12], 11811u32.to_be_bytes());
+        assert_eq!(png[phys + 12], 1);
+        // chunk checksum is valid and pixels are kept
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+        assert_eq!(decoded.to_rgba8(), img);
+
+        let plain = cast_to_extension::<DynamicImage>(
+            img.clone(),
+            Extensions::PNG,
+            None,
+            None,
+            None,
+            false,
+        );
+        assert!(!plain.windows(4).any(|chunk| chunk == b"pHYs"));
+        assert_eq!(
+            cast_to_extension::<DynamicImage>(
+                img.clone(),
+                Extensions::Webp,
+                None,
+                None,
+                Some(300),
+                false
+            ),
+            cast_to_extension::<DynamicImage>(img, Extensions::Webp, None, None, None, false)
+        );
+    }
+
+    #[test]
+    fn premultiplied_alpha_differs_from_straight() {
+        let encoded = |query: &str| {
+            let mut img = RgbaImage::from_fn(2, 1, |x, _| {
+                Rgba([200, 100, 50, if x == 0 { 128 } else { 255 }])
+            });
+            adjust(&mut img, &params(query));
+            let data =
+                cast_to_extension::<RgbaImage>(img, Extensions::PNG, None, None, None, false);
+            image::load_from_memory(&data).unwrap().to_rgba8()
+        };
+        let straight = encoded("");
+        let premultiplied = encoded("premultiply_alpha=true");
+
+        assert_eq!(straight.get_pixel(0, 0).0, [200, 100, 50, 128]);
+        assert_eq!(premultiplied.get_pixel(0, 0).0, [100, 50, 25, 128]);
+        // opaque pixels are the same
+        assert_eq!(straight.get_pixel(1, 0), premultiplied.get_pixel(1, 0));
+        assert_ne!(params(""), params("premultiply_alpha=true"));
+    }
+
+    #[test]
+    fn tint_shifts_toward_its_hue() {
+        let [r, g, b, a] = adjusted([128, 128, 128, 255], "tint=ff0000");
+        assert!(r > 200 && g < 30 && b < 30, "{:?}", [r, g, b]);
+        assert_eq!(a, 255);
+
+        // luminance is kept: black and white stay as they are
+        assert_eq!(adjusted([0, 0, 0, 255], "tint=336699"), [0, 0, 0, 255]);
+        assert_eq!(
+            adjusted([255, 255, 255, 255], "tint=336699"),
+            [255, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn adjustments_change_pixels_in_their_direction() {
+        let gray = [100, 100, 100, 255];
+        assert!(adjusted(gray, "brightness=20")[0] > 100);
+        assert!(adjusted(gray, "brightness=-20")[0] < 100);
+
+        // difference between dark and light pixels
+        let spread = |query: &str| {
+            let mut img = RgbaImage::from_fn(2, 1, |x, _| match x {
+                0 => Rgba([80, 80, 80, 255]),
+                _ => Rgba([170, 170, 170, 255]),
+            });
+            adjust(&mut img, &params(query));
+            img.get_pixel(1, 0).0[0] - img.get_pixel(0, 0).0[0]
+        };
+        assert!(spread("contrast=50") > 90);
+        assert!(spread("contrast=-50") < 90);
+
+        let reddish = [180, 100, 100, 255];
+        let [r, g, _, _] = adjusted(reddish, "saturation=50");
+        assert!(r - g > 80, "{:?}", [r, g]);
+        let [r, g, _, _] = adjusted(reddish, "saturation=-50");
+        assert!(r - g < 80, "{:?}", [r, g]);
+        let [r, g, b, _] = adjusted(reddish, "saturation=-100");
+        assert!(r == g && g == b, "{:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn sharpen_increases_edge_contrast() {
+        // soft edge, like on downscaled image
+        let row = [60, 60, 60, 60, 100, 150, 190, 190, 190, 190];
+        let contrast = |query: &str| {
+            let mut img = RgbaImage::from_fn(row.len() as u32, 3, |x, _| {
+                let value = row[x as usize];
+                Rgba([value, value, value, 255])
+            });
+            adjust(&mut img, &params(query));
+            let values: Vec<u8> = (0..img.width()).map(|x| img.get_pixel(x, 1).0[0]).collect();
+            values.iter().max().unwrap() - values.iter().min().unwrap()
+        };
+        assert_eq!(contrast(""), 130);
+        assert!(contrast("sharpen=50") > 130);
+        assert!(contrast("sharpen=100") > contrast("sharpen=50"));
+    }
+
+    #[test]
+    fn pad_fills_target_with_border() {
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 20, blue));
+        for (gravity, image_rows, border_rows) in [
+            (Gravity::Center, 5..15, [0, 4, 15, 19]),
+            (Gravity::North, 0..10, [10, 12, 15, 19]),
+            (Gravity::South, 10..20, [0, 4, 7, 9]),
+        ] {
+            let padded: RgbaImage = resize::<RgbaImage>(
+                &image,
+                Some(20),
+                Some(20),
+                Some(RatioPolicy::Pad),
+                Some(gravity),
+                ResizeFilters::default(),
+                Some(red),
+            );
+            assert_eq!(padded.dimensions(), (20, 20));
+            for y in border_rows {
+                assert_eq!(*padded.get_pixel(10, y), red, "{:?} row {}", gravity, y);
+            }
+            for y in image_rows {
+                assert_eq!(*padded.get_pixel(10, y), blue, "{:?} row {}", gravity, y);
+            }
+        }
+    }
+
+    /// Bits per channel of avif image, from its `pixi` box (after box type, version and flags, channels count)
+    fn avif_bit_depth(data: &[u8]) -> u8 {
+        let pixi = data.windows(4).position(|chunk| chunk == b"pixi").unwrap();
+        data[pixi + 9]
+    }
+
+    #[test]
+    fn avif_is_encoded_with_requested_bit_depth() {
+        let img = testing::gradient(16, 16).to_rgba8();
+        for (bit_depth, expected) in [(Some(10), 10), (Some(8), 8), (None, 8)] {
+            let data = cast_to_extension::<RgbaImage>(
+                img.clone(),
+                Extensions::Avif,
+                None,
+                bit_depth,
+                None,
+                false,
+            );
+            assert_eq!(avif_bit_depth(&data), expected, "{:?}", bit_depth);
         }
     }
 }
@@ -1,25 +1,48 @@
+use crate::config::{
+    EncodeTimeouts, FrameOutOfRangePolicy, QualityPerFormat, SingleDimensionPolicy,
+};
 use crate::image_ops::image_types::Extensions;
 use crate::image_ops::operations;
-use crate::image_ops::operations::{ProcessingParams, cast_to_extension};
-use crate::proxying_images::FileApiBackend;
-use crate::store::persistent_store::{PersistentStore, StorageBackgroundAdapter};
-use crate::store::processed_cache::ProcessedImagesCache;
+use crate::image_ops::operations::{
+    ImageColors, ProcessingParams, RatioPolicy, ResizeFilters, cast_to_extension,
+};
+use crate::image_ops::transforms;
+use crate::image_ops::transforms::ImageTransform;
+use crate::proxying_images::{
+    FetchOptions, FetchedStream, FileApiBackend, FileApiError, FileApiErrorKind, OriginValidators,
+};
+use crate::store::image_stats::{ImageStats, ImageStatsTracker};
+use crate::store::persistent_store::{
+    CompactionSchedule, CompactionService, PersistentStore, StorageBackgroundAdapter,
+};
+use crate::store::processed_cache::{ProcessedImagesCache, PurgedVariants};
 use crate::store::source_image_storage::OriginalImageStorage;
 use crate::utils::background::BackgroundService;
+use crate::utils::coalescer::Coalescer;
 use crate::utils::types::{ImageContainer, ImageId};
-use image::{DynamicImage, ImageFormat};
-use log::{debug, warn};
+use axum::body::Bytes;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use image::{DynamicImage, GenericImageView, ImageFormat, Rgba};
+use log::{debug, error, info, warn};
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::task::spawn_blocking;
 use tracing::instrument;
 
 pub enum ProcessingErrorType {
     UnsupportingExtension,
     NotFound,
-    FileApiError,
+    FileApiError(FileApiErrorKind),
     ProcessedImagesLimit,
+    UnavailableExtension,
+    InvalidSize,
+    /// Requested animation frame is after the last one
+    FrameOutOfRange,
+    /// Encoder failed or exceeded its deadline
+    EncodingFailed,
     // CorruptedCache
 }
 
@@ -30,14 +53,77 @@ impl ProcessingErrorType {
                 "Current image extension is not supported or not an image".to_string()
             }
             ProcessingErrorType::NotFound => "Current image is not found".to_string(),
-            ProcessingErrorType::FileApiError => "File not found".to_string(),
+            ProcessingErrorType::FileApiError(_) => "File not found".to_string(),
             ProcessingErrorType::ProcessedImagesLimit => {
                 "Limit exceed. No any new image formats allowed".to_string()
             }
+            ProcessingErrorType::UnavailableExtension => {
+                "Requested extension is not available on this server".to_string()
+            }
+            ProcessingErrorType::InvalidSize => "Requested size is not allowed".to_string(),
+            ProcessingErrorType::FrameOutOfRange => {
+                "Requested frame is after the last one".to_string()
+            }
+            ProcessingErrorType::EncodingFailed => "Failed to encode image".to_string(),
         }
     }
 }
 
+/// Whether served image was taken from processed cache or processed on request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// Served from processed cache, while its original is due for revalidation.
+    /// Caller should refresh it in background with [`Processor::refresh_stale`]
+    Stale,
+}
+
+/// Where original of processed image was taken from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum OriginalSource {
+    Storage,
+    /// Stored one, confirmed to be unchanged by conditional request to file api
+    Revalidated,
+    FileApi,
+}
+
+/// Count of originals, fetched at once for montage
+const MONTAGE_FETCH_CONCURRENCY: usize = 4;
+
+/// Count of image ids to keep computed colors for
+const COLORS_CACHE_CAPACITY: usize = 4096;
+
+/// Count of image ids, remembered as not found in file api
+const NOT_FOUND_CACHE_CAPACITY: usize = 4096;
+
+/// Max count of originals, tracked for revalidation. Untracked ones are not revalidated
+const ORIGINS_CACHE_CAPACITY: usize = 65536;
+
+/// Image id, used to cache processed versions of fallback image.
+/// Requested ids are sanitized, so they can't contain slash and collide with it
+const FALLBACK_IMAGE_ID: &str = "/fallback";
+
+/// Self-test report is reused during this time, so frequent probes don't load encoders
+const SELF_TEST_TTL: Duration = Duration::from_secs(5);
+
+/// Width of self-test results, so resizing is tested too
+const SELF_TEST_WIDTH: u32 = 16;
+
+/// Result of image serving
+pub struct ServedImage {
+    pub image: Arc<ImageContainer>,
+    pub cache_status: CacheStatus,
+    /// Source of processed original, None for processed cache hits and fallback image
+    pub original_source: Option<OriginalSource>,
+    /// Image is not found, configured fallback image is served instead
+    pub is_fallback: bool,
+    /// Client cache ttl (in seconds), provided on preloading image
+    pub cache_ttl: Option<u32>,
+}
+
 pub struct ProcessingError {
     pub err_type: ProcessingErrorType,
     pub detail: String,
@@ -50,14 +136,181 @@ impl ProcessingError {
     }
 }
 
+/// Processing settings of Processor
+pub struct ProcessorOptions {
+    pub default_extension: Extensions,
+    pub allow_custom_extension: bool,
+    /// Image, served instead of not found ones
+    pub fallback_image: Option<Vec<u8>>,
+    /// Max allowed aspect ratio change in Resize ratio policy
+    pub max_resize_distortion: Option<f64>,
+    /// Max count of pixels of all frames of animated output together. None is unlimited
+    pub max_animation_pixels: Option<u64>,
+    /// Originals fetched from file api above this size are not stored
+    pub max_cacheable_original_bytes: Option<usize>,
+    /// Interpolation filters by resize direction, if not overridden per request
+    pub resize_filters: ResizeFilters,
+    /// Max difference of channel from border color to consider pixel as border on trimming
+    pub trim_tolerance: u8,
+    /// Max count of avif encodes at once, so they can't occupy all blocking threads. None is unlimited
+    pub max_concurrent_avif_encodes: Option<usize>,
+    /// Encode webp instead of failed avif
+    pub avif_webp_fallback: bool,
+    /// Max time of encode per format, before failing (or falling back to webp for avif)
+    pub encode_timeouts: EncodeTimeouts,
+    /// Schedule of persistent store compaction. None disables compaction
+    pub compaction: Option<CompactionSchedule>,
+    /// Compute digest of processed images, to be stored with them
+    pub compute_digest: bool,
+    /// Max count of file api fetches at once, to not overload origin. None is unlimited
+    pub max_concurrent_origin_fetches: Option<usize>,
+    /// Apply EXIF orientation of originals, if not overridden per request
+    pub auto_orient: bool,
+    pub frame_out_of_range_policy: FrameOutOfRangePolicy,
+    pub single_dimension_policy: SingleDimensionPolicy,
+    /// Custom transforms, applied in order after resizing
+    pub transforms: Vec<Arc<dyn ImageTransform>>,
+    /// Quality of lossy formats, if not set in request. Formats without it use built-in one
+    pub default_quality: QualityPerFormat,
+    /// How long image ids not found in file api are answered with 404 without refetching. None disables it
+    pub not_found_ttl: Option<Duration>,
+    /// Age of originals fetched from file api, after which they are revalidated on access. None disables it
+    pub origin_revalidate_age: Option<Duration>,
+    /// Serve cached processed images of originals due for revalidation at once, as stale ones
+    pub stale_while_revalidate: bool,
+}
+
 pub struct Processor {
     storage: Arc<RwLock<dyn OriginalImageStorage + Send + Sync>>,
     cache: Arc<RwLock<dyn ProcessedImagesCache + Send + Sync>>,
     file_api: Option<Arc<dyn FileApiBackend + Send + Sync>>,
     persistent_storage: Option<Arc<PersistentStore>>,
+    /// Shares single file api fetch between concurrent requests of different variants of image
+    file_api_fetches: Coalescer<ImageId, Result<Arc<Vec<u8>>, FileApiError>>,
+    stats: ImageStatsTracker,
 
     default_extension: Extensions,
     allow_custom_extension: bool,
+    /// Extensions, which passed encoding self-check on startup
+    available_extensions: Vec<Extensions>,
+    /// Image, served instead of not found ones
+    fallback_image: Option<Arc<Vec<u8>>>,
+    /// Max allowed aspect ratio change in Resize ratio policy
+    max_resize_distortion: Option<f64>,
+    max_animation_pixels: Option<u64>,
+    /// Originals fetched from file api above this size are not stored
+    max_cacheable_original_bytes: Option<usize>,
+    /// Interpolation filters by resize direction, if not overridden per request
+    resize_filters: ResizeFilters,
+    /// Max difference of channel from border color to consider pixel as border on trimming
+    trim_tolerance: u8,
+    /// Computed colors of originals by image id
+    colors: quick_cache::sync::Cache<ImageId, Arc<ImageColors>>,
+    /// Permits of avif encodes, which are far heavier than other formats
+    avif_encodes: Option<Arc<Semaphore>>,
+    avif_webp_fallback: bool,
+    encode_timeouts: EncodeTimeouts,
+    compaction: Option<CompactionSchedule>,
+    compute_digest: bool,
+    /// Permits of file api fetches, excess ones wait for free slot
+    origin_fetches: Option<Arc<Semaphore>>,
+    auto_orient: bool,
+    frame_out_of_range_policy: FrameOutOfRangePolicy,
+    single_dimension_policy: SingleDimensionPolicy,
+    transforms: Vec<Arc<dyn ImageTransform>>,
+    default_quality: QualityPerFormat,
+    /// Time of file api 404 by image id, to fast-fail repeated requests of missing images
+    not_found: quick_cache::sync::Cache<ImageId, Instant>,
+    not_found_ttl: Option<Duration>,
+    /// Fetch (or last revalidation) time and validators of originals, fetched from file api
+    origins: quick_cache::sync::Cache<ImageId, (Instant, OriginValidators)>,
+    origin_revalidate_age: Option<Duration>,
+    stale_while_revalidate: bool,
+    /// Last self-test report with its time
+    self_test: Mutex<Option<(Instant, Arc<Vec<SelfTestResult>>)>>,
+}
+
+/// Original image for serving it verbatim
+pub enum OriginalBody {
+    Stored(Arc<Vec<u8>>),
+    /// Fetched from file api, which body is still being read
+    Streamed(FetchedStream),
+}
+
+/// Original, passed through from file api, which is collected to store it once it's read
+struct StreamedOriginal {
+    rest: BoxStream<'static, Result<Bytes, FileApiError>>,
+    /// Read part of original, `None` if it exceeds max size of stored originals
+    data: Option<Vec<u8>>,
+    content_length: Option<u64>,
+    /// Whole body is read
+    complete: bool,
+    image_id: ImageId,
+    storage: Arc<RwLock<dyn OriginalImageStorage + Send + Sync>>,
+    max_bytes: Option<usize>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl StreamedOriginal {
+    fn collect(&mut self, chunk: &[u8]) {
+        let Some(data) = &mut self.data else {
+            return;
+        };
+        if self
+            .max_bytes
+            .is_some_and(|max| data.len() + chunk.len() > max)
+        {
+            debug!(
+                "Image {} is too large to store original, it's only passed through",
+                self.image_id
+            );
+            self.data = None;
+            return;
+        }
+        data.extend_from_slice(chunk);
+        // body of declared length isn't polled after its last chunk
+        if self
+            .content_length
+            .is_some_and(|length| data.len() as u64 >= length)
+        {
+            self.complete = true;
+        }
+    }
+}
+
+/// Completely read original is stored in background, once it's passed through,
+/// even if client is gone meanwhile
+impl Drop for StreamedOriginal {
+    fn drop(&mut self) {
+        let Some(data) = self.data.take().filter(|_| self.complete) else {
+            return;
+        };
+        let image_id = std::mem::take(&mut self.image_id);
+        let storage = self.storage.clone();
+        tokio::spawn(async move {
+            let (data, decodable) = spawn_blocking(move || {
+                let decodable = image::load_from_memory(&data).is_ok();
+                (data, decodable)
+            })
+            .await
+            .unwrap();
+            if !decodable {
+                debug!(
+                    "File api responded with corrupted image {}, it's not stored",
+                    image_id
+                );
+                return;
+            }
+            storage.write().await.set(image_id, &data, None).await;
+        });
+    }
+}
+
+/// Result of processing self-test sample into one extension
+pub struct SelfTestResult {
+    pub extension: Extensions,
+    pub duration: Duration,
+    pub error: Option<String>,
 }
 
 impl Processor {
@@ -66,17 +319,179 @@ impl Processor {
         cache: Arc<RwLock<dyn ProcessedImagesCache + Send + Sync>>,
         file_api: Option<Arc<dyn FileApiBackend + Send + Sync>>,
         persistent_storage: Option<Arc<PersistentStore>>,
-        default_extension: Extensions,
-        allow_custom_extension: bool,
+        options: ProcessorOptions,
     ) -> Self {
+        let ProcessorOptions {
+            default_extension,
+            allow_custom_extension,
+            fallback_image,
+            max_resize_distortion,
+            max_animation_pixels,
+            max_cacheable_original_bytes,
+            resize_filters,
+            trim_tolerance,
+            max_concurrent_avif_encodes,
+            avif_webp_fallback,
+            encode_timeouts,
+            compaction,
+            compute_digest,
+            max_concurrent_origin_fetches,
+            auto_orient,
+            frame_out_of_range_policy,
+            single_dimension_policy,
+            transforms,
+            default_quality,
+            not_found_ttl,
+            origin_revalidate_age,
+            stale_while_revalidate,
+        } = options;
+
+        let available_extensions = Self::check_extensions(operations::can_encode);
+        if !available_extensions.contains(&default_extension) {
+            error!(
+                "Default extension {:?} is not available, requests without custom extension will fail",
+                default_extension
+            );
+        }
+
         Processor {
             storage,
             cache,
             file_api,
             persistent_storage,
+            file_api_fetches: Coalescer::new(),
+            stats: ImageStatsTracker::new(),
             default_extension,
             allow_custom_extension,
+            available_extensions,
+            fallback_image: fallback_image.map(Arc::new),
+            max_resize_distortion,
+            max_animation_pixels,
+            max_cacheable_original_bytes,
+            resize_filters,
+            trim_tolerance,
+            colors: quick_cache::sync::Cache::new(COLORS_CACHE_CAPACITY),
+            avif_encodes: max_concurrent_avif_encodes
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            avif_webp_fallback,
+            encode_timeouts,
+            compaction,
+            compute_digest,
+            origin_fetches: max_concurrent_origin_fetches
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            auto_orient,
+            frame_out_of_range_policy,
+            single_dimension_policy,
+            transforms,
+            default_quality,
+            not_found: quick_cache::sync::Cache::new(NOT_FOUND_CACHE_CAPACITY),
+            not_found_ttl,
+            origins: quick_cache::sync::Cache::new(ORIGINS_CACHE_CAPACITY),
+            origin_revalidate_age,
+            stale_while_revalidate,
+            self_test: Mutex::new(None),
+        }
+    }
+
+    pub fn available_extensions(&self) -> &[Extensions] {
+        &self.available_extensions
+    }
+
+    pub fn allow_custom_extension(&self) -> bool {
+        self.allow_custom_extension
+    }
+
+    /// Fill missing quality with default of resulting format, so processed images are cached
+    /// by effective quality and changing default doesn't serve stale ones
+    fn with_default_quality(&self, mut params: ProcessingParams) -> ProcessingParams {
+        if params.quality.is_none() {
+            let extension = self.determine_extension(&params);
+            params.quality = operations::default_quality(extension)
+                .map(|builtin| self.default_quality.get(extension).unwrap_or(builtin));
+        }
+        params
+    }
+
+    /// Quality is not requested or equal to default of resulting format (they are cached as the same variant)
+    fn is_default_quality(&self, params: &ProcessingParams) -> bool {
+        let default = self.with_default_quality(ProcessingParams {
+            quality: None,
+            ..params.clone()
+        });
+        params.quality == default.quality
+    }
+
+    /// Resulting size of resizing image with `source` dimensions by request params
+    pub fn target_size(&self, source: (u32, u32), params: &ProcessingParams) -> (u32, u32) {
+        self.single_dimension_policy
+            .target_size(source, params.width, params.height)
+    }
+
+    /// Encode test image with every extension, to disable ones, that are not working in current
+    /// build, instead of failing on real requests
+    fn check_extensions(can_encode: fn(Extensions) -> bool) -> Vec<Extensions> {
+        let mut available = Vec::new();
+        for extension in Extensions::iter() {
+            if can_encode(extension) {
+                available.push(extension);
+            } else {
+                warn!(
+                    "Encoding self-check failed for {:?}, extension is disabled",
+                    extension
+                );
+            }
+        }
+        info!("Available extensions: {:?}", available);
+        available
+    }
+
+    /// Decode, resize and encode bundled sample into every served extension.
+    ///
+    /// Report is cached for a few seconds, concurrent callers wait for the same run
+    pub async fn self_test(&self) -> Arc<Vec<SelfTestResult>> {
+        let mut last = self.self_test.lock().await;
+        if let Some((tested_at, report)) = last.as_ref()
+            && tested_at.elapsed() < SELF_TEST_TTL
+        {
+            return report.clone();
+        }
+
+        let extensions = match self.allow_custom_extension {
+            true => self.available_extensions.clone(),
+            false => vec![self.default_extension],
+        };
+        let sample = Arc::new(operations::self_test_sample());
+        let mut report = Vec::with_capacity(extensions.len());
+        for extension in extensions {
+            let params = ProcessingParams {
+                width: Some(SELF_TEST_WIDTH),
+                extension: Some(extension.into()),
+                ..Default::default()
+            };
+            let started = Instant::now();
+            let error = match self.transform(sample.clone(), params).await {
+                Ok(img) => {
+                    let format = image::guess_format(img.data.as_slice())
+                        .ok()
+                        .and_then(Extensions::from_format);
+                    (format != Some(extension))
+                        .then(|| format!("Encoded data is not {}", extension.name()))
+                }
+                Err(err) => Some(err.detail),
+            };
+            if let Some(error) = &error {
+                warn!("Self-test failed for {:?}: {}", extension, error);
+            }
+            report.push(SelfTestResult {
+                extension,
+                duration: started.elapsed(),
+                error,
+            });
         }
+
+        let report = Arc::new(report);
+        *last = Some((Instant::now(), report.clone()));
+        report
     }
 
     pub fn get_background_services(&self) -> Vec<Arc<RwLock<dyn BackgroundService + Send + Sync>>> {
@@ -84,6 +499,12 @@ impl Processor {
             vec![self.cache.clone(), self.storage.clone()];
 
         let store = self.persistent_storage.clone();
+        if let (Some(store), Some(schedule)) = (&store, self.compaction) {
+            res.push(Arc::new(RwLock::new(CompactionService::new(
+                store.clone(),
+                schedule,
+            ))));
+        }
         let adapter = StorageBackgroundAdapter::new(store);
         res.push(Arc::new(RwLock::new(adapter)));
 
@@ -108,12 +529,193 @@ impl Processor {
         None
     }
 
+    /// * `fetch_options` - used, if image is fetched from file api
+    /// * `fresh` - reprocess image, ignoring processed cache (result still replaces cached one)
     #[instrument(skip(self), fields(image_id = %image_id))]
     pub async fn get(
         &self,
         image_id: ImageId,
         params: ProcessingParams,
-    ) -> Result<Arc<ImageContainer>, ProcessingError> {
+        fetch_options: FetchOptions,
+        fresh: bool,
+    ) -> Result<ServedImage, ProcessingError> {
+        let params = self.with_default_quality(params);
+        let result = self
+            .get_image(image_id.clone(), params.clone(), fetch_options, fresh)
+            .await;
+        match (result, &self.fallback_image) {
+            (Err(err), Some(fallback)) if matches!(err.err_type, ProcessingErrorType::NotFound) => {
+                debug!("Image {} not found, serving fallback", image_id);
+                let (image, cache_status) = self
+                    .get_processed(FALLBACK_IMAGE_ID.to_string(), fallback.clone(), params)
+                    .await?;
+                Ok(ServedImage {
+                    image,
+                    cache_status,
+                    original_source: None,
+                    is_fallback: true,
+                    cache_ttl: None,
+                })
+            }
+            (result, _) => {
+                let (image, cache_status, original_source) = result?;
+                let cache_ttl = self.storage.read().await.cache_ttl(image_id).await;
+                Ok(ServedImage {
+                    image,
+                    cache_status,
+                    original_source,
+                    is_fallback: false,
+                    cache_ttl,
+                })
+            }
+        }
+    }
+
+    /// Original image as stored (or fetched from file api), without any processing,
+    /// with its client cache ttl
+    #[instrument(skip(self), fields(image_id = %image_id))]
+    pub async fn original(
+        &self,
+        image_id: ImageId,
+        fetch_options: FetchOptions,
+    ) -> Result<(Arc<Vec<u8>>, Option<u32>), ProcessingError> {
+        let (original, source) = self
+            .get_original_with_source(&image_id, fetch_options)
+            .await?;
+        let original = self.decodable_original(&image_id, original, source).await?;
+        let cache_ttl = self.storage.read().await.cache_ttl(image_id).await;
+        Ok((original, cache_ttl))
+    }
+
+    /// Original image for serving it verbatim, as `original`, but original missing in storage
+    /// is passed through as it's read from file api, instead of waiting for its whole body.
+    ///
+    /// Passed through original is stored once it's completely read and decoded,
+    /// interrupted and corrupted ones are not stored
+    pub async fn original_stream(
+        &self,
+        image_id: ImageId,
+        fetch_options: FetchOptions,
+    ) -> Result<(OriginalBody, Option<u32>), ProcessingError> {
+        if let Some(stored) = self.stored_original(&image_id).await {
+            let (original, source) = self
+                .revalidate_original(&image_id, stored, &fetch_options)
+                .await;
+            let original = self.decodable_original(&image_id, original, source).await?;
+            let cache_ttl = self.storage.read().await.cache_ttl(image_id).await;
+            return Ok((OriginalBody::Stored(original), cache_ttl));
+        }
+
+        let file_api = self.fetchable_file_api(&image_id)?;
+        // slot is held until body is read
+        let permit = self.origin_fetch_permit(&image_id).await;
+        let fetched = file_api
+            .fetch_stream_from_base_api(&image_id, &fetch_options)
+            .await
+            .map_err(|err| self.fetch_error(&image_id, err))?;
+        debug!("Streaming image {} from api", image_id);
+        if self.origin_revalidate_age.is_some() {
+            self.origins.insert(
+                image_id.clone(),
+                (Instant::now(), fetched.validators.clone()),
+            );
+        }
+
+        let mut streamed = StreamedOriginal {
+            rest: fetched.rest,
+            data: Some(Vec::new()),
+            content_length: fetched.content_length,
+            complete: false,
+            image_id,
+            storage: self.storage.clone(),
+            max_bytes: self.max_cacheable_original_bytes,
+            _permit: permit,
+        };
+        streamed.collect(&fetched.head);
+        let rest = stream::unfold(Some(streamed), |streamed| async move {
+            let mut streamed = streamed?;
+            match streamed.rest.next().await {
+                Some(Ok(chunk)) => {
+                    streamed.collect(&chunk);
+                    Some((Ok(chunk), Some(streamed)))
+                }
+                Some(Err(err)) => Some((Err(err), None)),
+                None => {
+                    streamed.complete = true;
+                    None
+                }
+            }
+        })
+        .boxed();
+        let fetched = FetchedStream {
+            head: fetched.head,
+            content_length: fetched.content_length,
+            validators: fetched.validators,
+            rest,
+        };
+        Ok((OriginalBody::Streamed(fetched), None))
+    }
+
+    /// Original, fetched from file api, is checked by decoding, as it's not processed
+    async fn decodable_original(
+        &self,
+        image_id: &ImageId,
+        original: Arc<Vec<u8>>,
+        source: OriginalSource,
+    ) -> Result<Arc<Vec<u8>>, ProcessingError> {
+        if source != OriginalSource::FileApi {
+            return Ok(original);
+        }
+        let decodable = {
+            let original = original.clone();
+            spawn_blocking(move || image::load_from_memory(&original).is_ok())
+                .await
+                .unwrap()
+        };
+        match decodable {
+            true => Ok(original),
+            false => Err(self.corrupted_original(image_id).await),
+        }
+    }
+
+    /// Get processed image from cache, or process provided original
+    async fn get_processed(
+        &self,
+        image_id: ImageId,
+        original_image: Arc<Vec<u8>>,
+        params: ProcessingParams,
+    ) -> Result<(Arc<ImageContainer>, CacheStatus), ProcessingError> {
+        let cached = self
+            .cache
+            .read()
+            .await
+            .get(image_id.clone(), params.clone())
+            .await;
+        if let Some(cached) = cached {
+            return Ok((cached, CacheStatus::Hit));
+        }
+        self._process_image(image_id, original_image, params, false)
+            .await
+            .map(|img| (img, CacheStatus::Miss))
+    }
+
+    async fn get_image(
+        &self,
+        image_id: ImageId,
+        params: ProcessingParams,
+        fetch_options: FetchOptions,
+        fresh: bool,
+    ) -> Result<(Arc<ImageContainer>, CacheStatus, Option<OriginalSource>), ProcessingError> {
+        if !self
+            .available_extensions
+            .contains(&self.determine_extension(&params))
+        {
+            return Err(ProcessingError::new(
+                ProcessingErrorType::UnavailableExtension,
+                None,
+            ));
+        }
+
         // Check processed image cache
         let cache_check_start = Instant::now();
         let cache = self.cache.clone();
@@ -132,109 +734,521 @@ impl Processor {
                 cache_check_time, image_id
             );
         }
-        if let Some(cached) = cached {
+        if let Some(cached) = cached
+            && !fresh
+        {
             debug!("Fetched image {} from cache", image_id);
-            return Ok(cached);
+            // otherwise cached images are served without revalidation of their originals
+            if self.stale_while_revalidate && self.revalidation_due(&image_id) {
+                return Ok((cached, CacheStatus::Stale, None));
+            }
+            return Ok((cached, CacheStatus::Hit, None));
         }
 
-        // Check storage for original image
-        let processed_from_storage = {
-            let orig_image = {
-                let storage = self.storage.clone();
-                let lock_start = Instant::now();
-                let storage_guard = storage.read().await;
-                let lock_wait = lock_start.elapsed();
-                if lock_wait.as_millis() > 10 {
-                    debug!("Storage lock wait: {:?} for image {}", lock_wait, image_id);
+        let (orig_image, source) = self
+            .get_original_with_source(&image_id, fetch_options)
+            .await?;
+        debug!("Start processing image {}", image_id);
+        match self
+            ._process_image(image_id.clone(), orig_image, params, fresh)
+            .await
+        {
+            Ok(img) => Ok((img, CacheStatus::Miss, Some(source))),
+            Err(err)
+                if source == OriginalSource::FileApi
+                    && matches!(err.err_type, ProcessingErrorType::UnsupportingExtension) =>
+            {
+                Err(self.corrupted_original(&image_id).await)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Fetched original turned out to be undecodable (like truncated one) on processing, as it's
+    /// not decoded on fetching to not decode it twice. It's removed, so next request fetches it again
+    async fn corrupted_original(&self, image_id: &ImageId) -> ProcessingError {
+        debug!("File api responded with corrupted image {}", image_id);
+        self.storage.write().await.remove(image_id.clone()).await;
+        self.origins.remove(image_id);
+        ProcessingError::new(
+            ProcessingErrorType::FileApiError(FileApiErrorKind::CorruptedImage),
+            Some("File api responded with corrupted or truncated image".to_string()),
+        )
+    }
+
+    /// Get original image from storage, or fetch it from file api (storing it for next requests)
+    async fn get_original(
+        &self,
+        image_id: &ImageId,
+        fetch_options: FetchOptions,
+    ) -> Result<Arc<Vec<u8>>, ProcessingError> {
+        self.get_original_with_source(image_id, fetch_options)
+            .await
+            .map(|(original, _)| original)
+    }
+
+    /// Original image along with where it was taken from
+    async fn get_original_with_source(
+        &self,
+        image_id: &ImageId,
+        fetch_options: FetchOptions,
+    ) -> Result<(Arc<Vec<u8>>, OriginalSource), ProcessingError> {
+        if let Some(orig_image) = self.stored_original(image_id).await {
+            return Ok(self
+                .revalidate_original(image_id, orig_image, &fetch_options)
+                .await);
+        }
+        let file_api = self.fetchable_file_api(image_id)?;
+        let response = self
+            .file_api_fetches
+            .run(image_id.clone(), || async {
+                let permit = self.origin_fetch_permit(image_id).await;
+                let fetched = file_api
+                    .fetch_img_from_base_api(image_id, &fetch_options)
+                    .await;
+                drop(permit);
+                let fetched = fetched?;
+                debug!("Fetched image {} from api", image_id);
+                if self.origin_revalidate_age.is_some() {
+                    self.origins
+                        .insert(image_id.clone(), (Instant::now(), fetched.validators));
                 }
-                storage_guard.get(image_id.clone()).await
-            };
-            match orig_image {
-                None => None,
-                Some(orig_image) => {
-                    let img_format = self.get_image_format(orig_image.as_ref());
-                    match img_format {
-                        None => {
-                            warn!(
-                                "Cache is corrupted for image {}. Fetching from api",
-                                image_id.clone()
-                            );
-                            None
-                        }
-                        Some(_) => {
-                            debug!("Found image {} in storage, start processing", image_id);
-                            return self._process_image(image_id, orig_image, params).await;
-                        }
-                    }
+                let orig_image = fetched.data;
+                if self
+                    .max_cacheable_original_bytes
+                    .is_some_and(|max| orig_image.len() > max)
+                {
+                    debug!(
+                        "Image {} is too large ({} bytes) to store original",
+                        image_id,
+                        orig_image.len()
+                    );
+                    return Ok(Arc::new(orig_image));
                 }
+                // stored before sharing, so requests after this fetch find image in storage
+                let storage = self.storage.clone();
+                storage
+                    .write()
+                    .await
+                    .set(image_id.clone(), &orig_image, None)
+                    .await;
+                Ok(Arc::new(orig_image))
+            })
+            .await;
+        let original = response.map_err(|err| self.fetch_error(image_id, err))?;
+        Ok((original, OriginalSource::FileApi))
+    }
+
+    /// Original image from storage, unless it's missing or corrupted
+    async fn stored_original(&self, image_id: &ImageId) -> Option<Arc<Vec<u8>>> {
+        let orig_image = {
+            let storage = self.storage.clone();
+            let lock_start = Instant::now();
+            let storage_guard = storage.read().await;
+            let lock_wait = lock_start.elapsed();
+            if lock_wait.as_millis() > 10 {
+                debug!("Storage lock wait: {:?} for image {}", lock_wait, image_id);
+            }
+            storage_guard.get(image_id.clone()).await
+        }?;
+        match self.get_image_format(orig_image.as_ref()) {
+            None => {
+                warn!(
+                    "Cache is corrupted for image {}. Fetching from api",
+                    image_id.clone()
+                );
+                None
+            }
+            Some(_) => {
+                debug!("Found image {} in storage", image_id);
+                Some(orig_image)
             }
-        };
-        if let Some(processed_image) = processed_from_storage {
-            return processed_image;
         }
+    }
 
-        if self.file_api.is_none() {
+    /// File api to fetch missing original from, unless it's disabled or image was recently not found
+    fn fetchable_file_api(
+        &self,
+        image_id: &ImageId,
+    ) -> Result<Arc<dyn FileApiBackend + Send + Sync>, ProcessingError> {
+        let Some(file_api) = self.file_api.clone() else {
             debug!("File api disabled. Image {} not found", image_id);
             return Err(ProcessingError::new(ProcessingErrorType::NotFound, None));
+        };
+
+        if let Some(ttl) = self.not_found_ttl
+            && let Some(found_at) = self.not_found.get(image_id)
+        {
+            if found_at.elapsed() < ttl {
+                debug!("Image {} was recently not found in file api", image_id);
+                return Err(ProcessingError::new(
+                    ProcessingErrorType::NotFound,
+                    Some("Image was recently not found in file api".to_string()),
+                ));
+            }
+            self.not_found.remove(image_id);
         }
+        Ok(file_api)
+    }
 
-        let response = self
-            .file_api
-            .clone()
-            .unwrap()
-            .fetch_img_from_base_api(&image_id)
-            .await;
-        match response {
+    /// Processing error of failed fetch from file api
+    fn fetch_error(&self, image_id: &ImageId, err: FileApiError) -> ProcessingError {
+        if err.kind == FileApiErrorKind::HttpStatus(404) {
+            if self.not_found_ttl.is_some() {
+                self.not_found.insert(image_id.clone(), Instant::now());
+            }
+            return ProcessingError::new(ProcessingErrorType::NotFound, Some(err.reason));
+        }
+        if err.kind == FileApiErrorKind::NotAnImage {
+            return ProcessingError::new(
+                ProcessingErrorType::UnsupportingExtension,
+                Some(err.reason),
+            );
+        }
+        warn!(
+            "Failed to fetch image {} from file api: {}",
+            image_id, err.kind
+        );
+        ProcessingError::new(
+            ProcessingErrorType::FileApiError(err.kind),
+            Some(format!("err: {}; kind: {}", err.reason, err.kind)),
+        )
+    }
+
+    /// Dimensions of original image, as it's processed (after EXIF orientation)
+    pub async fn original_dimensions(
+        &self,
+        image_id: &ImageId,
+        params: &ProcessingParams,
+        fetch_options: FetchOptions,
+    ) -> Result<(u32, u32), ProcessingError> {
+        let original = self.get_original(image_id, fetch_options).await?;
+        let auto_orient = params.auto_orient.unwrap_or(self.auto_orient);
+        operations::oriented_dimensions(original.as_ref(), auto_orient)
+            .ok_or_else(|| ProcessingError::new(ProcessingErrorType::UnsupportingExtension, None))
+    }
+
+    /// Slot of file api fetch, if their concurrency is limited
+    async fn origin_fetch_permit(&self, image_id: &ImageId) -> Option<OwnedSemaphorePermit> {
+        let permits = self.origin_fetches.clone()?;
+        let wait_start = Instant::now();
+        let permit = permits.acquire_owned().await.unwrap();
+        let wait = wait_start.elapsed();
+        if wait.as_millis() > 10 {
+            debug!(
+                "File api fetch slot wait: {:?} for image {}",
+                wait, image_id
+            );
+        }
+        Some(permit)
+    }
+
+    /// Original is fetched from file api longer than revalidate age ago
+    fn revalidation_due(&self, image_id: &ImageId) -> bool {
+        let (Some(age), Some(_)) = (self.origin_revalidate_age, &self.file_api) else {
+            return false;
+        };
+        self.origins
+            .get(image_id)
+            .is_some_and(|(fetched_at, _)| fetched_at.elapsed() >= age)
+    }
+
+    /// Revalidate original of stale served image and process it again, if original is changed,
+    /// so next request gets fresh image
+    #[instrument(skip(self), fields(image_id = %image_id))]
+    pub async fn refresh_stale(
+        &self,
+        image_id: ImageId,
+        params: ProcessingParams,
+        fetch_options: FetchOptions,
+    ) {
+        let params = self.with_default_quality(params);
+        let (original, source) = match self
+            .get_original_with_source(&image_id, fetch_options)
+            .await
+        {
+            Ok(original) => original,
             Err(err) => {
-                if err.http_error_code.unwrap_or(0) == 404 {
-                    return Err(ProcessingError::new(
-                        ProcessingErrorType::NotFound,
-                        Some(err.reason),
-                    ));
+                warn!("Failed to refresh stale image {}: {}", image_id, err.detail);
+                return;
+            }
+        };
+        // unchanged original keeps processed versions valid
+        if source != OriginalSource::FileApi {
+            return;
+        }
+        debug!("Processing refreshed image {}", image_id);
+        if let Err(err) = self
+            ._process_image(image_id.clone(), original, params, true)
+            .await
+        {
+            if matches!(err.err_type, ProcessingErrorType::UnsupportingExtension) {
+                self.corrupted_original(&image_id).await;
+            }
+            warn!(
+                "Failed to process refreshed image {}: {}",
+                image_id, err.detail
+            );
+        }
+    }
+
+    /// Refetch stored original with conditional request, if it was fetched from file api longer than
+    /// revalidate age ago. Changed one replaces stored original along with its processed versions.
+    ///
+    /// Originals, which weren't fetched from file api (like preloaded ones), are not revalidated.
+    /// Stored original is served, if revalidation fails
+    async fn revalidate_original(
+        &self,
+        image_id: &ImageId,
+        stored: Arc<Vec<u8>>,
+        fetch_options: &FetchOptions,
+    ) -> (Arc<Vec<u8>>, OriginalSource) {
+        let (Some(age), Some(file_api)) = (self.origin_revalidate_age, &self.file_api) else {
+            return (stored, OriginalSource::Storage);
+        };
+        let Some((fetched_at, validators)) = self.origins.get(image_id) else {
+            return (stored, OriginalSource::Storage);
+        };
+        if fetched_at.elapsed() < age {
+            return (stored, OriginalSource::Storage);
+        }
+        // postponed at once, so concurrent requests serve stored original instead of revalidating too
+        self.origins
+            .insert(image_id.clone(), (Instant::now(), validators.clone()));
+
+        debug!("Revalidating original {} in file api", image_id);
+        let options = FetchOptions {
+            validators: Some(validators),
+            ..fetch_options.clone()
+        };
+        let permit = self.origin_fetch_permit(image_id).await;
+        let fetched = file_api.fetch_img_from_base_api(image_id, &options).await;
+        drop(permit);
+        let fetched = match fetched {
+            Ok(fetched) => fetched,
+            Err(err) if err.kind == FileApiErrorKind::NotModified => {
+                debug!("Original {} is not modified in file api", image_id);
+                return (stored, OriginalSource::Revalidated);
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to revalidate original {}: {}, serving stored one",
+                    image_id, err.kind
+                );
+                return (stored, OriginalSource::Storage);
+            }
+        };
+        self.origins
+            .insert(image_id.clone(), (Instant::now(), fetched.validators));
+        if fetched.data == *stored {
+            return (stored, OriginalSource::Revalidated);
+        }
+
+        info!("Original {} is changed in file api, replacing it", image_id);
+        {
+            let mut storage = self.storage.write().await;
+            match self
+                .max_cacheable_original_bytes
+                .is_some_and(|max| fetched.data.len() > max)
+            {
+                true => storage.remove(image_id.clone()).await,
+                false => {
+                    // changed original keeps client cache ttl, stored one had
+                    let cache_ttl = storage.cache_ttl(image_id.clone()).await;
+                    storage
+                        .set(image_id.clone(), &fetched.data, cache_ttl)
+                        .await
                 }
-                Err(ProcessingError::new(
-                    ProcessingErrorType::FileApiError,
-                    Some(format!(
-                        "err: {}; status: {:#?}",
-                        err.reason, err.http_error_code
-                    )),
-                ))
             }
+        }
+        self.colors.remove(image_id);
+        self.cache.write().await.remove(image_id.clone()).await;
+        (Arc::new(fetched.data), OriginalSource::FileApi)
+    }
 
-            Ok(orig_image) => {
-                debug!("Fetched from api, start processing image {}", image_id);
+    /// Average and dominant colors of original image, cached per image id
+    #[instrument(skip(self), fields(image_id = %image_id))]
+    pub async fn colors(
+        &self,
+        image_id: ImageId,
+        fetch_options: FetchOptions,
+    ) -> Result<Arc<ImageColors>, ProcessingError> {
+        if let Some(colors) = self.colors.get(&image_id) {
+            return Ok(colors);
+        }
+        let (orig_image, source) = self
+            .get_original_with_source(&image_id, fetch_options)
+            .await?;
+        let colors = spawn_blocking(move || {
+            image::load_from_memory(orig_image.as_ref())
+                .ok()
+                .map(|img| Arc::new(operations::image_colors(&img)))
+        })
+        .await
+        .unwrap();
+        let colors = match (colors, source) {
+            (Some(colors), _) => colors,
+            (None, OriginalSource::FileApi) => {
+                return Err(self.corrupted_original(&image_id).await);
+            }
+            (None, _) => {
+                return Err(ProcessingError::new(
+                    ProcessingErrorType::UnsupportingExtension,
+                    None,
+                ));
+            }
+        };
+        self.colors.insert(image_id, colors.clone());
 
-                let storage = self.storage.clone();
-                let mut storage_guard = storage.write().await;
-                storage_guard.set(image_id.clone(), &orig_image).await;
+        Ok(colors)
+    }
 
-                self._process_image(image_id, Arc::new(orig_image), params)
-                    .await
+    /// Grid of originals, resized to square cells. Montages are not cached
+    ///
+    /// * `params` - only `extension` and `quality` are used
+    #[instrument(skip(self, params))]
+    pub async fn montage(
+        &self,
+        image_ids: Vec<ImageId>,
+        cols: u32,
+        cell: u32,
+        params: ProcessingParams,
+        fetch_options: FetchOptions,
+    ) -> Result<ImageContainer, ProcessingError> {
+        let params = self.with_default_quality(params);
+        let extension = self.determine_extension(&params);
+        if !self.available_extensions.contains(&extension) {
+            return Err(ProcessingError::new(
+                ProcessingErrorType::UnavailableExtension,
+                None,
+            ));
+        }
+        let originals: Vec<(Arc<Vec<u8>>, OriginalSource)> = stream::iter(image_ids.clone())
+            .map(|image_id| {
+                let fetch_options = fetch_options.clone();
+                async move {
+                    self.get_original_with_source(&image_id, fetch_options)
+                        .await
+                        .map_err(|mut err| {
+                            err.detail = format!("{}: {}", image_id, err.detail);
+                            err
+                        })
+                }
+            })
+            .buffered(MONTAGE_FETCH_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let filters = self.resize_filters;
+        let auto_orient = params.auto_orient.unwrap_or(self.auto_orient);
+        let sources: Vec<OriginalSource> = originals.iter().map(|(_, source)| *source).collect();
+        let decoded = spawn_blocking(move || {
+            originals
+                .iter()
+                .map(|(original, _)| {
+                    let format = image::guess_format(original.as_ref()).ok()?;
+                    operations::decode_image(original.as_ref(), format, auto_orient)
+                })
+                .collect::<Vec<Option<DynamicImage>>>()
+        })
+        .await
+        .unwrap();
+        if let Some(index) = decoded.iter().position(Option::is_none) {
+            return Err(match sources[index] {
+                OriginalSource::FileApi => {
+                    let mut err = self.corrupted_original(&image_ids[index]).await;
+                    err.detail = format!("{}: {}", image_ids[index], err.detail);
+                    err
+                }
+                _ => ProcessingError::new(
+                    ProcessingErrorType::UnsupportingExtension,
+                    Some("One of images is corrupted and can't be decoded".to_string()),
+                ),
+            });
+        }
+        let images: Vec<DynamicImage> = decoded.into_iter().flatten().collect();
+
+        let encode_timeout = self.encode_timeouts.get(extension);
+        let permit = self.encode_permit(extension).await;
+        spawn_blocking(move || {
+            let grid = operations::montage(&images, cols, cell, filters)
+                .ok_or_else(|| ProcessingError::new(ProcessingErrorType::InvalidSize, None))?;
+            let dimensions = grid.dimensions();
+            let data = operations::encode_within(
+                grid,
+                extension,
+                params.quality,
+                params.bit_depth,
+                params.dpi,
+                params.interlace == Some(true),
+                encode_timeout,
+                permit,
+            )
+            .map_err(|err| {
+                warn!("Montage {:?} encoding {}", extension, err);
+                ProcessingError::new(
+                    ProcessingErrorType::EncodingFailed,
+                    Some(format!("{:?} encoding {}", extension, err)),
+                )
+            })?;
+            Ok(ImageContainer::new(Box::new(data), None, extension)
+                .with_dimensions(Some(dimensions)))
+        })
+        .await
+        .map_err(|err| {
+            warn!("Montage processing failed: {}", err);
+            ProcessingError::new(
+                ProcessingErrorType::EncodingFailed,
+                Some("Montage processing failed".to_string()),
+            )
+        })?
+    }
+
+    /// Wait for encode slot of extension, if its encodes are limited. Permit should be held until encode is done
+    async fn encode_permit(&self, extension: Extensions) -> Option<OwnedSemaphorePermit> {
+        match (extension, &self.avif_encodes) {
+            (Extensions::Avif, Some(permits)) => {
+                let wait_start = Instant::now();
+                let permit = permits.clone().acquire_owned().await.unwrap();
+                let wait = wait_start.elapsed();
+                if wait.as_millis() > 10 {
+                    debug!("Avif encode slot wait: {:?}", wait);
+                }
+                Some(permit)
             }
+            _ => None,
         }
     }
 
-    fn determine_extension(&self, params: &ProcessingParams) -> Extensions {
+    /// Resulting extension of processing with params
+    pub fn determine_extension(&self, params: &ProcessingParams) -> Extensions {
         if !self.allow_custom_extension {
             return self.default_extension;
         }
-        match params.extension {
-            None => self.default_extension,
-            Some(v) => v,
-        }
+        // not negotiated `auto` (like in warm manifest) falls back to default
+        params
+            .extension
+            .and_then(Option::<Extensions>::from)
+            .unwrap_or(self.default_extension)
     }
 
-    /// Fully process image and puts it in all caches (storage + processing cache)
-    ///
-    /// * `image_id` - should be only the **original** image (cause it's passing into storage cache)
-    async fn _process_image(
+    /// Process image by params, without any storage or cache involvement
+    pub async fn transform(
         &self,
-        image_id: ImageId,
         original_image: Arc<Vec<u8>>,
         params: ProcessingParams,
     ) -> Result<Arc<ImageContainer>, ProcessingError> {
+        let params = self.with_default_quality(params);
+        if !self
+            .available_extensions
+            .contains(&self.determine_extension(&params))
+        {
+            return Err(ProcessingError::new(
+                ProcessingErrorType::UnavailableExtension,
+                None,
+            ));
+        }
         let params_clone = params.clone();
-        let resize_start = Instant::now();
 
         let img_format = self.get_image_format(original_image.as_ref());
         if img_format.is_none() {
@@ -244,38 +1258,303 @@ impl Processor {
             ));
         }
 
+        if let Some(max_distortion) = self.max_resize_distortion
+            && params.ratio_policy.clone().unwrap_or_default() == RatioPolicy::Resize
+            && let Some(dimensions) = operations::image_dimensions(original_image.as_ref())
+        {
+            let (width, height) = self.target_size(dimensions, &params);
+            let distortion = operations::resize_distortion(dimensions, Some(width), Some(height));
+            if distortion > max_distortion {
+                return Err(ProcessingError::new(
+                    ProcessingErrorType::InvalidSize,
+                    Some(format!(
+                        "Aspect ratio distortion {:.2} exceeds allowed {:.2}, use other ratio_policy",
+                        distortion, max_distortion
+                    )),
+                ));
+            }
+        }
+
         let original_image_clone = original_image.clone();
         let extension = self.determine_extension(&params);
+        let auto_orient = params.auto_orient.unwrap_or(self.auto_orient);
+        let pass_through = params.skip_smaller == Some(true)
+            && !params.has_adjustments()
+            && self.is_default_quality(&params)
+            && params.frame.is_none()
+            && params.fps.is_none()
+            && params.dpi.is_none()
+            && params.interlace.is_none()
+            && self.transforms.is_empty()
+            && Extensions::from_format(img_format.unwrap()) == Some(extension)
+            && operations::image_dimensions(original_image.as_ref())
+                .is_some_and(|dimensions| params.is_satisfied_by(dimensions))
+            && !(auto_orient && operations::is_reoriented(original_image.as_ref()));
+        let resize_filters = params
+            .filter
+            .map(ResizeFilters::single)
+            .unwrap_or(self.resize_filters);
+        let background = params
+            .background
+            .as_deref()
+            .and_then(operations::parse_hex_color)
+            .map(|[r, g, b]| Rgba([r, g, b, 255]));
+        let trim_tolerance = self.trim_tolerance;
+        let max_animation_pixels = self.max_animation_pixels;
+        let frame_out_of_range_policy = self.frame_out_of_range_policy;
+        let single_dimension_policy = self.single_dimension_policy;
+        let custom_transforms = self.transforms.clone();
+        let compute_digest = self.compute_digest;
+        let avif_webp_fallback = self.avif_webp_fallback && extension == Extensions::Avif;
+        let encode_timeout = self.encode_timeouts.get(extension);
+        // digest is computed along with encoding, so cache hits are served without recomputing it
+        let container = move |data: Vec<u8>, dimensions: Option<(u32, u32)>, extension| {
+            let container =
+                ImageContainer::new(Box::new(data), None, extension).with_dimensions(dimensions);
+            Arc::new(match compute_digest {
+                true => container.with_digest(),
+                false => container,
+            })
+        };
+        let permit = match pass_through {
+            true => None,
+            false => self.encode_permit(extension).await,
+        };
         let result = spawn_blocking(move || {
             let original_image = original_image_clone;
-            let img =
-                image::load_from_memory_with_format(original_image.as_ref(), img_format.unwrap())
-                    .unwrap();
-
             let params = params_clone;
+
+            if pass_through {
+                debug!("Source already satisfies request, serving it without processing");
+                let dimensions = operations::image_dimensions(original_image.as_ref());
+                return Ok(container(
+                    original_image.as_ref().clone(),
+                    dimensions,
+                    extension,
+                ));
+            }
+            // stored originals may be corrupted (like truncated preloads)
+            let corrupted = || {
+                ProcessingError::new(
+                    ProcessingErrorType::UnsupportingExtension,
+                    Some("Image is corrupted and can't be decoded".to_string()),
+                )
+            };
+
+            let animation = match extension {
+                _ if params.frame.is_some() => None,
+                Extensions::Webp => {
+                    operations::decode_animation(original_image.as_ref(), img_format.unwrap())
+                }
+                _ => None,
+            };
+            if let Some(mut frames) = animation {
+                if let Some(fps) = params.fps {
+                    frames = operations::resample_frames(frames, fps);
+                }
+                let frames_count = frames.len();
+                let animation_start = Instant::now();
+                // the same bounds for all frames, to keep their sizes equal
+                let trim = match params.trim {
+                    Some(true) => frames
+                        .first()
+                        .and_then(|(frame, _)| operations::trim_bounds(frame, trim_tolerance)),
+                    _ => None,
+                };
+                // every frame is resized and encoded, so frames multiply cost of a single image
+                if let Some(max_pixels) = max_animation_pixels
+                    && let Some((frame, _)) = frames.first()
+                {
+                    let source = trim.map_or(frame.dimensions(), |(_, _, w, h)| (w, h));
+                    let (width, height) =
+                        single_dimension_policy.target_size(source, params.width, params.height);
+                    let pixels = frames_count as u64 * width as u64 * height as u64;
+                    if pixels > max_pixels {
+                        return Err(ProcessingError::new(
+                            ProcessingErrorType::InvalidSize,
+                            Some(format!(
+                                "Animation of {} frames {}x{} exceeds {} pixels, request smaller size or lower fps",
+                                frames_count, width, height, max_pixels
+                            )),
+                        ));
+                    }
+                }
+                let frames = frames
+                    .into_iter()
+                    .map(|(frame, timestamp)| {
+                        let frame = match trim {
+                            Some((x, y, w, h)) => frame.crop_imm(x, y, w, h),
+                            None => frame,
+                        };
+                        let (width, height) = single_dimension_policy.target_size(
+                            frame.dimensions(),
+                            params.width,
+                            params.height,
+                        );
+                        let mut resized = operations::resize::<DynamicImage>(
+                            &frame,
+                            Some(width),
+                            Some(height),
+                            params.ratio_policy.clone(),
+                            params.gravity,
+                            resize_filters,
+                            background,
+                        );
+                        operations::adjust(&mut resized, &params);
+                        let resized = transforms::apply_all(&custom_transforms, resized, &params);
+                        (resized, timestamp)
+                    })
+                    .collect::<Vec<_>>();
+                let dimensions = frames.first().map(|(frame, _)| frame.dimensions());
+                let result_data = operations::encode_animation_within(
+                    frames,
+                    params.loop_count,
+                    params.frame_quality.or(params.quality),
+                    encode_timeout,
+                    permit,
+                )
+                .map_err(|err| {
+                    warn!("Animation encoding {}", err);
+                    ProcessingError::new(
+                        ProcessingErrorType::EncodingFailed,
+                        Some(format!("Animation encoding {}", err)),
+                    )
+                })?;
+                debug!(
+                    "Animation of {} frames processed in {:?}",
+                    frames_count,
+                    animation_start.elapsed()
+                );
+                return Ok(container(result_data, dimensions, extension));
+            }
+
+            let mut img = match params.frame {
+                Some(index) => {
+                    let (frame, actual) = operations::decode_frame(
+                        original_image.as_ref(),
+                        img_format.unwrap(),
+                        index,
+                    )
+                    .ok_or_else(corrupted)?;
+                    if actual < index && frame_out_of_range_policy == FrameOutOfRangePolicy::Reject
+                    {
+                        return Err(ProcessingError::new(
+                            ProcessingErrorType::FrameOutOfRange,
+                            Some(format!(
+                                "Frame {} is requested, but image has {} frames",
+                                index,
+                                actual + 1
+                            )),
+                        ));
+                    }
+                    frame
+                }
+                None => operations::decode_image(
+                    original_image.as_ref(),
+                    img_format.unwrap(),
+                    auto_orient,
+                )
+                .ok_or_else(corrupted)?,
+            };
+            if params.trim == Some(true)
+                && let Some((x, y, w, h)) = operations::trim_bounds(&img, trim_tolerance)
+            {
+                img = img.crop_imm(x, y, w, h);
+            }
+
             let resize_op_start = Instant::now();
-            let resized = operations::resize::<DynamicImage>(
+            let (width, height) =
+                single_dimension_policy.target_size(img.dimensions(), params.width, params.height);
+            let mut resized = operations::resize::<DynamicImage>(
                 &img,
-                params.width,
-                params.height,
+                Some(width),
+                Some(height),
                 params.ratio_policy.clone(),
+                params.gravity,
+                resize_filters,
+                background,
             );
+            operations::adjust(&mut resized, &params);
+            let resized = transforms::apply_all(&custom_transforms, resized, &params);
             let resize_op_time = resize_op_start.elapsed();
             if resize_op_time.as_millis() > 200 {
                 debug!("Resize operation took {:?}", resize_op_time);
             }
 
+            let dimensions = resized.dimensions();
             let encode_start = Instant::now();
-            let result_data =
-                cast_to_extension::<DynamicImage>(resized, extension.clone(), params.quality);
+            let fallback = avif_webp_fallback.then(|| resized.clone());
+            let encoded = operations::encode_within(
+                resized,
+                extension,
+                params.quality,
+                params.bit_depth,
+                params.dpi,
+                params.interlace == Some(true),
+                encode_timeout,
+                permit,
+            );
+            let (result_data, extension) = match (encoded, fallback) {
+                (Ok(data), _) => (data, extension),
+                (Err(err), Some(fallback)) => {
+                    warn!("AVIF encoding {}, falling back to WebP", err);
+                    let data = cast_to_extension::<DynamicImage>(
+                        fallback,
+                        Extensions::Webp,
+                        params.quality,
+                        None,
+                        params.dpi,
+                        params.interlace == Some(true),
+                    );
+                    (data, Extensions::Webp)
+                }
+                (Err(err), None) => {
+                    warn!("{:?} encoding {}", extension, err);
+                    return Err(ProcessingError::new(
+                        ProcessingErrorType::EncodingFailed,
+                        Some(format!("{:?} encoding {}", extension, err)),
+                    ));
+                }
+            };
             let encode_time = encode_start.elapsed();
             if encode_time.as_millis() > 100 {
                 debug!("Encode operation took {:?}ms", encode_time);
             }
-            Arc::new(ImageContainer::new(Box::new(result_data), None, extension))
+            Ok(container(result_data, Some(dimensions), extension))
         })
         .await
-        .unwrap();
+        .unwrap()?;
+
+        Ok(result)
+    }
+
+    /// Fully process image and puts it in all caches (storage + processing cache)
+    ///
+    /// * `image_id` - should be only the **original** image (cause it's passing into storage cache)
+    /// * `overwrite` - replace already cached processed image
+    async fn _process_image(
+        &self,
+        image_id: ImageId,
+        original_image: Arc<Vec<u8>>,
+        params: ProcessingParams,
+        overwrite: bool,
+    ) -> Result<Arc<ImageContainer>, ProcessingError> {
+        // reject before processing, otherwise restricted requests are still costly
+        if let Err(err) = self
+            .cache
+            .read()
+            .await
+            .check_limit(&image_id, &params)
+            .await
+        {
+            return Err(ProcessingError::new(
+                ProcessingErrorType::ProcessedImagesLimit,
+                Some(err.error.to_string()),
+            ));
+        }
+
+        let resize_start = Instant::now();
+        let result = self.transform(original_image, params.clone()).await?;
         let resize_total_time = resize_start.elapsed();
         if resize_total_time.as_millis() > 500 {
             debug!(
@@ -284,11 +1563,21 @@ impl Processor {
             );
         }
 
+        // WebP fallback of failed AVIF encode is cached as WebP, so AVIF is retried on next request
+        let params = match result.extension == self.determine_extension(&params) {
+            true => params,
+            false => ProcessingParams {
+                extension: Some(result.extension.into()),
+                ..params
+            },
+        };
+
         // Store in cache
         {
             let cache = self.cache.clone();
             let lock_start = Instant::now();
-            let mut cache_guard = cache.write().await;
+            // per image write locking is made by cache itself
+            let cache_guard = cache.read().await;
             let lock_wait = lock_start.elapsed();
             if lock_wait.as_millis() > 10 {
                 debug!(
@@ -297,7 +1586,7 @@ impl Processor {
                 );
             }
             match cache_guard
-                .set(image_id.clone(), params, result.clone())
+                .set(image_id.clone(), params, result.clone(), overwrite)
                 .await
             {
                 Ok(_) => {}
@@ -313,11 +1602,15 @@ impl Processor {
         Ok(result)
     }
 
+    /// Store original image, replacing processed versions of previous one
+    ///
+    /// * `cache_ttl` - client cache ttl (in seconds) of image, overriding global one
     pub async fn prefetch(
         &self,
         image_id: ImageId,
         _filename: String,
         data: Vec<u8>,
+        cache_ttl: Option<u32>,
     ) -> Result<(), ProcessingError> {
         if let Some(err) = self.ensure_correct_extension(&data) {
             return Err(err);
@@ -326,7 +1619,10 @@ impl Processor {
         let _storage = self.storage.clone();
         let mut storage = _storage.write().await;
 
-        storage.set(image_id.clone(), &data).await;
+        storage.set(image_id.clone(), &data, cache_ttl).await;
+        self.colors.remove(&image_id);
+        self.not_found.remove(&image_id);
+        self.origins.remove(&image_id);
 
         let _cache = self.cache.clone();
         let mut cache = _cache.write().await;
@@ -334,4 +1630,905 @@ impl Processor {
 
         Ok(())
     }
+
+    /// Count variant of image as served to client
+    pub fn record_served(&self, image_id: &ImageId, params: &ProcessingParams, bytes: usize) {
+        let params = self.with_default_quality(params.clone());
+        self.stats.record(image_id, &params, bytes);
+    }
+
+    /// Serving counters of image, if it was served since startup
+    pub fn stats(&self, image_id: &ImageId) -> Option<ImageStats> {
+        self.stats.get(image_id)
+    }
+
+    /// Keep stored original warm without refetching. Returns whether original is stored
+    pub async fn touch(&self, image_id: ImageId) -> bool {
+        self.storage.read().await.touch(image_id).await
+    }
+
+    /// Remove processed versions of all images, matching filter by their resulting extension and
+    /// params, until `limit` versions are removed. Originals are kept
+    pub async fn purge_variants(
+        &self,
+        filter: impl Fn(Extensions, &ProcessingParams) -> bool + Send + Sync,
+        limit: usize,
+    ) -> PurgedVariants {
+        let filter = |params: &ProcessingParams| filter(self.determine_extension(params), params);
+        let mut cache = self.cache.write().await;
+        cache.remove_matching(&filter, limit).await
+    }
+
+    /// Remove image from storage and all its processed versions from cache
+    ///
+    /// Returns count of removed processed versions
+    pub async fn invalidate(&self, image_id: ImageId) -> usize {
+        self.colors.remove(&image_id);
+        self.not_found.remove(&image_id);
+        self.origins.remove(&image_id);
+        {
+            let mut storage = self.storage.write().await;
+            storage.remove(image_id.clone()).await;
+        }
+
+        let mut cache = self.cache.write().await;
+        cache.remove(image_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use crate::utils::testing::params;
+    use image::RgbaImage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn animation_is_restricted_by_pixel_budget() {
+        let config = testing::config(&[("MAX_IMAGE_RESIZE", "16,16")]);
+        let gif = Arc::new(testing::animated_gif(3, 16, 16));
+
+        let result = config
+            .processor
+            .transform(gif.clone(), params("width=16&height=16"))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProcessingError {
+                err_type: ProcessingErrorType::InvalidSize,
+                ..
+            })
+        ));
+
+        let image = config
+            .processor
+            .transform(gif, params("width=8&height=8&loop_count=2"))
+            .await
+            .ok()
+            .unwrap();
+        let frames = operations::decode_animation(&image.data, ImageFormat::WebP).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].0.dimensions(), (8, 8));
+    }
+
+    #[tokio::test]
+    async fn extreme_distortion_is_rejected() {
+        let config = testing::config(&[("MAX_RESIZE_DISTORTION", "4")]);
+        let png = Arc::new(testing::png(40, 40));
+
+        let result = config
+            .processor
+            .transform(
+                png.clone(),
+                params("width=1&height=400&ratio_policy=Resize&extension=PNG"),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProcessingError {
+                err_type: ProcessingErrorType::InvalidSize,
+                ..
+            })
+        ));
+        for query in [
+            "width=40&height=20&ratio_policy=Resize",
+            "width=1&height=400",
+        ] {
+            assert!(
+                config
+                    .processor
+                    .transform(png.clone(), params(&format!("{}&extension=PNG", query)))
+                    .await
+                    .is_ok(),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_variants_fetch_original_once() {
+        let (origin, requests) =
+            testing::counting_origin(testing::png(40, 40), Duration::from_millis(200)).await;
+        let config = testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+        ]);
+
+        let results = futures_util::future::join_all([10, 20, 30].map(|width| {
+            config.processor.get(
+                "shared".to_string(),
+                params(&format!("width={}&extension=PNG", width)),
+                FetchOptions::default(),
+                false,
+            )
+        }))
+        .await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_original_is_not_stored() {
+        let small = testing::png(8, 8);
+        let large = testing::png(200, 200);
+        assert!(small.len() < 1000 && large.len() > 1000);
+
+        for (data, fetches) in [(small, 1), (large, 2)] {
+            let (origin, requests) = testing::counting_origin(data, Duration::ZERO).await;
+            let config = testing::config(&[
+                ("BASE_FILE_API_URL", &origin),
+                ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+                ("MAX_CACHEABLE_ORIGINAL_BYTES", "1000"),
+            ]);
+            for width in [4, 6] {
+                config
+                    .processor
+                    .get(
+                        "image".to_string(),
+                        params(&format!("width={}&extension=PNG", width)),
+                        FetchOptions::default(),
+                        false,
+                    )
+                    .await
+                    .ok()
+                    .unwrap();
+            }
+            assert_eq!(requests.load(Ordering::SeqCst), fetches);
+        }
+    }
+
+    #[tokio::test]
+    async fn fresh_overwrites_only_requested_variant() {
+        let config = testing::config(&[]);
+        testing::preload(&config, "fresh", testing::png(40, 20)).await;
+        let get = |query: &'static str, fresh| {
+            config.processor.get(
+                "fresh".to_string(),
+                params(query),
+                FetchOptions::default(),
+                fresh,
+            )
+        };
+        let requested = "width=4&height=4&extension=PNG";
+        let other = "width=6&height=6&extension=PNG";
+        let old = get(requested, false).await.ok().unwrap();
+        let other_old = get(other, false).await.ok().unwrap();
+
+        // original is replaced without invalidation, so only reprocessed variant changes
+        config
+            .processor
+            .storage
+            .write()
+            .await
+            .set("fresh".to_string(), &testing::png(20, 40), None)
+            .await;
+        let reprocessed = get(requested, true).await.ok().unwrap();
+        assert_eq!(reprocessed.cache_status, CacheStatus::Miss);
+        assert_ne!(reprocessed.image.data, old.image.data);
+
+        let cached = get(requested, false).await.ok().unwrap();
+        assert_eq!(cached.cache_status, CacheStatus::Hit);
+        assert_eq!(cached.image.data, reprocessed.image.data);
+        let other_cached = get(other, false).await.ok().unwrap();
+        assert_eq!(other_cached.cache_status, CacheStatus::Hit);
+        assert_eq!(other_cached.image.data, other_old.image.data);
+    }
+
+    #[tokio::test]
+    async fn trim_removes_uniform_border() {
+        // white border with slight noise (within tolerance) around blue content
+        let bordered = RgbaImage::from_fn(40, 40, |x, y| match (x, y) {
+            (10..30, 15..25) => Rgba([0, 0, 255, 255]),
+            _ => Rgba([255, 255, 250 + (x % 5) as u8, 255]),
+        });
+        let bordered = Arc::new(testing::encode(
+            &DynamicImage::ImageRgba8(bordered),
+            ImageFormat::Png,
+        ));
+        let config = testing::config(&[]);
+
+        for (query, dimensions) in [("trim=true", (20, 10)), ("trim=false", (40, 40))] {
+            let image = config
+                .processor
+                .transform(
+                    bordered.clone(),
+                    params(&format!("{}&extension=PNG", query)),
+                )
+                .await
+                .ok()
+                .unwrap();
+            let image = image::load_from_memory(&image.data).unwrap();
+            assert_eq!(image.dimensions(), dimensions, "{}", query);
+            assert_eq!(image.get_pixel(0, 0).0[0] == 0, query == "trim=true");
+        }
+        let config = testing::config(&[("TRIM_TOLERANCE", "0")]);
+        let image = config
+            .processor
+            .transform(bordered, params("trim=true&extension=PNG"))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(
+            image::load_from_memory(&image.data).unwrap().dimensions(),
+            (39, 40)
+        );
+    }
+
+    #[tokio::test]
+    async fn smaller_source_is_passed_through() {
+        let config = testing::config(&[]);
+        let png = testing::png(8, 8);
+
+        for (query, passthrough) in [
+            ("width=100&height=100&skip_smaller=true&extension=PNG", true),
+            ("width=100&height=100&extension=PNG", false),
+            ("width=4&height=4&skip_smaller=true&extension=PNG", false),
+            (
+                "width=100&height=100&skip_smaller=true&extension=Webp",
+                false,
+            ),
+            (
+                "width=100&height=100&skip_smaller=true&extension=PNG&quality=50",
+                false,
+            ),
+        ] {
+            let image = config
+                .processor
+                .transform(Arc::new(png.clone()), params(query))
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(*image.data == png, passthrough, "{}", query);
+        }
+
+        // applied orientation changes pixels, so source isn't served as is
+        let oriented = testing::oriented_png(8, 4, 6);
+        for (query, passthrough) in [
+            ("width=100&skip_smaller=true&extension=PNG", false),
+            (
+                "width=100&skip_smaller=true&extension=PNG&auto_orient=false",
+                true,
+            ),
+        ] {
+            let image = config
+                .processor
+                .transform(Arc::new(oriented.clone()), params(query))
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(*image.data == oriented, passthrough, "{}", query);
+        }
+        assert!(operations::is_reoriented(&oriented));
+        assert!(!operations::is_reoriented(&png));
+    }
+
+    #[tokio::test]
+    async fn webp_is_not_queued_behind_avif() {
+        let config = testing::config(&[("MAX_CONCURRENT_AVIF_ENCODES", "1")]);
+        testing::preload(&config, "queued", testing::png(40, 40)).await;
+        let processor = &config.processor;
+        let get = |query: &'static str| {
+            processor.get(
+                "queued".to_string(),
+                params(query),
+                FetchOptions::default(),
+                false,
+            )
+        };
+
+        // the only avif slot is taken, like by a long running encode
+        let slot = processor.avif_encodes.as_ref().unwrap().acquire().await;
+        let avif = get("width=10&extension=Avif");
+        tokio::pin!(avif);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut avif)
+                .await
+                .is_err()
+        );
+        let webp = tokio::time::timeout(Duration::from_secs(5), get("width=10&extension=Webp"))
+            .await
+            .expect("webp waits for avif slot");
+        assert_eq!(webp.ok().unwrap().image.extension, Extensions::Webp);
+
+        drop(slot);
+        assert_eq!(avif.await.ok().unwrap().image.extension, Extensions::Avif);
+    }
+
+    #[tokio::test]
+    async fn origin_fetches_are_capped() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let origin = testing::serve_router(axum::Router::new().fallback({
+            let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+            move || async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                testing::png(8, 8)
+            }
+        }))
+        .await;
+        let config = testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("MAX_CONCURRENT_ORIGIN_FETCHES", "2"),
+        ]);
+
+        let misses = (0..8).map(|index| {
+            config.processor.get(
+                format!("burst-{}", index),
+                params("width=4"),
+                FetchOptions::default(),
+                false,
+            )
+        });
+        for result in futures_util::future::join_all(misses).await {
+            assert!(result.is_ok());
+        }
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn animation_frame_rate_is_capped() {
+        let config = testing::config(&[]);
+        // 10 fps
+        testing::preload(&config, "fast", testing::animated_gif(10, 8, 8)).await;
+
+        for (query, timestamps) in [
+            ("extension=Webp&fps=5", vec![0, 200, 400, 600, 800]),
+            ("extension=Webp", (0..10).map(|index| index * 100).collect()),
+        ] {
+            let served = config
+                .processor
+                .get(
+                    "fast".to_string(),
+                    params(query),
+                    FetchOptions::default(),
+                    false,
+                )
+                .await
+                .ok()
+                .unwrap();
+            let frames = operations::decode_animation(&served.image.data, ImageFormat::WebP)
+                .unwrap()
+                .into_iter()
+                .map(|(_, timestamp)| timestamp)
+                .collect::<Vec<_>>();
+            assert_eq!(frames, timestamps, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn not_found_images_are_not_refetched() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let origin = testing::serve_router(axum::Router::new().fallback({
+            let fetches = fetches.clone();
+            move || async move {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                axum::http::StatusCode::NOT_FOUND
+            }
+        }))
+        .await;
+
+        for (ttl, expected_fetches) in [("10", 1), ("0", 2)] {
+            fetches.store(0, Ordering::SeqCst);
+            let config = testing::config(&[
+                ("BASE_FILE_API_URL", &origin),
+                ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+                ("NOT_FOUND_CACHE_TTL", ttl),
+            ]);
+            for _ in 0..2 {
+                let result = config
+                    .processor
+                    .get(
+                        "missing".to_string(),
+                        params("width=4"),
+                        FetchOptions::default(),
+                        false,
+                    )
+                    .await;
+                assert!(matches!(
+                    result,
+                    Err(ProcessingError {
+                        err_type: ProcessingErrorType::NotFound,
+                        ..
+                    })
+                ));
+            }
+            assert_eq!(fetches.load(Ordering::SeqCst), expected_fetches, "{}", ttl);
+
+            testing::preload(&config, "missing", testing::png(8, 8)).await;
+            assert!(
+                config
+                    .processor
+                    .get(
+                        "missing".to_string(),
+                        params("width=4"),
+                        FetchOptions::default(),
+                        false,
+                    )
+                    .await
+                    .is_ok()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn single_dimension_follows_policy() {
+        let png = Arc::new(testing::png(300, 200));
+        for (policy, query, dimensions) in [
+            ("PreserveRatio", "width=150", (150, 100)),
+            ("PreserveRatio", "height=100", (150, 100)),
+            ("SourceDimension", "width=150", (150, 200)),
+            ("SourceDimension", "height=100", (300, 100)),
+        ] {
+            let config = testing::config(&[("SINGLE_DIMENSION_POLICY", policy)]);
+            let image = config
+                .processor
+                .transform(png.clone(), params(&format!("{}&extension=PNG", query)))
+                .await
+                .ok()
+                .unwrap();
+            let image = image::load_from_memory(&image.data).unwrap();
+            assert_eq!(
+                (image.width(), image.height()),
+                dimensions,
+                "{} {}",
+                policy,
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn default_quality_is_applied_per_format() {
+        let configured = testing::config(&[("DEFAULT_QUALITY", "Webp=40")]);
+        let builtin = testing::config(&[]);
+        let gradient = testing::encode(&testing::gradient(64, 64), ImageFormat::Png);
+        let encode = async |config: &crate::config::Config, query: &str| {
+            config
+                .processor
+                .transform(Arc::new(gradient.clone()), params(query))
+                .await
+                .ok()
+                .unwrap()
+                .data
+                .clone()
+        };
+
+        let low = encode(&builtin, "extension=Webp&quality=40").await;
+        let high = encode(&builtin, "extension=Webp&quality=82").await;
+        assert_ne!(low, high);
+        assert_eq!(encode(&configured, "extension=Webp").await, low);
+        assert_eq!(encode(&builtin, "extension=Webp").await, high);
+
+        // cached by effective quality
+        testing::preload(&configured, "gradient", gradient.clone()).await;
+        for (query, cache_status) in [
+            ("width=32&extension=Webp", CacheStatus::Miss),
+            ("width=32&extension=Webp&quality=40", CacheStatus::Hit),
+            ("width=32&extension=Webp&quality=82", CacheStatus::Miss),
+        ] {
+            let served = configured
+                .processor
+                .get(
+                    "gradient".to_string(),
+                    params(query),
+                    FetchOptions::default(),
+                    false,
+                )
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(served.cache_status, cache_status, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_avif_falls_back_to_webp() {
+        // quality 0 is rejected by avif encoder (requests are clamped to min quality before)
+        let failing = "width=10&extension=Avif&quality=0";
+        let get = async |config: &crate::config::Config, query: &str| {
+            config
+                .processor
+                .get(
+                    "fragile".to_string(),
+                    params(query),
+                    FetchOptions::default(),
+                    false,
+                )
+                .await
+        };
+
+        let config = testing::config(&[]);
+        testing::preload(&config, "fragile", testing::png(40, 40)).await;
+        for _ in 0..2 {
+            let served = get(&config, failing).await.ok().unwrap();
+            assert_eq!(served.image.extension, Extensions::Webp);
+            assert_eq!(
+                image::guess_format(&served.image.data).unwrap(),
+                ImageFormat::WebP
+            );
+            // fallback is not cached as avif, so avif is retried
+            assert_eq!(served.cache_status, CacheStatus::Miss);
+        }
+        let served = get(&config, "width=10&extension=Webp&quality=0")
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(served.cache_status, CacheStatus::Hit);
+
+        let config = testing::config(&[("AVIF_FALLBACK_TO_WEBP", "false")]);
+        testing::preload(&config, "fragile", testing::png(40, 40)).await;
+        assert!(matches!(
+            get(&config, failing).await,
+            Err(ProcessingError {
+                err_type: ProcessingErrorType::EncodingFailed,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn stale_originals_are_revalidated() {
+        // etag is version of served image, matching one is answered with 304
+        let version = Arc::new(AtomicUsize::new(1));
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let origin = testing::serve_router(axum::Router::new().fallback({
+            let (version, requests) = (version.clone(), requests.clone());
+            move |headers: axum::http::HeaderMap| async move {
+                use axum::response::IntoResponse;
+
+                let version = version.load(Ordering::SeqCst);
+                let etag = format!("\"v{}\"", version);
+                let conditional = headers.get(axum::http::header::IF_NONE_MATCH).cloned();
+                requests.lock().unwrap().push(conditional.clone());
+                if conditional.is_some_and(|value| value == etag.as_str()) {
+                    return axum::http::StatusCode::NOT_MODIFIED.into_response();
+                }
+                let size = version as u32 * 8;
+                ([(axum::http::header::ETAG, etag)], testing::png(size, size)).into_response()
+            }
+        }))
+        .await;
+        let config = testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("ORIGIN_REVALIDATE_AGE", "60"),
+        ]);
+        let processor = &config.processor;
+        let image_id = "revalidated".to_string();
+        let get = async |query: &str| {
+            let served = processor
+                .get(
+                    image_id.clone(),
+                    params(&format!("extension=PNG{}", query)),
+                    FetchOptions::default(),
+                    false,
+                )
+                .await
+                .ok()
+                .unwrap();
+            let image = image::load_from_memory(&served.image.data).unwrap();
+            (served.cache_status, image.width())
+        };
+        // like if original was fetched long ago
+        let age_original = || {
+            let (_, validators) = processor.origins.get(&image_id).unwrap();
+            let fetched_at = Instant::now() - Duration::from_secs(120);
+            processor
+                .origins
+                .insert(image_id.clone(), (fetched_at, validators));
+        };
+        let etag = |index: usize| {
+            requests.lock().unwrap()[index]
+                .as_ref()
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        // originals are read (and revalidated) on processed cache misses
+        assert_eq!(get("").await, (CacheStatus::Miss, 8));
+        assert_eq!(get("&width=4").await, (CacheStatus::Miss, 4));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(etag(0), None);
+
+        age_original();
+        assert_eq!(get("&width=5").await, (CacheStatus::Miss, 5));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert_eq!(etag(1), Some("\"v1\"".to_string()));
+        assert_eq!(get("").await, (CacheStatus::Hit, 8));
+
+        // changed original replaces stored one along with its processed versions
+        let stored = processor.storage.read().await.get(image_id.clone()).await;
+        processor
+            .storage
+            .write()
+            .await
+            .set(image_id.clone(), &stored.unwrap(), Some(30))
+            .await;
+        version.store(2, Ordering::SeqCst);
+        assert_eq!(get("&width=6").await, (CacheStatus::Miss, 6));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        age_original();
+        assert_eq!(get("&width=7").await, (CacheStatus::Miss, 7));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert_eq!(get("").await, (CacheStatus::Miss, 16));
+        let cache_ttl = processor
+            .storage
+            .read()
+            .await
+            .cache_ttl(image_id.clone())
+            .await;
+        assert_eq!(cache_ttl, Some(30));
+    }
+
+    #[tokio::test]
+    async fn stale_images_are_served_while_refreshed() {
+        let version = Arc::new(AtomicUsize::new(1));
+        let origin = testing::serve_router(axum::Router::new().fallback({
+            let version = version.clone();
+            move |headers: axum::http::HeaderMap| async move {
+                use axum::response::IntoResponse;
+
+                let version = version.load(Ordering::SeqCst);
+                let etag = format!("\"v{}\"", version);
+                if headers
+                    .get(axum::http::header::IF_NONE_MATCH)
+                    .is_some_and(|value| value == etag.as_str())
+                {
+                    return axum::http::StatusCode::NOT_MODIFIED.into_response();
+                }
+                let size = version as u32 * 8;
+                ([(axum::http::header::ETAG, etag)], testing::png(size, size)).into_response()
+            }
+        }))
+        .await;
+        let config = testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("ORIGIN_REVALIDATE_AGE", "60"),
+            ("STALE_WHILE_REVALIDATE", "true"),
+        ]);
+        let processor = &config.processor;
+        let image_id = "stale".to_string();
+        let get = async || {
+            let served = processor
+                .get(
+                    image_id.clone(),
+                    params("extension=PNG"),
+                    FetchOptions::default(),
+                    false,
+                )
+                .await
+                .ok()
+                .unwrap();
+            let image = image::load_from_memory(&served.image.data).unwrap();
+            (served.cache_status, image.width())
+        };
+        let refresh = || {
+            processor.refresh_stale(
+                image_id.clone(),
+                params("extension=PNG"),
+                FetchOptions::default(),
+            )
+        };
+        // like if server ttl of original is expired
+        let age_original = || {
+            let (_, validators) = processor.origins.get(&image_id).unwrap();
+            let fetched_at = Instant::now() - Duration::from_secs(120);
+            processor
+                .origins
+                .insert(image_id.clone(), (fetched_at, validators));
+        };
+
+        assert_eq!(get().await, (CacheStatus::Miss, 8));
+        assert_eq!(get().await, (CacheStatus::Hit, 8));
+
+        // unchanged original is only revalidated
+        age_original();
+        assert_eq!(get().await, (CacheStatus::Stale, 8));
+        refresh().await;
+        assert_eq!(get().await, (CacheStatus::Hit, 8));
+
+        // stale image is served at once, and the refreshed one on the next request
+        version.store(2, Ordering::SeqCst);
+        age_original();
+        assert_eq!(get().await, (CacheStatus::Stale, 8));
+        refresh().await;
+        assert_eq!(get().await, (CacheStatus::Hit, 16));
+    }
+
+    #[tokio::test]
+    async fn fetched_originals_are_streamed_and_stored_once_read() {
+        let data = testing::png(300, 300);
+        let released = Arc::new(tokio::sync::Notify::new());
+        let origin = {
+            let (data, released) = (data.clone(), released.clone());
+            testing::serve_router(axum::Router::new().fallback(move || {
+                let (data, released) = (data.clone(), released.clone());
+                async move {
+                    let half = data.len() / 2;
+                    let (first, second) = (data[..half].to_vec(), data[half..].to_vec());
+                    let body = stream::once(async { Ok::<_, std::io::Error>(first) }).chain(
+                        stream::once(async move {
+                            released.notified().await;
+                            Ok(second)
+                        }),
+                    );
+                    axum::body::Body::from_stream(body)
+                }
+            }))
+            .await
+        };
+        let config = testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+        ]);
+
+        // responded before origin sends second half
+        let (original, _) = tokio::time::timeout(
+            Duration::from_secs(5),
+            config
+                .processor
+                .original_stream("streamed".to_string(), FetchOptions::default()),
+        )
+        .await
+        .expect("original is not streamed")
+        .ok()
+        .unwrap();
+        let OriginalBody::Streamed(fetched) = original else {
+            panic!("original is not fetched");
+        };
+        assert!(!config.processor.touch("streamed".to_string()).await);
+
+        released.notify_one();
+        let mut streamed = fetched.head.to_vec();
+        let rest: Vec<_> = fetched.rest.try_collect().await.ok().unwrap();
+        rest.iter()
+            .for_each(|chunk| streamed.extend_from_slice(chunk));
+        assert!(streamed == data);
+
+        let mut stored = None;
+        for _ in 0..100 {
+            stored = config
+                .processor
+                .storage
+                .read()
+                .await
+                .get("streamed".to_string())
+                .await;
+            if stored.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(stored.expect("original is not stored").as_slice() == data.as_slice());
+    }
+
+    #[tokio::test]
+    async fn empty_and_truncated_originals_are_not_stored() {
+        let png = testing::png(40, 40);
+        for (data, kind) in [
+            (Vec::new(), FileApiErrorKind::EmptyBody),
+            (
+                png[..png.len() / 2].to_vec(),
+                FileApiErrorKind::CorruptedImage,
+            ),
+        ] {
+            let (origin, requests) = testing::counting_origin(data, Duration::ZERO).await;
+            let config = testing::config(&[
+                ("BASE_FILE_API_URL", &origin),
+                ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ]);
+            for _ in 0..2 {
+                let result = config
+                    .processor
+                    .get(
+                        "broken".to_string(),
+                        params("width=10"),
+                        FetchOptions::default(),
+                        false,
+                    )
+                    .await;
+                assert!(
+                    matches!(
+                        result,
+                        Err(ProcessingError {
+                            err_type: ProcessingErrorType::FileApiError(err_kind),
+                            ..
+                        }) if err_kind == kind
+                    ),
+                    "{}",
+                    kind
+                );
+            }
+            assert_eq!(requests.load(Ordering::SeqCst), 2, "{}", kind);
+
+            // originals served verbatim and colors don't process them, but check them as well
+            let original = config
+                .processor
+                .original("broken".to_string(), FetchOptions::default())
+                .await;
+            assert!(
+                matches!(
+                    original.err().unwrap().err_type,
+                    ProcessingErrorType::FileApiError(err_kind) if err_kind == kind
+                ),
+                "{}",
+                kind
+            );
+            let colors = config
+                .processor
+                .colors("broken".to_string(), FetchOptions::default())
+                .await;
+            assert!(
+                matches!(
+                    colors.err().unwrap().err_type,
+                    ProcessingErrorType::FileApiError(err_kind) if err_kind == kind
+                ),
+                "{}",
+                kind
+            );
+            assert_eq!(requests.load(Ordering::SeqCst), 4, "{}", kind);
+        }
+    }
+
+    #[test]
+    fn failing_encoders_are_disabled() {
+        let available = Processor::check_extensions(|extension| extension != Extensions::Avif);
+        assert!(!available.contains(&Extensions::Avif));
+        assert!(available.contains(&Extensions::Webp));
+    }
+
+    #[tokio::test]
+    async fn unavailable_extension_is_rejected() {
+        let mut config = testing::config(&[]);
+        config
+            .processor
+            .available_extensions
+            .retain(|extension| *extension != Extensions::Avif);
+        let png = Arc::new(testing::png(8, 8));
+
+        let result = config
+            .processor
+            .transform(png.clone(), params("width=4&extension=Avif"))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProcessingError {
+                err_type: ProcessingErrorType::UnavailableExtension,
+                ..
+            })
+        ));
+        let image = config
+            .processor
+            .transform(png, params("width=4&extension=Webp"))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(image.extension, Extensions::Webp);
+    }
 }
@@ -0,0 +1,125 @@
+use crate::image_ops::operations::ProcessingParams;
+use image::RgbaImage;
+use std::sync::Arc;
+
+/// Custom transform of processed image, applied after resizing and adjustments, right before encoding.
+///
+/// Implement it and add to [`registered_transforms`] to extend processing without forking,
+/// then enable by name in `CUSTOM_TRANSFORMS`. Changing enabled transforms doesn't invalidate
+/// already processed images
+pub trait ImageTransform: Send + Sync {
+    /// Name to enable transform by
+    fn name(&self) -> &'static str;
+
+    /// Transform image. Params are the request ones, so transform can be applied conditionally
+    fn apply(&self, img: RgbaImage, params: &ProcessingParams) -> RgbaImage;
+}
+
+/// Transforms, which can be enabled by config
+pub fn registered_transforms() -> Vec<Arc<dyn ImageTransform>> {
+    vec![Arc::new(Sepia)]
+}
+
+/// Apply transforms in order
+pub fn apply_all(
+    transforms: &[Arc<dyn ImageTransform>],
+    img: RgbaImage,
+    params: &ProcessingParams,
+) -> RgbaImage {
+    transforms
+        .iter()
+        .fold(img, |img, transform| transform.apply(img, params))
+}
+
+/// Sample transform: warm brown tone of old photos
+pub struct Sepia;
+
+impl ImageTransform for Sepia {
+    fn name(&self) -> &'static str {
+        "sepia"
+    }
+
+    fn apply(&self, mut img: RgbaImage, _params: &ProcessingParams) -> RgbaImage {
+        for pixel in img.pixels_mut() {
+            let [r, g, b, _] = pixel.0.map(|v| v as f32);
+            pixel.0[0] = (0.393 * r + 0.769 * g + 0.189 * b).min(255.0) as u8;
+            pixel.0[1] = (0.349 * r + 0.686 * g + 0.168 * b).min(255.0) as u8;
+            pixel.0[2] = (0.272 * r + 0.534 * g + 0.131 * b).min(255.0) as u8;
+        }
+        img
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use image::{DynamicImage, ImageFormat, Rgba};
+
+    /// Inverts colors, only for requests with tint, to check params are passed
+    struct Invert;
+
+    impl ImageTransform for Invert {
+        fn name(&self) -> &'static str {
+            "invert"
+        }
+
+        fn apply(&self, mut img: RgbaImage, params: &ProcessingParams) -> RgbaImage {
+            if params.tint.is_some() {
+                for pixel in img.pixels_mut() {
+                    for channel in &mut pixel.0[..3] {
+                        *channel = 255 - *channel;
+                    }
+                }
+            }
+            img
+        }
+    }
+
+    #[test]
+    fn transforms_are_applied_in_order() {
+        let blue = || RgbaImage::from_pixel(1, 1, Rgba([0, 0, 255, 255]));
+        let apply = |transforms: &[Arc<dyn ImageTransform>], query: &str| {
+            apply_all(transforms, blue(), &testing::params(query))
+                .get_pixel(0, 0)
+                .0
+        };
+        let (sepia, invert): (Arc<dyn ImageTransform>, Arc<dyn ImageTransform>) =
+            (Arc::new(Sepia), Arc::new(Invert));
+
+        assert_eq!(apply(std::slice::from_ref(&sepia), ""), [48, 42, 33, 255]);
+        assert_eq!(
+            apply(&[sepia.clone(), invert.clone()], ""),
+            [48, 42, 33, 255]
+        );
+        assert_eq!(
+            apply(&[sepia.clone(), invert.clone()], "tint=ffffff"),
+            [207, 213, 222, 255]
+        );
+        assert_eq!(apply(&[invert, sepia], "tint=ffffff"), [255, 255, 205, 255]);
+    }
+
+    #[tokio::test]
+    async fn enabled_transform_is_applied_by_processor() {
+        let config = testing::config(&[("CUSTOM_TRANSFORMS", "sepia")]);
+        let blue = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 255, 255]));
+        let data = testing::encode(&DynamicImage::ImageRgba8(blue), ImageFormat::Png);
+        testing::preload(&config, "blue", data).await;
+
+        let served = config
+            .processor
+            .get(
+                "blue".to_string(),
+                testing::params("extension=PNG"),
+                Default::default(),
+                false,
+            )
+            .await
+            .ok()
+            .unwrap();
+        let img = image::load_from_memory(&served.image.data)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(img.get_pixel(4, 4).0, [48, 42, 33, 255]);
+    }
+}
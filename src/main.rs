@@ -1,5 +1,6 @@
 extern crate core;
 
+mod bench;
 mod config;
 mod image_ops;
 mod openapi;
@@ -7,26 +8,31 @@ mod proxying_images;
 mod routes;
 mod store;
 mod utils;
+mod warm;
 
 use crate::config::Config;
 use aide::axum::ApiRouter;
-use aide::axum::routing::{get_with, put_with};
+use aide::axum::routing::{get_with, post_with, put_with};
 use aide::openapi::{Info, OpenApi};
 use aide::swagger::Swagger;
 use axum::routing::get;
-use axum::{Extension, Router};
-use log::info;
-use routes::images;
+use axum::serve::ListenerExt;
+use axum::{Extension, Router, middleware};
+use log::{info, warn};
+use routes::{images, service};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 use tokio::signal;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, Semaphore};
 use tokio::task::JoinSet;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::registry;
 use tracing_subscriber::util::SubscriberInitExt;
 use utils::background::{BackgroundService, serve_background};
+use utils::connection_limit::LimitedListener;
 
 /// Configure async runtime and rayon cpu usage with optimal configuration
 fn configure_runtime() -> Runtime {
@@ -64,18 +70,111 @@ fn openapi_spec() -> OpenApi {
     }
 }
 
-fn app_init(state: Arc<Config>, enable_docs: bool) -> Router {
-    let mut openapi = openapi_spec();
-
-    let api = ApiRouter::new()
+/// Documented routes of api, state is provided by caller
+fn api_routes(enable_debug_endpoints: bool) -> ApiRouter<Arc<Config>> {
+    let mut api = ApiRouter::new()
+        .api_route("/", get_with(service::root, service::root_docs))
+        .api_route(
+            "/version",
+            get_with(service::version, service::version_docs),
+        )
+        .api_route(
+            "/selftest",
+            get_with(service::self_test, service::self_test_docs),
+        )
+        .route("/favicon.ico", get(service::favicon))
         .api_route(
             "/images/{id}",
-            get_with(images::serve_file, images::serve_file_docs),
+            get_with(images::serve_file, images::serve_file_docs)
+                .head_with(images::serve_file_head, images::serve_file_head_docs),
         )
         .api_route(
             "/images/{id}",
             put_with(images::preload_image, images::preload_image_docs),
         )
+        .api_route(
+            "/images/{id}/touch",
+            post_with(images::touch_image, images::touch_image_docs),
+        )
+        .api_route(
+            "/images/{id}/stats",
+            get_with(images::image_stats, images::image_stats_docs),
+        )
+        .api_route(
+            "/images/{id}/color",
+            get_with(images::image_colors, images::image_colors_docs),
+        )
+        .api_route(
+            "/images/{id}/all",
+            get_with(images::all_formats, images::all_formats_docs),
+        )
+        .api_route("/montage", get_with(images::montage, images::montage_docs))
+        .api_route(
+            "/transform",
+            post_with(images::transform, images::transform_docs),
+        )
+        .api_route(
+            "/invalidate",
+            post_with(images::invalidate_images, images::invalidate_images_docs),
+        )
+        .api_route(
+            "/webhook/origin-updated",
+            post_with(images::origin_updated, images::origin_updated_docs),
+        )
+        .api_route(
+            "/purge-variants",
+            post_with(images::purge_variants, images::purge_variants_docs),
+        );
+    if enable_debug_endpoints {
+        api = api.api_route(
+            "/images/{id}/debug",
+            get_with(images::debug_image, images::debug_image_docs),
+        );
+    }
+    api
+}
+
+/// OpenAPI document, served by `/openapi.json`
+fn openapi_document(enable_debug_endpoints: bool) -> OpenApi {
+    let mut openapi = openapi_spec();
+    let _ = api_routes(enable_debug_endpoints).finish_api(&mut openapi);
+    openapi
+}
+
+/// `openapi [--out <file>]` command: write OpenAPI document into file (or stdout), returning exit code
+fn write_openapi(args: &[String], enable_debug_endpoints: bool) -> i32 {
+    let out = match args {
+        [] => None,
+        [flag, path] if flag == "--out" => Some(path),
+        _ => {
+            eprintln!("Usage: imgr-serve openapi [--out <file>]");
+            return 2;
+        }
+    };
+    let openapi = openapi_document(enable_debug_endpoints);
+    let spec = serde_json::to_vec_pretty(&openapi).unwrap();
+    match out {
+        None => println!("{}", String::from_utf8(spec).unwrap()),
+        Some(path) => {
+            if let Err(err) = std::fs::write(path, spec) {
+                eprintln!("Failed to write {}: {}", path, err);
+                return 1;
+            }
+            info!("OpenAPI spec is written to {}", path);
+        }
+    }
+    0
+}
+
+fn app_init(state: Arc<Config>, enable_docs: bool) -> Router {
+    let mut openapi = openapi_spec();
+    let access_log_options = routes::access_log::AccessLogOptions {
+        format: state.access_log_format.clone(),
+        trust_forwarded_for: state.trust_forwarded_for,
+    };
+    let max_concurrent_requests = state.max_concurrent_requests;
+
+    let api = api_routes(state.enable_debug_endpoints)
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
@@ -83,10 +182,12 @@ fn app_init(state: Arc<Config>, enable_docs: bool) -> Router {
 
     if enable_docs {
         let openapi = Arc::new(openapi);
-        app = app
+        let docs = Router::new()
             .route("/openapi.json", get(routes::openapi::openapi_json))
             .route("/docs", get(Swagger::new("/openapi.json").axum_handler()))
+            .layer(middleware::from_fn(routes::openapi::compress))
             .layer(Extension(openapi));
+        app = app.merge(docs);
     }
 
     #[cfg(not(debug_assertions))]
@@ -100,7 +201,19 @@ fn app_init(state: Arc<Config>, enable_docs: bool) -> Router {
         ));
     }
 
-    app
+    if let Some(max) = max_concurrent_requests {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(Semaphore::new(max)),
+            routes::concurrency::limit_concurrency,
+        ));
+    }
+
+    // outer layers, to log every response including errors and timeouts
+    app.layer(middleware::from_fn_with_state(
+        access_log_options,
+        routes::access_log::access_log,
+    ))
+    .layer(middleware::from_fn(routes::request_id::request_id))
 }
 
 fn main() {
@@ -114,42 +227,166 @@ fn main() {
 
     let rt = configure_runtime();
 
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("warm") => {
+            let Some(manifest) = args.get(2) else {
+                eprintln!("Usage: imgr-serve warm <manifest>");
+                std::process::exit(2);
+            };
+            let warmed = rt.block_on(async { warm::warm(&Config::from_env(), manifest).await });
+            std::process::exit(if warmed { 0 } else { 1 });
+        }
+        Some("bench") => {
+            // rayon pool is already configured, as in server
+            let succeeded = bench::bench(&args[2..]);
+            std::process::exit(if succeeded { 0 } else { 1 });
+        }
+        Some("bench-cache") => {
+            let succeeded = bench::bench_cache(&args[2..]);
+            std::process::exit(if succeeded { 0 } else { 1 });
+        }
+        Some("bench-cache-locks") => {
+            let succeeded = bench::bench_cache_locks(&args[2..]);
+            std::process::exit(if succeeded { 0 } else { 1 });
+        }
+        Some("openapi") => {
+            let code = write_openapi(&args[2..], config::debug_endpoints_enabled());
+            std::process::exit(code);
+        }
+        _ => {}
+    }
+
     rt.block_on(async {
         let config = Config::from_env();
         let (host, port) = (config.host.clone(), config.port.clone());
+        let listen_uds = config.listen_uds.clone();
         let enable_docs = config.enable_docs;
+        let shutdown_grace = config.shutdown_grace;
+        let max_connections = config.max_connections;
 
         let shutdown_channel = tokio::sync::watch::channel(false);
         let background_services = config.processor.get_background_services();
         let background_tasks_runner =
             serve_background(background_services.clone(), shutdown_channel.1).await;
 
+        let (signal_tx, signal_rx) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            let _ = signal_tx.send(true);
+        });
+
         let state = Arc::new(config);
         let app = app_init(state, enable_docs);
 
-        info!("Running server on http://{}:{}", host, port);
-        if enable_docs {
-            info!("Docs available at http://{}:{}/docs", host, port);
+        match listen_uds {
+            Some(path) => {
+                #[cfg(unix)]
+                serve_uds(&path, app, max_connections, signal_rx, shutdown_grace).await;
+                #[cfg(not(unix))]
+                panic!(
+                    "LISTEN_UDS={} is set, but unix sockets are not supported",
+                    path
+                );
+            }
+            None => {
+                info!("Running server on http://{}:{}", host, port);
+                if enable_docs {
+                    info!("Docs available at http://{}:{}/docs", host, port);
+                }
+                let listener = LimitedListener::new(
+                    tokio::net::TcpListener::bind(format!("{}:{}", host, port))
+                        .await
+                        .unwrap(),
+                    max_connections,
+                )
+                // axum provides peer address of wrapped listeners only through TapIo
+                .tap_io(|_| {});
+                let server = axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(signal_received(signal_rx.clone()));
+                serve_with_grace(server, signal_rx, shutdown_grace).await;
+            }
         }
-        let listener = tokio::net::TcpListener::bind(format!("{}:{}", host, port))
-            .await
-            .unwrap();
-        axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_signal(
-                background_services,
-                background_tasks_runner,
-                shutdown_channel.0,
-            ))
-            .await
-            .unwrap();
+
+        stop_background(
+            background_services,
+            background_tasks_runner,
+            shutdown_channel.0,
+        )
+        .await;
     });
+    // requests, left after grace period, can't be awaited (like blocking encodes), data is already flushed
+    rt.shutdown_background();
+}
+
+async fn signal_received(mut signal_rx: tokio::sync::watch::Receiver<bool>) {
+    let _ = signal_rx.wait_for(|received| *received).await;
 }
 
-async fn shutdown_signal(
+/// Serve app on unix socket at `path` until shutdown, removing socket file
+#[cfg(unix)]
+async fn serve_uds(
+    path: &str,
+    app: Router,
+    max_connections: Option<usize>,
+    signal_rx: tokio::sync::watch::Receiver<bool>,
+    grace: Option<Duration>,
+) {
+    // socket file is left after unclean shutdown and prevents binding
+    let _ = std::fs::remove_file(path);
+    let listener = LimitedListener::new(
+        tokio::net::UnixListener::bind(path).unwrap(),
+        max_connections,
+    );
+    info!("Running server on unix:{}", path);
+    // there is no peer address for unix socket, access log relies on
+    // X-Forwarded-For from reverse proxy (with TRUST_FORWARDED_FOR)
+    let server = axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(signal_received(signal_rx.clone()));
+    serve_with_grace(server, signal_rx, grace).await;
+    let _ = std::fs::remove_file(path);
+}
+
+/// Run server until graceful shutdown is completed, or grace period after shutdown signal is over
+async fn serve_with_grace<S: IntoFuture<Output = std::io::Result<()>>>(
+    server: S,
+    signal_rx: tokio::sync::watch::Receiver<bool>,
+    grace: Option<Duration>,
+) {
+    let deadline = async {
+        signal_received(signal_rx).await;
+        match grace {
+            Some(grace) => tokio::time::sleep(grace).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = server.into_future() => result.unwrap(),
+        _ = deadline => warn!(
+            "In-flight requests are not finished in {:?} after shutdown signal, forcing shutdown",
+            grace.unwrap_or_default()
+        ),
+    }
+}
+
+/// Flush storages and stop background tasks
+async fn stop_background(
     background_services: Vec<Arc<RwLock<dyn BackgroundService + Send + Sync>>>,
     background_task_runner: JoinSet<()>,
     shutdown_channel: tokio::sync::watch::Sender<bool>,
 ) {
+    for s in background_services.iter() {
+        let mut service = s.write().await;
+        service.stop().await;
+    }
+    let _ = shutdown_channel.send(true);
+    background_task_runner.join_all().await;
+}
+
+async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -183,11 +420,101 @@ async fn shutdown_signal(
         _ = terminate => {},
         _ = interrupt => {},
     }
+}
 
-    for s in background_services.iter() {
-        let mut service = s.write().await;
-        service.stop().await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_on_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = testing::temp_path("imgr.sock");
+        let (signal_tx, signal_rx) = tokio::sync::watch::channel(false);
+        let app = app_init(Arc::new(testing::config(&[])), false);
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move { serve_uds(&path, app, None, signal_rx, None).await }
+        });
+
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET /version HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        signal_tx.send(true).unwrap();
+        server.await.unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn openapi_command_writes_served_spec() {
+        let path = testing::temp_path("openapi.json");
+        let _ = std::fs::remove_file(&path);
+        let args = ["--out".to_string(), path.clone()];
+        assert_eq!(write_openapi(&args, false), 0);
+
+        let spec: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(spec["paths"]["/images/{id}"]["get"].is_object());
+        assert!(spec["paths"]["/images/{id}/debug"].is_null());
+        let base = testing::serve_router(app_init(Arc::new(testing::config(&[])), true)).await;
+        let served = testing::json(testing::get(format!("{}/openapi.json", base)).await).await;
+        assert_eq!(spec, served);
+
+        assert_eq!(write_openapi(&args, true), 0);
+        let spec: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(spec["paths"]["/images/{id}/debug"]["get"].is_object());
+
+        assert_eq!(write_openapi(&["--out".to_string()], false), 2);
+        let missing_dir = testing::temp_path("missing-dir/openapi.json");
+        assert_eq!(write_openapi(&["--out".to_string(), missing_dir], false), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_forced_after_grace() {
+        let (started_tx, mut started_rx) = tokio::sync::mpsc::channel::<()>(1);
+        let app = Router::new().route(
+            "/stuck",
+            axum::routing::get(move || async move {
+                let _ = started_tx.send(()).await;
+                std::future::pending::<()>().await
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (signal_tx, signal_rx) = tokio::sync::watch::channel(false);
+        let server =
+            axum::serve(listener, app).with_graceful_shutdown(signal_received(signal_rx.clone()));
+        let server = tokio::spawn(serve_with_grace(
+            server,
+            signal_rx,
+            Some(Duration::from_millis(200)),
+        ));
+
+        tokio::spawn(testing::get(format!("http://{}/stuck", addr)));
+        started_rx.recv().await.unwrap();
+        let signalled = std::time::Instant::now();
+        signal_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("shutdown waits for stuck request")
+            .unwrap();
+        assert!(signalled.elapsed() >= Duration::from_millis(200));
     }
-    let _ = shutdown_channel.send(true);
-    background_task_runner.join_all().await;
 }
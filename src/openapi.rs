@@ -49,7 +49,9 @@ impl OperationInput for BinaryBody {
             ctx,
             operation,
             RequestBody {
-                description: Some("Binary image payload.".to_string()),
+                description: Some(
+                    "Binary image payload (or base64, see `encoding` parameter).".to_string(),
+                ),
                 content: IndexMap::from_iter([(
                     "application/octet-stream".to_string(),
                     MediaType {
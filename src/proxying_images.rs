@@ -1,76 +1,424 @@
 /// Fetching images from original files API
+use crate::routes::request_id::REQUEST_ID_HEADER;
 use crate::utils::types::ImageId;
 use async_trait::async_trait;
-use log::debug;
-use reqwest::{Client, StatusCode};
+use axum::body::Bytes;
+use futures_util::StreamExt;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, BoxStream};
+use log::{debug, warn};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Client, StatusCode, Url, header};
 use serde::Serialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 
+/// Kind of failure while fetching from base api
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum FileApiErrorKind {
+    DnsFailure,
+    ConnectFailure,
+    TlsFailure,
+    Timeout,
+    /// Base api responded with non 200 status
+    #[strum(to_string = "http_status_{0}")]
+    HttpStatus(u16),
+    /// Failed to read response body
+    BodyError,
+    /// Response is not an image (by content type or content itself)
+    NotAnImage,
+    /// Base api responded with empty body
+    EmptyBody,
+    /// Response is an image, but can't be decoded (like truncated one)
+    CorruptedImage,
+    /// Image id leads outside of base api url (like `../` or `@host`)
+    ForbiddenImageId,
+    /// Base api redirected to private network or redirected too many times
+    ForbiddenRedirect,
+    /// Base api host resolves only to private network addresses
+    PrivateAddress,
+    /// Image is not changed since fetch with provided validators (304 on conditional fetch)
+    NotModified,
+    /// Response body exceeds max accepted size of originals
+    TooLarge,
+}
+
+impl FileApiErrorKind {
+    /// Classify transport error of reqwest.
+    ///
+    /// reqwest doesn't expose dns and tls failures, so they are detected by messages of source errors
+    fn from_reqwest(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            return FileApiErrorKind::Timeout;
+        }
+        if err.is_redirect() {
+            return FileApiErrorKind::ForbiddenRedirect;
+        }
+        if err.is_body() || err.is_decode() {
+            return FileApiErrorKind::BodyError;
+        }
+
+        // rejection of resolver is wrapped into generic dns error
+        let mut source = std::error::Error::source(err);
+        while let Some(inner) = source {
+            if inner.is::<PrivateAddressError>() {
+                return FileApiErrorKind::PrivateAddress;
+            }
+            source = inner.source();
+        }
+
+        let mut source = std::error::Error::source(err);
+        while let Some(inner) = source {
+            let msg = inner.to_string().to_lowercase();
+            if msg.contains("dns error") || msg.contains("failed to lookup address") {
+                return FileApiErrorKind::DnsFailure;
+            }
+            if msg.contains("tls") || msg.contains("ssl") || msg.contains("certificate") {
+                return FileApiErrorKind::TlsFailure;
+            }
+            source = inner.source();
+        }
+        FileApiErrorKind::ConnectFailure
+    }
+}
+
+/// Content types of file api responses, accepted as images. Generic binary ones are checked by content.
+///
+/// `image/*` is accepted as well
+const ALLOWED_CONTENT_TYPES: [&str; 2] = ["application/octet-stream", "binary/octet-stream"];
+
+fn is_allowed_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    mime.starts_with("image/") || ALLOWED_CONTENT_TYPES.contains(&mime.as_str())
+}
+
+/// Per-request settings of fetching from base api
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Overrides backend default request timeout
+    pub timeout: Option<Duration>,
+    /// Forwarded as `X-Request-Id` header
+    pub request_id: Option<String>,
+    /// Fetch image only if it's changed since fetch with these validators, `NotModified` error otherwise
+    pub validators: Option<OriginValidators>,
+}
+
+/// Validators of fetched image (`ETag` and `Last-Modified`), to refetch it only if it's changed
+#[derive(Debug, Clone, Default)]
+pub struct OriginValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl OriginValidators {
+    fn from_headers(headers: &header::HeaderMap) -> Self {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        OriginValidators {
+            etag: value(header::ETAG),
+            last_modified: value(header::LAST_MODIFIED),
+        }
+    }
+}
+
+/// Image fetched from base api with its validators
+pub struct FetchedImage {
+    pub data: Vec<u8>,
+    pub validators: OriginValidators,
+}
+
+/// Image fetched from base api, which body is read as it arrives
+pub struct FetchedStream {
+    /// Beginning of body, enough to detect image format
+    pub head: Bytes,
+    /// Declared size of whole body
+    pub content_length: Option<u64>,
+    pub validators: OriginValidators,
+    /// Rest of body after `head`, still limited by max size of originals
+    pub rest: BoxStream<'static, Result<Bytes, FileApiError>>,
+}
+
+/// Bytes of body, read before returning `FetchedStream`, to check its format
+const FORMAT_HEAD_BYTES: usize = 32;
+
 /// Error while fetching files from base api
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FileApiError {
     pub reason: String,
-    pub http_error_code: Option<u32>,
+    pub kind: FileApiErrorKind,
 }
 
 impl FileApiError {
-    fn new(reason: String, http_error_code: Option<u32>) -> Self {
-        FileApiError {
-            reason,
-            http_error_code,
-        }
+    fn new(reason: String, kind: FileApiErrorKind) -> Self {
+        FileApiError { reason, kind }
     }
 }
 
 #[async_trait]
 pub trait FileApiBackend {
     /// Requesting file from original file api if it not found in cache
-    async fn fetch_img_from_base_api(&self, image_id: &ImageId) -> Result<Vec<u8>, FileApiError>;
+    async fn fetch_img_from_base_api(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+    ) -> Result<FetchedImage, FileApiError>;
+
+    /// Requesting file from original file api, reading only beginning of its body.
+    ///
+    /// By default, body is read completely
+    async fn fetch_stream_from_base_api(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+    ) -> Result<FetchedStream, FileApiError> {
+        let fetched = self.fetch_img_from_base_api(image_id, options).await?;
+        Ok(FetchedStream {
+            content_length: Some(fetched.data.len() as u64),
+            head: Bytes::from(fetched.data),
+            validators: fetched.validators,
+            rest: stream::empty().boxed(),
+        })
+    }
+}
+
+/// Whether address is not reachable from public internet (loopback, private, link-local and etc.)
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // shared address space (carrier-grade NAT), 100.64.0.0/10
+                || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_ip(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+/// Host resolved only to private network addresses
+#[derive(Debug)]
+struct PrivateAddressError(String);
+
+impl fmt::Display for PrivateAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} resolves only to private network addresses", self.0)
+    }
+}
+
+impl std::error::Error for PrivateAddressError {}
+
+/// Resolver, which drops private network addresses, so base api client can't reach internal services.
+///
+/// Ip literals are not resolved by reqwest, they are checked by config validation and redirect policy
+struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| !is_private_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(PrivateAddressError(host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Redirects are followed within base api host. Redirects to other hosts (like storage presigned urls)
+/// are followed, unless they lead to private network addresses (and those are not allowed).
+///
+/// Only ip literals are checked here, hostnames are checked on resolving
+fn redirect_policy(
+    base_url: Url,
+    max_redirects: usize,
+    allow_private_ips: bool,
+) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        // previous urls include the requested one
+        if attempt.previous().len() > max_redirects {
+            return match max_redirects {
+                0 => attempt.error("redirects are disabled"),
+                _ => attempt.error("too many redirects"),
+            };
+        }
+        let url = attempt.url().clone();
+        if let Some(from) = attempt.previous().last() {
+            debug!("Base api redirects {} to {}", from, url);
+        }
+        if url.host() == base_url.host()
+            && url.port_or_known_default() == base_url.port_or_known_default()
+        {
+            return attempt.follow();
+        }
+        if !["http", "https"].contains(&url.scheme()) {
+            return attempt.error(format!("redirect to {} scheme", url.scheme()));
+        }
+        let private = match url.host_str() {
+            None => true,
+            Some(host) => match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+                Ok(ip) => is_private_ip(ip),
+                Err(_) => host.eq_ignore_ascii_case("localhost"),
+            },
+        };
+        match private && !allow_private_ips {
+            true => attempt.error("redirect to private network address"),
+            false => attempt.follow(),
+        }
+    })
 }
 
 pub struct SimpleFileApiBackend {
-    base_api_url: String,
+    base_api_url: Url,
     client: Client,
+    /// Larger responses are rejected without reading them completely
+    max_body_bytes: Option<usize>,
 }
 
 impl SimpleFileApiBackend {
-    pub fn new(base_api_url: String, timeout: Option<u32>) -> Self {
+    /// * `user_agent` - defaults to `imgr-serve/{version}`
+    /// * `max_redirects` - redirects followed per fetch, 0 treats redirects as errors
+    /// * `allow_private_ips` - allow connecting to private network addresses (for internal base api)
+    /// * `max_body_bytes` - max size of fetched original, larger ones are `TooLarge` errors
+    pub fn new(
+        base_api_url: String,
+        timeout: Option<u32>,
+        user_agent: Option<String>,
+        max_redirects: usize,
+        allow_private_ips: bool,
+        max_body_bytes: Option<usize>,
+    ) -> Self {
+        let base_api_url = Url::parse(base_api_url.trim_end_matches("/"))
+            .expect("Base api url is validated by config");
         let timeout = Duration::from_secs(timeout.unwrap_or(30) as u64);
-        let client = Client::builder()
+        let user_agent =
+            user_agent.unwrap_or_else(|| format!("imgr-serve/{}", env!("CARGO_PKG_VERSION")));
+        let mut client = Client::builder()
+            .user_agent(user_agent)
             .timeout(timeout)
             .connect_timeout(timeout / 3)
-            .redirect(reqwest::redirect::Policy::limited(5))
+            .redirect(redirect_policy(
+                base_api_url.clone(),
+                max_redirects,
+                allow_private_ips,
+            ));
+        if !allow_private_ips {
+            client = client.dns_resolver(Arc::new(PublicOnlyResolver));
+        }
+        let client = client
             .build()
             .expect("Failed to create base api url client");
 
         SimpleFileApiBackend {
-            base_api_url: base_api_url.trim_end_matches("/").into(),
+            base_api_url,
             client,
+            max_body_bytes,
         }
     }
-}
 
-#[async_trait]
-impl FileApiBackend for SimpleFileApiBackend {
-    async fn fetch_img_from_base_api(&self, image_id: &ImageId) -> Result<Vec<u8>, FileApiError> {
-        let resp = self
-            .client
-            .get(format!("{}/{}", self.base_api_url, image_id))
-            .send()
-            .await;
-        if resp.is_err() {
-            let err = resp.err().unwrap();
-            debug!(
-                "Got http error while trying to fetch image from file api: {}. Err: {}",
-                image_id, err
-            );
+    /// Read response body by chunks, aborting as soon as it exceeds max size,
+    /// as `Content-Length` may be absent or understated
+    async fn read_body(
+        &self,
+        mut resp: reqwest::Response,
+        image_id: &ImageId,
+    ) -> Result<Vec<u8>, FileApiError> {
+        let declared_length = resp.content_length();
+        if declared_length.is_some_and(|len| self.exceeds_max(len as usize)) {
+            return Err(self.too_large(image_id));
+        }
+
+        let mut data = Vec::with_capacity(declared_length.unwrap_or(0) as usize);
+        loop {
+            match resp.chunk().await {
+                Ok(Some(chunk)) => {
+                    if self.exceeds_max(data.len() + chunk.len()) {
+                        return Err(self.too_large(image_id));
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                Ok(None) => return Ok(data),
+                Err(err) => return Err(body_error(image_id, err)),
+            }
+        }
+    }
+
+    fn exceeds_max(&self, len: usize) -> bool {
+        self.max_body_bytes.is_some_and(|max| len > max)
+    }
+
+    fn too_large(&self, image_id: &ImageId) -> FileApiError {
+        too_large_error(image_id, self.max_body_bytes)
+    }
+
+    /// Send request of image and check its status and content type, leaving body unread
+    async fn request(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+    ) -> Result<reqwest::Response, FileApiError> {
+        let mut request = self.client.get(self.image_url(image_id)?);
+        if let Some(timeout) = options.timeout {
+            request = request.timeout(timeout);
+        }
+        if let Some(request_id) = &options.request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        if let Some(validators) = &options.validators {
+            if let Some(etag) = &validators.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let resp = match request.send().await {
+            Ok(resp) => resp,
+            Err(err) => {
+                let kind = FileApiErrorKind::from_reqwest(&err);
+                debug!(
+                    "Got http error while trying to fetch image from file api: {}. Kind: {}. Err: {}",
+                    image_id, kind, err
+                );
+                return Err(FileApiError::new(
+                    "Failed to request image from base api".to_string(),
+                    kind,
+                ));
+            }
+        };
+        let status = resp.status();
+        if status == StatusCode::NOT_MODIFIED && options.validators.is_some() {
             return Err(FileApiError::new(
-                "Failed to request image from base api".to_string(),
-                None,
+                "Image is not modified in file api".to_string(),
+                FileApiErrorKind::NotModified,
             ));
         }
-        let resp = resp.unwrap();
-        let status = resp.status();
         if status != StatusCode::OK {
             debug!(
                 "Got http error from file api status={},resp={}",
@@ -84,10 +432,636 @@ impl FileApiBackend for SimpleFileApiBackend {
             );
             return Err(FileApiError::new(
                 "Got error from file api".to_string(),
-                Some(status.as_u16().into()),
+                FileApiErrorKind::HttpStatus(status.as_u16()),
             ));
         }
 
-        Ok(resp.bytes().await.unwrap().to_vec())
+        // missing content type is allowed, response is checked by content anyway
+        if let Some(content_type) = resp.headers().get(header::CONTENT_TYPE) {
+            let content_type = content_type.to_str().unwrap_or("");
+            if !is_allowed_content_type(content_type) {
+                debug!(
+                    "File api responded with non image content type {} for image {}",
+                    content_type, image_id
+                );
+                return Err(FileApiError::new(
+                    format!(
+                        "File api responded with non image content type {}",
+                        content_type
+                    ),
+                    FileApiErrorKind::NotAnImage,
+                ));
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Url of image in base api. Rejects ids, which change scheme, host or leave base path
+    /// (checked on the parsed url, so percent-encoded tricks are normalized first)
+    fn image_url(&self, image_id: &ImageId) -> Result<Url, FileApiError> {
+        let forbidden = || {
+            debug!("Image id {:?} leads outside of base api", image_id);
+            FileApiError::new(
+                "Image id is not allowed in base api url".to_string(),
+                FileApiErrorKind::ForbiddenImageId,
+            )
+        };
+        if image_id.contains(['\\', '?', '#']) || image_id.contains("://") {
+            return Err(forbidden());
+        }
+        let base = &self.base_api_url;
+        let url = Url::parse(&format!(
+            "{}/{}",
+            base.as_str().trim_end_matches('/'),
+            image_id
+        ))
+        .map_err(|_| forbidden())?;
+        let base_path = base.path().trim_end_matches('/');
+        let within_base = url.scheme() == base.scheme()
+            && url.host() == base.host()
+            && url.port_or_known_default() == base.port_or_known_default()
+            && url.username() == base.username()
+            && url.password() == base.password()
+            && url.query() == base.query()
+            && url
+                .path()
+                .strip_prefix(base_path)
+                .is_some_and(|rest| rest.len() > 1 && rest.starts_with('/'));
+        match within_base {
+            true => Ok(url),
+            false => Err(forbidden()),
+        }
+    }
+}
+
+#[async_trait]
+impl FileApiBackend for SimpleFileApiBackend {
+    async fn fetch_img_from_base_api(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+    ) -> Result<FetchedImage, FileApiError> {
+        let resp = self.request(image_id, options).await?;
+        let validators = OriginValidators::from_headers(resp.headers());
+        let data = self.read_body(resp, image_id).await?;
+        check_image_head(image_id, &data)?;
+        // truncated images are detected by decoding on processing, not to decode them twice
+        Ok(FetchedImage { data, validators })
+    }
+
+    /// Body is read until its format is known, the rest is read by caller
+    async fn fetch_stream_from_base_api(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+    ) -> Result<FetchedStream, FileApiError> {
+        let mut resp = self.request(image_id, options).await?;
+        let validators = OriginValidators::from_headers(resp.headers());
+        let content_length = resp.content_length();
+        if content_length.is_some_and(|len| self.exceeds_max(len as usize)) {
+            return Err(self.too_large(image_id));
+        }
+
+        let mut head = Vec::new();
+        let mut finished = false;
+        while head.len() < FORMAT_HEAD_BYTES {
+            match resp.chunk().await {
+                Ok(Some(chunk)) => {
+                    if self.exceeds_max(head.len() + chunk.len()) {
+                        return Err(self.too_large(image_id));
+                    }
+                    head.extend_from_slice(&chunk);
+                }
+                Ok(None) => {
+                    finished = true;
+                    break;
+                }
+                Err(err) => return Err(body_error(image_id, err)),
+            }
+        }
+        check_image_head(image_id, &head)?;
+
+        let rest = match finished {
+            true => stream::empty().boxed(),
+            false => {
+                let (image_id, max_body_bytes) = (image_id.clone(), self.max_body_bytes);
+                stream::unfold(Some((resp, head.len())), move |state| {
+                    let image_id = image_id.clone();
+                    async move {
+                        let (mut resp, read) = state?;
+                        match resp.chunk().await {
+                            Ok(Some(chunk)) => {
+                                let read = read + chunk.len();
+                                if max_body_bytes.is_some_and(|max| read > max) {
+                                    return Some((
+                                        Err(too_large_error(&image_id, max_body_bytes)),
+                                        None,
+                                    ));
+                                }
+                                Some((Ok(chunk), Some((resp, read))))
+                            }
+                            Ok(None) => None,
+                            Err(err) => Some((Err(body_error(&image_id, err)), None)),
+                        }
+                    }
+                })
+                .boxed()
+            }
+        };
+        Ok(FetchedStream {
+            head: Bytes::from(head),
+            content_length,
+            validators,
+            rest,
+        })
+    }
+}
+
+/// Check, that beginning of fetched body is an image
+fn check_image_head(image_id: &ImageId, data: &[u8]) -> Result<(), FileApiError> {
+    if data.is_empty() {
+        debug!("File api responded with empty body for image {}", image_id);
+        return Err(FileApiError::new(
+            "File api responded with empty body".to_string(),
+            FileApiErrorKind::EmptyBody,
+        ));
+    }
+    if image::guess_format(data).is_err() {
+        debug!("File api responded with non image for image {}", image_id);
+        return Err(FileApiError::new(
+            "File api responded with unknown image format".to_string(),
+            FileApiErrorKind::NotAnImage,
+        ));
+    }
+    Ok(())
+}
+
+fn too_large_error(image_id: &ImageId, max_body_bytes: Option<usize>) -> FileApiError {
+    debug!(
+        "File api response for image {} exceeds {:?} bytes",
+        image_id, max_body_bytes
+    );
+    FileApiError::new(
+        "File api response exceeds max size of originals".to_string(),
+        FileApiErrorKind::TooLarge,
+    )
+}
+
+fn body_error(image_id: &ImageId, err: reqwest::Error) -> FileApiError {
+    debug!(
+        "Failed to read file api response body for image {}. Err: {}",
+        image_id, err
+    );
+    let kind = match err.is_timeout() {
+        true => FileApiErrorKind::Timeout,
+        false => FileApiErrorKind::BodyError,
+    };
+    FileApiError::new("Failed to read image from base api".to_string(), kind)
+}
+
+/// Ordered list of file apis, image is served from the first one, which has it.
+///
+/// Useful on migrations between storage backends
+pub struct ChainedFileApiBackend {
+    backends: Vec<Arc<dyn FileApiBackend + Send + Sync>>,
+}
+
+impl ChainedFileApiBackend {
+    pub fn new(backends: Vec<Arc<dyn FileApiBackend + Send + Sync>>) -> Self {
+        ChainedFileApiBackend { backends }
+    }
+}
+
+#[async_trait]
+impl FileApiBackend for ChainedFileApiBackend {
+    async fn fetch_img_from_base_api(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+    ) -> Result<FetchedImage, FileApiError> {
+        self.first_found(image_id, options, |backend, image_id, options| {
+            backend.fetch_img_from_base_api(image_id, options)
+        })
+        .await
+    }
+
+    async fn fetch_stream_from_base_api(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+    ) -> Result<FetchedStream, FileApiError> {
+        self.first_found(image_id, options, |backend, image_id, options| {
+            backend.fetch_stream_from_base_api(image_id, options)
+        })
+        .await
+    }
+}
+
+impl ChainedFileApiBackend {
+    /// Fetch image from the first file api, which has it.
+    ///
+    /// Not found (404) is returned only if image is not found on every file api.
+    /// Otherwise, the last failure is returned, cause image may exist on failed file api
+    async fn first_found<T>(
+        &self,
+        image_id: &ImageId,
+        options: &FetchOptions,
+        fetch: impl for<'a> Fn(
+            &'a (dyn FileApiBackend + Send + Sync),
+            &'a ImageId,
+            &'a FetchOptions,
+        ) -> BoxFuture<'a, Result<T, FileApiError>>,
+    ) -> Result<T, FileApiError> {
+        let mut failure: Option<FileApiError> = None;
+        for (idx, backend) in self.backends.iter().enumerate() {
+            match fetch(backend.as_ref(), image_id, options).await {
+                Ok(image) => return Ok(image),
+                // validators belong to the file api, which has image, so the rest are not asked
+                Err(err) if err.kind == FileApiErrorKind::NotModified => return Err(err),
+                Err(err) if err.kind == FileApiErrorKind::HttpStatus(404) => {
+                    debug!("Image {} not found in file api #{}", image_id, idx);
+                }
+                Err(err) => {
+                    warn!(
+                        "File api #{} failed for image {}: {}",
+                        idx, image_id, err.kind
+                    );
+                    failure = Some(err);
+                }
+            }
+        }
+
+        Err(match failure {
+            None => FileApiError::new(
+                "Image not found in any file api".to_string(),
+                FileApiErrorKind::HttpStatus(404),
+            ),
+            Some(err) => FileApiError::new(
+                format!("All file apis failed, last: {}", err.reason),
+                err.kind,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use axum::Router;
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+
+    fn backend(base_api_url: String) -> SimpleFileApiBackend {
+        SimpleFileApiBackend::new(base_api_url, None, None, 0, true, None)
+    }
+
+    #[tokio::test]
+    async fn timeout_override_is_applied() {
+        let origin = testing::serve_router(Router::new().route(
+            "/{id}",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                testing::png(8, 8)
+            }),
+        ))
+        .await;
+        let backend = backend(origin);
+        let image_id = "slow".to_string();
+
+        let options = FetchOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let result = backend.fetch_img_from_base_api(&image_id, &options).await;
+        assert_eq!(result.err().unwrap().kind, FileApiErrorKind::Timeout);
+
+        let options = FetchOptions {
+            timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        assert!(
+            backend
+                .fetch_img_from_base_api(&image_id, &options)
+                .await
+                .is_ok()
+        );
+    }
+
+    /// Local address, which refuses connections
+    async fn closed_port_url() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{}", addr)
+    }
+
+    /// Server, responding with `response` bytes as is to every connection
+    async fn raw_origin(response: &'static [u8]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(response).await;
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn failures_are_classified() {
+        let status_origin = testing::serve_router(Router::new().route(
+            "/{id}",
+            get(|| async { axum::http::StatusCode::SERVICE_UNAVAILABLE }),
+        ))
+        .await;
+        let truncated_origin =
+            raw_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\ntruncated").await;
+        let tls_origin = raw_origin(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        let tls_origin = tls_origin.replacen("http://", "https://", 1);
+
+        for (base_api_url, kind) in [
+            (
+                "http://imgr-test.invalid".to_string(),
+                FileApiErrorKind::DnsFailure,
+            ),
+            (closed_port_url().await, FileApiErrorKind::ConnectFailure),
+            (tls_origin, FileApiErrorKind::TlsFailure),
+            (status_origin, FileApiErrorKind::HttpStatus(503)),
+            (truncated_origin, FileApiErrorKind::BodyError),
+        ] {
+            let result = backend(base_api_url.clone())
+                .fetch_img_from_base_api(&"image".to_string(), &FetchOptions::default())
+                .await;
+            assert_eq!(
+                result.err().map(|err| err.kind),
+                Some(kind),
+                "{}",
+                base_api_url
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn user_agent_and_request_id_are_sent() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let origin = testing::serve_router(Router::new().route(
+            "/{id}",
+            get({
+                let received = received.clone();
+                move |headers: header::HeaderMap| async move {
+                    received.lock().unwrap().push(headers);
+                    testing::png(8, 8)
+                }
+            }),
+        ))
+        .await;
+        let options = FetchOptions {
+            request_id: Some("request-1".to_string()),
+            ..Default::default()
+        };
+
+        SimpleFileApiBackend::new(
+            origin.clone(),
+            None,
+            Some("gallery/2".to_string()),
+            0,
+            true,
+            None,
+        )
+        .fetch_img_from_base_api(&"image".to_string(), &options)
+        .await
+        .ok()
+        .unwrap();
+        backend(origin)
+            .fetch_img_from_base_api(&"image".to_string(), &FetchOptions::default())
+            .await
+            .ok()
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0][header::USER_AGENT], "gallery/2");
+        assert_eq!(received[0][REQUEST_ID_HEADER], "request-1");
+        assert_eq!(
+            received[1][header::USER_AGENT],
+            format!("imgr-serve/{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert!(received[1].get(REQUEST_ID_HEADER).is_none());
+    }
+
+    #[test]
+    fn image_ids_escaping_base_url_are_rejected() {
+        let backend = backend("http://origin.local/files/".to_string());
+        for image_id in ["cat.png", "dir/cat.png", "@evil.com", "100%25.png"] {
+            assert_eq!(
+                backend
+                    .image_url(&image_id.to_string())
+                    .ok()
+                    .map(String::from),
+                Some(format!("http://origin.local/files/{}", image_id)),
+                "{}",
+                image_id
+            );
+        }
+        for image_id in [
+            "../admin",
+            "dir/../../admin",
+            "%2e%2e/admin",
+            "..",
+            "",
+            "http://evil.com/cat.png",
+            "cat.png?token=1",
+            "cat.png#fragment",
+            "..\\admin",
+        ] {
+            assert_eq!(
+                backend
+                    .image_url(&image_id.to_string())
+                    .err()
+                    .map(|err| err.kind),
+                Some(FileApiErrorKind::ForbiddenImageId),
+                "{}",
+                image_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_are_limited_by_config() {
+        // `{n}` redirects n times within base api before serving image
+        let origin = testing::serve_router(Router::new().route(
+            "/{hops}",
+            get(
+                |axum::extract::Path(hops): axum::extract::Path<u32>| async move {
+                    match hops {
+                        0 => testing::png(8, 8).into_response(),
+                        _ => axum::response::Redirect::temporary(&format!("/{}", hops - 1))
+                            .into_response(),
+                    }
+                },
+            ),
+        ))
+        .await;
+
+        for (max_redirects, hops, followed) in [
+            (5, 0, true),
+            (5, 3, true),
+            (5, 5, true),
+            (5, 6, false),
+            (0, 0, true),
+            (0, 1, false),
+        ] {
+            let result =
+                SimpleFileApiBackend::new(origin.clone(), None, None, max_redirects, true, None)
+                    .fetch_img_from_base_api(&hops.to_string(), &FetchOptions::default())
+                    .await;
+            match followed {
+                true => assert!(result.is_ok(), "{} {}", max_redirects, hops),
+                false => assert_eq!(
+                    result.err().map(|err| err.kind),
+                    Some(FileApiErrorKind::ForbiddenRedirect),
+                    "{} {}",
+                    max_redirects,
+                    hops
+                ),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_to_private_network_are_rejected() {
+        let origin = testing::serve_router(Router::new().route(
+            "/{id}",
+            get(
+                |axum::extract::Path(id): axum::extract::Path<String>| async move {
+                    let location = match id.as_str() {
+                        "private" => "http://10.0.0.1/cat.png",
+                        _ => "http://localhost:1/cat.png",
+                    };
+                    axum::response::Redirect::temporary(location)
+                },
+            ),
+        ))
+        .await;
+        // base api is local too, so it's allowed as configured one, but not as redirect target
+        let backend = SimpleFileApiBackend::new(origin, None, None, 5, false, None);
+        for image_id in ["private", "loopback"] {
+            let result = backend
+                .fetch_img_from_base_api(&image_id.to_string(), &FetchOptions::default())
+                .await;
+            assert_eq!(
+                result.err().map(|err| err.kind),
+                Some(FileApiErrorKind::ForbiddenRedirect),
+                "{}",
+                image_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn hosts_resolving_to_private_network_are_refused() {
+        let (origin, fetches) = testing::counting_origin(testing::png(8, 8), Duration::ZERO).await;
+        // resolved by resolver, unlike ip literal of configured base api
+        let origin = origin.replacen("127.0.0.1", "localhost", 1);
+        let image_id = "image".to_string();
+
+        let result = SimpleFileApiBackend::new(origin.clone(), None, None, 0, false, None)
+            .fetch_img_from_base_api(&image_id, &FetchOptions::default())
+            .await;
+        assert_eq!(
+            result.err().map(|err| err.kind),
+            Some(FileApiErrorKind::PrivateAddress)
+        );
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        SimpleFileApiBackend::new(origin, None, None, 0, true, None)
+            .fetch_img_from_base_api(&image_id, &FetchOptions::default())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        for (ip, private) in [
+            ("127.0.0.1", true),
+            ("10.1.2.3", true),
+            ("172.16.0.1", true),
+            ("192.168.1.1", true),
+            ("169.254.169.254", true),
+            ("100.64.0.1", true),
+            ("::1", true),
+            ("fd00::1", true),
+            ("fe80::1", true),
+            ("::ffff:10.0.0.1", true),
+            ("8.8.8.8", false),
+            ("100.128.0.1", false),
+            ("2606:4700::1111", false),
+        ] {
+            assert_eq!(is_private_ip(ip.parse().unwrap()), private, "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn chained_file_apis_are_tried_in_order() {
+        let chained = |urls: Vec<String>| {
+            ChainedFileApiBackend::new(
+                urls.into_iter()
+                    .map(|url| Arc::new(backend(url)) as Arc<dyn FileApiBackend + Send + Sync>)
+                    .collect(),
+            )
+        };
+        let missing = testing::missing_origin().await;
+        let (existing, _) = testing::counting_origin(testing::png(8, 8), Duration::ZERO).await;
+        let image_id = "image".to_string();
+        let options = FetchOptions::default();
+
+        let image = chained(vec![missing.clone(), existing])
+            .fetch_img_from_base_api(&image_id, &options)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(image.data, testing::png(8, 8));
+
+        let result = chained(vec![missing.clone(), missing.clone()])
+            .fetch_img_from_base_api(&image_id, &options)
+            .await;
+        assert_eq!(
+            result.err().unwrap().kind,
+            FileApiErrorKind::HttpStatus(404)
+        );
+
+        let result = chained(vec![closed_port_url().await, missing])
+            .fetch_img_from_base_api(&image_id, &options)
+            .await;
+        assert_eq!(result.err().unwrap().kind, FileApiErrorKind::ConnectFailure);
+    }
+
+    #[tokio::test]
+    async fn not_modified_stops_chained_file_apis() {
+        let unchanged = testing::serve_router(
+            Router::new().fallback(|| async { axum::http::StatusCode::NOT_MODIFIED }),
+        )
+        .await;
+        let (other, requests) = testing::counting_origin(testing::png(8, 8), Duration::ZERO).await;
+        let chained = ChainedFileApiBackend::new(vec![
+            Arc::new(backend(unchanged)) as Arc<dyn FileApiBackend + Send + Sync>,
+            Arc::new(backend(other)),
+        ]);
+        let options = FetchOptions {
+            validators: Some(OriginValidators {
+                etag: Some("\"v1\"".to_string()),
+                last_modified: None,
+            }),
+            ..Default::default()
+        };
+
+        let result = chained
+            .fetch_img_from_base_api(&"image".to_string(), &options)
+            .await;
+        assert_eq!(result.err().unwrap().kind, FileApiErrorKind::NotModified);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 0);
     }
 }
@@ -0,0 +1,166 @@
+use crate::config::AccessLogFormat;
+use crate::image_ops::processing::CacheStatus;
+use axum::body::HttpBody;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use log::info;
+use std::net::SocketAddr;
+use std::time::{Instant, SystemTime};
+
+/// State of access log middleware
+#[derive(Clone)]
+pub struct AccessLogOptions {
+    pub format: AccessLogFormat,
+    /// Take client address from `X-Forwarded-For`, which is set by trusted reverse proxy
+    pub trust_forwarded_for: bool,
+}
+
+/// Client address. Proxy headers can be sent by anyone, so they are preferred over the peer
+/// address of the connection only when trusted
+fn client_ip(
+    headers: &HeaderMap,
+    connect_info: Option<&ConnectInfo<SocketAddr>>,
+    trust_forwarded_for: bool,
+) -> String {
+    let forwarded = trust_forwarded_for
+        .then(|| headers.get("X-Forwarded-For"))
+        .flatten()
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    if let Some(ip) = forwarded {
+        return ip;
+    }
+    match connect_info {
+        Some(ConnectInfo(addr)) => addr.ip().to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string()
+}
+
+/// Log one line per request for production access logs.
+///
+/// Runs as the outermost layer, so error responses (`ApiError`, timeouts) are logged as well
+pub async fn access_log(
+    State(options): State<AccessLogOptions>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let format = options.format;
+    if format == AccessLogFormat::Off {
+        return next.run(request).await;
+    }
+
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let version = format!("{:?}", request.version());
+    let ip = client_ip(
+        request.headers(),
+        request.extensions().get::<ConnectInfo<SocketAddr>>(),
+        options.trust_forwarded_for,
+    );
+    let referer = header_str(request.headers(), header::REFERER);
+    let user_agent = header_str(request.headers(), header::USER_AGENT);
+
+    let response = next.run(request).await;
+
+    let duration = start.elapsed();
+    let status = response.status().as_u16();
+    let bytes_sent = response.body().size_hint().exact();
+    let cache = response
+        .extensions()
+        .get::<CacheStatus>()
+        .map(|v| v.to_string());
+
+    match format {
+        AccessLogFormat::Off => {}
+        AccessLogFormat::Combined => {
+            info!(
+                "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {}ms cache={}",
+                ip,
+                httpdate::fmt_http_date(SystemTime::now()),
+                method,
+                path,
+                version,
+                status,
+                bytes_sent.map_or("-".to_string(), |v| v.to_string()),
+                referer,
+                user_agent,
+                duration.as_millis(),
+                cache.as_deref().unwrap_or("-"),
+            );
+        }
+        AccessLogFormat::Json => {
+            let line = serde_json::json!({
+                "method": method,
+                "path": path,
+                "status": status,
+                "duration_ms": duration.as_secs_f64() * 1000.0,
+                "bytes_sent": bytes_sent,
+                "cache": cache,
+                "client_ip": ip,
+                "referer": referer,
+                "user_agent": user_agent,
+            });
+            info!("{}", line);
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn client_ip_ignores_untrusted_forwarded_for() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", "203.0.113.7, 10.0.0.1".parse().unwrap());
+        let peer = ConnectInfo(SocketAddr::from(([192, 0, 2, 1], 4000)));
+
+        assert_eq!(client_ip(&headers, Some(&peer), false), "192.0.2.1");
+        assert_eq!(client_ip(&headers, Some(&peer), true), "203.0.113.7");
+        assert_eq!(client_ip(&HeaderMap::new(), Some(&peer), true), "192.0.2.1");
+        assert_eq!(client_ip(&HeaderMap::new(), None, false), "-");
+    }
+
+    #[tokio::test]
+    async fn logs_error_responses() {
+        testing::capture_logs();
+        let base = testing::serve(testing::config(&[("ACCESS_LOG_FORMAT", "Json")])).await;
+
+        let response = testing::client()
+            .get(format!("{}/images/access-log-missing", base))
+            .header("X-Forwarded-For", "203.0.113.7")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+
+        let lines = testing::logged("/images/access-log-missing");
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        let line = lines[0].trim_start_matches("imgr_serve::routes::access_log ");
+        let line: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(line["method"], "GET");
+        assert_eq!(line["status"], 404);
+        assert_eq!(line["client_ip"], "127.0.0.1");
+        assert!(line["bytes_sent"].as_u64().unwrap() > 0);
+    }
+}
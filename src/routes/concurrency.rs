@@ -0,0 +1,162 @@
+use crate::routes::responses;
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit, TryAcquireError};
+
+/// Shed load with 503 on exceeding max count of requests in flight, instead of queueing them
+/// until memory is exhausted
+pub async fn limit_concurrency(
+    State(semaphore): State<Arc<Semaphore>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let _permit = match semaphore.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            let mut response = responses::api_error::<()>(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Server is overloaded, retry later".to_string(),
+                None,
+            )
+            .into_response();
+            response.headers_mut().insert(header::RETRY_AFTER, 1.into());
+            return response;
+        }
+    };
+    next.run(request).await
+}
+
+/// Window of ingestion rate limit
+const PRELOAD_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits of originals ingestion by preloads, separate from serving ones, to protect storage writes
+pub struct PreloadLimiter {
+    /// Permits of preloads in flight. None is unlimited
+    preloads: Option<Semaphore>,
+    /// Bytes allowed to ingest per minute. None is unlimited
+    bytes_per_minute: Option<u64>,
+    /// Start of current window and bytes ingested in it
+    window: Mutex<(Instant, u64)>,
+}
+
+impl PreloadLimiter {
+    pub fn new(max_concurrent: Option<usize>, bytes_per_minute: Option<u64>) -> Self {
+        PreloadLimiter {
+            preloads: max_concurrent.map(Semaphore::new),
+            bytes_per_minute,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Slot of preload, should be held until preload is stored. Err if all slots are taken
+    pub fn try_acquire(&self) -> Result<Option<SemaphorePermit<'_>>, TryAcquireError> {
+        self.preloads
+            .as_ref()
+            .map(Semaphore::try_acquire)
+            .transpose()
+    }
+
+    /// Account ingested bytes. Returns time until window reset, if rate is exceeded.
+    /// Preload into empty window is always allowed, so images larger than limit are not blocked forever
+    pub fn consume(&self, bytes: u64) -> Result<(), Duration> {
+        let Some(limit) = self.bytes_per_minute else {
+            return Ok(());
+        };
+        let mut window = self.window.lock().unwrap();
+        let (start, ingested) = &mut *window;
+        if start.elapsed() >= PRELOAD_RATE_WINDOW {
+            *start = Instant::now();
+            *ingested = 0;
+        }
+        if *ingested > 0 && *ingested + bytes > limit {
+            return Err(PRELOAD_RATE_WINDOW.saturating_sub(start.elapsed()));
+        }
+        *ingested += bytes;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn requests_over_limit_are_shed() {
+        let (origin, _) =
+            testing::counting_origin(testing::png(8, 8), Duration::from_millis(500)).await;
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("MAX_CONCURRENT_REQUESTS", "1"),
+        ]))
+        .await;
+
+        let slow = tokio::spawn(testing::get(format!("{}/images/slow", base)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = testing::get(format!("{}/version", base)).await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["Retry-After"], "1");
+
+        assert_eq!(slow.await.unwrap().status(), 200);
+        let response = testing::get(format!("{}/version", base)).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn preloads_in_flight_are_limited() {
+        let limiter = PreloadLimiter::new(Some(1), None);
+        let permit = limiter.try_acquire().unwrap();
+        assert!(permit.is_some());
+        assert!(limiter.try_acquire().is_err());
+        drop(permit);
+        assert!(limiter.try_acquire().is_ok());
+
+        assert!(
+            PreloadLimiter::new(None, None)
+                .try_acquire()
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn preload_flood_is_throttled() {
+        let image = testing::png(8, 8);
+        let limit = image.len() * 2;
+        let base = testing::serve(testing::config(&[(
+            "MAX_PRELOAD_BYTES_PER_MINUTE",
+            &limit.to_string(),
+        )]))
+        .await;
+
+        let mut statuses = Vec::new();
+        for index in 0..5 {
+            let response = testing::request(
+                reqwest::Method::PUT,
+                format!("{}/images/flood-{}", base, index),
+            )
+            .body(image.clone())
+            .send()
+            .await
+            .unwrap();
+            if response.status() == 429 {
+                let retry_after: u64 = response.headers()["Retry-After"]
+                    .to_str()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                assert!((1..=60).contains(&retry_after));
+            }
+            statuses.push(response.status().as_u16());
+        }
+        assert_eq!(statuses, [200, 200, 429, 429, 429]);
+        // serving is not throttled
+        let response = testing::get(format!("{}/images/flood-0?width=4", base)).await;
+        assert_eq!(response.status(), 200);
+    }
+}
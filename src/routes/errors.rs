@@ -1,14 +1,19 @@
 use schemars::JsonSchema;
 use serde::Serialize;
+use std::fmt;
 
 #[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum GetImageErrorType {
+    InvalidParams,
     InvalidSize,
     UnsupportingExtension,
     NotFound,
     FileApiError,
     ProcessedImagesLimit,
+    UnavailableExtension,
+    Unauthorized,
+    EncodingFailed,
 }
 
 #[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
@@ -17,6 +22,90 @@ pub enum PreloadImageErrorType {
     InvalidBody,
     Unauthorized,
     UnsupportingExtension,
+    ForbiddenId,
+    InvalidCacheTtl,
+    TooManyRequests,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidateImagesErrorType {
+    Unauthorized,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum OriginUpdatedErrorType {
+    Unauthorized,
+    InvalidParams,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeVariantsErrorType {
+    Unauthorized,
+    InvalidParams,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TouchImageErrorType {
+    Unauthorized,
+    NotFound,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageStatsErrorType {
+    Unauthorized,
+    NotFound,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageColorsErrorType {
+    UnsupportingExtension,
+    NotFound,
+    FileApiError,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AllFormatsErrorType {
+    InvalidParams,
+    InvalidSize,
+    UnsupportingExtension,
+    NotFound,
+    FileApiError,
+    ProcessedImagesLimit,
+    UnavailableExtension,
+    EncodingFailed,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum MontageErrorType {
+    InvalidParams,
+    UnsupportingExtension,
+    NotFound,
+    FileApiError,
+    UnavailableExtension,
+    InvalidSize,
+    EncodingFailed,
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformErrorType {
+    Unauthorized,
+    InvalidParams,
+    InvalidBody,
+    /// Body exceeds `MAX_TRANSFORM_BODY_BYTES`
+    BodyTooLarge,
+    InvalidSize,
+    UnsupportingExtension,
+    UnavailableExtension,
+    EncodingFailed,
 }
 
 #[derive(Debug, Serialize, JsonSchema)]
@@ -26,7 +115,51 @@ pub struct ErrorResponse<T> {
     pub detail: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error_type: Option<T>,
+    /// Invalid request params, each with violated constraint
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/// Request param, violating constraint
+#[derive(Debug, Serialize, JsonSchema, Clone)]
+pub struct FieldError {
+    /// Name of param, as in query
+    pub field: String,
+    /// Violated constraint, like `must be between 1 and 100`
+    pub constraint: String,
+    /// Received value, if it's known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+impl FieldError {
+    pub fn new(field: &str, constraint: impl Into<String>, value: Option<impl ToString>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            constraint: constraint.into(),
+            value: value.map(|v| v.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.constraint)?;
+        if let Some(value) = &self.value {
+            write!(f, " (got {})", value)?;
+        }
+        Ok(())
+    }
 }
 
 pub type GetImageErrorResponse = ErrorResponse<GetImageErrorType>;
 pub type PreloadImageErrorResponse = ErrorResponse<PreloadImageErrorType>;
+pub type InvalidateImagesErrorResponse = ErrorResponse<InvalidateImagesErrorType>;
+pub type OriginUpdatedErrorResponse = ErrorResponse<OriginUpdatedErrorType>;
+pub type PurgeVariantsErrorResponse = ErrorResponse<PurgeVariantsErrorType>;
+pub type TouchImageErrorResponse = ErrorResponse<TouchImageErrorType>;
+pub type ImageStatsErrorResponse = ErrorResponse<ImageStatsErrorType>;
+pub type ImageColorsErrorResponse = ErrorResponse<ImageColorsErrorType>;
+pub type AllFormatsErrorResponse = ErrorResponse<AllFormatsErrorType>;
+pub type MontageErrorResponse = ErrorResponse<MontageErrorType>;
+pub type TransformErrorResponse = ErrorResponse<TransformErrorType>;
@@ -1,24 +1,47 @@
-use crate::config::Config;
-use crate::image_ops::image_types::{Extensions, MimeType};
-use crate::image_ops::operations::ProcessingParams;
-use crate::image_ops::processing::ProcessingErrorType;
+use crate::config::{AllowedSizesPolicy, Config};
+use crate::image_ops::image_types::{Extensions, MimeType, RequestedExtension};
+use crate::image_ops::operations;
+use crate::image_ops::operations::{ImageColors, ProcessingParams, RatioPolicy};
+use crate::image_ops::processing::{
+    CacheStatus, OriginalBody, OriginalSource, ProcessingError, ProcessingErrorType, ServedImage,
+};
 use crate::openapi::{ApiKeyHeader, BinaryBody, ImageIdParam};
+use crate::proxying_images::{FetchOptions, FileApiErrorKind};
 use crate::routes::errors::{
-    GetImageErrorResponse, GetImageErrorType, PreloadImageErrorResponse, PreloadImageErrorType,
+    AllFormatsErrorResponse, AllFormatsErrorType, FieldError, GetImageErrorResponse,
+    GetImageErrorType, ImageColorsErrorResponse, ImageColorsErrorType, ImageStatsErrorResponse,
+    ImageStatsErrorType, InvalidateImagesErrorResponse, InvalidateImagesErrorType,
+    MontageErrorResponse, MontageErrorType, OriginUpdatedErrorResponse, OriginUpdatedErrorType,
+    PreloadImageErrorResponse, PreloadImageErrorType, PurgeVariantsErrorResponse,
+    PurgeVariantsErrorType, TouchImageErrorResponse, TouchImageErrorType, TransformErrorResponse,
+    TransformErrorType,
 };
+use crate::routes::request_id::REQUEST_ID_HEADER;
 use crate::routes::responses;
-use crate::routes::responses::{ApiError, ImageResponse};
+use crate::routes::responses::{ApiError, ImageOrJsonResponse, ImageResponse};
+use crate::store::image_stats::ImageStats;
 use crate::utils::filename_extractor::FileNameExtractor;
+use crate::utils::types::{ImageContainer, ImageId};
+use crate::warm;
 use aide::transform::{TransformOperation, TransformResponse};
 use axum::Json;
-use axum::body::{Body, to_bytes};
-use axum::extract::{Path, Query, State};
+use axum::body::{Body, Bytes, to_bytes};
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, RawQuery, State};
+use axum::http::header::InvalidHeaderValue;
 use axum::http::{HeaderMap, HeaderValue, Response, StatusCode, header};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use futures_util::{StreamExt, TryStreamExt, stream};
 use http::response::Builder;
-use log::{debug, info};
+use log::{debug, info, warn};
 use sanitize_filename::sanitize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
+use strum::IntoEnumIterator;
 
 /// Specify caching headers for serving files
 fn caching_headers(builder: Builder, cache_ttl: usize) -> Builder {
@@ -41,97 +64,943 @@ fn caching_headers(builder: Builder, cache_ttl: usize) -> Builder {
         )
 }
 
-/// Filename header, supporting UTF-8 chars
-fn content_disposition_header(filename: Option<String>, extensions: Extensions) -> HeaderValue {
-    let full_filename = format!(
-        "{}.{}",
-        filename
-            .unwrap_or("image".to_string())
-            .replace("\"", "\\\""),
-        extensions.name()
+/// Note quality clamping in response, so clients can tell served quality from requested one
+fn quality_clamped_header(builder: Builder, clamped_quality: Option<u32>) -> Builder {
+    match clamped_quality {
+        Some(quality) => builder.header(QUALITY_CLAMPED_HEADER, quality),
+        None => builder,
+    }
+}
+
+/// Note whether image was taken from processed cache and where its processed original came from
+fn cache_status_headers(
+    builder: Builder,
+    cache_status: CacheStatus,
+    original_source: Option<OriginalSource>,
+    enabled: bool,
+) -> Builder {
+    if !enabled {
+        return builder;
+    }
+    let builder = builder.header(
+        CACHE_STATUS_HEADER,
+        match cache_status {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Stale => "STALE",
+        },
     );
-    format!(
-        "inline; filename=\"{}\"; filename*=UTF-8''{}",
-        &full_filename,
-        urlencoding::encode(full_filename.as_str())
-    )
-    .parse()
-    .unwrap()
+    match original_source {
+        Some(source) => builder.header(
+            CACHE_ORIGINAL_HEADER,
+            match source {
+                OriginalSource::Storage => "STORAGE",
+                OriginalSource::Revalidated => "REVALIDATED",
+                OriginalSource::FileApi => "FETCHED",
+            },
+        ),
+        None => builder,
+    }
+}
+
+/// Note serving other format, than requested one
+fn format_fallback_header(
+    builder: Builder,
+    requested: Extensions,
+    img: &ImageContainer,
+) -> Builder {
+    match img.extension == requested {
+        true => builder,
+        false => builder.header(FORMAT_FALLBACK_HEADER, requested.name()),
+    }
+}
+
+/// Add checksum of served image, if enabled. Computed on the fly only for images, cached before enabling
+fn digest_header(builder: Builder, img: &ImageContainer, enabled: bool) -> Builder {
+    if !enabled {
+        return builder;
+    }
+    let digest = img.digest.clone().unwrap_or_else(|| img.compute_digest());
+    builder.header(DIGEST_HEADER, format!("sha-256={}", digest))
+}
+
+/// Add size and format of served image, if enabled. Size of images, cached before it was stored
+/// along with them, is read from image header
+fn image_info_headers(builder: Builder, img: &ImageContainer, enabled: bool) -> Builder {
+    if !enabled {
+        return builder;
+    }
+    let builder = builder.header(IMAGE_FORMAT_HEADER, img.extension.name());
+    match img
+        .dimensions
+        .or_else(|| operations::image_dimensions(img.data.as_slice()))
+    {
+        Some((width, height)) => builder
+            .header(IMAGE_WIDTH_HEADER, width)
+            .header(IMAGE_HEIGHT_HEADER, height),
+        None => builder,
+    }
+}
+
+/// Filename for images without known original filename, built from configured pattern
+fn default_filename(pattern: &str, image_id: &str) -> String {
+    let filename: String = sanitize(pattern.replace("{id}", image_id))
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    match filename.is_empty() {
+        true => "image".to_string(),
+        false => filename,
+    }
+}
+
+/// How client should present served image
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposition {
+    /// Displayed in browser
+    #[default]
+    Inline,
+    /// Downloaded as file
+    Attachment,
+}
+
+impl Disposition {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Disposition::Inline => "inline",
+            Disposition::Attachment => "attachment",
+        }
+    }
+}
+
+/// Filename header, supporting UTF-8 chars
+///
+/// Control chars can't be sent in header, so they are dropped. Header with the rest of unsendable
+/// filename falls back to percent-encoded `filename*` form only
+fn content_disposition_header(
+    disposition: Disposition,
+    filename: Option<String>,
+    default_filename: String,
+    extension: &str,
+) -> HeaderValue {
+    let filename: String = filename
+        .unwrap_or(default_filename)
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let full_filename = format!("{}.{}", filename, extension);
+    let encoded_filename = urlencoding::encode(full_filename.as_str());
+    content_disposition(disposition, &full_filename, &encoded_filename)
+        .or_else(|_| {
+            HeaderValue::from_str(&format!(
+                "{}; filename*=UTF-8''{}",
+                disposition.as_str(),
+                encoded_filename
+            ))
+        })
+        .unwrap_or_else(|_| HeaderValue::from_static(disposition.as_str()))
+}
+
+fn content_disposition(
+    disposition: Disposition,
+    full_filename: &str,
+    encoded_filename: &str,
+) -> Result<HeaderValue, InvalidHeaderValue> {
+    HeaderValue::from_str(&format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition.as_str(),
+        full_filename.replace("\"", "\\\""),
+        encoded_filename
+    ))
+}
+
+/// Encoding of preload request body
+#[derive(Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PreloadEncoding {
+    /// Raw image bytes
+    Binary,
+    /// Base64 encoded image bytes, also as `data:` url
+    Base64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PreloadParams {
+    /// Body encoding, base64 is also detected by `Content-Transfer-Encoding` header
+    /// and `data:` url prefix
+    pub encoding: Option<PreloadEncoding>,
+}
+
+/// Decode preload body into raw image bytes
+fn decode_preload_body(
+    body: &[u8],
+    headers: &HeaderMap,
+    encoding: Option<PreloadEncoding>,
+) -> Result<Vec<u8>, String> {
+    let is_data_url = body.starts_with(b"data:");
+    let encoding = encoding.unwrap_or_else(|| {
+        let transfer_encoding = headers
+            .get("Content-Transfer-Encoding")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if is_data_url || transfer_encoding.eq_ignore_ascii_case("base64") {
+            PreloadEncoding::Base64
+        } else {
+            PreloadEncoding::Binary
+        }
+    });
+    if encoding == PreloadEncoding::Binary {
+        return Ok(body.to_vec());
+    }
+
+    // data:[<mediatype>];base64,<data>
+    let data = match is_data_url {
+        true => match body.iter().position(|b| *b == b',') {
+            Some(pos) if body[..pos].ends_with(b";base64") => &body[pos + 1..],
+            _ => return Err("Only base64 data urls are supported".to_string()),
+        },
+        false => body,
+    };
+    let data: Vec<u8> = data
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    BASE64_STANDARD
+        .decode(data)
+        .map_err(|err| format!("Invalid base64 body: {}", err))
+}
+
+/// Check api key, required for cache management routes
+fn is_authorized(headers: &HeaderMap, server_api_key: &str) -> bool {
+    let api_key = match headers.get("X-API-Key") {
+        None => "",
+        Some(header) => header.to_str().unwrap_or(""),
+    };
+    api_key == server_api_key
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct InvalidateRequest {
+    pub image_ids: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct InvalidateResponse {
+    /// Count of purged processed versions per image id
+    pub purged: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct OriginUpdatedResponse {
+    /// Count of purged processed versions per image id
+    pub purged: BTreeMap<String, usize>,
+    /// Count of variants, scheduled to be processed again in background
+    pub warming: usize,
+}
+
+/// Filter of processed versions to purge, all set conditions should match
+#[derive(Deserialize, JsonSchema)]
+pub struct PurgeVariantsRequest {
+    /// Resulting extension of version
+    pub extension: Option<Extensions>,
+    /// Requested width is greater than this one
+    pub width_above: Option<u32>,
+    /// Requested height is greater than this one
+    pub height_above: Option<u32>,
+    /// Max count of purged versions, clamped to 10000
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PurgeVariantsResponse {
+    /// Count of purged processed versions
+    pub purged: usize,
+    /// Count of images, which had purged versions
+    pub images: usize,
+    /// All matching versions are purged, otherwise limit was reached and request should be repeated
+    pub complete: bool,
+}
+
+/// Max count of processed versions purged by one request, as cache is locked meanwhile
+const MAX_PURGED_VARIANTS: usize = 10000;
+
+/// Max supported device pixel ratio
+const MAX_DPR: f32 = 4.0;
+
+/// Max length of requested `filename`, to keep `Content-Disposition` header short
+const MAX_FILENAME_CHARS: usize = 200;
+
+/// Header with width of served image, when it differs from requested one after applying preset,
+/// dpr and snapping to allowed widths
+const EFFECTIVE_WIDTH_HEADER: &str = "X-Imgr-Effective-Width";
+
+/// Header of preload request with client cache ttl (in seconds) of image, overriding `CLIENT_CACHE_TTL`
+const CACHE_TTL_HEADER: &str = "X-Cache-TTL";
+
+/// Header with quality, requested one was raised to (min quality of resulting format)
+const QUALITY_CLAMPED_HEADER: &str = "X-Imgr-Quality-Clamped";
+
+/// Header with checksum of served bytes (`sha-256=<base64>`)
+const DIGEST_HEADER: &str = "Digest";
+
+/// Headers with size and format of served image
+const IMAGE_WIDTH_HEADER: &str = "X-Image-Width";
+const IMAGE_HEIGHT_HEADER: &str = "X-Image-Height";
+const IMAGE_FORMAT_HEADER: &str = "X-Image-Format";
+
+/// Headers with processed cache status and source of original, processed on cache miss
+const CACHE_STATUS_HEADER: &str = "X-Cache";
+const CACHE_ORIGINAL_HEADER: &str = "X-Cache-Original";
+
+/// Header with requested format, when other one is served instead (like WebP on failed AVIF encoding)
+const FORMAT_FALLBACK_HEADER: &str = "X-Imgr-Format-Fallback";
+
+/// Header, marking that requested image is not found and fallback image is served
+const FALLBACK_HEADER: &str = "X-Imgr-Fallback";
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ResponsiveParams {
+    /// Device pixel ratio (as in `srcset` `2x`), multiplies requested width and height
+    pub dpr: Option<f32>,
+    /// Name of configured size (`SIZE_PRESETS`) instead of width and height
+    pub preset: Option<String>,
+}
+
+/// Max count of images in montage
+const MAX_MONTAGE_IMAGES: usize = 16;
+const DEFAULT_MONTAGE_CELL: u32 = 200;
+/// Columns of montage, if not specified (fewer for fewer images)
+const DEFAULT_MONTAGE_COLS: u32 = 4;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct MontageParams {
+    /// Comma separated image ids
+    pub ids: String,
+    /// Count of columns in grid (default: 4)
+    pub cols: Option<u32>,
+    /// Side of square cell, each image is cropped to (default: 200)
+    pub cell: Option<u32>,
+    /// Short alias `fmt`
+    #[serde(alias = "fmt")]
+    pub extension: Option<Extensions>,
+    /// Short alias `q`
+    #[serde(alias = "q")]
+    pub quality: Option<u32>,
+}
+
+/// Count of formats, processed at once for multipart response
+const ALL_FORMATS_CONCURRENCY: usize = 2;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AllFormatsParams {
+    /// Comma separated formats, like `webp,avif,png` (case insensitive)
+    pub formats: String,
+}
+
+/// Params for debugging and operating, each one requires `X-API-Key`
+#[derive(Deserialize, JsonSchema)]
+pub struct PrivilegedParams {
+    /// Override of file api timeout (in seconds) for large originals. Clamped to `MAX_FETCH_TIMEOUT`
+    pub fetch_timeout: Option<u32>,
+    /// Reprocess image from original, ignoring processed cache. Result still replaces cached one
+    pub fresh: Option<bool>,
+}
+
+/// Params of served response, which don't affect processing and processed cache
+#[derive(Deserialize, JsonSchema)]
+pub struct ResponseParams {
+    /// Serve original bytes as stored (or fetched), with their own content type, ignoring processing params
+    pub original: Option<bool>,
+    /// Filename (without extension) of `Content-Disposition` header, overriding the stored one
+    pub filename: Option<String>,
+    /// `attachment` makes browsers download image instead of displaying it
+    #[serde(default)]
+    pub disposition: Disposition,
+}
+
+/// Requested filename, sanitized for `Content-Disposition`.
+/// Path-like values are rejected instead of being silently mangled
+fn requested_filename(params: &ResponseParams) -> Result<Option<String>, FieldError> {
+    let Some(filename) = params.filename.as_deref() else {
+        return Ok(None);
+    };
+    if filename.contains(['/', '\\'])
+        || filename.contains("..")
+        || filename.chars().any(char::is_control)
+    {
+        return Err(FieldError::new(
+            "filename",
+            "must not contain path separators, \"..\" or control chars",
+            Some(filename),
+        ));
+    }
+    if filename.chars().count() > MAX_FILENAME_CHARS {
+        return Err(FieldError::new(
+            "filename",
+            format!("must be at most {} chars", MAX_FILENAME_CHARS),
+            Some(filename),
+        ));
+    }
+    let sanitized = sanitize(filename);
+    match sanitized.is_empty() {
+        true => Err(FieldError::new(
+            "filename",
+            "must contain chars allowed in filenames",
+            Some(filename),
+        )),
+        false => Ok(Some(sanitized)),
+    }
+}
+
+/// Whether `Accept` header explicitly lists mime type with non-zero weight. Wildcards are not
+/// counted, as browsers send them regardless of modern formats support
+fn accepts_mime_type(headers: &HeaderMap, mime_type: &str) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        parts
+            .next()
+            .is_some_and(|v| v.eq_ignore_ascii_case(mime_type))
+            && parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_none_or(|weight| weight > 0.0)
+    })
+}
+
+/// Resolve `auto` extension into the first one of format priority, which is accepted by client
+/// and available. Default extension is used, if there is no such one.
+///
+/// Returns whether extension was negotiated, so response varies by `Accept`
+fn negotiate_extension(params: &mut ProcessingParams, headers: &HeaderMap, state: &Config) -> bool {
+    if params.extension != Some(RequestedExtension::Auto)
+        || !state.processor.allow_custom_extension()
+    {
+        return false;
+    }
+    params.extension = state
+        .format_priority
+        .0
+        .iter()
+        .find(|ext| {
+            state.processor.available_extensions().contains(ext)
+                && accepts_mime_type(headers, ext.mime_type())
+        })
+        .map(|ext| (*ext).into());
+    true
+}
+
+/// Apply size preset and dpr to requested dimensions
+fn apply_responsive_params(
+    params: &mut ProcessingParams,
+    responsive: &ResponsiveParams,
+    state: &Config,
+) -> Result<(), FieldError> {
+    if let Some(preset) = &responsive.preset {
+        if params.width.is_some() || params.height.is_some() {
+            return Err(FieldError::new(
+                "preset",
+                "can't be combined with width or height",
+                Some(preset),
+            ));
+        }
+        let Some((width, height)) = state.size_presets.get(preset) else {
+            return Err(FieldError::new(
+                "preset",
+                "is not one of SIZE_PRESETS",
+                Some(preset),
+            ));
+        };
+        params.width = width;
+        params.height = height;
+    } else if state.preset_sizes_only {
+        for (field, value) in [("width", params.width), ("height", params.height)] {
+            if let Some(value) = value {
+                return Err(FieldError::new(
+                    field,
+                    "is not allowed, only preset of SIZE_PRESETS is",
+                    Some(value),
+                ));
+            }
+        }
+    }
+    if state.preset_sizes_only
+        && let Some(dpr) = responsive.dpr
+    {
+        return Err(FieldError::new(
+            "dpr",
+            "is not allowed, only preset of SIZE_PRESETS is",
+            Some(dpr),
+        ));
+    }
+
+    if let Some(dpr) = responsive.dpr {
+        if !(dpr > 0.0 && dpr <= MAX_DPR) {
+            return Err(FieldError::new(
+                "dpr",
+                format!("must be greater than 0 and at most {}", MAX_DPR),
+                Some(dpr),
+            ));
+        }
+        params.width = params.width.map(|w| (w as f32 * dpr).round() as u32);
+        params.height = params.height.map(|h| (h as f32 * dpr).round() as u32);
+    }
+
+    Ok(())
+}
+
+/// Restrict requested dimensions to allowed widths and heights.
+///
+/// On snapping width, height is scaled to keep requested aspect ratio
+fn restrict_allowed_sizes(params: &mut ProcessingParams, state: &Config) -> Result<(), FieldError> {
+    if state.allowed_sizes_policy == AllowedSizesPolicy::Reject {
+        if let Some(width) = params.width
+            && !state.allowed_widths.is_allowed(width)
+        {
+            return Err(FieldError::new(
+                "width",
+                "is not one of ALLOWED_WIDTHS",
+                Some(width),
+            ));
+        }
+        if let Some(height) = params.height
+            && !state.allowed_heights.is_allowed(height)
+        {
+            return Err(FieldError::new(
+                "height",
+                "is not one of ALLOWED_HEIGHTS",
+                Some(height),
+            ));
+        }
+        return Ok(());
+    }
+
+    if let Some(width) = params.width
+        && width > 0
+        && let Some(snapped) = state.allowed_widths.nearest(width)
+        && snapped != width
+    {
+        params.height = params.height.map(|h| {
+            ((h as u64 * snapped as u64) as f64 / width as f64)
+                .round()
+                .max(1.0) as u32
+        });
+        params.width = Some(snapped);
+    }
+    if let Some(height) = params.height
+        && let Some(snapped) = state.allowed_heights.nearest(height)
+    {
+        params.height = Some(snapped);
+    }
+    Ok(())
 }
 
-/// Validate ProcessingParams
-fn validate_processing_params(params: &ProcessingParams) -> Result<(), String> {
-    if let Some(quality) = params.quality {
-        if quality < 10 || quality > 100 {
-            return Err("Quality must be between 10 and 100".to_string());
+/// Bit depths, supported by avif encoder
+const SUPPORTED_BIT_DEPTHS: [u8; 2] = [8, 10];
+
+/// Max `dpi`, higher ones are not used even by print
+const MAX_DPI: u32 = 10000;
+
+/// Validate ProcessingParams, collecting all invalid params
+fn validate_processing_params(params: &ProcessingParams, state: &Config) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let qualities = [
+        ("quality", params.quality),
+        ("frame_quality", params.frame_quality),
+    ];
+    for (name, value) in qualities {
+        if let Some(value) = value
+            && !(1..=100).contains(&value)
+        {
+            errors.push(FieldError::new(
+                name,
+                "must be between 1 and 100",
+                Some(value),
+            ));
+        }
+    }
+    let dimensions = [
+        ("width", params.width, state.max_image_resize.width),
+        ("height", params.height, state.max_image_resize.height),
+    ];
+    for (name, value, max) in dimensions {
+        match value {
+            // zero sized image can't be encoded
+            Some(0) => errors.push(FieldError::new(name, "must be greater than 0", Some(0))),
+            Some(value) if value > max => errors.push(FieldError::new(
+                name,
+                format!("must be at most {}", max),
+                Some(value),
+            )),
+            _ => {}
+        }
+    }
+    let colors = [("tint", &params.tint), ("background", &params.background)];
+    for (name, value) in colors {
+        if let Some(value) = value
+            && operations::normalize_hex_color(value).is_none()
+        {
+            errors.push(FieldError::new(
+                name,
+                "must be hex color, like ff8800",
+                Some(value),
+            ));
         }
     }
+    let adjustments = [
+        ("brightness", params.brightness),
+        ("contrast", params.contrast),
+        ("saturation", params.saturation),
+    ];
+    for (name, value) in adjustments {
+        if let Some(value) = value
+            && !(-100..=100).contains(&value)
+        {
+            errors.push(FieldError::new(
+                name,
+                "must be between -100 and 100",
+                Some(value),
+            ));
+        }
+    }
+    if let Some(sharpen) = params.sharpen
+        && !(1..=100).contains(&sharpen)
+    {
+        errors.push(FieldError::new(
+            "sharpen",
+            "must be between 1 and 100",
+            Some(sharpen),
+        ));
+    }
+    if let Some(bit_depth) = params.bit_depth
+        && !SUPPORTED_BIT_DEPTHS.contains(&bit_depth)
+    {
+        errors.push(FieldError::new(
+            "bit_depth",
+            format!(
+                "must be one of {:?} (12 bit is not supported by avif encoder)",
+                SUPPORTED_BIT_DEPTHS
+            ),
+            Some(bit_depth),
+        ));
+    }
+    if params.fps == Some(0) {
+        errors.push(FieldError::new("fps", "must be positive", Some(0)));
+    }
+    if let Some(dpi) = params.dpi
+        && !(1..=MAX_DPI).contains(&dpi)
+    {
+        errors.push(FieldError::new(
+            "dpi",
+            format!("must be between 1 and {}", MAX_DPI),
+            Some(dpi),
+        ));
+    }
+    let transforms = params.adjustments();
+    if let Some(max_transforms) = state.max_transforms
+        && transforms.len() > max_transforms
+    {
+        errors.push(FieldError::new(
+            "transforms",
+            format!("at most {} are allowed at once", max_transforms),
+            Some(transforms.join(", ")),
+        ));
+    }
+    errors
+}
+
+/// Validate processing params and bring them to canonical form, to not fragment cache by color notation
+pub fn prepare_processing_params(
+    params: &mut ProcessingParams,
+    state: &Config,
+) -> Result<(), Vec<FieldError>> {
+    let mut errors = Vec::new();
+    if let Err(err) = restrict_allowed_sizes(params, state) {
+        errors.push(err);
+    }
+    errors.extend(validate_processing_params(params, state));
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    params.tint = params
+        .tint
+        .as_deref()
+        .and_then(operations::normalize_hex_color);
+    params.background = params
+        .background
+        .as_deref()
+        .and_then(operations::normalize_hex_color);
+    // effective value is a part of cache key, so changing default doesn't serve stale orientation
+    params.auto_orient = Some(params.auto_orient.unwrap_or(state.auto_orient));
     Ok(())
 }
 
+/// Field error of query, which failed to deserialize (unknown variant, not a number and etc)
+fn query_rejection_field(rejection: &QueryRejection, raw_query: Option<&str>) -> FieldError {
+    let text = rejection.body_text();
+    let message = text
+        .strip_prefix("Failed to deserialize query string: ")
+        .unwrap_or(&text);
+    match message.split_once(": ") {
+        Some((field, constraint)) if !field.contains(' ') => {
+            let value = raw_query.and_then(|query| {
+                query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| *name == field)
+                    .map(|(_, value)| {
+                        urlencoding::decode(value).map_or(value.to_string(), |v| v.into_owned())
+                    })
+            });
+            FieldError::new(field, constraint, value)
+        }
+        _ => FieldError::new("query", message, None::<String>),
+    }
+}
+
+/// Response status of processing error: upstream failures are reported as gateway errors
+fn processing_error_status(err_type: &ProcessingErrorType) -> StatusCode {
+    match err_type {
+        ProcessingErrorType::NotFound => StatusCode::NOT_FOUND,
+        ProcessingErrorType::FrameOutOfRange => StatusCode::UNPROCESSABLE_ENTITY,
+        ProcessingErrorType::EncodingFailed => StatusCode::SERVICE_UNAVAILABLE,
+        ProcessingErrorType::FileApiError(FileApiErrorKind::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+        ProcessingErrorType::FileApiError(FileApiErrorKind::TooLarge) => {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        ProcessingErrorType::FileApiError(
+            FileApiErrorKind::DnsFailure
+            | FileApiErrorKind::ConnectFailure
+            | FileApiErrorKind::TlsFailure
+            | FileApiErrorKind::EmptyBody
+            | FileApiErrorKind::CorruptedImage
+            | FileApiErrorKind::ForbiddenRedirect
+            | FileApiErrorKind::PrivateAddress,
+        ) => StatusCode::BAD_GATEWAY,
+        ProcessingErrorType::FileApiError(FileApiErrorKind::HttpStatus(status))
+            if *status >= 500 =>
+        {
+            StatusCode::BAD_GATEWAY
+        }
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
 /// Serve images as static files
 ///
 /// If image is not existing, it will be attempted to fetch on configured base api
+#[allow(clippy::too_many_arguments)]
 pub async fn serve_file(
+    path: Path<String>,
+    query: Result<Query<ProcessingParams>, QueryRejection>,
+    raw_query: RawQuery,
+    responsive: Query<ResponsiveParams>,
+    privileged: Query<PrivilegedParams>,
+    response_params: Result<Query<ResponseParams>, QueryRejection>,
+    headers: HeaderMap,
+    state: State<Arc<Config>>,
+) -> Result<ImageResponse, ApiError<GetImageErrorType>> {
+    image_response(
+        path,
+        query,
+        raw_query,
+        responsive,
+        privileged,
+        response_params,
+        headers,
+        state,
+        false,
+    )
+    .await
+}
+
+/// Response of `/images/{id}`. Responses of `HEAD` requests (`head`) have no body
+/// and are not counted as served
+#[allow(clippy::too_many_arguments)]
+async fn image_response(
     Path(image_id): Path<String>,
-    query: Query<ProcessingParams>,
+    query: Result<Query<ProcessingParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    Query(responsive): Query<ResponsiveParams>,
+    Query(privileged): Query<PrivilegedParams>,
+    response_params: Result<Query<ResponseParams>, QueryRejection>,
+    headers: HeaderMap,
     State(state): State<Arc<Config>>,
+    head: bool,
 ) -> Result<ImageResponse, ApiError<GetImageErrorType>> {
-    // Validate processing parameters
-    if let Err(err) = validate_processing_params(&query.0) {
+    let (mut query, Query(response_params)) = query
+        .and_then(|query| Ok((query, response_params?)))
+        .map_err(|rejection| {
+            responses::invalid_params(
+                vec![query_rejection_field(&rejection, raw_query.as_deref())],
+                Some(GetImageErrorType::InvalidParams),
+            )
+        })?;
+    let filename = requested_filename(&response_params).map_err(|err| {
+        responses::invalid_params(vec![err], Some(GetImageErrorType::InvalidParams))
+    })?;
+    if (privileged.fetch_timeout.is_some() || privileged.fresh.is_some())
+        && !is_authorized(&headers, &state.api_key)
+    {
         return Err(responses::api_error(
-            StatusCode::BAD_REQUEST,
-            err,
-            Some(GetImageErrorType::InvalidSize),
+            StatusCode::UNAUTHORIZED,
+            "Fetch timeout override and fresh require valid api key".to_string(),
+            Some(GetImageErrorType::Unauthorized),
         ));
     }
+    let fetch_timeout = privileged
+        .fetch_timeout
+        .map(|secs| Duration::from_secs(secs.max(1) as u64).min(state.max_fetch_timeout));
+    let fetch_options = FetchOptions {
+        timeout: fetch_timeout,
+        request_id: headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        validators: None,
+    };
 
-    if !state
-        .max_image_resize
-        .is_allowed_size(&query.width, &query.height)
-    {
-        return Err(responses::api_error(
-            StatusCode::BAD_REQUEST,
-            "Extension too big".to_string(),
-            Some(GetImageErrorType::InvalidSize),
+    if response_params.original == Some(true) {
+        return serve_original(
+            state.normalize_image_id(image_id),
+            response_params.disposition,
+            filename,
+            fetch_options,
+            &state,
+            head,
+        )
+        .await;
+    }
+
+    let requested_width = query.width;
+    if let Err(err) = apply_responsive_params(&mut query.0, &responsive, &state) {
+        return Err(responses::invalid_params(
+            vec![err],
+            Some(GetImageErrorType::InvalidParams),
+        ));
+    }
+    if let Err(errors) = prepare_processing_params(&mut query.0, &state) {
+        return Err(responses::invalid_params(
+            errors,
+            Some(GetImageErrorType::InvalidParams),
         ));
     }
+    let negotiated = negotiate_extension(&mut query.0, &headers, &state);
+    let clamped_quality = state.clamp_quality(&mut query.0);
 
-    let image_id = sanitize(image_id);
+    let image_id = state.normalize_image_id(image_id);
+    // reported as missing, to not disclose patterns
+    if !state.is_allowed_image_id(&image_id) {
+        return Err(responses::api_error(
+            StatusCode::NOT_FOUND,
+            ProcessingErrorType::NotFound.default_detail(),
+            Some(GetImageErrorType::NotFound),
+        ));
+    }
     info!("Getting img {}", image_id);
 
-    let result = state.processor.get(image_id.clone(), query.0.clone()).await;
+    let result = state
+        .processor
+        .get(
+            image_id.clone(),
+            query.0.clone(),
+            fetch_options.clone(),
+            privileged.fresh.unwrap_or(false),
+        )
+        .await;
     debug!("processed image {}. Generating response", &image_id);
+    if let Ok(served) = &result
+        && served.cache_status == CacheStatus::Stale
+    {
+        let state = state.clone();
+        let (image_id, params) = (image_id.clone(), query.0.clone());
+        tokio::spawn(async move {
+            state
+                .processor
+                .refresh_stale(image_id, params, fetch_options)
+                .await
+        });
+    }
 
     let response = match result {
-        Ok(img) => ImageResponse(
-            caching_headers(Response::builder(), state.client_cache_ttl)
-                .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, img.extension.mime_type())
-                .header(
-                    header::CONTENT_DISPOSITION,
-                    content_disposition_header(img.filename.clone(), img.extension),
+        Ok(served) => {
+            let img = served.image;
+            if !head && !served.is_fallback {
+                state
+                    .processor
+                    .record_served(&image_id, &query.0, img.data.len());
+            }
+            let mut builder = match served.is_fallback {
+                // fallback should not be cached by clients, image may appear on origin later
+                true => Response::builder()
+                    .status(state.fallback_image_status)
+                    .header(header::CACHE_CONTROL, "no-cache")
+                    .header(FALLBACK_HEADER, "true"),
+                false => caching_headers(
+                    Response::builder(),
+                    served
+                        .cache_ttl
+                        .map_or(state.client_cache_ttl, |ttl| ttl as usize),
                 )
-                .body(Body::from(img.data.as_slice().to_owned()))
-                .unwrap(),
-        ),
-        Err(err) => {
-            let status = match err.err_type {
-                ProcessingErrorType::NotFound => StatusCode::NOT_FOUND,
-                _ => StatusCode::BAD_REQUEST.into(),
-            };
-            let error_type = match err.err_type {
-                ProcessingErrorType::UnsupportingExtension => {
-                    GetImageErrorType::UnsupportingExtension
-                }
-                ProcessingErrorType::NotFound => GetImageErrorType::NotFound,
-                ProcessingErrorType::FileApiError => GetImageErrorType::FileApiError,
-                ProcessingErrorType::ProcessedImagesLimit => {
-                    GetImageErrorType::ProcessedImagesLimit
-                }
+                .status(StatusCode::OK),
             };
-            return Err(responses::api_error(status, err.detail, Some(error_type)));
+            if let Some(width) = query.width
+                && query.width != requested_width
+            {
+                builder = builder.header(EFFECTIVE_WIDTH_HEADER, width);
+            }
+            // json variant is available only along with debug endpoints, to not vary production responses
+            if negotiated || state.enable_debug_endpoints {
+                builder = builder.header(header::VARY, header::ACCEPT.as_str());
+            }
+
+            let builder = format_fallback_header(
+                builder,
+                state.processor.determine_extension(&query.0),
+                &img,
+            );
+            let builder = image_info_headers(
+                quality_clamped_header(builder, clamped_quality),
+                &img,
+                state.image_info_headers,
+            );
+            let builder = cache_status_headers(
+                builder,
+                served.cache_status,
+                served.original_source,
+                state.cache_status_headers,
+            );
+            if state.enable_debug_endpoints && wants_json(&headers) {
+                return Ok(ImageResponse(response_body(
+                    builder
+                        .extension(served.cache_status)
+                        .header(header::CONTENT_TYPE, "application/json"),
+                    &serde_json::to_vec(&ImageJson::new(&img)).unwrap(),
+                    head,
+                )));
+            }
+            ImageResponse(response_body(
+                digest_header(builder, &img, state.response_digest)
+                    .extension(served.cache_status)
+                    .header(header::CONTENT_TYPE, img.extension.mime_type())
+                    .header(
+                        header::CONTENT_DISPOSITION,
+                        content_disposition_header(
+                            response_params.disposition,
+                            filename.or_else(|| img.filename.clone()),
+                            default_filename(&state.default_filename_pattern, &image_id),
+                            img.extension.name(),
+                        ),
+                    ),
+                img.data.as_slice(),
+                head,
+            ))
         }
+        Err(err) => return Err(get_image_error(err)),
     };
 
     debug!("generated response");
@@ -139,52 +1008,407 @@ pub async fn serve_file(
     Ok(response)
 }
 
-/// Pre fetch image into cache to prevent fetching on client image request
-#[axum::debug_handler]
-pub async fn preload_image(
-    Path(image_id): Path<String>,
-    State(state): State<Arc<Config>>,
-    headers: HeaderMap,
-    body: Body,
-) -> Result<Json<PreloadImageErrorResponse>, ApiError<PreloadImageErrorType>> {
-    let image_id = sanitize(image_id);
-    info!("Preloading img {}", image_id);
-
-    // Check API key without holding a lock
-    let server_api_key = state.api_key.clone();
-    let api_key = match headers.get("X-API-Key") {
-        None => String::new(),
-        Some(header) => header.to_str().unwrap_or("").into(),
-    };
-    if api_key != server_api_key {
+/// Original bytes of `/images/{id}?original=true`, with content type and file extension of their format
+async fn serve_original(
+    image_id: ImageId,
+    disposition: Disposition,
+    filename: Option<String>,
+    fetch_options: FetchOptions,
+    state: &Config,
+    head: bool,
+) -> Result<ImageResponse, ApiError<GetImageErrorType>> {
+    // reported as missing, to not disclose patterns
+    if !state.is_allowed_image_id(&image_id) {
         return Err(responses::api_error(
-            StatusCode::UNAUTHORIZED,
-            "Mismatched api key".to_string(),
-            Some(PreloadImageErrorType::Unauthorized),
+            StatusCode::NOT_FOUND,
+            ProcessingErrorType::NotFound.default_detail(),
+            Some(GetImageErrorType::NotFound),
         ));
     }
+    info!("Getting original of img {}", image_id);
 
-    // Prefetch without holding a lock on the entire config
-    let body_bytes = match to_bytes(body, usize::MAX).await {
-        Ok(bytes) => bytes,
-        Err(err) => {
-            return Err(responses::api_error(
-                StatusCode::BAD_REQUEST,
-                format!("Invalid body: {}", err),
-                Some(PreloadImageErrorType::InvalidBody),
-            ));
+    // HEAD needs only size of original, so it's read completely, to be stored for following GET
+    let (original, cache_ttl) = match head {
+        true => state
+            .processor
+            .original(image_id.clone(), fetch_options)
+            .await
+            .map(|(data, cache_ttl)| (OriginalBody::Stored(data), cache_ttl)),
+        false => {
+            state
+                .processor
+                .original_stream(image_id.clone(), fetch_options)
+                .await
+        }
+    }
+    .map_err(get_image_error)?;
+    // stored and fetched originals are already checked to be images
+    let format = match &original {
+        OriginalBody::Stored(data) => image::guess_format(data.as_slice()).ok(),
+        OriginalBody::Streamed(fetched) => image::guess_format(&fetched.head).ok(),
+    };
+    let builder = caching_headers(
+        Response::builder(),
+        cache_ttl.map_or(state.client_cache_ttl, |ttl| ttl as usize),
+    )
+    .status(StatusCode::OK)
+    .header(
+        header::CONTENT_TYPE,
+        format.map_or("application/octet-stream", |format| format.to_mime_type()),
+    )
+    .header(
+        header::CONTENT_DISPOSITION,
+        content_disposition_header(
+            disposition,
+            filename,
+            default_filename(&state.default_filename_pattern, &image_id),
+            format
+                .and_then(|format| format.extensions_str().first().copied())
+                .unwrap_or("bin"),
+        ),
+    );
+    let response = match original {
+        OriginalBody::Stored(data) if head => response_body(builder, data.as_slice(), true),
+        OriginalBody::Stored(data) => builder
+            .body(Body::from(Bytes::from_owner(SharedOriginal(data))))
+            .unwrap(),
+        // passed to client as it's read from file api
+        OriginalBody::Streamed(fetched) => {
+            let builder = match fetched.content_length {
+                Some(length) => builder.header(header::CONTENT_LENGTH, length),
+                None => builder,
+            };
+            let body = stream::once(async { Ok(fetched.head) })
+                .chain(fetched.rest)
+                .map_err(|err| std::io::Error::other(err.reason));
+            builder.body(Body::from_stream(body)).unwrap()
         }
     };
+    Ok(ImageResponse(response))
+}
 
-    let result = state
-        .processor
-        .prefetch(
-            image_id.clone(),
-            FileNameExtractor::extract(&headers).unwrap_or(image_id.to_string()),
-            body_bytes.to_vec(),
-        )
-        .await;
-    if let Err(err) = result {
+/// Original, shared by storage and response body, so its bytes are not copied
+struct SharedOriginal(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedOriginal {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+/// Response with `data` body. `HEAD` responses get only its length, so bytes are not copied
+fn response_body(builder: Builder, data: &[u8], head: bool) -> Response<Body> {
+    match head {
+        true => builder
+            .header(header::CONTENT_LENGTH, data.len())
+            .body(Body::empty()),
+        false => builder.body(Body::from(data.to_owned())),
+    }
+    .unwrap()
+}
+
+/// Headers of `/images/{id}` response without body, to check type and size of variant.
+/// Cached variants and stored originals are answered as they are, missing ones are processed
+/// and cached for following `GET`
+#[allow(clippy::too_many_arguments)]
+pub async fn serve_file_head(
+    path: Path<String>,
+    query: Result<Query<ProcessingParams>, QueryRejection>,
+    raw_query: RawQuery,
+    responsive: Query<ResponsiveParams>,
+    privileged: Query<PrivilegedParams>,
+    response_params: Result<Query<ResponseParams>, QueryRejection>,
+    headers: HeaderMap,
+    state: State<Arc<Config>>,
+) -> Result<ImageResponse, ApiError<GetImageErrorType>> {
+    image_response(
+        path,
+        query,
+        raw_query,
+        responsive,
+        privileged,
+        response_params,
+        headers,
+        state,
+        true,
+    )
+    .await
+}
+
+fn get_image_error(err: ProcessingError) -> ApiError<GetImageErrorType> {
+    let status = processing_error_status(&err.err_type);
+    let error_type = match err.err_type {
+        ProcessingErrorType::UnsupportingExtension => GetImageErrorType::UnsupportingExtension,
+        ProcessingErrorType::NotFound => GetImageErrorType::NotFound,
+        ProcessingErrorType::FileApiError(_) => GetImageErrorType::FileApiError,
+        ProcessingErrorType::ProcessedImagesLimit => GetImageErrorType::ProcessedImagesLimit,
+        ProcessingErrorType::UnavailableExtension => GetImageErrorType::UnavailableExtension,
+        ProcessingErrorType::InvalidSize => GetImageErrorType::InvalidSize,
+        ProcessingErrorType::FrameOutOfRange => GetImageErrorType::InvalidParams,
+        ProcessingErrorType::EncodingFailed => GetImageErrorType::EncodingFailed,
+    };
+    responses::api_error(status, err.detail, Some(error_type))
+}
+
+/// Served image with its metadata, for API tools, which can't handle binary responses
+#[derive(Serialize, JsonSchema)]
+pub struct ImageJson {
+    pub extension: Extensions,
+    pub content_type: String,
+    /// Size of encoded image
+    pub bytes: usize,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Base64 of encoded image
+    pub data: String,
+}
+
+impl ImageJson {
+    fn new(img: &ImageContainer) -> Self {
+        let dimensions = img
+            .dimensions
+            .or_else(|| operations::image_dimensions(img.data.as_slice()));
+        ImageJson {
+            extension: img.extension,
+            content_type: img.extension.mime_type().to_string(),
+            bytes: img.data.len(),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            data: BASE64_STANDARD.encode(img.data.as_slice()),
+        }
+    }
+}
+
+/// Whether client asks for json instead of image: `application/json` is accepted and image types are not
+fn wants_json(headers: &HeaderMap) -> bool {
+    accepts_mime_type(headers, "application/json")
+        && !headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| {
+                accept
+                    .split(',')
+                    .any(|item| item.trim().to_lowercase().starts_with("image/"))
+            })
+}
+
+/// Summary of processing result, to explain output without decoding it
+#[derive(Serialize, JsonSchema)]
+pub struct ImageDebugInfo {
+    /// Dimensions of original (after EXIF orientation), not set for fallback image
+    pub source_width: Option<u32>,
+    pub source_height: Option<u32>,
+    /// Dimensions of result, for AVIF results it's target size, as they can't be decoded
+    pub width: u32,
+    pub height: u32,
+    pub extension: Extensions,
+    /// Size of encoded image
+    pub bytes: usize,
+    /// Source was cropped to requested ratio (`CropToCenter` ratio policy)
+    pub cropped: bool,
+    /// Source was padded to requested ratio (`Pad` ratio policy)
+    pub padded: bool,
+    /// Image was taken from processed cache
+    pub cache_hit: bool,
+    pub is_fallback: bool,
+    /// Floor, requested quality was raised to
+    pub quality_clamped: Option<u32>,
+    /// Params after normalization, extension negotiation and snapping to allowed sizes
+    pub params: ProcessingParams,
+}
+
+/// Describe result of processing image with params, as `/images/{id}` would serve it
+pub async fn debug_image(
+    Path(image_id): Path<String>,
+    query: Result<Query<ProcessingParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    Query(responsive): Query<ResponsiveParams>,
+    headers: HeaderMap,
+    State(state): State<Arc<Config>>,
+) -> Result<Json<ImageDebugInfo>, ApiError<GetImageErrorType>> {
+    let mut query = query.map_err(|rejection| {
+        responses::invalid_params(
+            vec![query_rejection_field(&rejection, raw_query.as_deref())],
+            Some(GetImageErrorType::InvalidParams),
+        )
+    })?;
+    if let Err(err) = apply_responsive_params(&mut query.0, &responsive, &state) {
+        return Err(responses::invalid_params(
+            vec![err],
+            Some(GetImageErrorType::InvalidParams),
+        ));
+    }
+    if let Err(errors) = prepare_processing_params(&mut query.0, &state) {
+        return Err(responses::invalid_params(
+            errors,
+            Some(GetImageErrorType::InvalidParams),
+        ));
+    }
+    negotiate_extension(&mut query.0, &headers, &state);
+    let quality_clamped = state.clamp_quality(&mut query.0);
+    let params = query.0;
+
+    let image_id = state.normalize_image_id(image_id);
+    if !state.is_allowed_image_id(&image_id) {
+        return Err(responses::api_error(
+            StatusCode::NOT_FOUND,
+            ProcessingErrorType::NotFound.default_detail(),
+            Some(GetImageErrorType::NotFound),
+        ));
+    }
+    let fetch_options = FetchOptions {
+        timeout: None,
+        request_id: headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        validators: None,
+    };
+    let served = state
+        .processor
+        .get(
+            image_id.clone(),
+            params.clone(),
+            fetch_options.clone(),
+            false,
+        )
+        .await
+        .map_err(get_image_error)?;
+    let source = match served.is_fallback {
+        true => None,
+        false => Some(
+            state
+                .processor
+                .original_dimensions(&image_id, &params, fetch_options)
+                .await
+                .map_err(get_image_error)?,
+        ),
+    };
+
+    let img = &served.image;
+    // resizing always results in target size, it's used for formats without decoder (AVIF)
+    let (width, height) = img
+        .dimensions
+        .or_else(|| operations::image_dimensions(img.data.as_slice()))
+        .or_else(|| source.map(|source| state.processor.target_size(source, &params)))
+        .unwrap_or_default();
+    // output ratio differs from source one only on cropping or padding
+    let ratio_changed = source.is_some_and(|source| {
+        operations::resize_distortion(source, Some(width), Some(height)) > 1.0
+    });
+    let ratio_policy = params.ratio_policy.clone().unwrap_or_default();
+    Ok(Json(ImageDebugInfo {
+        source_width: source.map(|(w, _)| w),
+        source_height: source.map(|(_, h)| h),
+        width,
+        height,
+        extension: img.extension,
+        bytes: img.data.len(),
+        cropped: ratio_changed && ratio_policy == RatioPolicy::CropToCenter,
+        padded: ratio_changed && ratio_policy == RatioPolicy::Pad,
+        cache_hit: served.cache_status != CacheStatus::Miss,
+        is_fallback: served.is_fallback,
+        quality_clamped,
+        params,
+    }))
+}
+
+/// Pre fetch image into cache to prevent fetching on client image request
+#[axum::debug_handler]
+pub async fn preload_image(
+    Path(image_id): Path<String>,
+    Query(params): Query<PreloadParams>,
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<PreloadImageErrorResponse>, ApiError<PreloadImageErrorType>> {
+    let image_id = state.normalize_image_id(image_id);
+    info!("Preloading img {}", image_id);
+
+    // Check API key without holding a lock
+    if !is_authorized(&headers, &state.api_key) {
+        return Err(responses::api_error(
+            StatusCode::UNAUTHORIZED,
+            "Mismatched api key".to_string(),
+            Some(PreloadImageErrorType::Unauthorized),
+        ));
+    }
+    if !state.is_allowed_image_id(&image_id) {
+        return Err(responses::api_error(
+            StatusCode::FORBIDDEN,
+            "Image id is not allowed".to_string(),
+            Some(PreloadImageErrorType::ForbiddenId),
+        ));
+    }
+    let _permit = state.preload_limiter.try_acquire().map_err(|_| {
+        responses::api_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many preloads in flight, retry later".to_string(),
+            Some(PreloadImageErrorType::TooManyRequests),
+        )
+        .with_retry_after(1)
+    })?;
+
+    let cache_ttl = match headers.get(CACHE_TTL_HEADER) {
+        None => None,
+        Some(value) => match value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+        {
+            Some(ttl) => Some(ttl),
+            None => {
+                return Err(responses::api_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("{} should be count of seconds", CACHE_TTL_HEADER),
+                    Some(PreloadImageErrorType::InvalidCacheTtl),
+                ));
+            }
+        },
+    };
+
+    // Prefetch without holding a lock on the entire config
+    let body_bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            return Err(responses::api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid body: {}", err),
+                Some(PreloadImageErrorType::InvalidBody),
+            ));
+        }
+    };
+
+    let data = match decode_preload_body(&body_bytes, &headers, params.encoding) {
+        Ok(data) => data,
+        Err(err) => {
+            return Err(responses::api_error(
+                StatusCode::BAD_REQUEST,
+                err,
+                Some(PreloadImageErrorType::InvalidBody),
+            ));
+        }
+    };
+
+    if let Err(reset_in) = state.preload_limiter.consume(data.len() as u64) {
+        return Err(responses::api_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Preload ingestion rate is exceeded, retry later".to_string(),
+            Some(PreloadImageErrorType::TooManyRequests),
+        )
+        .with_retry_after(reset_in.as_secs().max(1)));
+    }
+
+    let result = state
+        .processor
+        .prefetch(
+            image_id.clone(),
+            FileNameExtractor::extract(&headers).unwrap_or(image_id.to_string()),
+            data,
+            cache_ttl,
+        )
+        .await;
+    if let Err(err) = result {
         let error_type = match err.err_type {
             ProcessingErrorType::UnsupportingExtension => {
                 PreloadImageErrorType::UnsupportingExtension
@@ -203,38 +1427,2453 @@ pub async fn preload_image(
     ))
 }
 
-pub fn serve_file_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
-    op.description("Serve image by id with optional processing parameters.")
-        .input::<ImageIdParam>()
-        .response_with::<200, ImageResponse, _>(|res: TransformResponse<'_, ()>| {
-            res.description("Binary image response.")
-        })
-        .response_with::<400, Json<GetImageErrorResponse>, _>(
-            |res: TransformResponse<'_, GetImageErrorResponse>| {
-                res.description("Invalid request or processing error.")
+/// Purge images from storage and processed cache, when they are changed on origin
+pub async fn invalidate_images(
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+    Json(request): Json<InvalidateRequest>,
+) -> Result<Json<InvalidateResponse>, ApiError<InvalidateImagesErrorType>> {
+    if !is_authorized(&headers, &state.api_key) {
+        return Err(responses::api_error(
+            StatusCode::UNAUTHORIZED,
+            "Mismatched api key".to_string(),
+            Some(InvalidateImagesErrorType::Unauthorized),
+        ));
+    }
+
+    let mut purged = BTreeMap::new();
+    for image_id in request.image_ids {
+        let image_id = state.normalize_image_id(image_id);
+        let removed = state.processor.invalidate(image_id.clone()).await;
+        debug!("Invalidated img {}, removed {} versions", image_id, removed);
+        purged.insert(image_id, removed);
+    }
+    info!("Invalidated {} images", purged.len());
+
+    Ok(Json(InvalidateResponse { purged }))
+}
+
+/// Change notification of origin: purge changed images and process their common variants again in
+/// background, so origin pushes freshness instead of relying on cache ttls
+pub async fn origin_updated(
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<Json<OriginUpdatedResponse>, ApiError<OriginUpdatedErrorType>> {
+    if !is_authorized(&headers, &state.api_key) {
+        return Err(responses::api_error(
+            StatusCode::UNAUTHORIZED,
+            "Mismatched api key".to_string(),
+            Some(OriginUpdatedErrorType::Unauthorized),
+        ));
+    }
+
+    let image_ids = match payload.pointer(&state.origin_webhook_ids_pointer) {
+        Some(serde_json::Value::String(image_id)) => Some(vec![image_id.clone()]),
+        Some(serde_json::Value::Array(values)) => values
+            .iter()
+            .map(|value| value.as_str().map(str::to_string))
+            .collect(),
+        _ => None,
+    };
+    let Some(image_ids) = image_ids else {
+        return Err(responses::invalid_params(
+            vec![FieldError::new(
+                state.origin_webhook_ids_pointer.as_str(),
+                "should be image id or array of image ids",
+                None::<String>,
+            )],
+            Some(OriginUpdatedErrorType::InvalidParams),
+        ));
+    };
+
+    let mut purged = BTreeMap::new();
+    for image_id in image_ids {
+        let image_id = state.normalize_image_id(image_id);
+        let removed = state.processor.invalidate(image_id.clone()).await;
+        debug!("Invalidated img {}, removed {} versions", image_id, removed);
+        purged.insert(image_id, removed);
+    }
+    info!("Origin updated {} images", purged.len());
+
+    let warmed_ids: Vec<ImageId> = purged
+        .keys()
+        .filter(|image_id| state.is_allowed_image_id(image_id))
+        .cloned()
+        .collect();
+    let warming = warmed_ids.len() * state.origin_webhook_warm_variants.len();
+    if warming > 0 {
+        let state = state.clone();
+        tokio::spawn(async move {
+            for image_id in warmed_ids {
+                for params in state.origin_webhook_warm_variants.iter() {
+                    match warm::warm_variant(&state, image_id.clone(), params.clone()).await {
+                        Ok(cache_status) => {
+                            debug!("Warmed variant of img {} ({})", image_id, cache_status)
+                        }
+                        Err(err) => warn!("Failed to warm variant of img {}: {}", image_id, err),
+                    }
+                }
+            }
+        });
+    }
+
+    Ok(Json(OriginUpdatedResponse { purged, warming }))
+}
+
+/// Purge processed versions of all images by params filter (e.g. after changing encoder settings).
+/// Originals are kept, so versions are reprocessed on next request
+pub async fn purge_variants(
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+    Json(request): Json<PurgeVariantsRequest>,
+) -> Result<Json<PurgeVariantsResponse>, ApiError<PurgeVariantsErrorType>> {
+    if !is_authorized(&headers, &state.api_key) {
+        return Err(responses::api_error(
+            StatusCode::UNAUTHORIZED,
+            "Mismatched api key".to_string(),
+            Some(PurgeVariantsErrorType::Unauthorized),
+        ));
+    }
+    if request.extension.is_none()
+        && request.width_above.is_none()
+        && request.height_above.is_none()
+    {
+        return Err(responses::invalid_params(
+            vec![FieldError::new(
+                "extension",
+                "at least one of extension, width_above or height_above is required",
+                None::<String>,
+            )],
+            Some(PurgeVariantsErrorType::InvalidParams),
+        ));
+    }
+
+    let limit = request
+        .limit
+        .unwrap_or(MAX_PURGED_VARIANTS)
+        .min(MAX_PURGED_VARIANTS);
+    let result = state
+        .processor
+        .purge_variants(
+            |extension, params| {
+                request.extension.is_none_or(|ext| ext == extension)
+                    && request
+                        .width_above
+                        .is_none_or(|above| params.width.is_some_and(|width| width > above))
+                    && request
+                        .height_above
+                        .is_none_or(|above| params.height.is_some_and(|height| height > above))
             },
+            limit,
         )
-        .response_with::<404, Json<GetImageErrorResponse>, _>(
-            |res: TransformResponse<'_, GetImageErrorResponse>| res.description("Image not found."),
-        )
+        .await;
+    info!(
+        "Purged {} processed versions of {} images by filter",
+        result.removed, result.images
+    );
+
+    Ok(Json(PurgeVariantsResponse {
+        purged: result.removed,
+        images: result.images,
+        complete: result.complete,
+    }))
 }
 
-pub fn preload_image_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
-    op.description("Preload image into cache to avoid processing on request.")
-        .input::<(ImageIdParam, ApiKeyHeader, BinaryBody)>()
-        .response_with::<200, Json<PreloadImageErrorResponse>, _>(
-            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
-                res.description("Preload request accepted.")
+/// Keep stored original warm (protect from eviction) without refetching or reprocessing
+pub async fn touch_image(
+    Path(image_id): Path<String>,
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<TouchImageErrorResponse>, ApiError<TouchImageErrorType>> {
+    if !is_authorized(&headers, &state.api_key) {
+        return Err(responses::api_error(
+            StatusCode::UNAUTHORIZED,
+            "Mismatched api key".to_string(),
+            Some(TouchImageErrorType::Unauthorized),
+        ));
+    }
+
+    let image_id = state.normalize_image_id(image_id);
+    if !state.processor.touch(image_id.clone()).await {
+        return Err(responses::api_error(
+            StatusCode::NOT_FOUND,
+            "Image is not stored".to_string(),
+            Some(TouchImageErrorType::NotFound),
+        ));
+    }
+    debug!("Touched img {}", image_id);
+
+    Ok(responses::ok_json::<TouchImageErrorType>("Ok".to_string()))
+}
+
+/// Serving counters of image, to find hot and cold images
+pub async fn image_stats(
+    Path(image_id): Path<String>,
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<ImageStats>, ApiError<ImageStatsErrorType>> {
+    if !is_authorized(&headers, &state.api_key) {
+        return Err(responses::api_error(
+            StatusCode::UNAUTHORIZED,
+            "Mismatched api key".to_string(),
+            Some(ImageStatsErrorType::Unauthorized),
+        ));
+    }
+
+    let image_id = state.normalize_image_id(image_id);
+    match state.processor.stats(&image_id) {
+        Some(stats) => Ok(Json(stats)),
+        None => Err(responses::api_error(
+            StatusCode::NOT_FOUND,
+            "Image was not served since startup".to_string(),
+            Some(ImageStatsErrorType::NotFound),
+        )),
+    }
+}
+
+/// Average and dominant colors of image, for placeholder backgrounds and theming
+pub async fn image_colors(
+    Path(image_id): Path<String>,
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<ImageColors>, ApiError<ImageColorsErrorType>> {
+    let image_id = state.normalize_image_id(image_id);
+    if !state.is_allowed_image_id(&image_id) {
+        return Err(responses::api_error(
+            StatusCode::NOT_FOUND,
+            ProcessingErrorType::NotFound.default_detail(),
+            Some(ImageColorsErrorType::NotFound),
+        ));
+    }
+    let result = state
+        .processor
+        .colors(
+            image_id,
+            FetchOptions {
+                timeout: None,
+                request_id: headers
+                    .get(REQUEST_ID_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string()),
+                validators: None,
             },
         )
-        .response_with::<400, Json<PreloadImageErrorResponse>, _>(
-            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
-                res.description("Invalid image or payload.")
-            },
+        .await;
+
+    match result {
+        Ok(colors) => Ok(Json(colors.as_ref().clone())),
+        Err(err) => {
+            let status = processing_error_status(&err.err_type);
+            let error_type = match err.err_type {
+                ProcessingErrorType::NotFound => ImageColorsErrorType::NotFound,
+                ProcessingErrorType::FileApiError(_) => ImageColorsErrorType::FileApiError,
+                _ => ImageColorsErrorType::UnsupportingExtension,
+            };
+            Err(responses::api_error(status, err.detail, Some(error_type)))
+        }
+    }
+}
+
+/// Body of `multipart/mixed` response with part per image
+fn multipart_body(
+    boundary: &str,
+    parts: &[(ServedImage, Option<u32>)],
+    default_filename: &str,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (served, clamped_quality) in parts {
+        let img = &served.image;
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(
+            format!("Content-Type: {}\r\n", img.extension.mime_type()).as_bytes(),
+        );
+        body.extend_from_slice(b"Content-Disposition: ");
+        body.extend_from_slice(
+            content_disposition_header(
+                Disposition::Inline,
+                img.filename.clone(),
+                default_filename.to_string(),
+                img.extension.name(),
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(b"\r\n");
+        if let Some(quality) = clamped_quality {
+            body.extend_from_slice(
+                format!("{}: {}\r\n", QUALITY_CLAMPED_HEADER, quality).as_bytes(),
+            );
+        }
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(&img.data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+/// Serve image in several formats at once, as `multipart/mixed` response with part per format.
+///
+/// Each format is processed (and cached) as separate request to `/images/{id}`
+pub async fn all_formats(
+    Path(image_id): Path<String>,
+    query: Result<Query<ProcessingParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    Query(formats): Query<AllFormatsParams>,
+    Query(responsive): Query<ResponsiveParams>,
+    headers: HeaderMap,
+    State(state): State<Arc<Config>>,
+) -> Result<ImageResponse, ApiError<AllFormatsErrorType>> {
+    let mut query = query.map_err(|rejection| {
+        responses::invalid_params(
+            vec![query_rejection_field(&rejection, raw_query.as_deref())],
+            Some(AllFormatsErrorType::InvalidParams),
         )
-        .response_with::<401, Json<PreloadImageErrorResponse>, _>(
-            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
-                res.description("Missing or invalid API key.")
-            },
+    })?;
+    let mut errors = Vec::new();
+    let mut extensions = Vec::new();
+    for name in formats.formats.split(',').map(str::trim) {
+        match Extensions::iter().find(|ext| ext.name().eq_ignore_ascii_case(name)) {
+            Some(ext) if !extensions.contains(&ext) => extensions.push(ext),
+            Some(_) => {}
+            None => errors.push(FieldError::new(
+                "formats",
+                "must be list of webp, avif, png",
+                Some(name),
+            )),
+        }
+    }
+    if let Err(err) = apply_responsive_params(&mut query.0, &responsive, &state) {
+        errors.push(err);
+    } else if let Err(params_errors) = prepare_processing_params(&mut query.0, &state) {
+        errors.extend(params_errors);
+    }
+    if !errors.is_empty() {
+        return Err(responses::invalid_params(
+            errors,
+            Some(AllFormatsErrorType::InvalidParams),
+        ));
+    }
+
+    let image_id = state.normalize_image_id(image_id);
+    if !state.is_allowed_image_id(&image_id) {
+        return Err(responses::api_error(
+            StatusCode::NOT_FOUND,
+            ProcessingErrorType::NotFound.default_detail(),
+            Some(AllFormatsErrorType::NotFound),
+        ));
+    }
+    info!("Getting img {} in formats {:?}", image_id, extensions);
+
+    let fetch_options = FetchOptions {
+        timeout: None,
+        request_id: headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        validators: None,
+    };
+    let parts: Vec<(ServedImage, Option<u32>)> = stream::iter(extensions)
+        .map(|extension| {
+            let mut params = ProcessingParams {
+                extension: Some(extension.into()),
+                ..query.0.clone()
+            };
+            let clamped_quality = state.clamp_quality(&mut params);
+            let state = state.clone();
+            let image_id = image_id.clone();
+            let fetch_options = fetch_options.clone();
+            async move {
+                if state.processor.determine_extension(&params) != extension {
+                    return Err(responses::api_error(
+                        StatusCode::BAD_REQUEST,
+                        "Custom extensions are not allowed".to_string(),
+                        Some(AllFormatsErrorType::UnavailableExtension),
+                    ));
+                }
+                match state
+                    .processor
+                    .get(image_id, params, fetch_options, false)
+                    .await
+                {
+                    // parts of fallback image would be indistinguishable from requested one
+                    Ok(served) if served.is_fallback => Err(responses::api_error(
+                        StatusCode::NOT_FOUND,
+                        ProcessingErrorType::NotFound.default_detail(),
+                        Some(AllFormatsErrorType::NotFound),
+                    )),
+                    Ok(served) => Ok((served, clamped_quality)),
+                    Err(err) => {
+                        let status = processing_error_status(&err.err_type);
+                        let error_type = match err.err_type {
+                            ProcessingErrorType::UnsupportingExtension => {
+                                AllFormatsErrorType::UnsupportingExtension
+                            }
+                            ProcessingErrorType::NotFound => AllFormatsErrorType::NotFound,
+                            ProcessingErrorType::FileApiError(_) => {
+                                AllFormatsErrorType::FileApiError
+                            }
+                            ProcessingErrorType::ProcessedImagesLimit => {
+                                AllFormatsErrorType::ProcessedImagesLimit
+                            }
+                            ProcessingErrorType::UnavailableExtension => {
+                                AllFormatsErrorType::UnavailableExtension
+                            }
+                            ProcessingErrorType::InvalidSize => AllFormatsErrorType::InvalidSize,
+                            ProcessingErrorType::FrameOutOfRange => {
+                                AllFormatsErrorType::InvalidParams
+                            }
+                            ProcessingErrorType::EncodingFailed => {
+                                AllFormatsErrorType::EncodingFailed
+                            }
+                        };
+                        Err(responses::api_error(status, err.detail, Some(error_type)))
+                    }
+                }
+            }
+        })
+        .buffered(ALL_FORMATS_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let boundary = format!("imgr-{:016x}", fastrand::u64(..));
+    let cache_ttl = parts
+        .first()
+        .and_then(|(served, _)| served.cache_ttl)
+        .map_or(state.client_cache_ttl, |ttl| ttl as usize);
+    Ok(ImageResponse(
+        caching_headers(Response::builder(), cache_ttl)
+            .status(StatusCode::OK)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/mixed; boundary={}", boundary),
+            )
+            .body(Body::from(multipart_body(
+                &boundary,
+                &parts,
+                &default_filename(&state.default_filename_pattern, &image_id),
+            )))
+            .unwrap(),
+    ))
+}
+
+/// Grid of several images in a single image, for previews and emails
+pub async fn montage(
+    Query(params): Query<MontageParams>,
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<ImageResponse, ApiError<MontageErrorType>> {
+    let invalid = |detail: String| {
+        responses::api_error(
+            StatusCode::BAD_REQUEST,
+            detail,
+            Some(MontageErrorType::InvalidParams),
         )
+    };
+    let image_ids: Vec<ImageId> = params
+        .ids
+        .split(',')
+        .map(|id| state.normalize_image_id(id.to_string()))
+        .filter(|id| !id.is_empty())
+        .collect();
+    if image_ids.is_empty() || image_ids.len() > MAX_MONTAGE_IMAGES {
+        return Err(invalid(format!(
+            "Montage requires from 1 to {} image ids",
+            MAX_MONTAGE_IMAGES
+        )));
+    }
+    if let Some(id) = image_ids.iter().find(|id| !state.is_allowed_image_id(id)) {
+        return Err(responses::api_error(
+            StatusCode::NOT_FOUND,
+            format!("{}: {}", id, ProcessingErrorType::NotFound.default_detail()),
+            Some(MontageErrorType::NotFound),
+        ));
+    }
+    // cell is a width of each image, so it follows allowed widths like resizing
+    let mut cell_params = ProcessingParams {
+        width: Some(params.cell.unwrap_or(DEFAULT_MONTAGE_CELL)),
+        ..Default::default()
+    };
+    let invalid_size = |detail: String| {
+        responses::api_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            detail,
+            Some(MontageErrorType::InvalidParams),
+        )
+    };
+    if state.preset_sizes_only
+        && let Some(cell) = params.cell
+        && !state.size_presets.has_width(cell)
+    {
+        return Err(invalid_size(format!(
+            "Cell {} is not allowed, only width of SIZE_PRESETS is",
+            cell
+        )));
+    }
+    if let Err(err) = restrict_allowed_sizes(&mut cell_params, &state) {
+        return Err(invalid_size(format!("Cell {}", err)));
+    }
+    let cell = cell_params.width.unwrap_or(DEFAULT_MONTAGE_CELL);
+    if cell == 0
+        || !state
+            .max_image_resize
+            .is_allowed_size(&Some(cell), &Some(cell))
+    {
+        return Err(invalid_size(format!("Cell {} is not allowed", cell)));
+    }
+    let cols = params
+        .cols
+        .unwrap_or(DEFAULT_MONTAGE_COLS)
+        .clamp(1, image_ids.len() as u32);
+    let rows = (image_ids.len() as u32).div_ceil(cols);
+    let size = cols.checked_mul(cell).zip(rows.checked_mul(cell));
+    if !size.is_some_and(|(width, height)| {
+        state
+            .max_image_resize
+            .is_allowed_size(&Some(width), &Some(height))
+    }) {
+        return Err(invalid_size(format!(
+            "Montage of {}x{} cells of {} is not allowed",
+            cols, rows, cell
+        )));
+    }
+    if let Some(quality) = params.quality
+        && !(1..=100).contains(&quality)
+    {
+        return Err(invalid("Quality must be between 1 and 100".to_string()));
+    }
+    let mut processing_params = ProcessingParams {
+        extension: params.extension.map(Into::into),
+        quality: params.quality,
+        auto_orient: Some(state.auto_orient),
+        ..Default::default()
+    };
+    let clamped_quality = state.clamp_quality(&mut processing_params);
+
+    let result = state
+        .processor
+        .montage(
+            image_ids,
+            cols,
+            cell,
+            processing_params,
+            FetchOptions {
+                timeout: None,
+                request_id: headers
+                    .get(REQUEST_ID_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string()),
+                validators: None,
+            },
+        )
+        .await;
+
+    match result {
+        Ok(img) => Ok(ImageResponse(
+            quality_clamped_header(
+                caching_headers(Response::builder(), state.client_cache_ttl),
+                clamped_quality,
+            )
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, img.extension.mime_type())
+            .header(
+                header::CONTENT_DISPOSITION,
+                content_disposition_header(
+                    Disposition::Inline,
+                    None,
+                    "montage".to_string(),
+                    img.extension.name(),
+                ),
+            )
+            .body(Body::from(*img.data))
+            .unwrap(),
+        )),
+        Err(err) => {
+            let status = processing_error_status(&err.err_type);
+            let error_type = match err.err_type {
+                ProcessingErrorType::NotFound => MontageErrorType::NotFound,
+                ProcessingErrorType::FileApiError(_) => MontageErrorType::FileApiError,
+                ProcessingErrorType::UnavailableExtension => MontageErrorType::UnavailableExtension,
+                ProcessingErrorType::InvalidSize => MontageErrorType::InvalidSize,
+                ProcessingErrorType::EncodingFailed => MontageErrorType::EncodingFailed,
+                _ => MontageErrorType::UnsupportingExtension,
+            };
+            Err(responses::api_error(status, err.detail, Some(error_type)))
+        }
+    }
+}
+
+/// Process image from request body and return result directly, without storage and caches
+pub async fn transform(
+    query: Result<Query<ProcessingParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    Query(responsive): Query<ResponsiveParams>,
+    State(state): State<Arc<Config>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<ImageResponse, ApiError<TransformErrorType>> {
+    if !is_authorized(&headers, &state.api_key) {
+        return Err(responses::api_error(
+            StatusCode::UNAUTHORIZED,
+            "Mismatched api key".to_string(),
+            Some(TransformErrorType::Unauthorized),
+        ));
+    }
+    let mut query = query.map_err(|rejection| {
+        responses::invalid_params(
+            vec![query_rejection_field(&rejection, raw_query.as_deref())],
+            Some(TransformErrorType::InvalidParams),
+        )
+    })?;
+    // body is processed by the same size rules, as stored images
+    if let Err(err) = apply_responsive_params(&mut query.0, &responsive, &state) {
+        return Err(responses::invalid_params(
+            vec![err],
+            Some(TransformErrorType::InvalidParams),
+        ));
+    }
+    if let Err(errors) = prepare_processing_params(&mut query.0, &state) {
+        return Err(responses::invalid_params(
+            errors,
+            Some(TransformErrorType::InvalidParams),
+        ));
+    }
+    negotiate_extension(&mut query.0, &headers, &state);
+    let clamped_quality = state.clamp_quality(&mut query.0);
+
+    let data = read_transform_body(body, state.max_transform_body_bytes).await?;
+
+    match state.processor.transform(Arc::new(data), query.0).await {
+        Ok(img) => Ok(ImageResponse(
+            digest_header(
+                quality_clamped_header(Response::builder(), clamped_quality),
+                &img,
+                state.response_digest,
+            )
+            .status(StatusCode::OK)
+            .header(header::CACHE_CONTROL, "no-store")
+            .header(header::CONTENT_TYPE, img.extension.mime_type())
+            .header(
+                header::CONTENT_DISPOSITION,
+                content_disposition_header(
+                    Disposition::Inline,
+                    FileNameExtractor::extract(&headers),
+                    default_filename(&state.default_filename_pattern, "transformed"),
+                    img.extension.name(),
+                ),
+            )
+            .body(Body::from(img.data.as_slice().to_owned()))
+            .unwrap(),
+        )),
+        Err(err) => {
+            let error_type = match err.err_type {
+                ProcessingErrorType::InvalidSize => TransformErrorType::InvalidSize,
+                ProcessingErrorType::UnavailableExtension => {
+                    TransformErrorType::UnavailableExtension
+                }
+                ProcessingErrorType::FrameOutOfRange => TransformErrorType::InvalidParams,
+                ProcessingErrorType::EncodingFailed => TransformErrorType::EncodingFailed,
+                _ => TransformErrorType::UnsupportingExtension,
+            };
+            Err(responses::api_error(
+                processing_error_status(&err.err_type),
+                err.detail,
+                Some(error_type),
+            ))
+        }
+    }
+}
+
+/// Read image body of `/transform` by chunks, aborting as soon as it exceeds `max_bytes`
+async fn read_transform_body(
+    body: Body,
+    max_bytes: Option<usize>,
+) -> Result<Vec<u8>, ApiError<TransformErrorType>> {
+    let mut data = Vec::new();
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|err| {
+            responses::api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid body: {}", err),
+                Some(TransformErrorType::InvalidBody),
+            )
+        })?;
+        if max_bytes.is_some_and(|max| data.len() + chunk.len() > max) {
+            return Err(responses::api_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Body exceeds {} bytes", max_bytes.unwrap()),
+                Some(TransformErrorType::BodyTooLarge),
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    if data.is_empty() {
+        return Err(responses::api_error(
+            StatusCode::BAD_REQUEST,
+            "Body is empty".to_string(),
+            Some(TransformErrorType::InvalidBody),
+        ));
+    }
+    Ok(data)
+}
+
+pub fn serve_file_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Serve image by id with optional processing parameters.")
+        .input::<ImageIdParam>()
+        .response_with::<200, ImageOrJsonResponse<ImageJson>, _>(
+            |res: TransformResponse<'_, ()>| {
+                res.description(
+                    "Binary image response. With `Accept: application/json` (and debug endpoints enabled) \
+                     image is returned as base64 with its metadata.",
+                )
+            },
+        )
+        .response_with::<400, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description("Invalid request or processing error.")
+            },
+        )
+        .response_with::<422, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description("Invalid processing params, listed in `fields`.")
+            },
+        )
+        .response_with::<401, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description("Privileged params (fetch_timeout, fresh) without valid api key.")
+            },
+        )
+        .response_with::<404, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description("Image not found (or image id is not allowed).")
+            },
+        )
+        .response_with::<413, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description("Original in file api exceeds `MAX_ORIGIN_BYTES`.")
+            },
+        )
+        .response_with::<503, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description("Encoder failed or exceeded its `ENCODE_TIMEOUTS` deadline.")
+            },
+        )
+        .response_with::<502, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description(
+                    "File api is unreachable or failed (dns, connect, tls, server error, empty or corrupted image).",
+                )
+            },
+        )
+        .response_with::<504, Json<GetImageErrorResponse>, _>(
+            |res: TransformResponse<'_, GetImageErrorResponse>| {
+                res.description("File api timed out.")
+            },
+        )
+}
+
+pub fn debug_image_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description(
+        "Describe result of `/images/{id}` with the same params: dimensions, extension, size, cropping and \
+        effective params. Enabled by `ENABLE_DEBUG_ENDPOINTS`.",
+    )
+    .input::<ImageIdParam>()
+    .response_with::<200, Json<ImageDebugInfo>, _>(|res: TransformResponse<'_, ImageDebugInfo>| {
+        res.description("Summary of processed image.")
+    })
+    .response_with::<400, Json<GetImageErrorResponse>, _>(
+        |res: TransformResponse<'_, GetImageErrorResponse>| {
+            res.description("Invalid request or processing error.")
+        },
+    )
+    .response_with::<422, Json<GetImageErrorResponse>, _>(
+        |res: TransformResponse<'_, GetImageErrorResponse>| {
+            res.description("Invalid processing params, listed in `fields`.")
+        },
+    )
+    .response_with::<404, Json<GetImageErrorResponse>, _>(
+        |res: TransformResponse<'_, GetImageErrorResponse>| {
+            res.description("Image not found (or image id is not allowed).")
+        },
+    )
+    .response_with::<502, Json<GetImageErrorResponse>, _>(
+        |res: TransformResponse<'_, GetImageErrorResponse>| {
+            res.description("File api is unreachable or failed.")
+        },
+    )
+    .response_with::<504, Json<GetImageErrorResponse>, _>(
+        |res: TransformResponse<'_, GetImageErrorResponse>| res.description("File api timed out."),
+    )
+}
+
+pub fn serve_file_head_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description(
+        "Headers of `GET /images/{id}` response (type, length, caching) without body. Cached variants are not \
+        re-encoded.",
+    )
+    .input::<ImageIdParam>()
+    .response_with::<200, (), _>(|res: TransformResponse<'_, ()>| {
+        res.description("Image headers without body.")
+    })
+    .response_with::<404, (), _>(|res: TransformResponse<'_, ()>| {
+        res.description("Image not found (or image id is not allowed).")
+    })
+}
+
+pub fn preload_image_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Preload image into cache to avoid processing on request.")
+        .input::<(ImageIdParam, ApiKeyHeader, BinaryBody)>()
+        .response_with::<200, Json<PreloadImageErrorResponse>, _>(
+            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
+                res.description("Preload request accepted.")
+            },
+        )
+        .response_with::<400, Json<PreloadImageErrorResponse>, _>(
+            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
+                res.description("Invalid image or payload.")
+            },
+        )
+        .response_with::<401, Json<PreloadImageErrorResponse>, _>(
+            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
+                res.description("Missing or invalid API key.")
+            },
+        )
+        .response_with::<403, Json<PreloadImageErrorResponse>, _>(
+            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
+                res.description("Image id doesn't match allowed patterns.")
+            },
+        )
+        .response_with::<429, Json<PreloadImageErrorResponse>, _>(
+            |res: TransformResponse<'_, PreloadImageErrorResponse>| {
+                res.description(
+                    "Preload concurrency or ingestion rate limit is exceeded, see `Retry-After`.",
+                )
+            },
+        )
+}
+
+pub fn invalidate_images_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Purge images from storage and processed cache.")
+        .input::<ApiKeyHeader>()
+        .response_with::<200, Json<InvalidateResponse>, _>(
+            |res: TransformResponse<'_, InvalidateResponse>| {
+                res.description("Count of purged processed versions per image id.")
+            },
+        )
+        .response_with::<401, Json<InvalidateImagesErrorResponse>, _>(
+            |res: TransformResponse<'_, InvalidateImagesErrorResponse>| {
+                res.description("Missing or invalid API key.")
+            },
+        )
+}
+
+pub fn origin_updated_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description(
+        "Change notification of origin: purge changed images (found by `ORIGIN_WEBHOOK_IDS_POINTER` in \
+         payload) and process `ORIGIN_WEBHOOK_WARM_VARIANTS` of them again in background.",
+    )
+    .input::<ApiKeyHeader>()
+    .response_with::<200, Json<OriginUpdatedResponse>, _>(
+        |res: TransformResponse<'_, OriginUpdatedResponse>| {
+            res.description("Count of purged versions per image id and count of warmed variants.")
+        },
+    )
+    .response_with::<401, Json<OriginUpdatedErrorResponse>, _>(
+        |res: TransformResponse<'_, OriginUpdatedErrorResponse>| {
+            res.description("Missing or invalid API key.")
+        },
+    )
+    .response_with::<422, Json<OriginUpdatedErrorResponse>, _>(
+        |res: TransformResponse<'_, OriginUpdatedErrorResponse>| {
+            res.description("Payload has no image ids at `ORIGIN_WEBHOOK_IDS_POINTER`.")
+        },
+    )
+}
+
+pub fn purge_variants_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description(
+        "Purge processed versions of all images, matching params filter (like all Avif ones). \
+         Originals are kept.",
+    )
+    .input::<ApiKeyHeader>()
+    .response_with::<200, Json<PurgeVariantsResponse>, _>(
+        |res: TransformResponse<'_, PurgeVariantsResponse>| {
+            res.description("Count of purged versions and whether all matching ones are purged.")
+        },
+    )
+    .response_with::<401, Json<PurgeVariantsErrorResponse>, _>(
+        |res: TransformResponse<'_, PurgeVariantsErrorResponse>| {
+            res.description("Missing or invalid API key.")
+        },
+    )
+    .response_with::<422, Json<PurgeVariantsErrorResponse>, _>(
+        |res: TransformResponse<'_, PurgeVariantsErrorResponse>| {
+            res.description("Filter has no conditions.")
+        },
+    )
+}
+
+pub fn touch_image_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Mark stored original as recently used to protect it from eviction.")
+        .input::<(ImageIdParam, ApiKeyHeader)>()
+        .response_with::<200, Json<TouchImageErrorResponse>, _>(
+            |res: TransformResponse<'_, TouchImageErrorResponse>| {
+                res.description("Original is stored and touched.")
+            },
+        )
+        .response_with::<401, Json<TouchImageErrorResponse>, _>(
+            |res: TransformResponse<'_, TouchImageErrorResponse>| {
+                res.description("Missing or invalid API key.")
+            },
+        )
+        .response_with::<404, Json<TouchImageErrorResponse>, _>(
+            |res: TransformResponse<'_, TouchImageErrorResponse>| {
+                res.description("Original is not stored.")
+            },
+        )
+}
+
+pub fn image_stats_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Serving counters of image per variant since startup.")
+        .input::<(ImageIdParam, ApiKeyHeader)>()
+        .response_with::<200, Json<ImageStats>, _>(|res: TransformResponse<'_, ImageStats>| {
+            res.description("Serving counters of image.")
+        })
+        .response_with::<401, Json<ImageStatsErrorResponse>, _>(
+            |res: TransformResponse<'_, ImageStatsErrorResponse>| {
+                res.description("Missing or invalid API key.")
+            },
+        )
+        .response_with::<404, Json<ImageStatsErrorResponse>, _>(
+            |res: TransformResponse<'_, ImageStatsErrorResponse>| {
+                res.description("Image was not served since startup.")
+            },
+        )
+}
+
+pub fn image_colors_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Average and dominant colors of original image.")
+        .input::<ImageIdParam>()
+        .response_with::<200, Json<ImageColors>, _>(|res: TransformResponse<'_, ImageColors>| {
+            res.description("Average color and palette, most common color first.")
+        })
+        .response_with::<400, Json<ImageColorsErrorResponse>, _>(
+            |res: TransformResponse<'_, ImageColorsErrorResponse>| {
+                res.description("Original is not an image or file api failed.")
+            },
+        )
+        .response_with::<404, Json<ImageColorsErrorResponse>, _>(
+            |res: TransformResponse<'_, ImageColorsErrorResponse>| {
+                res.description("Image not found.")
+            },
+        )
+        .response_with::<502, Json<ImageColorsErrorResponse>, _>(
+            |res: TransformResponse<'_, ImageColorsErrorResponse>| {
+                res.description("File api is unreachable or failed.")
+            },
+        )
+        .response_with::<504, Json<ImageColorsErrorResponse>, _>(
+            |res: TransformResponse<'_, ImageColorsErrorResponse>| {
+                res.description("File api timed out.")
+            },
+        )
+}
+
+pub fn montage_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Grid of several images, cropped to square cells, in a single image.")
+        .response_with::<200, ImageResponse, _>(|res: TransformResponse<'_, ()>| {
+            res.description("Binary image response.")
+        })
+        .response_with::<400, Json<MontageErrorResponse>, _>(
+            |res: TransformResponse<'_, MontageErrorResponse>| {
+                res.description("Invalid params, too many images or too large montage.")
+            },
+        )
+        .response_with::<404, Json<MontageErrorResponse>, _>(
+            |res: TransformResponse<'_, MontageErrorResponse>| {
+                res.description("One of images not found.")
+            },
+        )
+        .response_with::<502, Json<MontageErrorResponse>, _>(
+            |res: TransformResponse<'_, MontageErrorResponse>| {
+                res.description("File api is unreachable or failed.")
+            },
+        )
+        .response_with::<504, Json<MontageErrorResponse>, _>(
+            |res: TransformResponse<'_, MontageErrorResponse>| {
+                res.description("File api timed out.")
+            },
+        )
+}
+
+pub fn transform_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description(
+        "Process image from body with the same params, as on serving, without storing and caching.",
+    )
+    .input::<(ApiKeyHeader, BinaryBody)>()
+    .response_with::<200, ImageResponse, _>(|res: TransformResponse<'_, ()>| {
+        res.description("Binary image response.")
+    })
+    .response_with::<400, Json<TransformErrorResponse>, _>(
+        |res: TransformResponse<'_, TransformErrorResponse>| {
+            res.description("Invalid body or not an image.")
+        },
+    )
+    .response_with::<422, Json<TransformErrorResponse>, _>(
+        |res: TransformResponse<'_, TransformErrorResponse>| {
+            res.description("Invalid processing params, listed in `fields`.")
+        },
+    )
+    .response_with::<401, Json<TransformErrorResponse>, _>(
+        |res: TransformResponse<'_, TransformErrorResponse>| {
+            res.description("Missing or invalid API key.")
+        },
+    )
+    .response_with::<413, Json<TransformErrorResponse>, _>(
+        |res: TransformResponse<'_, TransformErrorResponse>| {
+            res.description("Body exceeds `MAX_TRANSFORM_BODY_BYTES`.")
+        },
+    )
+}
+
+pub fn all_formats_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description(
+        "Serve image in several formats at once (`multipart/mixed`, part per format), with the same processing params, as on serving.",
+    )
+    .input::<ImageIdParam>()
+    .response_with::<200, ImageResponse, _>(|res: TransformResponse<'_, ()>| {
+        res.description("Multipart response with part per requested format.")
+    })
+    .response_with::<400, Json<AllFormatsErrorResponse>, _>(
+        |res: TransformResponse<'_, AllFormatsErrorResponse>| {
+            res.description("Processing error.")
+        },
+    )
+    .response_with::<422, Json<AllFormatsErrorResponse>, _>(
+        |res: TransformResponse<'_, AllFormatsErrorResponse>| {
+            res.description("Invalid formats or processing params, listed in `fields`.")
+        },
+    )
+    .response_with::<404, Json<AllFormatsErrorResponse>, _>(
+        |res: TransformResponse<'_, AllFormatsErrorResponse>| {
+            res.description("Image is not found.")
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD;
+    use image::{DynamicImage, GenericImageView, ImageFormat, Rgba, RgbaImage};
+    use reqwest::Method;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn restricted_options_limit_is_reported() {
+        let config = testing::config(&[
+            ("MAX_OPTIONS_PER_IMAGE", "1"),
+            ("MAX_OPTIONS_PER_IMAGE_OVERFLOW_POLICY", "Restrict"),
+        ]);
+        testing::preload(&config, "restricted", testing::png(8, 8)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!("{}/images/restricted?width=4", base)).await;
+        assert_eq!(response.status(), 200);
+        // already cached variant is still served
+        let response = testing::get(format!("{}/images/restricted?width=4", base)).await;
+        assert_eq!(response.status(), 200);
+
+        let response = testing::get(format!("{}/images/restricted?width=5", base)).await;
+        assert_eq!(response.status(), 400);
+        let body = testing::json(response).await;
+        assert_eq!(body["error_type"], "processed_images_limit");
+    }
+
+    #[tokio::test]
+    async fn image_id_patterns_are_enforced() {
+        let (origin, requests) = testing::counting_origin(testing::png(8, 8), Duration::ZERO).await;
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("ALLOWED_IMAGE_IDS", "avatar_*,*.png"),
+            ("DENIED_IMAGE_IDS", "avatar_secret"),
+        ]))
+        .await;
+
+        for (image_id, status) in [
+            ("avatar_1", 200),
+            ("cat.png", 200),
+            ("photo", 404),
+            ("avatar_secret", 404),
+        ] {
+            let response = testing::get(format!("{}/images/{}?width=4", base, image_id)).await;
+            assert_eq!(response.status(), status, "{}", image_id);
+        }
+        // denied ids never reach base api
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        for (image_id, status) in [("avatar_2", 200), ("photo", 403), ("avatar_secret", 403)] {
+            let response = testing::request(Method::PUT, format!("{}/images/{}", base, image_id))
+                .body(testing::png(8, 8))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", image_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn preloads_base64_body() {
+        let base = testing::serve(testing::config(&[])).await;
+        let encoded = BASE64_STANDARD.encode(testing::png(8, 6));
+
+        let response =
+            testing::request(Method::PUT, format!("{}/images/b64?encoding=base64", base))
+                .body(encoded.clone())
+                .send()
+                .await
+                .unwrap();
+        assert_eq!(response.status(), 200);
+        let response = testing::request(Method::PUT, format!("{}/images/data-url", base))
+            .body(format!("data:image/png;base64,{}", encoded))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        for image_id in ["b64", "data-url"] {
+            let response =
+                testing::get(format!("{}/images/{}?extension=PNG", base, image_id)).await;
+            assert_eq!(response.status(), 200);
+            let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!(image.dimensions(), (8, 6));
+        }
+
+        let response = testing::request(
+            Method::PUT,
+            format!("{}/images/broken?encoding=base64", base),
+        )
+        .body("not base64!")
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(testing::json(response).await["error_type"], "invalid_body");
+    }
+
+    #[test]
+    fn default_filename_follows_pattern() {
+        assert_eq!(default_filename("img-{id}", "cat"), "img-cat");
+        assert_eq!(default_filename("{id}\u{7f}", "cat\u{1b}"), "cat");
+        assert_eq!(default_filename("{id}", ""), "image");
+    }
+
+    #[test]
+    fn content_disposition_drops_control_chars() {
+        let header = content_disposition_header(
+            Disposition::Attachment,
+            Some("bad\u{7f}name\n".to_string()),
+            "image".to_string(),
+            "webp",
+        );
+        assert_eq!(
+            header,
+            "attachment; filename=\"badname.webp\"; filename*=UTF-8''badname.webp"
+        );
+
+        let header =
+            content_disposition_header(Disposition::Inline, None, "фото".to_string(), "png");
+        assert!(
+            header
+                .as_bytes()
+                .ends_with(b"filename*=UTF-8''%D1%84%D0%BE%D1%82%D0%BE.png")
+        );
+    }
+
+    #[tokio::test]
+    async fn default_filename_pattern_is_served() {
+        let config = testing::config(&[("DEFAULT_FILENAME_PATTERN", "img-{id}")]);
+        testing::preload(&config, "cat", testing::png(8, 8)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!("{}/images/cat?width=4", base)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "inline; filename=\"img-cat.webp\"; filename*=UTF-8''img-cat.webp"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidates_several_images() {
+        let config = testing::config(&[]);
+        for image_id in ["first", "second", "kept"] {
+            testing::preload(&config, image_id, testing::png(8, 8)).await;
+        }
+        let base = testing::serve(config).await;
+        for query in ["first?width=4", "first?width=5", "second?width=4"] {
+            let response = testing::get(format!("{}/images/{}", base, query)).await;
+            assert_eq!(response.status(), 200);
+        }
+
+        let response = testing::post_json(
+            format!("{}/invalidate", base),
+            serde_json::json!({"image_ids": ["first", "second"]}),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            testing::json(response).await["purged"],
+            serde_json::json!({"first": 2, "second": 1})
+        );
+
+        for (image_id, status) in [("first", 404), ("second", 404), ("kept", 200)] {
+            let response = testing::get(format!("{}/images/{}?width=4", base, image_id)).await;
+            assert_eq!(response.status(), status, "{}", image_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn effective_width_is_reported_when_changed() {
+        let config = testing::config(&[("ALLOWED_WIDTHS", "50,100,200")]);
+        testing::preload(&config, "responsive", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        for (query, effective) in [
+            ("width=90", Some("100")),
+            ("width=60&dpr=2", Some("100")),
+            ("width=120&height=60", Some("100")),
+            ("width=50", None),
+            ("height=10", None),
+        ] {
+            let response = testing::get(format!(
+                "{}/images/responsive?extension=PNG&{}",
+                base, query
+            ))
+            .await;
+            assert_eq!(response.status(), 200, "{}", query);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(EFFECTIVE_WIDTH_HEADER)
+                    .map(|v| v.to_str().unwrap()),
+                effective,
+                "{}",
+                query
+            );
+            let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            if let Some(effective) = effective {
+                assert_eq!(image.width().to_string(), effective, "{}", query);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn transforms_over_limit_are_rejected() {
+        let config = testing::config(&[("MAX_TRANSFORMS_PER_REQUEST", "2")]);
+        testing::preload(&config, "busy", testing::png(20, 20)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!(
+            "{}/images/busy?width=10&sharpen=10&tint=ff0000&brightness=10",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 422);
+        let body = testing::json(response).await;
+        assert_eq!(body["fields"][0]["field"], "transforms");
+
+        let response = testing::get(format!(
+            "{}/images/busy?width=10&sharpen=10&tint=ff0000",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn montage_composes_grid_of_images() {
+        let config = testing::config(&[("MAX_IMAGE_RESIZE", "4294967295,4294967295")]);
+        for (image_id, color) in [
+            ("red", [255, 0, 0, 255]),
+            ("green", [0, 255, 0, 255]),
+            ("blue", [0, 0, 255, 255]),
+        ] {
+            let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(20, 10, Rgba(color)));
+            testing::preload(&config, image_id, testing::encode(&image, ImageFormat::Png)).await;
+        }
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!(
+            "{}/montage?ids=red,green,blue&cols=2&cell=8&extension=PNG",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let grid = image::load_from_memory(&response.bytes().await.unwrap())
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(grid.dimensions(), (16, 16));
+        assert_eq!(grid.get_pixel(4, 4).0, [255, 0, 0, 255]);
+        assert_eq!(grid.get_pixel(12, 4).0, [0, 255, 0, 255]);
+        assert_eq!(grid.get_pixel(4, 12).0, [0, 0, 255, 255]);
+        assert_eq!(grid.get_pixel(12, 12).0[3], 0);
+
+        // grid size overflowing u32 is rejected instead of panicking
+        let response = testing::get(format!(
+            "{}/montage?ids=red,green,blue&cols=3&cell=2000000000",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn transforms_posted_image() {
+        let base = testing::serve(testing::config(&[])).await;
+
+        let response = testing::request(
+            Method::POST,
+            format!("{}/transform?width=10&extension=PNG", base),
+        )
+        .body(testing::png(40, 20))
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(image.dimensions(), (10, 5));
+
+        let response = testing::client()
+            .post(format!("{}/transform?width=10", base))
+            .body(testing::png(40, 20))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+        let response = testing::request(Method::POST, format!("{}/transform?width=10", base))
+            .body("not an image")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn oversize_transform_body_is_rejected() {
+        let png = testing::png(40, 20);
+        let base = testing::serve(testing::config(&[(
+            "MAX_TRANSFORM_BODY_BYTES",
+            &png.len().to_string(),
+        )]))
+        .await;
+        let url = format!("{}/transform?width=10&extension=PNG", base);
+
+        let mut oversize = png.clone();
+        oversize.push(0);
+        let response = testing::request(Method::POST, url.clone())
+            .body(oversize)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 413);
+        assert_eq!(
+            testing::json(response).await["error_type"],
+            "body_too_large"
+        );
+
+        let response = testing::request(Method::POST, url)
+            .body(png)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn quality_below_floor_is_clamped() {
+        let config = testing::config(&[("MIN_QUALITY", "Webp=30")]);
+        testing::preload(&config, "floor", testing::png(20, 20)).await;
+        let base = testing::serve(config).await;
+
+        for (query, clamped) in [
+            ("extension=Webp&quality=5", Some("30")),
+            ("extension=Avif&quality=5", Some("10")),
+            ("extension=Webp&quality=50", None),
+        ] {
+            let response = testing::get(format!("{}/images/floor?width=10&{}", base, query)).await;
+            assert_eq!(response.status(), 200, "{}", query);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(QUALITY_CLAMPED_HEADER)
+                    .map(|v| v.to_str().unwrap()),
+                clamped,
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn all_formats_are_served_in_multipart() {
+        let config = testing::config(&[]);
+        testing::preload(&config, "multi", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!(
+            "{}/images/multi/all?formats=webp,avif,png&width=20",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 200);
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/mixed; boundary=")
+            .unwrap();
+        let body = response.bytes().await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        let parts: Vec<&str> = body
+            .split(&format!("--{}", boundary))
+            .filter(|part| part.starts_with("\r\nContent-Type"))
+            .collect();
+        let types: Vec<&str> = parts
+            .iter()
+            .map(|part| part.lines().nth(1).unwrap())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "Content-Type: image/webp",
+                "Content-Type: image/avif",
+                "Content-Type: image/png"
+            ]
+        );
+        for (part, extension) in parts.iter().zip(["webp", "avif", "png"]) {
+            assert!(
+                part.contains(&format!("filename=\"image.{}\"", extension)),
+                "{}",
+                extension
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn preloaded_cache_ttl_is_served() {
+        let base = testing::serve(testing::config(&[("CLIENT_CACHE_TTL", "86400")])).await;
+        for (image_id, ttl) in [("volatile", Some("60")), ("static", None)] {
+            let mut request =
+                testing::request(Method::PUT, format!("{}/images/{}", base, image_id))
+                    .body(testing::png(8, 8));
+            if let Some(ttl) = ttl {
+                request = request.header(CACHE_TTL_HEADER, ttl);
+            }
+            assert_eq!(request.send().await.unwrap().status(), 200);
+        }
+        let response = testing::request(Method::PUT, format!("{}/images/bad", base))
+            .header(CACHE_TTL_HEADER, "soon")
+            .body(testing::png(8, 8))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        for (image_id, max_age) in [("volatile", 60), ("static", 86400)] {
+            // processed cache hit keeps ttl of original
+            for _ in 0..2 {
+                let response = testing::get(format!("{}/images/{}?width=4", base, image_id)).await;
+                assert_eq!(
+                    response.headers()[header::CACHE_CONTROL],
+                    format!("public, max-age={}, immutable", max_age),
+                    "{}",
+                    image_id
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_params_are_reported_per_field() {
+        let config = testing::config(&[]);
+        testing::preload(&config, "fields", testing::png(8, 8)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!(
+            "{}/images/fields?quality=500&width=5000&tint=zzz",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 422);
+        let body = testing::json(response).await;
+        assert_eq!(body["error_type"], "invalid_params");
+        assert_eq!(
+            body["fields"],
+            serde_json::json!([
+                {"field": "quality", "constraint": "must be between 1 and 100", "value": "500"},
+                {"field": "width", "constraint": "must be at most 1920", "value": "5000"},
+                {"field": "tint", "constraint": "must be hex color, like ff8800", "value": "zzz"},
+            ])
+        );
+
+        for query in [
+            "width=0",
+            "height=0",
+            "width=0&ratio_policy=Pad",
+            "width=10&height=0&ratio_policy=CropToCenter",
+        ] {
+            let response = testing::get(format!("{}/images/fields?{}", base, query)).await;
+            assert_eq!(response.status(), 422, "{}", query);
+            let body = testing::json(response).await;
+            let field = match query.contains("height") {
+                true => "height",
+                false => "width",
+            };
+            assert_eq!(
+                body["fields"],
+                serde_json::json!([{"field": field, "constraint": "must be greater than 0", "value": "0"}]),
+                "{}",
+                query
+            );
+        }
+        let response =
+            testing::get(format!("{}/images/fields/all?formats=png&width=0", base)).await;
+        assert_eq!(response.status(), 422);
+        assert_eq!(testing::json(response).await["fields"][0]["field"], "width");
+
+        let response = testing::get(format!("{}/images/fields?ratio_policy=Bogus", base)).await;
+        assert_eq!(response.status(), 422);
+        let body = testing::json(response).await;
+        assert_eq!(body["fields"][0]["field"], "ratio_policy");
+        assert_eq!(body["fields"][0]["value"], "Bogus");
+    }
+
+    #[tokio::test]
+    async fn digest_matches_served_body() {
+        let config = testing::config(&[("RESPONSE_DIGEST", "true")]);
+        testing::preload(&config, "digested", testing::png(20, 20)).await;
+        let base = testing::serve(config).await;
+
+        // the second response is processed cache hit with stored digest
+        for _ in 0..2 {
+            let response = testing::get(format!("{}/images/digested?width=10", base)).await;
+            assert_eq!(response.status(), 200);
+            let digest = response.headers()[DIGEST_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string();
+            let body = response.bytes().await.unwrap();
+            let expected =
+                BASE64_STANDARD.encode(ring::digest::digest(&ring::digest::SHA256, &body));
+            assert_eq!(digest, format!("sha-256={}", expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn exif_orientation_is_toggleable() {
+        for (auto_orient, query, dimensions) in [
+            ("true", "", (10, 20)),
+            ("true", "&auto_orient=false", (20, 10)),
+            ("false", "", (20, 10)),
+            ("false", "&auto_orient=true", (10, 20)),
+        ] {
+            let config = testing::config(&[("AUTO_ORIENT", auto_orient)]);
+            testing::preload(&config, "rotated", testing::oriented_jpeg(20, 10, 6)).await;
+            let base = testing::serve(config).await;
+
+            let response =
+                testing::get(format!("{}/images/rotated?extension=PNG{}", base, query)).await;
+            assert_eq!(response.status(), 200);
+            let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!(image.dimensions(), dimensions, "{} {}", auto_orient, query);
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_extension_follows_format_priority() {
+        for (priority, accept, mime) in [
+            ("Avif,Webp,PNG", "image/avif,image/webp,*/*", "image/avif"),
+            ("Webp,Avif,PNG", "image/avif,image/webp,*/*", "image/webp"),
+            ("Webp,Avif,PNG", "image/avif,*/*", "image/avif"),
+            ("Webp,Avif,PNG", "*/*", "image/webp"),
+        ] {
+            let config = testing::config(&[("FORMAT_PRIORITY", priority)]);
+            testing::preload(&config, "auto", testing::png(20, 20)).await;
+            let base = testing::serve(config).await;
+
+            let response = testing::client()
+                .get(format!("{}/images/auto?width=10&extension=auto", base))
+                .header(header::ACCEPT, accept)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                mime,
+                "{} {}",
+                priority,
+                accept
+            );
+            assert_eq!(response.headers()[header::VARY], "accept");
+        }
+    }
+
+    #[tokio::test]
+    async fn debug_describes_crop() {
+        let config = testing::config(&[("ENABLE_DEBUG_ENDPOINTS", "true")]);
+        testing::preload(&config, "wide", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!(
+            "{}/images/wide/debug?width=10&height=10&extension=PNG&quality=5",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 200);
+        let info = testing::json(response).await;
+        assert_eq!(info["source_width"], 40);
+        assert_eq!(info["source_height"], 20);
+        assert_eq!(info["width"], 10);
+        assert_eq!(info["height"], 10);
+        assert_eq!(info["extension"], "PNG");
+        assert_eq!(info["cropped"], true);
+        assert_eq!(info["padded"], false);
+        assert_eq!(info["quality_clamped"], 10);
+        assert_eq!(info["params"]["quality"], 10);
+        assert!(info["bytes"].as_u64().unwrap() > 0);
+
+        let base = testing::serve(testing::config(&[])).await;
+        let response = testing::get(format!("{}/images/wide/debug?width=10", base)).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn animation_frame_is_served_as_still() {
+        for (policy, out_of_range_status) in [("Last", 200), ("Reject", 422)] {
+            let config = testing::config(&[("FRAME_OUT_OF_RANGE_POLICY", policy)]);
+            testing::preload(&config, "animated", testing::animated_gif(3, 8, 8)).await;
+            let base = testing::serve(config).await;
+
+            for (frame, status, color) in [
+                (1, 200, [0, 255, 0, 255]),
+                (2, 200, [0, 0, 255, 255]),
+                (5, out_of_range_status, [0, 0, 255, 255]),
+            ] {
+                let response = testing::get(format!(
+                    "{}/images/animated?frame={}&extension=PNG",
+                    base, frame
+                ))
+                .await;
+                assert_eq!(response.status(), status, "{} {}", policy, frame);
+                if status == 200 {
+                    let image = image::load_from_memory(&response.bytes().await.unwrap())
+                        .unwrap()
+                        .to_rgba8();
+                    assert_eq!(image.get_pixel(4, 4).0, color, "{} {}", policy, frame);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn head_headers_match_get() {
+        let config = testing::config(&[
+            ("IMAGE_INFO_HEADERS", "true"),
+            ("CACHE_STATUS_HEADERS", "true"),
+            ("RESPONSE_DIGEST", "true"),
+        ]);
+        testing::preload(&config, "headed", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        for query in ["width=10&extension=PNG", "original=true"] {
+            let url = format!("{}/images/headed?{}", base, query);
+            // the first GET processes variant, so both following requests are cache hits
+            testing::get(url.clone()).await;
+            let get = testing::get(url.clone()).await;
+            let head = testing::client().head(url).send().await.unwrap();
+            assert_eq!(head.status(), get.status(), "{}", query);
+
+            // time and request dependent headers are skipped
+            let stable_headers = |response: &reqwest::Response| {
+                response
+                    .headers()
+                    .iter()
+                    .filter(|(name, _)| {
+                        ![header::DATE, header::EXPIRES].contains(name)
+                            && name.as_str() != REQUEST_ID_HEADER.to_lowercase()
+                    })
+                    .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+                    .collect::<std::collections::BTreeMap<_, _>>()
+            };
+            assert_eq!(stable_headers(&head), stable_headers(&get), "{}", query);
+            let length = get.bytes().await.unwrap().len();
+            assert_eq!(
+                head.headers()[header::CONTENT_LENGTH],
+                length.to_string(),
+                "{}",
+                query
+            );
+            assert!(head.bytes().await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn image_info_headers_match_served_image() {
+        let config = testing::config(&[("IMAGE_INFO_HEADERS", "true")]);
+        testing::preload(&config, "described", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        // the second response is processed cache hit
+        for (query, format) in [
+            ("width=10&height=10&extension=PNG", "png"),
+            ("width=10&height=10&extension=PNG", "png"),
+            ("width=16&extension=Webp", "webp"),
+        ] {
+            let response = testing::get(format!("{}/images/described?{}", base, query)).await;
+            assert_eq!(response.status(), 200);
+            let headers = response.headers().clone();
+            let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!(
+                headers[IMAGE_WIDTH_HEADER],
+                image.width().to_string(),
+                "{}",
+                query
+            );
+            assert_eq!(
+                headers[IMAGE_HEIGHT_HEADER],
+                image.height().to_string(),
+                "{}",
+                query
+            );
+            assert_eq!(headers[IMAGE_FORMAT_HEADER], format, "{}", query);
+        }
+
+        let config = testing::config(&[]);
+        testing::preload(&config, "described", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+        let response = testing::get(format!("{}/images/described?width=10", base)).await;
+        assert_eq!(response.status(), 200);
+        for name in [IMAGE_WIDTH_HEADER, IMAGE_HEIGHT_HEADER, IMAGE_FORMAT_HEADER] {
+            assert!(response.headers().get(name).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn json_variant_describes_image() {
+        let get = |url: String, accept: &'static str| async move {
+            testing::client()
+                .get(url)
+                .header(header::ACCEPT, accept)
+                .send()
+                .await
+                .unwrap()
+        };
+        let config = testing::config(&[("ENABLE_DEBUG_ENDPOINTS", "true")]);
+        testing::preload(&config, "encoded", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+        let url = format!("{}/images/encoded?width=10&extension=PNG", base);
+
+        let response = get(url.clone(), "application/json").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()[header::VARY], "accept");
+        let body = testing::json(response).await;
+        let data = BASE64_STANDARD
+            .decode(body["data"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "extension": "PNG",
+                "content_type": "image/png",
+                "bytes": data.len(),
+                "width": 10,
+                "height": 5,
+                "data": body["data"],
+            })
+        );
+        let image = image::load_from_memory(&data).unwrap();
+        assert_eq!(image.dimensions(), (10, 5));
+
+        for accept in ["image/webp,application/json", "*/*"] {
+            let response = get(url.clone(), accept).await;
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "image/png",
+                "{}",
+                accept
+            );
+        }
+
+        let config = testing::config(&[]);
+        testing::preload(&config, "encoded", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+        let response = get(
+            format!("{}/images/encoded?width=10&extension=PNG", base),
+            "application/json",
+        )
+        .await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    }
+
+    #[tokio::test]
+    async fn original_is_returned_byte_identical() {
+        let config = testing::config(&[]);
+        // exif orientation is kept and large bmp is sent whole
+        let originals = [
+            (
+                "rotated",
+                testing::oriented_jpeg(20, 10, 6),
+                "image/jpeg",
+                "jpg",
+            ),
+            (
+                "bitmap",
+                testing::encode(&testing::gradient(200, 200), ImageFormat::Bmp),
+                "image/bmp",
+                "bmp",
+            ),
+        ];
+        for (image_id, data, _, _) in &originals {
+            testing::preload(&config, image_id, data.clone()).await;
+        }
+        let base = testing::serve(config).await;
+
+        for (image_id, data, content_type, extension) in originals {
+            let url = format!(
+                "{}/images/{}?original=true&width=5&extension=Webp",
+                base, image_id
+            );
+            let response = testing::get(url.clone()).await;
+            assert_eq!(response.status(), 200);
+            let headers = response.headers().clone();
+            assert_eq!(headers[header::CONTENT_TYPE], content_type);
+            assert_eq!(headers[header::CONTENT_LENGTH], data.len().to_string());
+            assert!(
+                headers[header::CONTENT_DISPOSITION]
+                    .to_str()
+                    .unwrap()
+                    .contains(&format!("image.{}", extension)),
+                "{:?}",
+                headers
+            );
+            assert!(response.bytes().await.unwrap() == data, "{}", image_id);
+
+            let response = testing::request(Method::HEAD, url).send().await.unwrap();
+            assert_eq!(
+                response.headers()[header::CONTENT_LENGTH],
+                data.len().to_string()
+            );
+            assert!(response.bytes().await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn purge_variants_keeps_unmatched_ones() {
+        let dir = testing::temp_path("purge-variants");
+        for implementation in ["InMemory", "Persistent"] {
+            let config = testing::config(&[
+                ("CACHE_STATUS_HEADERS", "true"),
+                ("PROCESSING_CACHE_IMPLEMENTATION", implementation),
+                ("PERSISTENT_STORAGE_DIR", &dir),
+            ]);
+            for image_id in ["first", "second"] {
+                testing::preload(&config, image_id, testing::png(40, 40)).await;
+            }
+            let base = testing::serve(config).await;
+            let cache_status = |image_id: &'static str, extension: &'static str| {
+                let url = format!(
+                    "{}/images/{}?width=10&extension={}",
+                    base, image_id, extension
+                );
+                async move {
+                    let response = testing::get(url).await;
+                    assert_eq!(response.status(), 200);
+                    response.headers()[CACHE_STATUS_HEADER].clone()
+                }
+            };
+            for image_id in ["first", "second"] {
+                for extension in ["Avif", "Webp"] {
+                    assert_eq!(cache_status(image_id, extension).await, "MISS");
+                }
+            }
+
+            let url = format!("{}/purge-variants", base);
+            let response = testing::post_json(url.clone(), serde_json::json!({})).await;
+            assert_eq!(response.status(), 422);
+            let response =
+                testing::post_json(url.clone(), serde_json::json!({"extension": "Avif"})).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(
+                testing::json(response).await,
+                serde_json::json!({"purged": 2, "images": 2, "complete": true})
+            );
+
+            for image_id in ["first", "second"] {
+                assert_eq!(cache_status(image_id, "Webp").await, "HIT", "{}", image_id);
+                assert_eq!(cache_status(image_id, "Avif").await, "MISS", "{}", image_id);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn filename_overrides_content_disposition() {
+        let config = testing::config(&[]);
+        testing::preload(&config, "named", testing::png(20, 20)).await;
+        let base = testing::serve(config).await;
+
+        for (query, disposition) in [
+            (
+                "extension=Webp&filename=report",
+                "inline; filename=\"report.webp\"; filename*=UTF-8''report.webp",
+            ),
+            (
+                "original=true&filename=report",
+                "inline; filename=\"report.png\"; filename*=UTF-8''report.png",
+            ),
+            (
+                "extension=PNG&filename=%D0%BE%D1%82%D1%87%D1%91%D1%82",
+                "inline; filename=\"отчёт.png\"; filename*=UTF-8''%D0%BE%D1%82%D1%87%D1%91%D1%82.png",
+            ),
+        ] {
+            let response = testing::get(format!("{}/images/named?{}", base, query)).await;
+            assert_eq!(response.status(), 200, "{}", query);
+            assert_eq!(
+                response.headers()[header::CONTENT_DISPOSITION].as_bytes(),
+                disposition.as_bytes(),
+                "{}",
+                query
+            );
+        }
+
+        for filename in ["../secret", "dir/name", "dir%5Cname", "%0Aname", ".."] {
+            let response =
+                testing::get(format!("{}/images/named?filename={}", base, filename)).await;
+            assert_eq!(response.status(), 422, "{}", filename);
+            let body = testing::json(response).await;
+            assert_eq!(body["fields"][0]["field"], "filename", "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn attachment_disposition_is_emitted_when_requested() {
+        let config = testing::config(&[]);
+        testing::preload(&config, "download", testing::png(20, 20)).await;
+        let base = testing::serve(config).await;
+
+        for (query, disposition) in [
+            ("extension=PNG", "inline"),
+            ("extension=PNG&disposition=inline", "inline"),
+            ("extension=PNG&disposition=attachment", "attachment"),
+            ("original=true&disposition=attachment", "attachment"),
+        ] {
+            let response = testing::get(format!("{}/images/download?{}", base, query)).await;
+            assert_eq!(response.status(), 200, "{}", query);
+            assert_eq!(
+                response.headers()[header::CONTENT_DISPOSITION],
+                format!(
+                    "{}; filename=\"image.png\"; filename*=UTF-8''image.png",
+                    disposition
+                ),
+                "{}",
+                query
+            );
+        }
+
+        let response = testing::get(format!("{}/images/download?disposition=download", base)).await;
+        assert!(response.status().is_client_error());
+    }
+
+    #[tokio::test]
+    async fn interlaced_png_is_served_and_cached_separately() {
+        let config = testing::config(&[("CACHE_STATUS_HEADERS", "true")]);
+        testing::preload(&config, "progressive", testing::png(33, 17)).await;
+        let base = testing::serve(config).await;
+        let fetch = async |query: &str| {
+            let response = testing::get(format!("{}/images/progressive?{}", base, query)).await;
+            assert_eq!(response.status(), 200, "{}", query);
+            let cache_status = response.headers()[CACHE_STATUS_HEADER].clone();
+            (cache_status, response.bytes().await.unwrap())
+        };
+        // interlace method is the last byte of IHDR data, after sizes, depth, color, compression and filter
+        let interlace_method = |png: &[u8]| {
+            let ihdr = png.windows(4).position(|chunk| chunk == b"IHDR").unwrap();
+            png[ihdr + 16]
+        };
+
+        let (_, plain) = fetch("extension=PNG").await;
+        let (cache_status, interlaced) = fetch("extension=PNG&interlace=true").await;
+        assert_eq!(cache_status, "MISS");
+        assert_eq!(interlace_method(&plain), 0);
+        assert_eq!(interlace_method(&interlaced), 1);
+        assert_eq!(
+            image::load_from_memory(&interlaced).unwrap().to_rgba8(),
+            image::load_from_memory(&plain).unwrap().to_rgba8()
+        );
+        assert_eq!(fetch("extension=PNG&interlace=true").await.0, "HIT");
+
+        let (_, webp) = fetch("extension=Webp").await;
+        assert_eq!(fetch("extension=Webp&interlace=true").await.1, webp);
+    }
+
+    #[tokio::test]
+    async fn oversize_origins_are_rejected() {
+        let large = testing::png(300, 300);
+        assert!(large.len() > 1024);
+        let origin = testing::serve_router(
+            axum::Router::new()
+                .route(
+                    "/small",
+                    axum::routing::get(|| async { testing::png(8, 8) }),
+                )
+                .route("/declared", axum::routing::get(|| async move { large }))
+                // never ending body without length, so it's rejected only by counting read bytes
+                .route(
+                    "/endless",
+                    axum::routing::get(|| async {
+                        Body::from_stream(stream::repeat_with(|| {
+                            Ok::<_, std::convert::Infallible>(Bytes::from(vec![0; 1024]))
+                        }))
+                    }),
+                ),
+        )
+        .await;
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("MAX_ORIGIN_BYTES", "1024"),
+        ]))
+        .await;
+
+        let response = testing::get(format!("{}/images/small?extension=PNG", base)).await;
+        assert_eq!(response.status(), 200);
+        for image_id in ["declared", "endless"] {
+            let response = tokio::time::timeout(
+                Duration::from_secs(5),
+                testing::get(format!("{}/images/{}?extension=PNG", base, image_id)),
+            )
+            .await
+            .expect("Oversize origin is read completely");
+            assert_eq!(response.status(), 413, "{}", image_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn origin_update_purges_and_rewarms_image() {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        // origin serves changed image since second fetch
+        let origin = testing::serve_router(axum::Router::new().fallback(move || {
+            let version = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match version {
+                    0 => testing::png(40, 40),
+                    _ => testing::png(60, 30),
+                }
+            }
+        }))
+        .await;
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("ORIGIN_WEBHOOK_WARM_VARIANTS", "w=10&fmt=PNG"),
+        ]))
+        .await;
+        let dimensions = async || {
+            let response =
+                testing::get(format!("{}/images/changed?width=10&extension=PNG", base)).await;
+            assert_eq!(response.status(), 200);
+            image::load_from_memory(&response.bytes().await.unwrap())
+                .unwrap()
+                .dimensions()
+        };
+        assert_eq!(dimensions().await, (10, 10));
+
+        let url = format!("{}/webhook/origin-updated", base);
+        let response = testing::client()
+            .post(url.clone())
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({"image_ids": ["changed"]}).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+        let response =
+            testing::post_json(url.clone(), serde_json::json!({"ids": ["changed"]})).await;
+        assert_eq!(response.status(), 422);
+        let response = testing::post_json(url, serde_json::json!({"image_ids": ["changed"]})).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            testing::json(response).await,
+            serde_json::json!({"purged": {"changed": 1}, "warming": 1})
+        );
+
+        // warmed variant is observed by purging it, as requesting it would process it as well
+        let mut warmed = false;
+        for _ in 0..100 {
+            let response = testing::post_json(
+                format!("{}/purge-variants", base),
+                serde_json::json!({"extension": "PNG"}),
+            )
+            .await;
+            if testing::json(response).await["purged"] == 1 {
+                warmed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(warmed);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // changed original is stored by warming
+        assert_eq!(dimensions().await, (10, 5));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn raw_sizes_are_rejected_in_preset_only_mode() {
+        let config = testing::config(&[
+            ("SIZE_PRESETS", "thumb=16x16,card=32x"),
+            ("PRESET_SIZES_ONLY", "true"),
+        ]);
+        testing::preload(&config, "strict", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        for (path, field) in [
+            ("images/strict?width=20", "width"),
+            ("images/strict?height=10", "height"),
+            ("images/strict?preset=thumb&dpr=2", "dpr"),
+            ("images/strict?preset=huge", "preset"),
+            ("images/strict/all?formats=png&width=20", "width"),
+            ("images/strict/all?formats=png&preset=card&dpr=2", "dpr"),
+        ] {
+            let response = testing::get(format!("{}/{}", base, path)).await;
+            assert_eq!(response.status(), 422, "{}", path);
+            let body = testing::json(response).await;
+            assert_eq!(body["fields"][0]["field"], field, "{}", path);
+        }
+        let response = testing::get(format!("{}/montage?ids=strict&cell=20", base)).await;
+        assert_eq!(response.status(), 422);
+
+        for (path, dimensions) in [
+            ("images/strict?extension=PNG&preset=thumb", (16, 16)),
+            ("images/strict?extension=PNG&preset=card", (32, 16)),
+            ("montage?ids=strict&cell=32&fmt=PNG", (32, 32)),
+        ] {
+            let response = testing::get(format!("{}/{}", base, path)).await;
+            assert_eq!(response.status(), 200, "{}", path);
+            let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!(image.dimensions(), dimensions, "{}", path);
+        }
+        let response = testing::get(format!(
+            "{}/images/strict/all?formats=png,webp&preset=card",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 200);
+
+        // posted images are restricted as well, though transform requires api key
+        let transform = async |query: &str| {
+            testing::request(Method::POST, format!("{}/transform?{}", base, query))
+                .body(testing::png(40, 20))
+                .send()
+                .await
+                .unwrap()
+        };
+        let response = transform("extension=PNG&width=20").await;
+        assert_eq!(response.status(), 422);
+        assert_eq!(testing::json(response).await["fields"][0]["field"], "width");
+        let response = transform("extension=PNG&preset=thumb").await;
+        assert_eq!(response.status(), 200);
+        let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(image.dimensions(), (16, 16));
+    }
+
+    #[tokio::test]
+    async fn cache_status_is_reported_when_enabled() {
+        let (origin, _) = testing::counting_origin(testing::png(40, 40), Duration::ZERO).await;
+        let vars = [
+            ("BASE_FILE_API_URL", origin.as_str()),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+        ];
+        let base = testing::serve(testing::config(
+            &[&vars[..], &[("CACHE_STATUS_HEADERS", "true")]].concat(),
+        ))
+        .await;
+        let cache_headers = async |query: &str| {
+            let response = testing::get(format!("{}/images/tracked?{}", base, query)).await;
+            assert_eq!(response.status(), 200, "{}", query);
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .map(|value: &HeaderValue| value.to_str().unwrap().to_string())
+            };
+            (header(CACHE_STATUS_HEADER), header(CACHE_ORIGINAL_HEADER))
+        };
+
+        for (query, cache_status, original) in [
+            ("width=10&extension=PNG", "MISS", Some("FETCHED")),
+            ("width=10&extension=PNG", "HIT", None),
+            ("width=12&extension=PNG", "MISS", Some("STORAGE")),
+            ("width=12&extension=PNG", "HIT", None),
+        ] {
+            assert_eq!(
+                cache_headers(query).await,
+                (Some(cache_status.to_string()), original.map(str::to_string)),
+                "{}",
+                query
+            );
+        }
+
+        let base = testing::serve(testing::config(&vars)).await;
+        let response = testing::get(format!("{}/images/tracked?width=10", base)).await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get(CACHE_STATUS_HEADER).is_none());
+        assert!(response.headers().get(CACHE_ORIGINAL_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn allowed_sizes_are_rejected() {
+        let config = testing::config(&[
+            ("ALLOWED_WIDTHS", "50,100"),
+            ("ALLOWED_HEIGHTS", "20"),
+            ("ALLOWED_SIZES_POLICY", "Reject"),
+        ]);
+        testing::preload(&config, "allowed", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        for (path, field) in [
+            ("images/allowed?width=90", "width"),
+            ("images/allowed?width=50&height=30", "height"),
+            ("images/allowed/all?formats=png&width=90", "width"),
+        ] {
+            let response = testing::get(format!("{}/{}", base, path)).await;
+            assert_eq!(response.status(), 422, "{}", path);
+            let body = testing::json(response).await;
+            assert_eq!(body["fields"][0]["field"], field, "{}", path);
+        }
+        let response = testing::get(format!("{}/montage?ids=allowed&cell=90", base)).await;
+        assert_eq!(response.status(), 422);
+        let response = testing::get(format!(
+            "{}/images/allowed?extension=PNG&width=100&height=20",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn allowed_sizes_are_snapped() {
+        let config = testing::config(&[("ALLOWED_WIDTHS", "50,100")]);
+        testing::preload(&config, "snapped", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::request(
+            Method::POST,
+            format!("{}/transform?extension=PNG&width=90&height=45", base),
+        )
+        .body(testing::png(40, 20))
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(image.dimensions(), (100, 50));
+
+        let response = testing::get(format!(
+            "{}/montage?ids=snapped&cell=90&extension=PNG",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 200);
+        let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(image.dimensions(), (100, 100));
+    }
+
+    #[tokio::test]
+    async fn fallback_image_is_served_resized() {
+        let origin = testing::missing_origin().await;
+        let fallback = testing::temp_file("fallback.png", &testing::png(40, 20));
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("FALLBACK_IMAGE_PATH", &fallback),
+        ]))
+        .await;
+
+        let response = testing::get(format!(
+            "{}/images/missing?extension=PNG&width=20&height=10",
+            base
+        ))
+        .await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.headers()[FALLBACK_HEADER], "true");
+        let image = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(image.dimensions(), (20, 10));
+
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+        ]))
+        .await;
+        let response = testing::get(format!("{}/images/missing", base)).await;
+        assert_eq!(response.status(), 404);
+        assert!(response.headers().get(FALLBACK_HEADER).is_none());
+        assert_eq!(testing::json(response).await["error_type"], "not_found");
+    }
+
+    #[test]
+    fn file_api_errors_map_to_gateway_statuses() {
+        for (kind, status) in [
+            (FileApiErrorKind::Timeout, StatusCode::GATEWAY_TIMEOUT),
+            (FileApiErrorKind::DnsFailure, StatusCode::BAD_GATEWAY),
+            (FileApiErrorKind::ConnectFailure, StatusCode::BAD_GATEWAY),
+            (FileApiErrorKind::TlsFailure, StatusCode::BAD_GATEWAY),
+            (FileApiErrorKind::HttpStatus(503), StatusCode::BAD_GATEWAY),
+        ] {
+            assert_eq!(
+                processing_error_status(&ProcessingErrorType::FileApiError(kind)),
+                status,
+                "{}",
+                kind
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn non_image_origin_response_is_rejected() {
+        let origin = testing::serve_router(
+            axum::Router::new().fallback(|| async { axum::response::Html("<html>Login</html>") }),
+        )
+        .await;
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+        ]))
+        .await;
+
+        let response = testing::get(format!("{}/images/page", base)).await;
+        assert_eq!(response.status(), 400);
+        let body = testing::json(response).await;
+        assert_eq!(body["error_type"], "unsupporting_extension");
+        assert!(
+            body["detail"].as_str().unwrap().contains("text/html"),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn large_original_is_served_verbatim() {
+        let data = testing::png(1500, 1500);
+        let (origin, requests) = testing::counting_origin(data.clone(), Duration::ZERO).await;
+        let config = Arc::new(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+        ]));
+        let base = testing::serve_router(crate::app_init(config.clone(), true)).await;
+
+        for _ in 0..2 {
+            let response = testing::get(format!("{}/images/large?original=true", base)).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+            assert!(response.bytes().await.unwrap() == data);
+            // passed through original is stored in background
+            for _ in 0..100 {
+                if config.processor.touch("large".to_string()).await {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        // second one is served from storage
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn served_variants_are_counted() {
+        let config = testing::config(&[]);
+        testing::preload(&config, "counted", testing::png(40, 20)).await;
+        let base = testing::serve(config).await;
+
+        let url = format!("{}/images/counted?extension=PNG&width=10", base);
+        for _ in 0..2 {
+            assert_eq!(testing::get(url.clone()).await.status(), 200);
+        }
+        let response = testing::request(Method::HEAD, url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let response =
+            testing::get(format!("{}/images/counted/all?formats=png&width=10", base)).await;
+        assert_eq!(response.status(), 200);
+
+        let response = testing::request(Method::GET, format!("{}/images/counted/stats", base))
+            .send()
+            .await
+            .unwrap();
+        let stats = testing::json(response).await;
+        assert_eq!(stats["served"], 2, "{}", stats);
+        assert_eq!(stats["variants"].as_array().unwrap().len(), 1);
+        assert_eq!(stats["variants"][0]["served"], 2);
+        assert_eq!(stats["variants"][0]["params"]["width"], 10);
+    }
+
+    #[tokio::test]
+    async fn normalized_ids_share_cache_entry() {
+        let (origin, requests) = testing::counting_origin(testing::png(8, 8), Duration::ZERO).await;
+        let base = testing::serve(testing::config(&[
+            ("BASE_FILE_API_URL", &origin),
+            ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+            ("IMAGE_ID_LOWERCASE", "true"),
+            ("CACHE_STATUS_HEADERS", "true"),
+        ]))
+        .await;
+
+        for (id, cache_status) in [("Photo.png", "MISS"), ("%20photo.PNG%20", "HIT")] {
+            let response =
+                testing::get(format!("{}/images/{}?extension=PNG&width=4", base, id)).await;
+            assert_eq!(response.status(), 200, "{}", id);
+            assert_eq!(
+                response.headers()[CACHE_STATUS_HEADER],
+                cache_status,
+                "{}",
+                id
+            );
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn dominant_color_of_mostly_red_image() {
+        let image = RgbaImage::from_fn(50, 50, |x, _| match x {
+            0..40 => Rgba([230, 20, 20, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        });
+        let config = testing::config(&[]);
+        let data = testing::encode(&DynamicImage::ImageRgba8(image), ImageFormat::Png);
+        testing::preload(&config, "red", data).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!("{}/images/red/color", base)).await;
+        assert_eq!(response.status(), 200);
+        let colors = testing::json(response).await;
+        let dominant = &colors["palette"][0];
+        let hex = dominant["color"].as_str().unwrap();
+        let channel = |idx: usize| u8::from_str_radix(&hex[1 + idx * 2..3 + idx * 2], 16).unwrap();
+        assert!(
+            channel(0) > 200 && channel(1) < 60 && channel(2) < 60,
+            "{}",
+            colors
+        );
+        assert!(dominant["share"].as_f64().unwrap() > 0.7, "{}", colors);
+    }
 }
@@ -1,4 +1,8 @@
+pub mod access_log;
+pub mod concurrency;
 pub mod errors;
 pub mod images;
 pub mod openapi;
+pub mod request_id;
 mod responses;
+pub mod service;
@@ -1,5 +1,13 @@
 use aide::openapi::OpenApi;
 use axum::Extension;
+use axum::body::{Body, to_bytes};
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::Write;
 use std::sync::Arc;
 
 pub async fn openapi_json(Extension(openapi): Extension<Arc<OpenApi>>) -> axum::response::Response {
@@ -10,3 +18,141 @@ pub async fn openapi_json(Extension(openapi): Extension<Arc<OpenApi>>) -> axum::
         ))
         .unwrap()
 }
+
+#[derive(Clone, Copy, PartialEq)]
+enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            // http "deflate" is zlib format
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Pick supported encoding from Accept-Encoding, gzip is preferred on equal weights
+fn negotiate_encoding(headers: &HeaderMap) -> Option<ContentEncoding> {
+    let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let encoding = match parts.next().unwrap_or("").to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => ContentEncoding::Gzip,
+            "deflate" => ContentEncoding::Deflate,
+            _ => continue,
+        };
+        let weight = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if weight <= 0.0 {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some((best_encoding, best_weight)) => {
+                weight > best_weight
+                    || (weight == best_weight
+                        && encoding == ContentEncoding::Gzip
+                        && best_encoding != ContentEncoding::Gzip)
+            }
+        };
+        if better {
+            best = Some((encoding, weight));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compress docs responses (spec and swagger page) according to Accept-Encoding.
+///
+/// Should not be used for images, they are already compressed
+pub async fn compress(request: Request, next: Next) -> Response {
+    let encoding = negotiate_encoding(request.headers());
+    let response = next.run(request).await;
+
+    let encoding = match encoding {
+        Some(encoding) if !response.headers().contains_key(header::CONTENT_ENCODING) => encoding,
+        _ => return response,
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let data = match to_bytes(body, usize::MAX).await {
+        Ok(data) => data,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let compressed = match encoding.encode(&data) {
+        Ok(compressed) => compressed,
+        Err(_) => return Response::from_parts(parts, Body::from(data)),
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    parts.headers.append(
+        header::VARY,
+        HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+    );
+    Response::from_parts(parts, Body::from(compressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::testing;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn spec_is_gzipped_on_request() {
+        let config = testing::config(&[]);
+        testing::preload(&config, "plain", testing::png(8, 8)).await;
+        let base = testing::serve(config).await;
+
+        let response = testing::client()
+            .get(format!("{}/openapi.json", base))
+            .header("Accept-Encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+        let mut spec = String::new();
+        GzDecoder::new(response.bytes().await.unwrap().as_ref())
+            .read_to_string(&mut spec)
+            .unwrap();
+        let spec: serde_json::Value = serde_json::from_str(&spec).unwrap();
+        assert!(spec["paths"].get("/images/{id}").is_some());
+
+        let response = testing::client()
+            .get(format!("{}/images/plain", base))
+            .header("Accept-Encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("Content-Encoding").is_none());
+    }
+}
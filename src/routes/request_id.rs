@@ -0,0 +1,24 @@
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Ensure every request has `X-Request-Id` (keeping one, set by client or proxy) and echo it in response.
+///
+/// Id is forwarded to file api, to correlate requests across the hop
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = match request.headers().get(REQUEST_ID_HEADER) {
+        Some(id) if !id.is_empty() => id.clone(),
+        _ => {
+            let id = HeaderValue::from_str(&format!("{:016x}", fastrand::u64(..))).unwrap();
+            request.headers_mut().insert(REQUEST_ID_HEADER, id.clone());
+            id
+        }
+    };
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    response
+}
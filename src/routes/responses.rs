@@ -1,18 +1,30 @@
-use crate::routes::errors::ErrorResponse;
+use crate::routes::errors::{ErrorResponse, FieldError};
 use aide::OperationOutput;
 use aide::generate::GenContext;
 use aide::openapi::{MediaType, Operation, Response as OpenApiResponse};
 use axum::Json;
 use axum::body::Body;
 use axum::response::IntoResponse;
-use http::{Response, StatusCode};
+use http::{Response, StatusCode, header};
 use indexmap::IndexMap;
+use schemars::JsonSchema;
 use serde::Serialize;
+use std::marker::PhantomData;
 
 pub(crate) struct ApiError<T> {
     status: StatusCode,
     detail: String,
     error_type: Option<T>,
+    fields: Vec<FieldError>,
+    /// Seconds, after which request can be retried (for throttling errors)
+    retry_after: Option<u64>,
+}
+
+impl<T> ApiError<T> {
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
 }
 
 impl<T: Serialize> IntoResponse for ApiError<T> {
@@ -20,8 +32,15 @@ impl<T: Serialize> IntoResponse for ApiError<T> {
         let payload = ErrorResponse {
             detail: self.detail,
             error_type: self.error_type,
+            fields: self.fields,
         };
-        (self.status, Json(payload)).into_response()
+        let mut response = (self.status, Json(payload)).into_response();
+        if let Some(seconds) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.into());
+        }
+        response
     }
 }
 
@@ -79,11 +98,53 @@ impl OperationOutput for ImageResponse {
     }
 }
 
+/// Binary image, which is also served as json (`Accept: application/json`)
+pub(crate) struct ImageOrJsonResponse<T>(PhantomData<T>);
+
+impl<T: JsonSchema> OperationOutput for ImageOrJsonResponse<T> {
+    type Inner = ();
+
+    fn operation_response(
+        ctx: &mut GenContext,
+        operation: &mut Operation,
+    ) -> Option<OpenApiResponse> {
+        let mut response = ImageResponse::operation_response(ctx, operation)?;
+        if let Some(json) = Json::<T>::operation_response(ctx, operation) {
+            response.content.extend(json.content);
+        }
+        Some(response)
+    }
+
+    fn inferred_responses(
+        _ctx: &mut GenContext,
+        _operation: &mut Operation,
+    ) -> Vec<(Option<u16>, OpenApiResponse)> {
+        Vec::new()
+    }
+}
+
 pub fn api_error<T>(status: StatusCode, detail: String, error_type: Option<T>) -> ApiError<T> {
     ApiError {
         status,
         detail,
         error_type,
+        fields: Vec::new(),
+        retry_after: None,
+    }
+}
+
+/// Error of invalid request params, listing all of them at once
+pub fn invalid_params<T>(fields: Vec<FieldError>, error_type: Option<T>) -> ApiError<T> {
+    ApiError {
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+        detail: fields
+            .iter()
+            .map(FieldError::to_string)
+            .collect::<Vec<_>>()
+            .join("; "),
+        error_type,
+        fields,
+        retry_after: None,
     }
 }
 
@@ -91,5 +152,6 @@ pub fn ok_json<T>(detail: String) -> Json<ErrorResponse<T>> {
     Json(ErrorResponse {
         detail,
         error_type: None,
+        fields: Vec::new(),
     })
 }
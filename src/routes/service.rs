@@ -0,0 +1,171 @@
+use crate::config::Config;
+use crate::image_ops::image_types::Extensions;
+use aide::transform::TransformOperation;
+use axum::Json;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize, JsonSchema)]
+pub struct ServiceInfo {
+    pub name: String,
+    pub version: String,
+    /// Swagger UI location, if docs are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docs: Option<String>,
+    /// OpenAPI spec location, if docs are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub openapi: Option<String>,
+}
+
+/// Service info, also usable as health check
+pub async fn root(State(state): State<Arc<Config>>) -> Json<ServiceInfo> {
+    Json(ServiceInfo {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        docs: state.enable_docs.then(|| "/docs".to_string()),
+        openapi: state.enable_docs.then(|| "/openapi.json".to_string()),
+    })
+}
+
+pub fn root_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Service name, version and docs links. Can be used as health check.")
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct VersionInfo {
+    pub version: String,
+    pub git_commit: String,
+    /// Output extensions, which passed encoders self-check on startup
+    pub encoders: Vec<Extensions>,
+}
+
+/// Deployed version and available encoders
+pub async fn version(State(state): State<Arc<Config>>) -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: env!("GIT_COMMIT").to_string(),
+        encoders: state.processor.available_extensions().to_vec(),
+    })
+}
+
+pub fn version_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description("Service version, git commit and available output encoders.")
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct FormatSelfTest {
+    pub extension: Extensions,
+    pub passed: bool,
+    /// Time of decoding, resizing and encoding sample
+    pub millis: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SelfTestReport {
+    /// All formats passed
+    pub passed: bool,
+    pub formats: Vec<FormatSelfTest>,
+}
+
+/// Process bundled sample image into every served extension
+pub async fn self_test(State(state): State<Arc<Config>>) -> (StatusCode, Json<SelfTestReport>) {
+    let formats: Vec<FormatSelfTest> = state
+        .processor
+        .self_test()
+        .await
+        .iter()
+        .map(|result| FormatSelfTest {
+            extension: result.extension,
+            passed: result.error.is_none(),
+            millis: result.duration.as_secs_f64() * 1000.0,
+            error: result.error.clone(),
+        })
+        .collect();
+    let passed = formats.iter().all(|format| format.passed);
+    let status = match passed {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(SelfTestReport { passed, formats }))
+}
+
+pub fn self_test_docs(op: TransformOperation<'_>) -> TransformOperation<'_> {
+    op.description(
+        "Decode, resize and encode bundled sample image into every served extension, \
+        reporting result and time per extension. Responds 503 if any of them failed. \
+        Result is cached for 5 seconds.",
+    )
+    .response_with::<200, Json<SelfTestReport>, _>(|res| res.description("All formats passed"))
+    .response_with::<503, Json<SelfTestReport>, _>(|res| res.description("Some formats failed"))
+}
+
+/// There is no favicon, but browsers shouldn't request it on every page open
+pub async fn favicon() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [(header::CACHE_CONTROL, "public, max-age=86400")],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::testing;
+
+    #[tokio::test]
+    async fn root_reports_version() {
+        let base = testing::serve(testing::config(&[])).await;
+
+        let response = testing::get(format!("{}/", base)).await;
+        assert_eq!(response.status(), 200);
+        let info = testing::json(response).await;
+        assert_eq!(info["name"], env!("CARGO_PKG_NAME"));
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["docs"], "/docs");
+
+        let response = testing::get(format!("{}/favicon.ico", base)).await;
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn version_matches_package() {
+        let config = testing::config(&[]);
+        let encoders = serde_json::to_value(config.processor.available_extensions()).unwrap();
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!("{}/version", base)).await;
+        assert_eq!(response.status(), 200);
+        let info = testing::json(response).await;
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["git_commit"], env!("GIT_COMMIT"));
+        assert_eq!(info["encoders"], encoders);
+    }
+
+    #[tokio::test]
+    async fn self_test_passes_for_all_encoders() {
+        let config = testing::config(&[]);
+        let encoders = serde_json::to_value(config.processor.available_extensions()).unwrap();
+        let base = testing::serve(config).await;
+
+        let response = testing::get(format!("{}/selftest", base)).await;
+        assert_eq!(response.status(), 200);
+        let report = testing::json(response).await;
+        assert_eq!(report["passed"], true);
+        let formats = report["formats"].as_array().unwrap();
+        let extensions: Vec<_> = formats.iter().map(|format| &format["extension"]).collect();
+        assert_eq!(serde_json::to_value(extensions).unwrap(), encoders);
+        for format in formats {
+            assert_eq!(format["passed"], true, "{}", format);
+            assert!(format.get("error").is_none(), "{}", format);
+        }
+
+        // report is reused within ttl
+        let response = testing::get(format!("{}/selftest", base)).await;
+        assert_eq!(testing::json(response).await, report);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::image_ops::operations::ProcessingParams;
+use crate::utils::types::ImageId;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Count of image ids to keep stats for, least recently served ones are evicted
+const STATS_CAPACITY: usize = 16384;
+
+#[derive(Default)]
+struct ImageStatsEntry {
+    variants: BTreeMap<ProcessingParams, u64>,
+    bytes_served: u64,
+    last_access: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct VariantStats {
+    pub params: ProcessingParams,
+    pub served: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ImageStats {
+    /// Times image was served (in any variant)
+    pub served: u64,
+    pub bytes_served: u64,
+    /// Unix timestamp (in seconds) of last serving
+    pub last_access: u64,
+    pub variants: Vec<VariantStats>,
+}
+
+/// Lightweight per image serving counters, to find hot and cold images.
+///
+/// Kept in memory only, so they are reset on restart
+pub struct ImageStatsTracker {
+    entries: quick_cache::sync::Cache<ImageId, Arc<Mutex<ImageStatsEntry>>>,
+}
+
+impl ImageStatsTracker {
+    pub fn new() -> Self {
+        ImageStatsTracker {
+            entries: quick_cache::sync::Cache::new(STATS_CAPACITY),
+        }
+    }
+
+    pub fn record(&self, image_id: &ImageId, params: &ProcessingParams, bytes: usize) {
+        let Ok(entry) = self.entries.get_or_insert_with(image_id, || {
+            Ok::<_, Infallible>(Arc::new(Mutex::new(ImageStatsEntry::default())))
+        });
+
+        let mut entry = entry.lock().unwrap();
+        *entry.variants.entry(params.clone()).or_default() += 1;
+        entry.bytes_served += bytes as u64;
+        entry.last_access = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+    }
+
+    pub fn get(&self, image_id: &ImageId) -> Option<ImageStats> {
+        let entry = self.entries.peek(image_id)?;
+        let entry = entry.lock().unwrap();
+        Some(ImageStats {
+            served: entry.variants.values().sum(),
+            bytes_served: entry.bytes_served,
+            last_access: entry.last_access,
+            variants: entry
+                .variants
+                .iter()
+                .map(|(params, served)| VariantStats {
+                    params: params.clone(),
+                    served: *served,
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Default for ImageStatsTracker {
+    fn default() -> Self {
+        ImageStatsTracker::new()
+    }
+}
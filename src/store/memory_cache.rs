@@ -0,0 +1,148 @@
+use crate::image_ops::operations::ProcessingParams;
+use crate::utils::types::ImageId;
+use quick_cache::sync::DefaultLifecycle;
+use quick_cache::{DefaultHashBuilder, Lifecycle, OptionsBuilder, UnitWeighter};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Image ids, which memory caches never evict (like logos or default avatars)
+#[derive(Clone, Default)]
+pub struct PinnedImages(Arc<HashSet<ImageId>>);
+
+impl PinnedImages {
+    pub fn new(image_ids: impl IntoIterator<Item = ImageId>) -> Self {
+        PinnedImages(Arc::new(image_ids.into_iter().collect()))
+    }
+
+    pub fn contains(&self, image_id: &str) -> bool {
+        !self.0.is_empty() && self.0.contains(image_id)
+    }
+}
+
+/// Key of cache, holding data of single image
+pub trait ImageKey {
+    fn image_id(&self) -> &str;
+}
+
+impl ImageKey for ImageId {
+    fn image_id(&self) -> &str {
+        self
+    }
+}
+
+impl ImageKey for (ImageId, ProcessingParams) {
+    fn image_id(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Cache lifecycle, which protects entries of pinned images from eviction.
+///
+/// Pinned entries still take capacity, so it should be larger than count of pinned entries
+pub struct PinningLifecycle<K, V> {
+    pinned: PinnedImages,
+    default: DefaultLifecycle<K, V>,
+}
+
+impl<K, V> Clone for PinningLifecycle<K, V> {
+    fn clone(&self) -> Self {
+        PinningLifecycle {
+            pinned: self.pinned.clone(),
+            default: self.default.clone(),
+        }
+    }
+}
+
+impl<K: ImageKey, V> Lifecycle<K, V> for PinningLifecycle<K, V> {
+    type RequestState = <DefaultLifecycle<K, V> as Lifecycle<K, V>>::RequestState;
+
+    fn is_pinned(&self, key: &K, _val: &V) -> bool {
+        self.pinned.contains(key.image_id())
+    }
+
+    fn begin_request(&self) -> Self::RequestState {
+        self.default.begin_request()
+    }
+
+    fn on_evict(&self, state: &mut Self::RequestState, key: K, val: V) {
+        self.default.on_evict(state, key, val)
+    }
+}
+
+/// Settings, shared by memory caches of originals and processed images
+#[derive(Clone, Default)]
+pub struct MemoryCacheOptions {
+    /// Images, which are never evicted
+    pub pinned: PinnedImages,
+    /// Count of independently locked parts of cache. More shards lower lock contention on many cores,
+    /// but each one holds smaller part of capacity. None is 4 per core
+    pub shards: Option<usize>,
+    /// Part of capacity (0-1) for frequently used entries, the rest is for recent ones. None is 0.97
+    pub hot_allocation: Option<f64>,
+}
+
+/// Memory cache by image, never evicting pinned images
+pub type MemoryCache<K, V> =
+    quick_cache::sync::Cache<K, V, UnitWeighter, DefaultHashBuilder, PinningLifecycle<K, V>>;
+
+pub fn memory_cache<K, V>(capacity: usize, options: &MemoryCacheOptions) -> MemoryCache<K, V>
+where
+    K: ImageKey + Eq + std::hash::Hash + Clone,
+    V: Clone,
+{
+    let mut builder = OptionsBuilder::new();
+    builder
+        .estimated_items_capacity(capacity)
+        .weight_capacity(capacity as u64);
+    if let Some(shards) = options.shards {
+        builder.shards(shards);
+    }
+    if let Some(hot_allocation) = options.hot_allocation {
+        builder.hot_allocation(hot_allocation);
+    }
+    quick_cache::sync::Cache::with_options(
+        builder.build().expect("Capacity of memory cache is set"),
+        UnitWeighter,
+        DefaultHashBuilder::default(),
+        PinningLifecycle {
+            pinned: options.pinned.clone(),
+            default: DefaultLifecycle::default(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::params;
+
+    #[test]
+    fn pinned_images_survive_eviction() {
+        let options = MemoryCacheOptions {
+            pinned: PinnedImages::new(["logo".to_string()]),
+            shards: Some(1),
+            ..Default::default()
+        };
+        let originals: MemoryCache<ImageId, u32> = memory_cache(4, &options);
+        let processed: MemoryCache<(ImageId, ProcessingParams), u32> = memory_cache(4, &options);
+
+        let insert = |index: u32| {
+            originals.insert(format!("image-{}", index), index);
+            processed.insert((format!("image-{}", index), params("width=10")), index);
+        };
+        // cache is filled before pinned entry, so it isn't kept as the oldest one
+        (1..10).for_each(insert);
+        originals.insert("logo".to_string(), 0);
+        processed.insert(("logo".to_string(), params("width=10")), 0);
+        (10..100).for_each(insert);
+
+        assert_eq!(originals.get("logo"), Some(0));
+        assert_eq!(
+            processed.get(&("logo".to_string(), params("width=10"))),
+            Some(0)
+        );
+        // the rest are evicted to fit capacity
+        assert!(originals.len() <= 4);
+        assert!(processed.len() <= 4);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod image_stats;
+pub mod memory_cache;
 pub mod persistent_store;
 pub mod procesessed_persistent_cache;
 pub mod processed_cache;
@@ -1,13 +1,15 @@
 use crate::utils::background::BackgroundService;
 use async_trait::async_trait;
 use fjall::{Keyspace, KeyspaceCreateOptions, PersistMode, Slice};
-use log::{debug, warn};
+use log::{debug, info, warn};
 use postcard::to_stdvec;
 use serde::Serialize;
 use std::num::NonZeroUsize;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use strum::IntoEnumIterator;
 use strum::{Display, EnumIter, EnumString};
 use tokio::sync::watch::Receiver;
@@ -16,17 +18,20 @@ use tokio::task::spawn_blocking;
 #[derive(Debug, EnumString, Display, EnumIter)]
 pub enum PersistSpace {
     Storage,
+    StorageTtl,
     Cache,
     CacheEntries,
 }
 
 const PERSISTENT_STORAGE_KEYSPACE: &str = "storage";
+const PERSISTENT_STORAGE_TTL_KEYSPACE: &str = "storage_ttl";
 const PERSISTENT_CACHE_KEYSPACE: &str = "cache";
 const PERSISTENT_CACHE_ENTRIES_KEYSPACE: &str = "cache_entries";
 
 pub struct PersistentStore {
     db: fjall::Database,
     store_keyspace: Keyspace,
+    store_ttl_keyspace: Keyspace,
     cache_keyspace: Keyspace,
     cache_entries_keyspace: Keyspace,
 }
@@ -54,6 +59,7 @@ impl PersistentStore {
             .unwrap();
 
         let mut storage_keyspace: Option<Keyspace> = None;
+        let mut storage_ttl_keyspace: Option<Keyspace> = None;
         let mut cache_keyspace: Option<Keyspace> = None;
         let mut cache_entries_keyspace: Option<Keyspace> = None;
         for key in PersistSpace::iter() {
@@ -64,6 +70,15 @@ impl PersistentStore {
                             .unwrap(),
                     );
                 }
+                PersistSpace::StorageTtl => {
+                    storage_ttl_keyspace = Some(
+                        db.keyspace(
+                            PERSISTENT_STORAGE_TTL_KEYSPACE,
+                            KeyspaceCreateOptions::default,
+                        )
+                        .unwrap(),
+                    );
+                }
                 PersistSpace::Cache => {
                     cache_keyspace = Some(
                         db.keyspace(PERSISTENT_CACHE_KEYSPACE, KeyspaceCreateOptions::default)
@@ -85,6 +100,7 @@ impl PersistentStore {
         PersistentStore {
             db,
             store_keyspace: storage_keyspace.unwrap(),
+            store_ttl_keyspace: storage_ttl_keyspace.unwrap(),
             cache_keyspace: cache_keyspace.unwrap(),
             cache_entries_keyspace: cache_entries_keyspace.unwrap(),
         }
@@ -93,6 +109,7 @@ impl PersistentStore {
     fn keyspace(&self, space: PersistSpace) -> Keyspace {
         match space {
             PersistSpace::Storage => self.store_keyspace.clone(),
+            PersistSpace::StorageTtl => self.store_ttl_keyspace.clone(),
             PersistSpace::Cache => self.cache_keyspace.clone(),
             PersistSpace::CacheEntries => self.cache_entries_keyspace.clone(),
         }
@@ -135,24 +152,20 @@ impl PersistentStore {
             .unwrap();
     }
 
-    pub async fn remove_by_prefix<K>(&self, space: PersistSpace, prefix: &K)
-    where
-        K: Serialize + Send + Sync + 'static,
-    {
+    /// Values of all records in space, loaded at once, so it's meant for small ones (like cache entries)
+    pub async fn values(&self, space: PersistSpace) -> Vec<Slice> {
         let keyspace = self.keyspace(space);
 
-        let key = to_stdvec(&prefix).unwrap();
-
         spawn_blocking(move || {
-            for key in keyspace.prefix(key) {
-                let _ = keyspace.remove(key.key().unwrap());
-            }
+            keyspace
+                .iter()
+                .filter_map(|item| item.value().ok())
+                .collect()
         })
         .await
-        .unwrap();
+        .unwrap()
     }
 
-    #[allow(dead_code)]
     pub async fn remove<K>(&self, space: PersistSpace, key: &K)
     where
         K: Serialize + Send + Sync + 'static,
@@ -222,3 +235,192 @@ impl BackgroundService for StorageBackgroundAdapter {
         }
     }
 }
+
+/// Range of UTC hours `start-end` (end exclusive, may wrap midnight, like `22-4`)
+#[derive(Clone, Copy)]
+pub struct HoursWindow {
+    start: u8,
+    end: u8,
+}
+
+impl HoursWindow {
+    pub fn contains(&self, hour: u8) -> bool {
+        match self.start <= self.end {
+            true => (self.start..self.end).contains(&hour),
+            false => hour >= self.start || hour < self.end,
+        }
+    }
+}
+
+pub struct ParseHoursWindowError {
+    #[allow(dead_code)]
+    msg: String,
+}
+
+impl FromStr for HoursWindow {
+    type Err = ParseHoursWindowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once('-')
+            .and_then(|(start, end)| Some((start.trim().parse().ok()?, end.trim().parse().ok()?)))
+            .filter(|(start, end): &(u8, u8)| *start < 24 && *end <= 24 && start != end)
+            .map(|(start, end)| HoursWindow { start, end })
+            .ok_or(ParseHoursWindowError {
+                msg: format!("Expected hours range \"start-end\" (0-24), got {}", s),
+            })
+    }
+}
+
+/// When to compact persistent store
+#[derive(Clone, Copy)]
+pub struct CompactionSchedule {
+    /// Min time between compactions
+    pub interval: Duration,
+    /// Low traffic hours, compaction is postponed until them
+    pub hours: Option<HoursWindow>,
+}
+
+/// Max time between checks, whether compaction is due
+const COMPACTION_CHECK_PERIOD: Duration = Duration::from_secs(15 * 60);
+
+/// Compacts keyspaces, dropping tombstones of evicted and invalidated entries, which are
+/// left on disk (and slow down reads) after rewriting by lsm tree
+pub struct CompactionService {
+    store: Arc<PersistentStore>,
+    schedule: CompactionSchedule,
+    last_compaction: Instant,
+    /// Compaction runs detached, so stop is not blocked by it
+    running: Arc<AtomicBool>,
+    cancel_chan: (
+        tokio::sync::watch::Sender<bool>,
+        tokio::sync::watch::Receiver<bool>,
+    ),
+}
+
+impl CompactionService {
+    pub fn new(store: Arc<PersistentStore>, schedule: CompactionSchedule) -> Self {
+        CompactionService {
+            store,
+            schedule,
+            last_compaction: Instant::now(),
+            running: Arc::new(AtomicBool::new(false)),
+            cancel_chan: tokio::sync::watch::channel(false),
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        if self.last_compaction.elapsed() < self.schedule.interval {
+            return false;
+        }
+        let Some(hours) = self.schedule.hours else {
+            return true;
+        };
+        let hour = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| (d.as_secs() / 3600 % 24) as u8)
+            .unwrap_or_default();
+        hours.contains(hour)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CompactionService {
+    fn background_period(&self) -> Duration {
+        self.schedule.interval.min(COMPACTION_CHECK_PERIOD)
+    }
+
+    async fn background(&mut self) {
+        if !self.is_due() || self.running.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.last_compaction = Instant::now();
+
+        let store = self.store.clone();
+        let running = self.running.clone();
+        let cancel_token = self.cancel_chan.1.clone();
+        spawn_blocking(move || {
+            let started = Instant::now();
+            let disk_space_before = store.db.disk_space().unwrap_or_default();
+            for space in PersistSpace::iter() {
+                if *cancel_token.borrow() {
+                    info!("Compaction is cancelled before {} keyspace", space);
+                    break;
+                }
+                if let Err(err) = store.keyspace(space).major_compact() {
+                    warn!("Failed to compact keyspace, got error: {}", err);
+                }
+            }
+            info!(
+                "Compacted persistent store in {:?}: {} -> {} bytes",
+                started.elapsed(),
+                disk_space_before,
+                store.db.disk_space().unwrap_or_default()
+            );
+            running.store(false, Ordering::Release);
+        });
+    }
+
+    fn cancel_token(&self) -> Receiver<bool> {
+        self.cancel_chan.1.clone()
+    }
+
+    async fn stop(&mut self) {
+        let _ = self.cancel_chan.0.send(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    #[tokio::test]
+    async fn compaction_drops_removed_records() {
+        let path = testing::temp_path("compaction");
+        let _ = std::fs::remove_dir_all(&path);
+        let capacity = NonZeroUsize::new(16).unwrap();
+        let store = Arc::new(PersistentStore::new(
+            Path::new(&path).into(),
+            capacity,
+            capacity,
+        ));
+        let keyspace = store.keyspace(PersistSpace::Storage);
+        // records and their tombstones are flushed into separate segments, like evictions long after inserts
+        for batch in 0..4 {
+            for key in 0..50 {
+                let key = format!("{}-{}", batch, key);
+                store
+                    .set(PersistSpace::Storage, &key, &[7; 16 * 1024])
+                    .await;
+            }
+            keyspace.rotate_memtable_and_wait().unwrap();
+            for key in 0..50 {
+                let key = format!("{}-{}", batch, key);
+                store.remove(PersistSpace::Storage, &key).await;
+            }
+            keyspace.rotate_memtable_and_wait().unwrap();
+        }
+        let disk_space_before = keyspace.disk_space();
+
+        let mut service = CompactionService::new(
+            store.clone(),
+            CompactionSchedule {
+                interval: Duration::ZERO,
+                hours: None,
+            },
+        );
+        service.background().await;
+        while service.running.load(Ordering::Acquire) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(
+            keyspace.disk_space() < disk_space_before / 2,
+            "{} -> {}",
+            disk_space_before,
+            keyspace.disk_space()
+        );
+        drop((service, keyspace, store));
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -1,26 +1,64 @@
-use crate::config::ImageOptionsOverflowPolicy;
+use crate::config::{ImageOptionsOverflowPolicy, MaxOptionsPerImage};
 use crate::image_ops::operations::ProcessingParams;
 use crate::store::persistent_store::{PersistSpace, PersistentStore};
-use crate::store::processed_cache::ProcessedImagesCache;
+use crate::store::processed_cache::{ProcessedImagesCache, PurgedVariants, VariantFilter};
 use crate::utils::background::BackgroundService;
+use crate::utils::striped_lock::StripedLock;
 use crate::utils::types::{ImageContainer, ImageId};
 use async_trait::async_trait;
 use image::EncodableLayout;
+use log::{info, warn};
 use postcard::to_stdvec;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
-use tokio::sync::Mutex;
 use tokio::sync::watch::Receiver;
+use tokio::sync::{Mutex, OnceCell};
 
 /// Custom key serialization into memory to surely correct work over lsm-tree
-///
-/// Also, remove method is depends on current structure, so be careful on refactoring
 fn cache_key(image_id: &ImageId, params: &ProcessingParams) -> String {
     format!("{}_{}", &image_id, serde_json::to_string(&params).unwrap())
 }
 
+/// Count of processed versions, evicted by `MAX_PROCESSED_VARIANTS` limit since startup
+pub static GLOBAL_LIMIT_EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Recency of processed versions across all images, used to evict the least recently used ones
+/// on exceeding global limit of versions count
+#[derive(Default)]
+struct VariantsRecency {
+    ticks: HashMap<String, u64>,
+    order: BTreeMap<u64, (ImageId, ProcessingParams)>,
+    tick: u64,
+}
+
+impl VariantsRecency {
+    fn touch(&mut self, image_id: &ImageId, params: &ProcessingParams) {
+        self.tick += 1;
+        if let Some(prev) = self.ticks.insert(cache_key(image_id, params), self.tick) {
+            self.order.remove(&prev);
+        }
+        self.order
+            .insert(self.tick, (image_id.clone(), params.clone()));
+    }
+
+    fn forget(&mut self, image_id: &ImageId, params: &ProcessingParams) {
+        if let Some(tick) = self.ticks.remove(&cache_key(image_id, params)) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn oldest(&self) -> Option<(ImageId, ProcessingParams)> {
+        self.order.first_key_value().map(|(_, entry)| entry.clone())
+    }
+
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+}
+
 /// Inmemory cache for processed images
 pub struct PersistentProcessedImageCache {
     store: Arc<PersistentStore>,
@@ -28,24 +66,136 @@ pub struct PersistentProcessedImageCache {
         tokio::sync::watch::Sender<bool>,
         tokio::sync::watch::Receiver<bool>,
     ),
-    max_options_per_image: NonZeroUsize,
+    max_options_per_image: MaxOptionsPerImage,
     max_options_per_image_overflow_policy: ImageOptionsOverflowPolicy,
-    write_lock: Arc<Mutex<()>>,
+    write_lock: StripedLock,
+    /// Max count of processed versions of all images, None - unlimited
+    max_variants: Option<NonZeroUsize>,
+    /// Loaded from stored entries on first access, only used with `max_variants`
+    recency: OnceCell<std::sync::Mutex<VariantsRecency>>,
 }
 
 impl PersistentProcessedImageCache {
     pub fn new(
         store: Arc<PersistentStore>,
         _capacity: Option<NonZeroUsize>,
-        max_options_per_image: NonZeroUsize,
+        max_options_per_image: MaxOptionsPerImage,
         max_options_per_image_overflow_policy: ImageOptionsOverflowPolicy,
+        max_variants: Option<NonZeroUsize>,
     ) -> Self {
         PersistentProcessedImageCache {
             store,
             cancel_chan: tokio::sync::watch::channel(false),
             max_options_per_image,
             max_options_per_image_overflow_policy,
-            write_lock: Arc::new(Mutex::new(())),
+            write_lock: StripedLock::default(),
+            max_variants,
+            recency: OnceCell::new(),
+        }
+    }
+
+    /// Recency of stored versions. Access order isn't persisted,
+    /// so versions stored before restart are considered the least recent ones
+    async fn recency(&self) -> &std::sync::Mutex<VariantsRecency> {
+        self.recency
+            .get_or_init(|| async {
+                let mut recency = VariantsRecency::default();
+                for entries in self.store.values(PersistSpace::CacheEntries).await {
+                    let Ok(entries) = postcard::from_bytes::<BTreeSet<(ImageId, ProcessingParams)>>(
+                        entries.as_bytes(),
+                    ) else {
+                        continue;
+                    };
+                    for (image_id, params) in entries.iter() {
+                        recency.touch(image_id, params);
+                    }
+                }
+                if let Some(max_variants) = self.max_variants {
+                    info!(
+                        "Persistent processing cache holds {} versions (limit {})",
+                        recency.len(),
+                        max_variants
+                    );
+                }
+                std::sync::Mutex::new(recency)
+            })
+            .await
+    }
+
+    /// Forget removed versions in recency, if it's tracked
+    fn forget(&self, removed: &[(ImageId, ProcessingParams)]) {
+        if let Some(recency) = self.recency.get() {
+            let mut recency = recency.lock().unwrap();
+            for (image_id, params) in removed {
+                recency.forget(image_id, params);
+            }
+        }
+    }
+
+    /// Evict the least recently used versions of any images, until global limit is satisfied
+    ///
+    /// Versions of images, which are being written right now, aren't evicted to keep their entries
+    /// consistent, so limit may be exceeded for a moment, until the next insertion
+    async fn evict_over_limit(&self, image_id: &ImageId) {
+        let Some(max_variants) = self.max_variants else {
+            return;
+        };
+        let own_lock = self.set_lock(image_id);
+        loop {
+            let (victim_id, victim_params, count) = {
+                let recency = self.recency().await.lock().unwrap();
+                if recency.len() <= max_variants.get() {
+                    return;
+                }
+                let Some((victim_id, victim_params)) = recency.oldest() else {
+                    return;
+                };
+                (victim_id, victim_params, recency.len())
+            };
+
+            // lock of the current image is already held by caller
+            let victim_lock = self.set_lock(&victim_id);
+            let _guard = match Arc::ptr_eq(&victim_lock, &own_lock) {
+                true => None,
+                false => match victim_lock.try_lock() {
+                    Ok(guard) => Some(guard),
+                    Err(_) => return,
+                },
+            };
+
+            self.store
+                .remove(PersistSpace::Cache, &cache_key(&victim_id, &victim_params))
+                .await;
+            let entries = self.store.get(PersistSpace::CacheEntries, &victim_id).await;
+            let mut entries: BTreeSet<(ImageId, ProcessingParams)> = match entries {
+                None => BTreeSet::new(),
+                Some(slice) => postcard::from_bytes(slice.as_bytes()).unwrap_or_default(),
+            };
+            entries.remove(&(victim_id.clone(), victim_params.clone()));
+            match entries.is_empty() {
+                true => {
+                    self.store
+                        .remove(PersistSpace::CacheEntries, &victim_id)
+                        .await
+                }
+                false => {
+                    let entries_bytes = to_stdvec(&entries).unwrap();
+                    self.store
+                        .set(
+                            PersistSpace::CacheEntries,
+                            &victim_id,
+                            entries_bytes.as_slice(),
+                        )
+                        .await
+                }
+            }
+            self.forget(&[(victim_id.clone(), victim_params)]);
+
+            let total = GLOBAL_LIMIT_EVICTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "Processed versions limit is reached ({} versions), the least recent version of image {} is evicted. Total evictions: {}",
+                count, victim_id, total
+            );
         }
     }
 }
@@ -61,15 +211,25 @@ impl ProcessedImagesCache for PersistentProcessedImageCache {
 
         let v = self.store.get(PersistSpace::Cache, &key).await;
 
-        match v {
-            None => None,
-            Some(v) => Some(Arc::new(
-                postcard::from_bytes::<ImageContainer>(v.as_bytes()).unwrap(),
-            )),
+        // entries of previous versions may not match current layout, they are reprocessed then
+        let decoded = postcard::from_bytes::<ImageContainer>(v?.as_bytes());
+        if decoded.is_err() {
+            warn!(
+                "Cached image {} can't be decoded, reprocessing it",
+                image_id
+            );
         }
+        if decoded.is_ok() && self.max_variants.is_some() {
+            self.recency()
+                .await
+                .lock()
+                .unwrap()
+                .touch(&image_id, &params);
+        }
+        decoded.ok().map(Arc::new)
     }
 
-    fn max_options_per_image(&self) -> &NonZeroUsize {
+    fn max_options_per_image(&self) -> &MaxOptionsPerImage {
         &self.max_options_per_image
     }
 
@@ -96,6 +256,7 @@ impl ProcessedImagesCache for PersistentProcessedImageCache {
             let last = entries.pop_last().unwrap();
             let last_key = cache_key(&last.0, &last.1);
             self.store.remove(PersistSpace::Cache, &last_key).await;
+            self.forget(&[last]);
         }
 
         // TODO: prevent postcard parsing unwrap
@@ -112,6 +273,11 @@ impl ProcessedImagesCache for PersistentProcessedImageCache {
                 entries_bytes.as_slice(),
             )
             .await;
+
+        if self.max_variants.is_some() {
+            self.recency().await.lock().unwrap().touch(image_id, params);
+            self.evict_over_limit(image_id).await;
+        }
     }
 
     async fn records_count(&self, image_id: &ImageId) -> usize {
@@ -132,16 +298,99 @@ impl ProcessedImagesCache for PersistentProcessedImageCache {
         self.store.exists(PersistSpace::Cache, &key).await
     }
 
-    fn set_lock(&self) -> Arc<Mutex<()>> {
-        self.write_lock.clone()
+    fn set_lock(&self, image_id: &ImageId) -> Arc<Mutex<()>> {
+        self.write_lock.get(image_id)
     }
 
-    async fn remove(&mut self, image_id: ImageId) {
-        // we build key as {image_id}_{params}, where params - json object, so we can rely on
-        // structure _{, which is pretty unique to use in prefix removal
+    async fn remove(&mut self, image_id: ImageId) -> usize {
+        // entries are authoritative list of image variants, so exact keys are removed instead of
+        // prefix scan, which would also match ids, starting with this one
+        let entries = self.store.get(PersistSpace::CacheEntries, &image_id).await;
+        let entries: BTreeSet<(ImageId, ProcessingParams)> = match entries {
+            None => BTreeSet::new(),
+            Some(slice) => postcard::from_bytes(slice.as_bytes()).unwrap_or_else(|err| {
+                warn!("Can't decode cache entries of {}: {}", image_id, err);
+                BTreeSet::new()
+            }),
+        };
+
+        let mut removed = 0;
+        for (entry_image_id, params) in entries.iter() {
+            let key = cache_key(entry_image_id, params);
+            if self.store.exists(PersistSpace::Cache, &key).await {
+                self.store.remove(PersistSpace::Cache, &key).await;
+                removed += 1;
+            }
+        }
         self.store
-            .remove_by_prefix(PersistSpace::Cache, &format!("{}_{{", &image_id))
+            .remove(PersistSpace::CacheEntries, &image_id)
             .await;
+        self.forget(&entries.into_iter().collect::<Vec<_>>());
+        removed
+    }
+
+    async fn remove_matching(&mut self, filter: VariantFilter<'_>, limit: usize) -> PurgedVariants {
+        let mut removed = 0;
+        let mut images = 0;
+        let mut complete = true;
+        for entries in self.store.values(PersistSpace::CacheEntries).await {
+            let Ok(entries) =
+                postcard::from_bytes::<BTreeSet<(ImageId, ProcessingParams)>>(entries.as_bytes())
+            else {
+                continue;
+            };
+            let Some((image_id, _)) = entries.first().cloned() else {
+                continue;
+            };
+
+            let mut kept = BTreeSet::new();
+            let mut image_removed = 0;
+            for (entry_image_id, params) in entries {
+                if !filter(&params) {
+                    kept.insert((entry_image_id, params));
+                    continue;
+                }
+                if removed >= limit {
+                    complete = false;
+                    kept.insert((entry_image_id, params));
+                    continue;
+                }
+                let key = cache_key(&entry_image_id, &params);
+                self.store.remove(PersistSpace::Cache, &key).await;
+                self.forget(&[(entry_image_id, params)]);
+                removed += 1;
+                image_removed += 1;
+            }
+            if image_removed == 0 {
+                continue;
+            }
+            images += 1;
+            match kept.is_empty() {
+                true => {
+                    self.store
+                        .remove(PersistSpace::CacheEntries, &image_id)
+                        .await
+                }
+                false => {
+                    let entries_bytes = to_stdvec(&kept).unwrap();
+                    self.store
+                        .set(
+                            PersistSpace::CacheEntries,
+                            &image_id,
+                            entries_bytes.as_slice(),
+                        )
+                        .await
+                }
+            }
+            if !complete {
+                break;
+            }
+        }
+        PurgedVariants {
+            removed,
+            images,
+            complete,
+        }
     }
 }
 
@@ -162,3 +411,125 @@ impl BackgroundService for PersistentProcessedImageCache {
         let _ = self.cancel_chan.0.send(true);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_ops::image_types::Extensions;
+    use crate::utils::testing;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn removes_only_variants_of_exact_id() {
+        let path = testing::temp_path("persistent-cache-remove");
+        let _ = std::fs::remove_dir_all(&path);
+        let capacity = NonZeroUsize::new(16).unwrap();
+        let store = Arc::new(PersistentStore::new(
+            Path::new(&path).into(),
+            capacity,
+            capacity,
+        ));
+        let mut cache = PersistentProcessedImageCache::new(
+            store,
+            None,
+            MaxOptionsPerImage::new(capacity, Default::default()),
+            ImageOptionsOverflowPolicy::Rewrite,
+            None,
+        );
+        let image = Arc::new(ImageContainer::new(
+            Box::new(vec![0]),
+            None,
+            Extensions::Webp,
+        ));
+        // key of the second id starts with key prefix of the first one
+        let (image_id, tricky_id) = ("cat".to_string(), "cat_{\"width\"".to_string());
+        for width in [10, 20] {
+            for image_id in [&image_id, &tricky_id] {
+                let params = testing::params(&format!("width={}", width));
+                assert!(
+                    cache
+                        .set(image_id.clone(), params, image.clone(), false)
+                        .await
+                        .is_ok()
+                );
+            }
+        }
+
+        assert_eq!(cache.remove(image_id.clone()).await, 2);
+        assert_eq!(cache.records_count(&image_id).await, 0);
+        assert_eq!(cache.records_count(&tricky_id).await, 2);
+        let params = testing::params("width=10");
+        assert!(cache.get(image_id, params.clone()).await.is_none());
+        assert!(cache.get(tricky_id.clone(), params).await.is_some());
+        assert_eq!(cache.remove(tricky_id).await, 2);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[tokio::test]
+    async fn global_limit_evicts_least_recent_variants_across_images() {
+        let path = testing::temp_path("persistent-cache-global-limit");
+        let _ = std::fs::remove_dir_all(&path);
+        let capacity = NonZeroUsize::new(16).unwrap();
+        let store = Arc::new(PersistentStore::new(
+            Path::new(&path).into(),
+            capacity,
+            capacity,
+        ));
+        let cache = PersistentProcessedImageCache::new(
+            store,
+            None,
+            MaxOptionsPerImage::new(capacity, Default::default()),
+            ImageOptionsOverflowPolicy::Rewrite,
+            NonZeroUsize::new(3),
+        );
+        let image = Arc::new(ImageContainer::new(
+            Box::new(vec![0]),
+            None,
+            Extensions::Webp,
+        ));
+        let set = async |image_id: &str, query: &str| {
+            let params = testing::params(query);
+            assert!(
+                cache
+                    .set(image_id.to_string(), params, image.clone(), false)
+                    .await
+                    .is_ok()
+            );
+        };
+        let count = async |image_id: &str| cache.records_count(&image_id.to_string()).await;
+
+        for image_id in ["first", "second", "third"] {
+            set(image_id, "width=10").await;
+        }
+        // reading makes the first image recent, so the second one is the least recent
+        assert!(
+            cache
+                .get("first".to_string(), testing::params("width=10"))
+                .await
+                .is_some()
+        );
+        let evictions = GLOBAL_LIMIT_EVICTIONS.load(Ordering::Relaxed);
+        set("fourth", "width=10").await;
+        assert_eq!(count("second").await, 0);
+        assert!(
+            cache
+                .get("second".to_string(), testing::params("width=10"))
+                .await
+                .is_none()
+        );
+        for image_id in ["first", "third", "fourth"] {
+            assert_eq!(count(image_id).await, 1, "{}", image_id);
+        }
+
+        set("fourth", "width=20").await;
+        assert_eq!(count("third").await, 0);
+        assert_eq!(count("first").await, 1);
+        assert_eq!(count("fourth").await, 2);
+        assert!(GLOBAL_LIMIT_EVICTIONS.load(Ordering::Relaxed) >= evictions + 2);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -1,24 +1,44 @@
-use crate::config::ImageOptionsOverflowPolicy;
+use crate::config::{ImageOptionsOverflowPolicy, MaxOptionsPerImage};
 use crate::image_ops::operations::ProcessingParams;
 use crate::utils::background::BackgroundService;
 use crate::utils::types::{ImageContainer, ImageId};
 use async_trait::async_trait;
-use std::num::NonZeroUsize;
+use log::{debug, warn};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 use tokio::sync::Mutex;
 
+/// Count of processed versions, evicted by `Rewrite` overflow policy since startup
+pub static OVERFLOW_REWRITES: AtomicU64 = AtomicU64::new(0);
+/// Count of processed versions, rejected by `Restrict` overflow policy since startup
+pub static OVERFLOW_REJECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Result of purging processed versions by params filter
+pub struct PurgedVariants {
+    /// Count of removed versions
+    pub removed: usize,
+    /// Count of images, which had removed versions
+    pub images: usize,
+    /// All matching versions are removed, otherwise limit was reached
+    pub complete: bool,
+}
+
+/// Filter of processed versions by their params
+pub type VariantFilter<'a> = &'a (dyn Fn(&ProcessingParams) -> bool + Send + Sync);
+
 pub struct ProcessingError<'a> {
     pub error: &'a str,
 }
 
 /// Cache for processed images with different params
 #[async_trait]
-pub trait ProcessedImagesCache: BackgroundService {
+pub trait ProcessedImagesCache: BackgroundService + Send + Sync {
     async fn get(&self, image_id: ImageId, params: ProcessingParams)
     -> Option<Arc<ImageContainer>>;
 
     /// Max count of images, stored in the cache per ImageId, this option should be honored in impl
-    fn max_options_per_image(&self) -> &NonZeroUsize;
+    fn max_options_per_image(&self) -> &MaxOptionsPerImage;
 
     /// Policy on overflow of max_options_per_image, this option should be honored in impl
     fn max_options_per_image_overflow_policy(&self) -> &ImageOptionsOverflowPolicy;
@@ -39,50 +59,209 @@ pub trait ProcessedImagesCache: BackgroundService {
 
     async fn have_record(&self, image_id: &ImageId, params: &ProcessingParams) -> bool;
 
-    /// Lock, using for setting value
-    fn set_lock(&self) -> Arc<Mutex<()>>;
+    /// Lock, using for setting value of specified image
+    ///
+    /// Should be shared for the same image id, to keep options count invariant
+    fn set_lock(&self, image_id: &ImageId) -> Arc<Mutex<()>>;
 
+    /// Check, that new processed option of image fits into the limit of options per image
+    ///
+    /// Returns Ok(true), if it will fit only with rewriting the last option
+    async fn check_limit(
+        &self,
+        image_id: &ImageId,
+        params: &ProcessingParams,
+    ) -> Result<bool, ProcessingError> {
+        if self.have_record(image_id, params).await {
+            // key is already there nothing to do.
+            // invalidation for now is made via prefetch (fully invalidating all params options)
+            // , so we don't have to reset the value
+            return Ok(false);
+        }
+        let records_count = self.records_count(image_id).await;
+        if records_count < self.max_options_per_image().for_image(image_id).get() {
+            return Ok(false);
+        }
+        match self.max_options_per_image_overflow_policy() {
+            // overflow case is more like a DOS scenario,
+            // so we neither probit it, or overwrite
+            ImageOptionsOverflowPolicy::Restrict => {
+                let total = OVERFLOW_REJECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Options limit of image {} is reached ({} versions), new version is rejected. Total rejections: {}",
+                    image_id, records_count, total
+                );
+                Err(ProcessingError {
+                    error: "Limit exceed. No any new image formats allowed",
+                })
+            }
+            // use lru cache internally can be better,
+            // but in DOS scenario there is no actual difference.
+            // If it's attempt to DOS after all usual extension for image is required,
+            // we, at least, keep actual using images in cache that way
+            ImageOptionsOverflowPolicy::Rewrite => Ok(true),
+        }
+    }
+
+    /// * `overwrite` - replace already stored processed image, otherwise it's kept
     async fn set(
-        &mut self,
+        &self,
         image_id: ImageId,
         params: ProcessingParams,
         image: Arc<ImageContainer>,
+        overwrite: bool,
     ) -> Result<(), ProcessingError> {
         // without guard, there can be parallel insertions over limit
-        let lock = self.set_lock();
+        let lock = self.set_lock(&image_id);
+        let lock_start = Instant::now();
         let _guard = lock.lock().await;
+        let lock_wait = lock_start.elapsed();
+        if lock_wait.as_millis() > 10 {
+            debug!(
+                "Cache set lock contended: {:?} for image {}",
+                lock_wait, image_id
+            );
+        }
 
-        let records_count = self.records_count(&image_id).await;
-        match self.have_record(&image_id, &params).await {
-            false => {
-                if records_count >= self.max_options_per_image().get() {
-                    match self.max_options_per_image_overflow_policy() {
-                        // overflow case is more like a DOS scenario,
-                        // so we neither probit it, or overwrite
-                        ImageOptionsOverflowPolicy::Restrict => Err(ProcessingError {
-                            error: "Limit exceed. No any new image formats allowed",
-                        }),
-                        ImageOptionsOverflowPolicy::Rewrite => {
-                            // use lru cache internally can be better,
-                            // but in DOS scenario there is no actual difference.
-                            // If it's attempt to DOS after all usual extension for image is required,
-                            // we, at least, keep actual using images in cache that way
-                            self._insert(&image_id, &params, image, true).await;
-                            Ok(())
-                        }
-                    }
-                } else {
-                    self._insert(&image_id, &params, image, false).await;
-                    Ok(())
-                }
-            }
-            // key is already there nothing to do.
-            // invalidation for now is made via prefetch (fully invalidating all params options)
-            // , so we don't have to reset the value
-            true => Ok(()),
+        if !overwrite && self.have_record(&image_id, &params).await {
+            return Ok(());
+        }
+        let pop_last = self.check_limit(&image_id, &params).await?;
+        if pop_last {
+            let total = OVERFLOW_REWRITES.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "Options limit of image {} is reached ({} versions), the last version is evicted. Total evictions: {}",
+                image_id,
+                self.records_count(&image_id).await,
+                total
+            );
         }
+        self._insert(&image_id, &params, image, pop_last).await;
+        Ok(())
     }
 
-    /// Flushes all version of specified image id
-    async fn remove(&mut self, image_id: ImageId);
+    /// Flushes all version of specified image id, returns count of removed versions
+    async fn remove(&mut self, image_id: ImageId) -> usize;
+
+    /// Flushes versions of all images, matching filter, until `limit` versions are removed
+    async fn remove_matching(&mut self, filter: VariantFilter<'_>, limit: usize) -> PurgedVariants;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_ops::image_types::Extensions;
+    use crate::store::memory_cache::MemoryCacheOptions;
+    use crate::store::processed_memory_cache::MemoryProcessedImageCache;
+    use std::num::NonZeroUsize;
+
+    fn cache(
+        max_options: usize,
+        overrides: &str,
+        policy: ImageOptionsOverflowPolicy,
+    ) -> MemoryProcessedImageCache {
+        MemoryProcessedImageCache::new(
+            None,
+            MaxOptionsPerImage::new(
+                NonZeroUsize::new(max_options).unwrap(),
+                overrides.parse().ok().unwrap(),
+            ),
+            policy,
+            &MemoryCacheOptions::default(),
+        )
+    }
+
+    fn image() -> Arc<ImageContainer> {
+        Arc::new(ImageContainer::new(
+            Box::new(vec![0]),
+            None,
+            Extensions::Webp,
+        ))
+    }
+
+    fn width(width: u32) -> ProcessingParams {
+        ProcessingParams {
+            width: Some(width),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn prefixed_image_gets_overridden_limit() {
+        let cache = cache(2, "hero-=4", ImageOptionsOverflowPolicy::Restrict);
+        let hero = ImageId::from("hero-banner");
+        let avatar = ImageId::from("avatar");
+
+        for w in 1..=4 {
+            assert!(
+                cache
+                    .set(hero.clone(), width(w), image(), false)
+                    .await
+                    .is_ok()
+            );
+        }
+        assert!(
+            cache
+                .set(hero.clone(), width(5), image(), false)
+                .await
+                .is_err()
+        );
+        assert_eq!(cache.records_count(&hero).await, 4);
+
+        for w in 1..=2 {
+            assert!(
+                cache
+                    .set(avatar.clone(), width(w), image(), false)
+                    .await
+                    .is_ok()
+            );
+        }
+        assert!(
+            cache
+                .set(avatar.clone(), width(3), image(), false)
+                .await
+                .is_err()
+        );
+        assert_eq!(cache.records_count(&avatar).await, 2);
+    }
+
+    #[tokio::test]
+    async fn overflow_is_counted() {
+        for (policy, counter) in [
+            (ImageOptionsOverflowPolicy::Rewrite, &OVERFLOW_REWRITES),
+            (ImageOptionsOverflowPolicy::Restrict, &OVERFLOW_REJECTIONS),
+        ] {
+            let cache = cache(1, "", policy);
+            let image_id = ImageId::from("hammered");
+            let _ = cache.set(image_id.clone(), width(1), image(), false).await;
+            // counters are shared with concurrently running tests
+            let before = counter.load(Ordering::Relaxed);
+            let _ = cache.set(image_id.clone(), width(2), image(), false).await;
+            assert!(counter.load(Ordering::Relaxed) > before);
+            assert_eq!(cache.records_count(&image_id).await, 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes_keep_limit() {
+        for policy in [
+            ImageOptionsOverflowPolicy::Rewrite,
+            ImageOptionsOverflowPolicy::Restrict,
+        ] {
+            let cache = Arc::new(cache(3, "", policy));
+            let writes = (0..32).map(|index| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    let image_id = format!("image-{}", index % 4);
+                    let _ = cache.set(image_id, width(index), image(), false).await;
+                })
+            });
+            futures_util::future::join_all(writes).await;
+
+            for index in 0..4 {
+                let image_id = format!("image-{}", index);
+                assert_eq!(cache.records_count(&image_id).await, 3);
+            }
+        }
+    }
 }
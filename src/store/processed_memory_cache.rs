@@ -1,7 +1,9 @@
-use crate::config::ImageOptionsOverflowPolicy;
+use crate::config::{ImageOptionsOverflowPolicy, MaxOptionsPerImage};
 use crate::image_ops::operations::ProcessingParams;
-use crate::store::processed_cache::ProcessedImagesCache;
+use crate::store::memory_cache::{MemoryCache, MemoryCacheOptions, memory_cache};
+use crate::store::processed_cache::{ProcessedImagesCache, PurgedVariants, VariantFilter};
 use crate::utils::background::BackgroundService;
+use crate::utils::striped_lock::StripedLock;
 use crate::utils::types::{ImageContainer, ImageId};
 use async_trait::async_trait;
 use std::collections::BTreeSet;
@@ -12,34 +14,41 @@ use tokio::sync::Mutex;
 
 /// Inmemory cache for processed images
 pub struct MemoryProcessedImageCache {
-    cache: quick_cache::sync::Cache<(ImageId, ProcessingParams), Arc<ImageContainer>>,
+    cache: MemoryCache<(ImageId, ProcessingParams), Arc<ImageContainer>>,
     cancel_chan: (
         tokio::sync::watch::Sender<bool>,
         tokio::sync::watch::Receiver<bool>,
     ),
-    max_options_per_image: NonZeroUsize,
+    max_options_per_image: MaxOptionsPerImage,
     max_options_per_image_overflow_policy: ImageOptionsOverflowPolicy,
-    cache_entries: quick_cache::sync::Cache<ImageId, BTreeSet<ProcessingParams>>,
-    write_lock: Arc<Mutex<()>>,
+    cache_entries: MemoryCache<ImageId, BTreeSet<ProcessingParams>>,
+    write_lock: StripedLock,
 }
 
 impl MemoryProcessedImageCache {
     pub fn new(
         capacity: Option<NonZeroUsize>,
-        max_options_per_image: NonZeroUsize,
+        max_options_per_image: MaxOptionsPerImage,
         max_options_per_image_overflow_policy: ImageOptionsOverflowPolicy,
+        options: &MemoryCacheOptions,
     ) -> Self {
         let capacity = capacity.unwrap_or(NonZeroUsize::new(1024).unwrap());
 
         MemoryProcessedImageCache {
-            cache: quick_cache::sync::Cache::new(capacity.into()),
+            cache: memory_cache(capacity.into(), options),
             cancel_chan: tokio::sync::watch::channel(false),
             max_options_per_image,
             max_options_per_image_overflow_policy,
-            cache_entries: quick_cache::sync::Cache::new(capacity.into()),
-            write_lock: Arc::new(Mutex::new(())),
+            cache_entries: memory_cache(capacity.into(), options),
+            write_lock: StripedLock::default(),
         }
     }
+
+    /// Use `stripes` locks of setting values instead of default count (1 serializes all sets)
+    pub fn with_lock_stripes(mut self, stripes: usize) -> Self {
+        self.write_lock = StripedLock::new(stripes);
+        self
+    }
 }
 
 #[async_trait]
@@ -52,7 +61,7 @@ impl ProcessedImagesCache for MemoryProcessedImageCache {
         self.cache.get(&(image_id, params))
     }
 
-    fn max_options_per_image(&self) -> &NonZeroUsize {
+    fn max_options_per_image(&self) -> &MaxOptionsPerImage {
         &self.max_options_per_image
     }
 
@@ -92,11 +101,11 @@ impl ProcessedImagesCache for MemoryProcessedImageCache {
         self.cache.contains_key(&(image_id.clone(), params.clone()))
     }
 
-    fn set_lock(&self) -> Arc<Mutex<()>> {
-        self.write_lock.clone()
+    fn set_lock(&self, image_id: &ImageId) -> Arc<Mutex<()>> {
+        self.write_lock.get(image_id)
     }
 
-    async fn remove(&mut self, image_id: ImageId) {
+    async fn remove(&mut self, image_id: ImageId) -> usize {
         let matched: Vec<(ImageId, ProcessingParams)> = self
             .cache
             .iter()
@@ -113,6 +122,44 @@ impl ProcessedImagesCache for MemoryProcessedImageCache {
             self.cache.remove(key);
         }
         self.cache_entries.remove(&image_id);
+        matched.len()
+    }
+
+    async fn remove_matching(&mut self, filter: VariantFilter<'_>, limit: usize) -> PurgedVariants {
+        let mut matched: Vec<(ImageId, ProcessingParams)> = Vec::new();
+        let mut complete = true;
+        for (key, _) in self.cache.iter() {
+            if !filter(&key.1) {
+                continue;
+            }
+            if matched.len() >= limit {
+                complete = false;
+                break;
+            }
+            matched.push(key);
+        }
+
+        let mut images = BTreeSet::new();
+        for key in matched.iter() {
+            self.cache.remove(key);
+            let (image_id, params) = key;
+            if let Some(mut entries) = self.cache_entries.get(image_id) {
+                entries.remove(params);
+                match entries.is_empty() {
+                    true => self.cache_entries.remove(image_id),
+                    false => {
+                        self.cache_entries.insert(image_id.clone(), entries);
+                        None
+                    }
+                };
+            }
+            images.insert(image_id);
+        }
+        PurgedVariants {
+            removed: matched.len(),
+            images: images.len(),
+            complete,
+        }
     }
 }
 
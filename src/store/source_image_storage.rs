@@ -1,28 +1,45 @@
+use crate::store::memory_cache::{MemoryCache, MemoryCacheOptions, memory_cache};
 use crate::store::persistent_store::{PersistSpace, PersistentStore};
 use crate::utils::background::BackgroundService;
 use crate::utils::types::ImageId;
 use async_trait::async_trait;
-use image::EncodableLayout;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use image::{EncodableLayout, ImageFormat};
+use log::debug;
 use postcard::to_stdvec;
+use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::Duration;
+use strum::{Display, EnumString};
+use tokio::sync::RwLock;
 use tokio::sync::watch::Receiver;
+use tokio::task::spawn_blocking;
 
 /// Storage to cache original image files, receiving from base api
 #[async_trait]
 pub trait OriginalImageStorage: BackgroundService {
     async fn get(&self, image_id: ImageId) -> Option<Arc<Vec<u8>>>;
 
-    async fn set(&mut self, image_id: ImageId, data: &Vec<u8>);
+    /// Store original. `cache_ttl` - client cache ttl (in seconds) of image, overriding global one
+    async fn set(&mut self, image_id: ImageId, data: &Vec<u8>, cache_ttl: Option<u32>);
+
+    /// Client cache ttl of image, if it was provided on storing
+    async fn cache_ttl(&self, image_id: ImageId) -> Option<u32>;
 
-    #[allow(dead_code)]
     async fn remove(&mut self, image_id: ImageId);
+
+    /// Mark image as recently used, to protect it from eviction. Returns whether image is stored
+    async fn touch(&self, image_id: ImageId) -> bool;
 }
 
 /// Storage implementation with inmemory files caching
 pub struct CachingStorage {
-    cache: quick_cache::sync::Cache<String, Arc<Vec<u8>>>,
+    /// Originals with their client cache ttls, so ttl is evicted along with its original
+    cache: MemoryCache<String, (Arc<Vec<u8>>, Option<u32>)>,
     cancel_chan: (
         tokio::sync::watch::Sender<bool>,
         tokio::sync::watch::Receiver<bool>,
@@ -30,11 +47,11 @@ pub struct CachingStorage {
 }
 
 impl CachingStorage {
-    pub fn new(capacity: Option<NonZeroUsize>) -> Self {
+    pub fn new(capacity: Option<NonZeroUsize>, options: &MemoryCacheOptions) -> Self {
         let capacity = capacity.unwrap_or(NonZeroUsize::new(256).unwrap());
 
         CachingStorage {
-            cache: quick_cache::sync::Cache::new(capacity.into()),
+            cache: memory_cache(capacity.into(), options),
             cancel_chan: tokio::sync::watch::channel(false),
         }
     }
@@ -43,16 +60,28 @@ impl CachingStorage {
 #[async_trait]
 impl OriginalImageStorage for CachingStorage {
     async fn get(&self, image_id: ImageId) -> Option<Arc<Vec<u8>>> {
-        self.cache.get(&image_id)
+        self.cache.get(&image_id).map(|(data, _)| data)
+    }
+
+    async fn set(&mut self, image_id: ImageId, data: &Vec<u8>, cache_ttl: Option<u32>) {
+        self.cache
+            .insert(image_id, (Arc::new(data.clone()), cache_ttl));
     }
 
-    async fn set(&mut self, image_id: ImageId, data: &Vec<u8>) {
-        self.cache.insert(image_id, Arc::new(data.clone()));
+    async fn cache_ttl(&self, image_id: ImageId) -> Option<u32> {
+        self.cache
+            .peek(&image_id)
+            .and_then(|(_, cache_ttl)| cache_ttl)
     }
 
     async fn remove(&mut self, image_id: ImageId) {
         self.cache.remove(&image_id);
     }
+
+    /// Cache evicts by recency, so access is enough to refresh entry
+    async fn touch(&self, image_id: ImageId) -> bool {
+        self.cache.get(&image_id).is_some()
+    }
 }
 
 #[async_trait]
@@ -73,9 +102,46 @@ impl BackgroundService for CachingStorage {
     }
 }
 
+/// Prefix of compressed originals in persistent storage. Images never start with it,
+/// so originals stored without compression are read as is
+const COMPRESSED_ORIGINAL_MAGIC: &[u8] = b"imgr-zlib\0";
+
+/// Compress original, if its format isn't compressed already and compression saves space
+fn compress_original(data: &[u8]) -> Option<Vec<u8>> {
+    let compressed_format = matches!(
+        image::guess_format(data),
+        Ok(ImageFormat::Png
+            | ImageFormat::Jpeg
+            | ImageFormat::WebP
+            | ImageFormat::Avif
+            | ImageFormat::Gif)
+    );
+    if compressed_format {
+        return None;
+    }
+    let mut encoder = ZlibEncoder::new(COMPRESSED_ORIGINAL_MAGIC.to_vec(), Compression::fast());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Original as it was stored, decompressing it if needed
+fn decompress_original(data: Vec<u8>) -> Option<Vec<u8>> {
+    let Some(compressed) = data.strip_prefix(COMPRESSED_ORIGINAL_MAGIC) else {
+        return Some(data);
+    };
+    let mut decompressed = Vec::new();
+    ZlibDecoder::new(compressed)
+        .read_to_end(&mut decompressed)
+        .ok()?;
+    Some(decompressed)
+}
+
 /// Storage implementation with disk files caching
 pub struct PersistentStorage {
     store: Arc<PersistentStore>,
+    /// Store originals of uncompressed formats (like BMP) compressed
+    compress: bool,
     cancel_chan: (
         tokio::sync::watch::Sender<bool>,
         tokio::sync::watch::Receiver<bool>,
@@ -83,9 +149,14 @@ pub struct PersistentStorage {
 }
 
 impl PersistentStorage {
-    pub fn new(store: Arc<PersistentStore>, _capacity: Option<NonZeroUsize>) -> Self {
+    pub fn new(
+        store: Arc<PersistentStore>,
+        _capacity: Option<NonZeroUsize>,
+        compress: bool,
+    ) -> Self {
         PersistentStorage {
             store,
+            compress,
             cancel_chan: tokio::sync::watch::channel(false),
         }
     }
@@ -100,21 +171,54 @@ impl OriginalImageStorage for PersistentStorage {
             None => return None,
             Some(v) => {
                 let decoded = postcard::from_bytes::<Vec<u8>>(v.as_bytes()).ok()?;
+                // compressed ones are read regardless of current setting, as it may be changed
+                let decoded = spawn_blocking(move || decompress_original(decoded))
+                    .await
+                    .unwrap()?;
                 Some(Arc::new(decoded))
             }
         }
     }
 
-    async fn set(&mut self, image_id: ImageId, data: &Vec<u8>) {
-        let encoded = to_stdvec(data).unwrap();
+    async fn set(&mut self, image_id: ImageId, data: &Vec<u8>, cache_ttl: Option<u32>) {
+        match cache_ttl {
+            Some(ttl) => {
+                let encoded = to_stdvec(&ttl).unwrap();
+                self.store
+                    .set(PersistSpace::StorageTtl, &image_id, encoded.as_slice())
+                    .await;
+            }
+            None => self.store.remove(PersistSpace::StorageTtl, &image_id).await,
+        }
+        let compressed = match self.compress {
+            true => {
+                let data = data.clone();
+                spawn_blocking(move || compress_original(&data))
+                    .await
+                    .unwrap()
+            }
+            false => None,
+        };
+        let encoded = to_stdvec(compressed.as_ref().unwrap_or(data)).unwrap();
         self.store
             .set(PersistSpace::Storage, &image_id, encoded.as_slice())
             .await;
     }
 
+    async fn cache_ttl(&self, image_id: ImageId) -> Option<u32> {
+        let v = self.store.get(PersistSpace::StorageTtl, &image_id).await?;
+        postcard::from_bytes::<u32>(v.as_bytes()).ok()
+    }
+
     async fn remove(&mut self, image_id: ImageId) {
+        self.store.remove(PersistSpace::StorageTtl, &image_id).await;
         self.store.remove(PersistSpace::Storage, &image_id).await;
     }
+
+    /// Persistent storage doesn't evict images, so only existence is checked
+    async fn touch(&self, image_id: ImageId) -> bool {
+        self.store.exists(PersistSpace::Storage, &image_id).await
+    }
 }
 
 #[async_trait]
@@ -134,3 +238,299 @@ impl BackgroundService for PersistentStorage {
         let _ = self.cancel_chan.0.send(true);
     }
 }
+
+/// When originals are written to second (slower) layer of tiered storage
+#[derive(Clone, Copy, EnumString, Display, Eq, PartialEq)]
+pub enum TieredWritePolicy {
+    /// Write to both layers at once
+    WriteThrough,
+    /// Write to first layer, second one is written in background (and on stop)
+    WriteBack,
+}
+
+type StorageLayer = RwLock<Box<dyn OriginalImageStorage + Send + Sync>>;
+
+/// Storage of two layers: fast (like in memory) and large one (like persistent).
+///
+/// Miss in first layer is read from second one and promoted into first
+pub struct TieredStorage {
+    l1: StorageLayer,
+    l2: StorageLayer,
+    write_policy: TieredWritePolicy,
+    /// Originals, not written to second layer yet (on WriteBack policy)
+    pending: HashMap<ImageId, (Arc<Vec<u8>>, Option<u32>)>,
+    cancel_chan: (
+        tokio::sync::watch::Sender<bool>,
+        tokio::sync::watch::Receiver<bool>,
+    ),
+}
+
+impl TieredStorage {
+    pub fn new(
+        l1: Box<dyn OriginalImageStorage + Send + Sync>,
+        l2: Box<dyn OriginalImageStorage + Send + Sync>,
+        write_policy: TieredWritePolicy,
+    ) -> Self {
+        TieredStorage {
+            l1: RwLock::new(l1),
+            l2: RwLock::new(l2),
+            write_policy,
+            pending: HashMap::new(),
+            cancel_chan: tokio::sync::watch::channel(false),
+        }
+    }
+
+    async fn flush_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        debug!("Writing {} originals to second layer", self.pending.len());
+        let mut l2 = self.l2.write().await;
+        for (image_id, (data, cache_ttl)) in self.pending.drain() {
+            l2.set(image_id, &data, cache_ttl).await;
+        }
+    }
+}
+
+#[async_trait]
+impl OriginalImageStorage for TieredStorage {
+    async fn get(&self, image_id: ImageId) -> Option<Arc<Vec<u8>>> {
+        if let Some(data) = self.l1.read().await.get(image_id.clone()).await {
+            return Some(data);
+        }
+        if let Some((data, _)) = self.pending.get(&image_id) {
+            return Some(data.clone());
+        }
+        let (data, cache_ttl) = {
+            let l2 = self.l2.read().await;
+            let data = l2.get(image_id.clone()).await?;
+            (data, l2.cache_ttl(image_id.clone()).await)
+        };
+        debug!("Promoting image {} into first storage layer", image_id);
+        self.l1.write().await.set(image_id, &data, cache_ttl).await;
+        Some(data)
+    }
+
+    async fn set(&mut self, image_id: ImageId, data: &Vec<u8>, cache_ttl: Option<u32>) {
+        self.l1
+            .write()
+            .await
+            .set(image_id.clone(), data, cache_ttl)
+            .await;
+        match self.write_policy {
+            TieredWritePolicy::WriteThrough => {
+                self.l2.write().await.set(image_id, data, cache_ttl).await;
+            }
+            TieredWritePolicy::WriteBack => {
+                self.pending
+                    .insert(image_id, (Arc::new(data.clone()), cache_ttl));
+            }
+        }
+    }
+
+    async fn cache_ttl(&self, image_id: ImageId) -> Option<u32> {
+        if let Some(cache_ttl) = self.l1.read().await.cache_ttl(image_id.clone()).await {
+            return Some(cache_ttl);
+        }
+        if let Some((_, cache_ttl)) = self.pending.get(&image_id) {
+            return *cache_ttl;
+        }
+        self.l2.read().await.cache_ttl(image_id).await
+    }
+
+    async fn remove(&mut self, image_id: ImageId) {
+        self.pending.remove(&image_id);
+        self.l1.write().await.remove(image_id.clone()).await;
+        self.l2.write().await.remove(image_id).await;
+    }
+
+    async fn touch(&self, image_id: ImageId) -> bool {
+        self.l1.read().await.touch(image_id.clone()).await
+            || self.pending.contains_key(&image_id)
+            || self.l2.read().await.touch(image_id).await
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TieredStorage {
+    fn background_period(&self) -> Duration {
+        Duration::new(60, 0)
+    }
+
+    async fn background(&mut self) {
+        self.flush_pending().await;
+        self.l1.write().await.background().await;
+        self.l2.write().await.background().await;
+    }
+
+    fn cancel_token(&self) -> Receiver<bool> {
+        self.cancel_chan.1.clone()
+    }
+
+    async fn stop(&mut self) {
+        self.flush_pending().await;
+        self.l1.write().await.stop().await;
+        self.l2.write().await.stop().await;
+        let _ = self.cancel_chan.0.send(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    fn storage() -> CachingStorage {
+        CachingStorage::new(
+            NonZeroUsize::new(2),
+            &MemoryCacheOptions {
+                shards: Some(1),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn touch_protects_from_eviction() {
+        for touched in ["first", "second"] {
+            let mut storage = storage();
+            storage.set("first".to_string(), &vec![1], None).await;
+            storage.set("second".to_string(), &vec![2], None).await;
+            assert!(storage.touch(touched.to_string()).await);
+            storage.set("third".to_string(), &vec![3], None).await;
+
+            assert!(
+                storage.get(touched.to_string()).await.is_some(),
+                "{}",
+                touched
+            );
+        }
+        assert!(!storage().touch("missing".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn cache_ttl_is_evicted_with_original() {
+        let mut storage = storage();
+        storage.set("first".to_string(), &vec![1], Some(30)).await;
+        assert_eq!(storage.cache_ttl("first".to_string()).await, Some(30));
+        storage.set("second".to_string(), &vec![2], None).await;
+        storage.set("third".to_string(), &vec![3], Some(60)).await;
+
+        for image_id in ["first", "second", "third"] {
+            let image_id = image_id.to_string();
+            let stored = storage.get(image_id.clone()).await.is_some();
+            let cache_ttl = storage.cache_ttl(image_id.clone()).await;
+            // ttl is kept exactly as long as its original
+            assert_eq!(
+                cache_ttl.is_some(),
+                stored && image_id != "second",
+                "{}",
+                image_id
+            );
+        }
+        assert_eq!(storage.cache_ttl("third".to_string()).await, Some(60));
+        storage.remove("third".to_string()).await;
+        assert_eq!(storage.cache_ttl("third".to_string()).await, None);
+    }
+
+    #[tokio::test]
+    async fn compressed_originals_round_trip() {
+        let path = testing::temp_path("compressed-originals");
+        let _ = std::fs::remove_dir_all(&path);
+        let capacity = NonZeroUsize::new(16).unwrap();
+        let store = Arc::new(PersistentStore::new(
+            std::path::Path::new(&path).into(),
+            capacity,
+            capacity,
+        ));
+        let mut storage = PersistentStorage::new(store.clone(), None, true);
+        let bitmap = testing::encode(&testing::gradient(64, 64), ImageFormat::Bmp);
+        let png = testing::png(64, 64);
+        let stored_bytes = async |image_id: &str| {
+            let stored = store
+                .get(PersistSpace::Storage, &image_id.to_string())
+                .await
+                .unwrap();
+            postcard::from_bytes::<Vec<u8>>(stored.as_bytes()).unwrap()
+        };
+
+        storage.set("bitmap".to_string(), &bitmap, None).await;
+        storage.set("png".to_string(), &png, None).await;
+        let stored = stored_bytes("bitmap").await;
+        assert!(stored.starts_with(COMPRESSED_ORIGINAL_MAGIC));
+        assert!(stored.len() < bitmap.len());
+        // already compressed formats are stored as is
+        assert_eq!(stored_bytes("png").await, png);
+
+        // compressed ones are readable after disabling compression
+        for storage in [storage, PersistentStorage::new(store.clone(), None, false)] {
+            assert!(storage.get("bitmap".to_string()).await.unwrap().as_slice() == bitmap);
+            assert!(storage.get("png".to_string()).await.unwrap().as_slice() == png);
+        }
+    }
+
+    fn tiered(write_policy: TieredWritePolicy) -> TieredStorage {
+        TieredStorage::new(Box::new(storage()), Box::new(storage()), write_policy)
+    }
+
+    #[tokio::test]
+    async fn second_layer_original_is_promoted() {
+        let storage = tiered(TieredWritePolicy::WriteThrough);
+        let image_id = "cold".to_string();
+        storage
+            .l2
+            .write()
+            .await
+            .set(image_id.clone(), &vec![1, 2], Some(60))
+            .await;
+        assert!(
+            storage
+                .l1
+                .read()
+                .await
+                .get(image_id.clone())
+                .await
+                .is_none()
+        );
+
+        assert_eq!(
+            storage.get(image_id.clone()).await.unwrap().as_slice(),
+            [1, 2]
+        );
+        let l1 = storage.l1.read().await;
+        assert_eq!(l1.get(image_id.clone()).await.unwrap().as_slice(), [1, 2]);
+        assert_eq!(l1.cache_ttl(image_id).await, Some(60));
+    }
+
+    #[tokio::test]
+    async fn second_layer_is_written_by_policy() {
+        let image_id = "hot".to_string();
+        for (write_policy, written_at_once) in [
+            (TieredWritePolicy::WriteThrough, true),
+            (TieredWritePolicy::WriteBack, false),
+        ] {
+            let mut storage = tiered(write_policy);
+            storage.set(image_id.clone(), &vec![3], None).await;
+            let in_l2 = storage
+                .l2
+                .read()
+                .await
+                .get(image_id.clone())
+                .await
+                .is_some();
+            assert_eq!(in_l2, written_at_once, "{}", write_policy);
+            assert!(storage.get(image_id.clone()).await.is_some());
+
+            storage.background().await;
+            assert!(
+                storage
+                    .l2
+                    .read()
+                    .await
+                    .get(image_id.clone())
+                    .await
+                    .is_some()
+            );
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// Deduplication of concurrent operations by key
+///
+/// Callers with the same key, arriving while operation is in flight, wait for it and share its result.
+/// Result is not memoized: after completion, next call with the key runs operation again
+pub struct Coalescer<K, V> {
+    in_flight: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Coalescer<K, V> {
+    pub fn new() -> Self {
+        Coalescer {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, operation: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        // if leader is cancelled, one of waiters runs its own operation instead
+        let result = cell.get_or_init(operation).await.clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(&key);
+        }
+        result
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Default for Coalescer<K, V> {
+    fn default() -> Self {
+        Coalescer::new()
+    }
+}
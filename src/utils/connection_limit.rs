@@ -0,0 +1,139 @@
+use axum::serve::Listener;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Listener, which stops accepting on reaching max count of open connections. Excess connections wait
+/// in the kernel backlog, until one of open connections is closed
+pub struct LimitedListener<L> {
+    listener: L,
+    /// Permits of open connections. None is unlimited
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl<L: Listener> LimitedListener<L> {
+    pub fn new(listener: L, max_connections: Option<usize>) -> Self {
+        LimitedListener {
+            listener,
+            connections: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+}
+
+impl<L: Listener> Listener for LimitedListener<L> {
+    type Io = LimitedIo<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // permit is acquired before accept, so connections over limit are not even taken from backlog
+        let permit = match &self.connections {
+            Some(connections) => Some(
+                connections
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Connections semaphore is never closed"),
+            ),
+            None => None,
+        };
+        let (io, addr) = self.listener.accept().await;
+        (
+            LimitedIo {
+                io,
+                _permit: permit,
+            },
+            addr,
+        )
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Connection, holding its permit until closed
+pub struct LimitedIo<I> {
+    io: I,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for LimitedIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for LimitedIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn open_connections_are_capped() {
+        let mut listener =
+            LimitedListener::new(TcpListener::bind("127.0.0.1:0").await.unwrap(), Some(2));
+        let addr = listener.local_addr().unwrap();
+        // excess clients are connected by kernel backlog, but not accepted
+        let mut clients = Vec::new();
+        for _ in 0..4 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let accept = Duration::from_millis(100);
+
+        let first = listener.accept().await.0;
+        let _second = listener.accept().await.0;
+        assert!(
+            tokio::time::timeout(accept, listener.accept())
+                .await
+                .is_err()
+        );
+
+        drop(first);
+        let _third = tokio::time::timeout(accept, listener.accept())
+            .await
+            .expect("closed connection frees its slot");
+        assert!(
+            tokio::time::timeout(accept, listener.accept())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,3 +1,8 @@
 pub mod background;
+pub mod coalescer;
+pub mod connection_limit;
 pub mod filename_extractor;
+pub mod striped_lock;
+#[cfg(test)]
+pub mod testing;
 pub mod types;
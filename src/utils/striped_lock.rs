@@ -0,0 +1,62 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Default count of stripes, enough to make collisions of concurrently written images rare
+pub const DEFAULT_STRIPES: usize = 64;
+
+/// Set of locks, selected by key hash
+///
+/// Operations over the same key are serialized, while different keys mostly proceed concurrently
+pub struct StripedLock {
+    stripes: Vec<Arc<Mutex<()>>>,
+}
+
+impl StripedLock {
+    pub fn new(stripes: usize) -> Self {
+        StripedLock {
+            stripes: (0..stripes.max(1))
+                .map(|_| Arc::new(Mutex::new(())))
+                .collect(),
+        }
+    }
+
+    pub fn get<K: Hash + ?Sized>(&self, key: &K) -> Arc<Mutex<()>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = (hasher.finish() % self.stripes.len() as u64) as usize;
+        self.stripes[idx].clone()
+    }
+}
+
+impl Default for StripedLock {
+    fn default() -> Self {
+        StripedLock::new(DEFAULT_STRIPES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_key_shares_lock() {
+        let locks = StripedLock::default();
+        assert!(Arc::ptr_eq(&locks.get("image"), &locks.get("image")));
+    }
+
+    #[test]
+    fn different_keys_are_locked_independently() {
+        let locks = StripedLock::default();
+        let first = locks.get("image-0");
+        let other = (1..)
+            .map(|index| locks.get(&format!("image-{}", index)))
+            .find(|lock| !Arc::ptr_eq(lock, &first))
+            .unwrap();
+
+        let _guard = first.try_lock().unwrap();
+        assert!(other.try_lock().is_ok());
+        assert!(locks.get("image-0").try_lock().is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! Helpers of tests: config without env, sample images, running server and captured logs
+use crate::config::Config;
+use crate::image_ops::operations::ProcessingParams;
+use axum::extract::Query;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, ImageFormat, Rgba, RgbaImage};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+/// Api key of test configs, sent by [`request`]
+pub const API_KEY: &str = "test-key";
+
+/// In-memory config with defaults, overridden by `vars`
+pub fn config(vars: &[(&str, &str)]) -> Config {
+    Config::from_vars(
+        [("API_KEY", API_KEY)]
+            .iter()
+            .chain(vars)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    )
+    .unwrap_or_else(|problems| panic!("Invalid test config: {:?}", problems))
+}
+
+/// Run app with `config` on random local port, returning its base url
+pub async fn serve(config: Config) -> String {
+    serve_router(crate::app_init(Arc::new(config), true)).await
+}
+
+/// Run any router (like mock of base api) on random local port, returning its base url
+pub async fn serve_router(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    format!("http://{}", addr)
+}
+
+/// Store original, as if it was preloaded
+pub async fn preload(config: &Config, image_id: &str, data: Vec<u8>) {
+    config
+        .processor
+        .prefetch(image_id.to_string(), String::new(), data, None)
+        .await
+        .ok()
+        .expect("Failed to preload image");
+}
+
+/// Path in temp dir, unique for test process
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("imgr-test-{}-{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Write `data` into file of temp dir, returning its path
+pub fn temp_file(name: &str, data: &[u8]) -> String {
+    let path = temp_path(name);
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+/// Base api, responding with 404 to everything
+pub async fn missing_origin() -> String {
+    serve_router(axum::Router::new().fallback(|| async { axum::http::StatusCode::NOT_FOUND })).await
+}
+
+/// Base api, serving `data` for any image after `delay`, returning its url and counter of requests
+pub async fn counting_origin(data: Vec<u8>, delay: Duration) -> (String, Arc<AtomicUsize>) {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let router = axum::Router::new().fallback(move || {
+        let data = data.clone();
+        counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            tokio::time::sleep(delay).await;
+            data
+        }
+    });
+    (serve_router(router).await, requests)
+}
+
+/// Send GET request to `url`
+pub async fn get(url: String) -> reqwest::Response {
+    client().get(url).send().await.unwrap()
+}
+
+/// Authorized request
+pub fn request(method: reqwest::Method, url: String) -> reqwest::RequestBuilder {
+    client().request(method, url).header("X-API-Key", API_KEY)
+}
+
+/// Authorized POST request with json body
+pub async fn post_json(url: String, body: serde_json::Value) -> reqwest::Response {
+    request(reqwest::Method::POST, url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .unwrap()
+}
+
+/// Body of json response
+pub async fn json(response: reqwest::Response) -> serde_json::Value {
+    serde_json::from_slice(&response.bytes().await.unwrap()).unwrap()
+}
+
+/// Processing params, parsed from query string like in requests
+pub fn params(query: &str) -> ProcessingParams {
+    let uri = format!("/?{}", query).parse().unwrap();
+    Query::<ProcessingParams>::try_from_uri(&uri).unwrap().0
+}
+
+/// Http client, ignoring proxies of environment
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder().no_proxy().build().unwrap()
+}
+
+/// Gradient image, so resized and cropped versions differ from each other
+pub fn gradient(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+        Rgba([(x * 255 / width) as u8, (y * 255 / height) as u8, 128, 255])
+    }))
+}
+
+/// Encode image into `format`
+pub fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, format).unwrap();
+    data.into_inner()
+}
+
+/// Gradient PNG of given size
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    encode(&gradient(width, height), ImageFormat::Png)
+}
+
+/// Gradient JPEG of given (stored) size with EXIF `orientation` tag
+pub fn oriented_jpeg(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+    let jpeg = encode(
+        &DynamicImage::ImageRgb8(gradient(width, height).to_rgb8()),
+        ImageFormat::Jpeg,
+    );
+    // big endian tiff with single IFD entry: orientation (0x0112) of SHORT type
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0; 6]);
+    let mut data = jpeg[..2].to_vec();
+    data.extend_from_slice(&[0xff, 0xe1]);
+    data.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    data.extend_from_slice(&exif);
+    data.extend_from_slice(&jpeg[2..]);
+    data
+}
+
+/// Gradient PNG of given (stored) size with EXIF `orientation` tag (`eXIf` chunk)
+pub fn oriented_png(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+    let png = png(width, height);
+    let mut chunk = b"eXIfMM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    chunk.extend_from_slice(&orientation.to_be_bytes());
+    chunk.extend_from_slice(&[0; 6]);
+    let mut crc = flate2::Crc::new();
+    crc.update(&chunk);
+    // after signature and IHDR chunk
+    let mut data = png[..33].to_vec();
+    data.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+    data.extend_from_slice(&chunk);
+    data.extend_from_slice(&crc.sum().to_be_bytes());
+    data.extend_from_slice(&png[33..]);
+    data
+}
+
+/// Gif with `count` frames of solid colors (red, green, blue, ...), 100ms each
+pub fn animated_gif(count: u32, width: u32, height: u32) -> Vec<u8> {
+    let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+    let mut data = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut data);
+        encoder.set_repeat(Repeat::Infinite).unwrap();
+        for index in 0..count {
+            let color = Rgba(colors[index as usize % colors.len()]);
+            encoder
+                .encode_frame(Frame::from_parts(
+                    RgbaImage::from_pixel(width, height, color),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                ))
+                .unwrap();
+        }
+    }
+    data
+}
+
+static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            LOGS.lock()
+                .unwrap()
+                .push(format!("{} {}", record.target(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Start capturing info (and more severe) log lines of all tests
+pub fn capture_logs() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CapturingLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+    });
+}
+
+/// Captured log lines (prefixed with target), containing `pattern`
+pub fn logged(pattern: &str) -> Vec<String> {
+    LOGS.lock()
+        .unwrap()
+        .iter()
+        .filter(|line| line.contains(pattern))
+        .cloned()
+        .collect()
+}
@@ -1,4 +1,6 @@
 use crate::image_ops::image_types::Extensions;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
 use serde::{Deserialize, Serialize};
 /// it may be uuid, or complex link with path, either will work as simple string
 pub type ImageId = String;
@@ -8,6 +10,10 @@ pub struct ImageContainer {
     pub data: Box<Vec<u8>>,
     pub filename: Option<String>,
     pub extension: Extensions,
+    /// Base64 sha-256 of data, computed once on encoding (if enabled)
+    pub digest: Option<String>,
+    /// Width and height of encoded image, known on encoding
+    pub dimensions: Option<(u32, u32)>,
 }
 
 impl ImageContainer {
@@ -16,6 +22,23 @@ impl ImageContainer {
             data,
             filename,
             extension,
+            digest: None,
+            dimensions: None,
         }
     }
+
+    pub fn with_dimensions(mut self, dimensions: Option<(u32, u32)>) -> Self {
+        self.dimensions = dimensions;
+        self
+    }
+
+    /// Base64 sha-256 of data
+    pub fn compute_digest(&self) -> String {
+        BASE64_STANDARD.encode(ring::digest::digest(&ring::digest::SHA256, &self.data))
+    }
+
+    pub fn with_digest(mut self) -> Self {
+        self.digest = Some(self.compute_digest());
+        self
+    }
 }
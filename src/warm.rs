@@ -0,0 +1,144 @@
+use crate::config::Config;
+use crate::image_ops::operations::ProcessingParams;
+use crate::proxying_images::FetchOptions;
+use crate::routes::images::prepare_processing_params;
+use crate::utils::types::ImageId;
+use axum::extract::Query;
+use axum::http::Uri;
+use log::{info, warn};
+
+/// Parse processing params query, like `w=320&fmt=Avif`
+pub fn parse_params(query: &str) -> Result<ProcessingParams, String> {
+    let uri: Uri = format!("/?{}", query)
+        .parse()
+        .map_err(|err| format!("Invalid query: {}", err))?;
+    let Query(params) =
+        Query::<ProcessingParams>::try_from_uri(&uri).map_err(|err| err.body_text())?;
+    Ok(params)
+}
+
+/// Parse manifest line `<image_id>[?<processing params query>]`, like `photo.jpg?w=320&fmt=Avif`
+fn parse_entry(line: &str) -> Result<(String, ProcessingParams), String> {
+    let (image_id, query) = line.split_once('?').unwrap_or((line, ""));
+    if image_id.is_empty() {
+        return Err("Image id is empty".to_string());
+    }
+    Ok((image_id.to_string(), parse_params(query)?))
+}
+
+/// Process image variant like `/images/{id}` request does, populating caches. Returns cache status
+pub async fn warm_variant(
+    config: &Config,
+    image_id: ImageId,
+    mut params: ProcessingParams,
+) -> Result<String, String> {
+    prepare_processing_params(&mut params, config).map_err(|errors| {
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ")
+    })?;
+    config.clamp_quality(&mut params);
+    let served = config
+        .processor
+        .get(image_id, params, FetchOptions::default(), false)
+        .await
+        .map_err(|err| err.detail)?;
+    match served.is_fallback {
+        true => Err("Image is not found, fallback is served".to_string()),
+        false => Ok(served.cache_status.to_string()),
+    }
+}
+
+/// Populate caches with images of manifest, by running processing pipeline directly (without http).
+///
+/// Manifest contains one image variant per line, empty lines and `#` comments are skipped.
+/// Processed images outlive the run only with `PROCESSING_CACHE_IMPLEMENTATION=Persistent`.
+/// Returns whether all entries were warmed
+pub async fn warm(config: &Config, manifest_path: &str) -> bool {
+    let manifest = match std::fs::read_to_string(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            warn!("Failed to read manifest {}: {}", manifest_path, err);
+            return false;
+        }
+    };
+    let entries: Vec<&str> = manifest
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    let mut failed = 0;
+    for (idx, line) in entries.iter().enumerate() {
+        let result = match parse_entry(line) {
+            Ok((image_id, params)) => {
+                warm_variant(config, config.normalize_image_id(image_id), params).await
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(cache_status) => info!(
+                "[{}/{}] Warmed {} ({})",
+                idx + 1,
+                entries.len(),
+                line,
+                cache_status
+            ),
+            Err(err) => {
+                failed += 1;
+                warn!("[{}/{}] Failed {}: {}", idx + 1, entries.len(), line, err);
+            }
+        }
+    }
+
+    for service in config.processor.get_background_services() {
+        service.write().await.stop().await;
+    }
+    info!(
+        "Warmed {} of {} entries, {} failed",
+        entries.len() - failed,
+        entries.len(),
+        failed
+    );
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_ops::processing::CacheStatus;
+    use crate::utils::testing;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn warmed_variants_are_stored() {
+        let (origin, _) = testing::counting_origin(testing::png(40, 20), Duration::ZERO).await;
+        let dir = testing::temp_path("warm-store");
+        let config = || {
+            testing::config(&[
+                ("BASE_FILE_API_URL", &origin),
+                ("ALLOW_PRIVATE_ORIGIN_IPS", "true"),
+                ("STORAGE_IMPLEMENTATION", "Persistent"),
+                ("PROCESSING_CACHE_IMPLEMENTATION", "Persistent"),
+                ("PERSISTENT_STORAGE_DIR", &dir),
+            ])
+        };
+        let manifest = testing::temp_file(
+            "warm-manifest",
+            b"# thumbnails\nphoto.png?w=10&fmt=PNG\n\nphoto.png?w=20&fmt=PNG\nphoto.png?w=0x\n",
+        );
+
+        // invalid entry fails the run, but doesn't stop other ones
+        assert!(!warm(&config(), &manifest).await);
+
+        let config = config();
+        for width in [10, 20] {
+            let params = parse_params(&format!("w={}&fmt=PNG", width)).unwrap();
+            let cache_status = warm_variant(&config, "photo.png".to_string(), params).await;
+            assert_eq!(cache_status, Ok(CacheStatus::Hit.to_string()), "{}", width);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}